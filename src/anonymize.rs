@@ -0,0 +1,262 @@
+//! Anonymization of captures, so that they can be shared without revealing
+//! data from the devices in them.
+//!
+//! String descriptors, which include serial numbers, are replaced with
+//! text of the same length, except for the list of languages. The data of
+//! class and vendor control requests, and all data on other endpoints, is
+//! replaced with zeros. Other standard requests and descriptors are kept,
+//! so that the anonymized capture decodes to the same structure. Packets
+//! keep their lengths and timestamps, and the CRC of every modified data
+//! packet is recalculated.
+
+use thiserror::Error;
+
+use crate::input::InputError;
+use crate::usb::{
+    data_crc,
+    DescriptorType,
+    PacketFields,
+    PID,
+    RequestType,
+    SetupFields,
+    StandardRequest,
+};
+
+#[derive(Error, Debug)]
+pub enum AnonymizeError {
+    #[error(transparent)]
+    InputError(#[from] InputError),
+    #[error("failed to write {filename}: {error}")]
+    OutputError { filename: String, error: pcap::Error },
+}
+
+/// What to do with the data stage of a control request.
+#[derive(Copy, Clone, PartialEq)]
+enum DataStage {
+    Keep,
+    String,
+    Zero,
+}
+
+impl DataStage {
+    fn for_request(fields: &SetupFields) -> DataStage {
+        match (fields.type_fields.request_type(),
+               StandardRequest::from(fields.request))
+        {
+            (RequestType::Standard, StandardRequest::GetDescriptor) |
+            (RequestType::Standard, StandardRequest::SetDescriptor) =>
+                match DescriptorType::from((fields.value >> 8) as u8) {
+                    DescriptorType::String if fields.value & 0xFF != 0 =>
+                        DataStage::String,
+                    _ => DataStage::Keep,
+                },
+            (RequestType::Standard, _) => DataStage::Keep,
+            _ => DataStage::Zero,
+        }
+    }
+}
+
+/// The control request in progress on a device's default endpoint.
+#[derive(Copy, Clone)]
+struct ControlState {
+    stage: DataStage,
+    /// Bytes of the data stage seen so far.
+    offset: usize,
+    /// PID and starting offset of the last data packet. A data packet with
+    /// the same PID is a retry of it, so starts at the same offset.
+    last: Option<(PID, usize)>,
+}
+
+/// Anonymizes a stream of packets, following enough of the traffic to
+/// know what each data packet carries.
+pub struct Anonymizer {
+    /// The last token seen, as PID, address and endpoint number.
+    token: Option<(PID, u8, u8)>,
+    control: Vec<ControlState>,
+    /// Number of packets modified.
+    pub modified: u64,
+}
+
+impl Default for Anonymizer {
+    fn default() -> Self {
+        Anonymizer::new()
+    }
+}
+
+impl Anonymizer {
+    pub fn new() -> Self {
+        Anonymizer {
+            token: None,
+            control: vec![ControlState {
+                stage: DataStage::Zero,
+                offset: 0,
+                last: None,
+            }; 128],
+            modified: 0,
+        }
+    }
+
+    /// Anonymize one packet in place.
+    pub fn packet(&mut self, packet: &mut [u8]) {
+        if packet.is_empty() {
+            return;
+        }
+        let pid = PID::from(packet[0]);
+        match PacketFields::from_packet(packet) {
+            PacketFields::Token(token) if packet.len() >= 3 => {
+                self.token = Some(
+                    (pid, token.device_address(), token.endpoint_number()));
+                return;
+            },
+            PacketFields::Data(_) if packet.len() >= 3 => {},
+            _ => return,
+        }
+        let end = packet.len() - 2;
+        let original = packet.to_vec();
+        match self.token.take() {
+            Some((PID::SETUP, address, 0)) if packet.len() == 11 => {
+                let fields = SetupFields::from_data_packet(packet);
+                self.control[address as usize] = ControlState {
+                    stage: DataStage::for_request(&fields),
+                    offset: 0,
+                    last: None,
+                };
+            },
+            Some((PID::IN | PID::OUT, address, 0)) => {
+                let state = &mut self.control[address as usize];
+                let start = match state.last {
+                    Some((last, start)) if last == pid => start,
+                    _ => state.offset,
+                };
+                state.offset = start + end - 1;
+                state.last = Some((pid, start));
+                match state.stage {
+                    DataStage::Keep => {},
+                    DataStage::String => {
+                        for (i, byte) in packet[1..end].iter_mut().enumerate()
+                        {
+                            let offset = start + i;
+                            // Keep the length and type, and make the text
+                            // a string of 'x' characters in UTF-16.
+                            if offset >= 2 {
+                                *byte = if offset & 1 == 0 { b'x' } else { 0 };
+                            }
+                        }
+                    },
+                    DataStage::Zero => packet[1..end].fill(0),
+                }
+            },
+            _ => packet[1..end].fill(0),
+        }
+        if *packet != original[..] {
+            let crc = data_crc(&packet[1..end]);
+            packet[end..].copy_from_slice(&crc.to_le_bytes());
+            self.modified += 1;
+        }
+    }
+}
+
+/// Write an anonymized copy of a capture file. Returns the number of
+/// packets modified, and the total number of packets.
+pub fn anonymize_file(input: &str, output: &str)
+    -> Result<(u64, u64), AnonymizeError>
+{
+    let input_error = |error: pcap::Error| InputError {
+        filename: input.to_string(),
+        error: error.into(),
+    };
+    let mut pcap = pcap::Capture::from_file(input).map_err(input_error)?;
+    let mut savefile = pcap.savefile(output)
+        .map_err(|error| AnonymizeError::OutputError {
+            filename: output.to_string(),
+            error: error,
+        })?;
+    let mut anonymizer = Anonymizer::new();
+    let mut count = 0;
+    while let Ok(packet) = pcap.next() {
+        let mut data = packet.data.to_vec();
+        anonymizer.packet(&mut data);
+        savefile.write(&pcap::Packet {
+            header: packet.header,
+            data: &data,
+        });
+        count += 1;
+    }
+    savefile.flush().map_err(|error| AnonymizeError::OutputError {
+        filename: output.to_string(),
+        error: error,
+    })?;
+    Ok((anonymizer.modified, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Capture;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    fn data_packet(pid: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![pid];
+        packet.extend_from_slice(payload);
+        packet.extend_from_slice(&data_crc(payload).to_le_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_anonymize_packets() {
+        let mut anonymizer = Anonymizer::new();
+        let mut packets = vec![
+            // GET_DESCRIPTOR for string 3, on device 0.
+            vec![0x2d, 0x00, 0x10],
+            data_packet(0xc3, &[0x80, 0x06, 0x03, 0x03, 0x09, 0x04,
+                                0xff, 0x00]),
+            vec![0xd2],
+            // The descriptor, "AB", sent twice as the first ACK was lost.
+            vec![0x69, 0x00, 0x10],
+            data_packet(0x4b, &[6, 3, 0x41, 0, 0x42, 0]),
+            vec![0x69, 0x00, 0x10],
+            data_packet(0x4b, &[6, 3, 0x41, 0, 0x42, 0]),
+            vec![0xd2],
+            // Interrupt data on endpoint 1.
+            vec![0x69, 0x80, 0x58],
+            data_packet(0xc3, &[0x01, 0x02, 0x03]),
+            vec![0xd2],
+        ];
+        let original = packets.clone();
+        for packet in packets.iter_mut() {
+            anonymizer.packet(packet);
+        }
+        assert!(anonymizer.modified == 3);
+        assert!(packets[..4] == original[..4]);
+        assert!(packets[4] == data_packet(0x4b, &[6, 3, b'x', 0, b'x', 0]));
+        assert!(packets[6] == packets[4]);
+        assert!(packets[9] == data_packet(0xc3, &[0, 0, 0]));
+        assert!(packets.iter().zip(original.iter())
+            .all(|(a, b)| a.len() == b.len()));
+    }
+
+    #[test]
+    fn test_anonymize_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("anonymized.pcap");
+        let output = output.to_str().unwrap();
+        let (modified, count) =
+            anonymize_file("./tests/mouse/capture.pcap", output).unwrap();
+        assert!(modified > 0 && modified < count);
+        let decode = |path| {
+            let mut cap = Capture::new();
+            let mut decoder = Decoder::new(&mut cap);
+            decode_files(&mut decoder, &[path]).unwrap();
+            drop(decoder);
+            cap
+        };
+        let mut original = decode("./tests/mouse/capture.pcap");
+        let mut anonymized = decode(output);
+        crate::verify::compare(&mut original, &mut anonymized).unwrap();
+        let strings = &anonymized.device_data.last().unwrap().strings;
+        let product = strings[2].as_ref().unwrap();
+        assert!(!product.is_empty());
+        assert!(product.chunks(2).all(|c| c == [b'x', 0]));
+    }
+}
@@ -0,0 +1,479 @@
+//! Export of USB Audio Class streams to WAV files.
+//!
+//! The sample format of each streaming interface is found from its
+//! class-specific descriptors, and the sample rate from those descriptors
+//! or from the requests which set it. The isochronous payloads on the
+//! stream's endpoint are then joined together as the audio data.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::capture::Capture;
+use crate::usb::{
+    Interface,
+    PID,
+    Recipient,
+    RequestType,
+    StandardRequest,
+};
+
+const AUDIO_CLASS: u8 = 0x01;
+const AUDIO_STREAMING: u8 = 0x02;
+const UAC2_PROTOCOL: u8 = 0x20;
+
+const CS_INTERFACE: u8 = 0x24;
+const AS_GENERAL: u8 = 0x01;
+const FORMAT_TYPE: u8 = 0x02;
+const FORMAT_TYPE_I: u8 = 0x01;
+
+const SET_CUR: u8 = 0x01;
+const GET_CUR: u8 = 0x81;
+const SAMPLING_FREQ_CONTROL: u8 = 0x01;
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+
+#[derive(Error, Debug)]
+pub enum AudioError {
+    #[error("no audio streams found in the capture")]
+    NoStreams,
+    #[error("the sample rate of the audio streams was not found")]
+    UnknownSampleRate,
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// The sample format of an audio stream.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AudioFormat {
+    pub float: bool,
+    pub channels: u8,
+    /// Bytes used for each sample of each channel.
+    pub subslot_size: u8,
+    /// Bits of each sample actually used.
+    pub bit_resolution: u8,
+    pub sample_rate: Option<u32>,
+}
+
+impl AudioFormat {
+    /// Read the format from the class-specific descriptors of a streaming
+    /// interface, if it is a supported Type I format.
+    pub fn from_descriptors(version2: bool, descriptors: &[Vec<u8>])
+        -> Option<AudioFormat>
+    {
+        let find = |subtype| descriptors.iter().find(|desc|
+            desc.len() >= 3 && desc[1] == CS_INTERFACE && desc[2] == subtype);
+        let general = find(AS_GENERAL)?;
+        let format = find(FORMAT_TYPE)?;
+        if format.len() < 4 || format[3] != FORMAT_TYPE_I {
+            return None;
+        }
+        if version2 {
+            if general.len() < 11 || format.len() < 6 {
+                return None;
+            }
+            let formats =
+                u32::from_le_bytes(general[6..10].try_into().unwrap());
+            Some(AudioFormat {
+                float: match formats {
+                    f if f & 0x01 != 0 => false,
+                    f if f & 0x04 != 0 => true,
+                    _ => return None,
+                },
+                channels: general[10],
+                subslot_size: format[4],
+                bit_resolution: format[5],
+                sample_rate: None,
+            })
+        } else {
+            if general.len() < 7 || format.len() < 8 {
+                return None;
+            }
+            let format_tag = u16::from_le_bytes([general[5], general[6]]);
+            // A single discrete rate is fixed by the descriptor.
+            let sample_rate = match (format[7], format.get(8..11)) {
+                (1, Some(rate)) => Some(read_u24(rate)),
+                _ => None,
+            };
+            Some(AudioFormat {
+                float: match format_tag {
+                    WAVE_FORMAT_PCM => false,
+                    WAVE_FORMAT_IEEE_FLOAT => true,
+                    _ => return None,
+                },
+                channels: format[4],
+                subslot_size: format[5],
+                bit_resolution: format[6],
+                sample_rate: sample_rate,
+            })
+        }
+    }
+
+    pub fn description(&self) -> String {
+        format!("{} channel{}, {}-bit {}{}",
+            self.channels,
+            if self.channels == 1 {""} else {"s"},
+            self.bit_resolution,
+            if self.float {"float"} else {"PCM"},
+            match self.sample_rate {
+                Some(rate) => format!(" at {} Hz", rate),
+                None => ", unknown sample rate".to_string(),
+            })
+    }
+
+    fn block_size(&self) -> usize {
+        self.channels as usize * self.subslot_size as usize
+    }
+}
+
+fn read_u24(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])
+}
+
+/// An audio stream on an isochronous endpoint.
+pub struct AudioStream {
+    pub device_id: u64,
+    pub address: u8,
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub endpoint_address: u8,
+    pub format: AudioFormat,
+}
+
+impl AudioStream {
+    pub fn summary(&self) -> String {
+        format!("Device {}, interface {} alternate {}, endpoint {} {}: {}",
+            self.address, self.interface_number, self.alternate_setting,
+            self.endpoint_address & 0x0F,
+            if self.endpoint_address & 0x80 != 0 {"IN"} else {"OUT"},
+            self.format.description())
+    }
+
+    /// The payloads sent on the stream's endpoint, read from the capture
+    /// as they are taken.
+    pub fn payloads<'c>(&self, cap: &'c mut Capture)
+        -> impl Iterator<Item=Vec<u8>> + 'c
+    {
+        let pid = if self.endpoint_address & 0x80 != 0 {
+            PID::IN
+        } else {
+            PID::OUT
+        };
+        let address = self.endpoint_address;
+        cap.get_device_transaction_ids(self.device_id, &[address])
+            .into_iter()
+            .filter_map(move |id| match cap.get_payload(id) {
+                Some((token, payload)) if token == pid => Some(payload),
+                _ => None
+            })
+    }
+}
+
+/// Settings made by control requests to one device.
+#[derive(Default)]
+struct DeviceSettings {
+    /// Alternate settings selected, by interface number.
+    alternates: BTreeMap<u8, u8>,
+    /// Sample rates set on audio class 1 endpoints, by endpoint address.
+    endpoint_rates: BTreeMap<u8, u32>,
+    /// Last clock frequency set or read on an audio class 2 device.
+    clock_rate: Option<u32>,
+}
+
+/// Find the settings made by the control requests in a capture.
+fn find_settings(cap: &mut Capture) -> BTreeMap<u64, DeviceSettings> {
+    let mut settings: BTreeMap<u64, DeviceSettings> = BTreeMap::new();
+    for item_index in 0..cap.item_index.len() {
+        let transfer_index_id = cap.item_index.get(item_index).unwrap();
+        let transfer = match cap.control_transfer(transfer_index_id) {
+            Some(transfer) => transfer,
+            None => continue,
+        };
+        let entry = cap.transfer_index.get(transfer_index_id).unwrap();
+        let endpoint = cap.endpoints.get(entry.endpoint_id() as u64).unwrap();
+        let device = settings.entry(endpoint.device_id()).or_default();
+        let fields = &transfer.fields;
+        let data = &transfer.data;
+        let control = (fields.value >> 8) as u8;
+        match (fields.type_fields.request_type(),
+               fields.type_fields.recipient())
+        {
+            (RequestType::Standard, Recipient::Interface) => {
+                if let StandardRequest::SetInterface =
+                    StandardRequest::from(fields.request)
+                {
+                    device.alternates.insert(fields.index as u8,
+                                             fields.value as u8);
+                }
+            },
+            (RequestType::Class, recipient)
+                if control == SAMPLING_FREQ_CONTROL &&
+                    (fields.request == SET_CUR || fields.request == GET_CUR) =>
+            {
+                match (recipient, data.len()) {
+                    (Recipient::Endpoint, 3) => {
+                        device.endpoint_rates.insert(fields.index as u8,
+                                                     read_u24(data));
+                    },
+                    (Recipient::Interface, 4) => {
+                        device.clock_rate = Some(u32::from_le_bytes(
+                            data[0..4].try_into().unwrap()));
+                    },
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+    }
+    settings
+}
+
+/// Find the isochronous data endpoint of a streaming interface, skipping
+/// any feedback endpoint.
+fn data_endpoint(iface: &Interface) -> Option<u8> {
+    iface.endpoint_descriptors.iter()
+        .find(|ep_desc| ep_desc.attributes & 0x03 == 0x01 &&
+                        (ep_desc.attributes >> 4) & 0x03 != 0x01)
+        .map(|ep_desc| ep_desc.endpoint_address)
+}
+
+/// Find the audio streams in the capture.
+///
+/// Where an interface has several alternate settings, the one last
+/// selected by a SET_INTERFACE request is used, or otherwise the first
+/// with a supported format.
+pub fn find_audio_streams(cap: &mut Capture) -> Vec<AudioStream> {
+    profile!("find_audio_streams");
+    let settings = find_settings(cap);
+    let no_settings = DeviceSettings::default();
+    let mut streams = Vec::new();
+    for device_id in 0..cap.device_data.len() {
+        let config = match cap.device_data[device_id].configuration() {
+            Some(config) => config,
+            None => continue,
+        };
+        let device = settings.get(&(device_id as u64)).unwrap_or(&no_settings);
+        let mut found: BTreeMap<u8, AudioStream> = BTreeMap::new();
+        for iface in &config.interfaces {
+            let desc = &iface.descriptor;
+            if desc.interface_class != AUDIO_CLASS ||
+                desc.interface_subclass != AUDIO_STREAMING
+            {
+                continue;
+            }
+            let number = desc.interface_number;
+            let selected = device.alternates.get(&number);
+            let wanted = selected.is_none() ||
+                selected == Some(&desc.alternate_setting);
+            if !wanted || found.contains_key(&number) {
+                continue;
+            }
+            let version2 = desc.interface_protocol == UAC2_PROTOCOL;
+            let format = AudioFormat::from_descriptors(
+                version2, &iface.other_descriptors);
+            let (mut format, endpoint_address) =
+                match (format, data_endpoint(iface)) {
+                    (Some(format), Some(address)) => (format, address),
+                    _ => continue,
+                };
+            let requested = if version2 {
+                device.clock_rate
+            } else {
+                device.endpoint_rates.get(&endpoint_address).cloned()
+            };
+            format.sample_rate = requested.or(format.sample_rate);
+            found.insert(number, AudioStream {
+                device_id: device_id as u64,
+                address: 0,
+                interface_number: number,
+                alternate_setting: desc.alternate_setting,
+                endpoint_address: endpoint_address,
+                format: format,
+            });
+        }
+        let address = cap.devices.get(device_id as u64).unwrap().address;
+        for (_, mut stream) in found {
+            stream.address = address;
+            streams.push(stream);
+        }
+    }
+    streams
+}
+
+/// Write audio data, given as a sequence of payloads, as a WAV file.
+///
+/// The data is written as the payloads are taken, and the lengths in the
+/// header are filled in at the end. Any partial sample frame at the end of
+/// the data is dropped.
+pub fn write_wav<W, I>(output: &mut W, format: &AudioFormat,
+                       sample_rate: u32, payloads: I)
+    -> std::io::Result<()>
+    where W: Write + Seek, I: IntoIterator<Item=Vec<u8>>
+{
+    let start = output.stream_position()?;
+    write_wav_header(output, format, sample_rate, 0)?;
+    let block_size = format.block_size();
+    let mut length = 0;
+    let mut pending = Vec::new();
+    for payload in payloads {
+        if block_size == 0 {
+            continue;
+        }
+        pending.extend(payload);
+        let whole = pending.len() - pending.len() % block_size;
+        output.write_all(&pending[..whole])?;
+        pending.drain(..whole);
+        length += whole;
+    }
+    output.seek(SeekFrom::Start(start))?;
+    write_wav_header(output, format, sample_rate, length)?;
+    output.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
+/// Write the header of a WAV file with `length` bytes of data.
+fn write_wav_header<W: Write>(output: &mut W, format: &AudioFormat,
+                              sample_rate: u32, length: usize)
+    -> std::io::Result<()>
+{
+    let block_size = format.block_size();
+    let format_tag = if format.float {
+        WAVE_FORMAT_IEEE_FLOAT
+    } else {
+        WAVE_FORMAT_PCM
+    };
+    output.write_all(b"RIFF")?;
+    output.write_all(&(36 + length as u32).to_le_bytes())?;
+    output.write_all(b"WAVEfmt ")?;
+    output.write_all(&16u32.to_le_bytes())?;
+    output.write_all(&format_tag.to_le_bytes())?;
+    output.write_all(&(format.channels as u16).to_le_bytes())?;
+    output.write_all(&sample_rate.to_le_bytes())?;
+    output.write_all(&(sample_rate * block_size as u32).to_le_bytes())?;
+    output.write_all(&(block_size as u16).to_le_bytes())?;
+    output.write_all(&(format.subslot_size as u16 * 8).to_le_bytes())?;
+    output.write_all(b"data")?;
+    output.write_all(&(length as u32).to_le_bytes())
+}
+
+/// Path to write one of several files to, numbering all but the first.
+pub fn numbered_path(path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, index + 1,
+                             ext.to_string_lossy()),
+        None => format!("{}-{}", stem, index + 1),
+    };
+    path.with_file_name(name)
+}
+
+/// Export each audio stream in the capture with a known sample rate to a
+/// WAV file. The first is written to the path given, and any others to
+/// numbered paths alongside it. Returns a summary of each stream written,
+/// with its path.
+pub fn export_wav(cap: &mut Capture, path: &Path)
+    -> Result<Vec<(String, PathBuf)>, AudioError>
+{
+    let streams = find_audio_streams(cap);
+    if streams.is_empty() {
+        return Err(AudioError::NoStreams);
+    }
+    let mut paths = Vec::new();
+    for stream in streams {
+        let sample_rate = match stream.format.sample_rate {
+            Some(rate) => rate,
+            None => continue,
+        };
+        let stream_path = numbered_path(path, paths.len());
+        let mut output = BufWriter::new(File::create(&stream_path)?);
+        write_wav(&mut output, &stream.format, sample_rate,
+                  stream.payloads(cap))?;
+        output.flush()?;
+        paths.push((stream.summary(), stream_path));
+    }
+    if paths.is_empty() {
+        return Err(AudioError::UnknownSampleRate);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uac1_format() {
+        let descriptors = vec![
+            vec![0x07, 0x24, 0x01, 0x01, 0x01, 0x01, 0x00],
+            vec![0x0B, 0x24, 0x02, 0x01, 0x02, 0x02, 0x10, 0x01,
+                 0x44, 0xAC, 0x00],
+        ];
+        let format = AudioFormat::from_descriptors(false, &descriptors);
+        assert!(format == Some(AudioFormat {
+            float: false,
+            channels: 2,
+            subslot_size: 2,
+            bit_resolution: 16,
+            sample_rate: Some(44100),
+        }));
+        assert!(format.unwrap().description() ==
+                "2 channels, 16-bit PCM at 44100 Hz");
+    }
+
+    #[test]
+    fn test_uac2_format() {
+        let descriptors = vec![
+            vec![0x10, 0x24, 0x01, 0x02, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00,
+                 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            vec![0x06, 0x24, 0x02, 0x01, 0x04, 0x18],
+        ];
+        let format =
+            AudioFormat::from_descriptors(true, &descriptors).unwrap();
+        assert!(format.description() ==
+                "1 channel, 24-bit PCM, unknown sample rate");
+        assert!(format.block_size() == 4);
+        // Type II formats are not supported.
+        let mut descriptors = descriptors;
+        descriptors[1][3] = 0x02;
+        assert!(AudioFormat::from_descriptors(true, &descriptors).is_none());
+    }
+
+    #[test]
+    fn test_write_wav() {
+        let format = AudioFormat {
+            float: false,
+            channels: 2,
+            subslot_size: 2,
+            bit_resolution: 16,
+            sample_rate: None,
+        };
+        let mut output = std::io::Cursor::new(Vec::new());
+        let payloads = vec![vec![1, 2, 3], vec![4, 5, 6]];
+        write_wav(&mut output, &format, 48000, payloads).unwrap();
+        let output = output.into_inner();
+        assert!(output.len() == 44 + 4);
+        assert!(&output[0..4] == b"RIFF");
+        assert!(output[4..8] == 40u32.to_le_bytes());
+        assert!(output[22..24] == 2u16.to_le_bytes());
+        assert!(output[24..28] == 48000u32.to_le_bytes());
+        assert!(output[28..32] == 192000u32.to_le_bytes());
+        assert!(output[32..34] == 4u16.to_le_bytes());
+        assert!(output[34..36] == 16u16.to_le_bytes());
+        assert!(output[40..44] == 4u32.to_le_bytes());
+        assert!(output[44..] == [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_numbered_path() {
+        let path = Path::new("/tmp/audio.wav");
+        assert!(numbered_path(path, 0) == path);
+        assert!(numbered_path(path, 1) == Path::new("/tmp/audio-2.wav"));
+    }
+}
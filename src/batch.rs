@@ -0,0 +1,377 @@
+//! Scripts for analyzing many captures unattended, such as those taken
+//! each night by an automated test rig.
+//!
+//! A script lists the captures to open, and the steps to run on each of
+//! them, in a subset of TOML:
+//!
+//! ```toml
+//! # Captures to open, each on its own. A directory stands for each of the
+//! # pcap and pcapng files in it, including compressed ones.
+//! captures = ["rig/nightly", "rig/reference.pcap"]
+//! # Directory for the files written by the steps.
+//! output = "results"
+//!
+//! [[step]]
+//! summary = "{name}.summary.txt"
+//!
+//! [[step]]
+//! filter = "4.1"
+//! export = "{name}.mouse.pcap"
+//! ```
+//!
+//! Each step writes one file, named for the capture by replacing `{name}`
+//! with the capture's file name, less its extensions. Relative paths are
+//! taken from the directory holding the script.
+
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::filter::{Filter, FilterError};
+
+/// Placeholder in output paths for the name of the capture.
+pub const NAME_PLACEHOLDER: &str = "{name}";
+
+/// Extensions of the files opened from a directory of captures.
+const CAPTURE_EXTENSIONS: [&str; 2] = ["pcap", "pcapng"];
+
+/// Extensions of compressed files, which are decompressed when opened.
+const COMPRESSED_EXTENSIONS: [&str; 2] = ["gz", "zst"];
+
+/// Split a capture's file name into its name and extension, leaving out
+/// any extension for compression.
+fn split_name(path: &Path) -> (String, Option<String>) {
+    let mut name = path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if let Some((rest, extension)) = name.rsplit_once('.') {
+        if COMPRESSED_EXTENSIONS.contains(&extension) {
+            name = rest.to_string();
+        }
+    }
+    match name.rsplit_once('.') {
+        Some((name, extension)) =>
+            (name.to_string(), Some(extension.to_string())),
+        None => (name, None),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BatchError {
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error("line {0}: expected KEY = VALUE or [[step]]")]
+    InvalidLine(usize),
+    #[error("line {0}: invalid value for {1}")]
+    InvalidValue(usize, String),
+    #[error("line {0}: unknown key {1}")]
+    UnknownKey(usize, String),
+    #[error("line {0}: {1}")]
+    InvalidFilter(usize, FilterError),
+    #[error("line {0}: output paths must contain {{name}}")]
+    NoName(usize),
+    #[error("step at line {0} needs one of export, summary or report")]
+    NoAction(usize),
+    #[error("step at line {0} has more than one of export, summary or \
+             report")]
+    MultipleActions(usize),
+    #[error("no captures given")]
+    NoCaptures,
+    #[error("no steps given")]
+    NoSteps,
+}
+
+/// What a step writes for each capture.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Export the capture, in the format given by the path's extension as
+    /// for `--export`.
+    Export(String),
+    /// Write a summary of the capture, as from `--headless`.
+    Summary(String),
+    /// Write a report on how well the capture decoded, as from `--report`.
+    Report(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub action: Action,
+    /// Filter on the packets exported to a .pcap file.
+    pub filter: Filter,
+}
+
+impl Step {
+    fn path(&self) -> &str {
+        match &self.action {
+            Action::Export(path) |
+            Action::Summary(path) |
+            Action::Report(path) => path,
+        }
+    }
+
+    /// The file this step writes for a capture.
+    pub fn output_path(&self, output: &Path, capture: &Path) -> PathBuf {
+        output_path(output, self.path(), capture)
+    }
+}
+
+/// The file to write for a capture, given a path containing
+/// [NAME_PLACEHOLDER].
+pub fn output_path(output: &Path, path: &str, capture: &Path) -> PathBuf {
+    let (name, _) = split_name(capture);
+    output.join(path.replace(NAME_PLACEHOLDER, &name))
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Script {
+    pub captures: Vec<PathBuf>,
+    pub output: PathBuf,
+    pub steps: Vec<Step>,
+}
+
+/// Parse a string, in double quotes with backslash escapes, returning it
+/// and the rest of the text.
+fn parse_string(text: &str) -> Option<(String, &str)> {
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    let mut string = String::new();
+    while let Some((_, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, chars.as_str())),
+            '\\' => string.push(match chars.next()?.1 {
+                'n' => '\n',
+                't' => '\t',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            }),
+            c => string.push(c),
+        }
+    }
+    None
+}
+
+/// Check that nothing but a comment follows a value.
+fn end_of_line(text: &str) -> Option<()> {
+    let text = text.trim();
+    (text.is_empty() || text.starts_with('#')).then_some(())
+}
+
+fn string_value(text: &str) -> Option<String> {
+    let (string, rest) = parse_string(text)?;
+    end_of_line(rest)?;
+    Some(string)
+}
+
+fn array_value(text: &str) -> Option<Vec<String>> {
+    let mut rest = text.strip_prefix('[')?.trim_start();
+    let mut strings = Vec::new();
+    loop {
+        if let Some(after) = rest.strip_prefix(']') {
+            end_of_line(after)?;
+            return Some(strings);
+        }
+        let (string, after) = parse_string(rest)?;
+        strings.push(string);
+        rest = after.trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after.trim_start();
+        } else if !rest.starts_with(']') {
+            return None;
+        }
+    }
+}
+
+/// A step as it is read, before checking that it is complete.
+struct StepLines {
+    line: usize,
+    filter: Filter,
+    actions: Vec<Action>,
+}
+
+impl StepLines {
+    fn finish(mut self) -> Result<Step, BatchError> {
+        match self.actions.len() {
+            0 => Err(BatchError::NoAction(self.line)),
+            1 => Ok(Step {
+                action: self.actions.remove(0),
+                filter: self.filter,
+            }),
+            _ => Err(BatchError::MultipleActions(self.line)),
+        }
+    }
+}
+
+impl Script {
+    /// Parse a script, with relative paths taken from the directory given.
+    pub fn parse(text: &str, dir: &Path) -> Result<Script, BatchError> {
+        let mut script = Script {
+            output: dir.to_path_buf(),
+            ..Script::default()
+        };
+        let mut step: Option<StepLines> = None;
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line == "[[step]]" {
+                if let Some(step) = step.take() {
+                    script.steps.push(step.finish()?);
+                }
+                step = Some(StepLines {
+                    line: number,
+                    filter: Filter::default(),
+                    actions: Vec::new(),
+                });
+                continue;
+            }
+            let (key, value) = line.split_once('=')
+                .ok_or(BatchError::InvalidLine(number))?;
+            let (key, value) = (key.trim(), value.trim());
+            let invalid = || BatchError::InvalidValue(number, key.to_string());
+            match (&mut step, key) {
+                (None, "captures") => {
+                    let captures = array_value(value).ok_or_else(invalid)?;
+                    script.captures = captures.iter()
+                        .map(|capture| dir.join(capture))
+                        .collect();
+                },
+                (None, "output") => {
+                    let output = string_value(value).ok_or_else(invalid)?;
+                    script.output = dir.join(output);
+                },
+                (Some(step), "filter") => {
+                    let filter = string_value(value).ok_or_else(invalid)?;
+                    step.filter = filter.parse()
+                        .map_err(|err| BatchError::InvalidFilter(number, err))?;
+                },
+                (Some(step), "export" | "summary" | "report") => {
+                    let path = string_value(value).ok_or_else(invalid)?;
+                    if !path.contains(NAME_PLACEHOLDER) {
+                        return Err(BatchError::NoName(number));
+                    }
+                    step.actions.push(match key {
+                        "export" => Action::Export(path),
+                        "summary" => Action::Summary(path),
+                        _ => Action::Report(path),
+                    });
+                },
+                _ => return Err(
+                    BatchError::UnknownKey(number, key.to_string())),
+            }
+        }
+        if let Some(step) = step.take() {
+            script.steps.push(step.finish()?);
+        }
+        if script.captures.is_empty() {
+            return Err(BatchError::NoCaptures);
+        }
+        if script.steps.is_empty() {
+            return Err(BatchError::NoSteps);
+        }
+        Ok(script)
+    }
+
+    pub fn load(path: &Path) -> Result<Script, BatchError> {
+        let text = std::fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        Script::parse(&text, dir)
+    }
+
+    /// List the capture files to open, with those in each directory given
+    /// in name order.
+    pub fn capture_files(&self) -> Result<Vec<PathBuf>, BatchError> {
+        let mut files = Vec::new();
+        for capture in &self.captures {
+            if !capture.is_dir() {
+                files.push(capture.clone());
+                continue;
+            }
+            files.extend(find_captures(capture)?);
+        }
+        Ok(files)
+    }
+}
+
+/// Find the capture files in a directory, in name order.
+pub fn find_captures(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let (_, extension) = split_name(&path);
+        if path.is_file() &&
+            extension.is_some_and(|extension|
+                CAPTURE_EXTENSIONS.contains(&extension.as_str()))
+        {
+            found.push(path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let dir = Path::new("/rig");
+        let script = Script::parse(r#"
+            # Nightly run
+            captures = ["nightly", "/ref/a \"b\".pcap",]
+            output = "results"
+
+            [[step]]
+            summary = "{name}.txt"  # comment
+            [[step]]
+            filter = "4.1"
+            export = "{name}.pcap"
+        "#, dir).unwrap();
+        assert!(script.captures == [Path::new("/rig/nightly"),
+                                    Path::new("/ref/a \"b\".pcap")]);
+        assert!(script.output == Path::new("/rig/results"));
+        assert!(script.steps.len() == 2);
+        assert!(script.steps[0].action == Action::Summary("{name}.txt".into()));
+        assert!(script.steps[1].filter == "4.1".parse().unwrap());
+        assert!(script.steps[1].output_path(&script.output,
+                                            Path::new("/x/run1.pcap.gz")) ==
+                Path::new("/rig/results/run1.pcap"));
+
+        let error = |text: &str| Script::parse(text, dir).unwrap_err()
+            .to_string();
+        let captures = "captures = [\"a\"]\n";
+        assert!(error("") == "no captures given");
+        assert!(error(captures) == "no steps given");
+        for (text, expected) in [
+            ("x\n", "line 2: expected KEY = VALUE or [[step]]"),
+            ("output = results\n", "line 2: invalid value for output"),
+            ("filter = \"4\"\n", "line 2: unknown key filter"),
+            ("[[step]]\nexport = \"a.pcap\"\n",
+             "line 3: output paths must contain {name}"),
+            ("[[step]]\nfilter = \"4\"\n",
+             "step at line 2 needs one of export, summary or report"),
+            ("[[step]]\nsummary = \"{name}\"\nreport = \"{name}\"\n",
+             "step at line 2 has more than one of export, summary or report"),
+        ] {
+            assert!(error(&format!("{}{}", captures, text)) == expected);
+        }
+    }
+
+    #[test]
+    fn test_capture_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b.pcap", "a.pcapng", "c.pcap.zst", "notes.txt",
+                     "notes.gz"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        let script = Script::parse(
+            "captures = [\".\", \"other.csv\"]\n[[step]]\nreport = \"{name}\"",
+            dir.path()).unwrap();
+        let files = script.capture_files().unwrap();
+        assert!(files == [dir.path().join("./a.pcapng"),
+                          dir.path().join("./b.pcap"),
+                          dir.path().join("./c.pcap.zst"),
+                          dir.path().join("other.csv")]);
+    }
+}
@@ -0,0 +1,208 @@
+//! Import of traces exported as CSV from Total Phase Data Center, such as
+//! those captured with a Beagle USB 480 analyzer.
+//!
+//! An export has a row for each record shown in Data Center, at every
+//! level: transfers, transactions and the packets they are made of. Only
+//! packet rows are imported, as their data is the whole packet as seen on
+//! the bus, from its PID to its CRC. The decoder builds the transactions
+//! and transfers from them again, so the trace must be exported with its
+//! packets included.
+//!
+//! Timestamps, and bus events such as resets and speed changes, are not
+//! imported.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+use crate::usb::PID;
+
+/// Number of bytes read from a file to find whether it is an export.
+const PROBE_LENGTH: u64 = 4096;
+
+/// Heading of the column holding the name of each record.
+const RECORD_HEADING: &str = "Record";
+
+/// Heading of the column holding the data of each record, in hex.
+const DATA_HEADING: &str = "Data";
+
+/// Whether a line comes before the header row, or between records.
+pub fn is_comment(line: &str) -> bool {
+    line.starts_with('#') || line.trim().is_empty()
+}
+
+/// Split a CSV row into its fields, removing any quotes around them.
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Parse data written as bytes in hex separated by spaces.
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    text.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect()
+}
+
+/// Converts the rows of a Data Center export into packets.
+pub struct BeagleImporter {
+    record_column: usize,
+    data_column: usize,
+}
+
+impl BeagleImporter {
+    /// Create an importer for an export with the given header row, if it
+    /// has the columns needed.
+    pub fn new(header: &str) -> Option<BeagleImporter> {
+        let headings = split_fields(header);
+        let column = |heading: &str| headings.iter()
+            .position(|field| field.trim().eq_ignore_ascii_case(heading));
+        Some(BeagleImporter {
+            record_column: column(RECORD_HEADING)?,
+            data_column: column(DATA_HEADING)?,
+        })
+    }
+
+    /// Convert one row to a packet, if it is the row of a packet.
+    ///
+    /// Packet rows are named after their PID, e.g. "IN packet", which is
+    /// checked against their first byte. Other rows, such as "IN txn",
+    /// hold only the payload of what they describe.
+    pub fn packet(&self, line: &str) -> Option<Vec<u8>> {
+        let fields = split_fields(line);
+        let record = fields.get(self.record_column)?;
+        let data = parse_hex(fields.get(self.data_column)?)?;
+        let pid = PID::from(*data.first()?);
+        let mut words = record.split_whitespace();
+        let named = words.next() == Some(&pid.to_string());
+        let packet = match words.next() {
+            None => true,
+            Some(word) => word.eq_ignore_ascii_case("packet"),
+        };
+        if pid != PID::Malformed && named && packet {
+            Some(data)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a file is a Data Center export, judged by whether its header
+/// row has the columns needed.
+pub fn is_beagle_export(path: &str) -> std::io::Result<bool> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file.take(PROBE_LENGTH));
+    for line in reader.split(b'\n') {
+        let line = line?;
+        let line = match std::str::from_utf8(&line) {
+            Ok(line) => line,
+            Err(_) => return Ok(false),
+        };
+        if !is_comment(line) {
+            return Ok(BeagleImporter::new(line).is_some());
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Capture;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+    use crate::usb::{data_crc, token_crc};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    fn token(pid: PID, address: u8) -> Vec<u8> {
+        let fields = address as u16;
+        let fields = fields | (token_crc(fields) as u16) << 11;
+        let bytes = fields.to_le_bytes();
+        vec![pid as u8, bytes[0], bytes[1]]
+    }
+
+    fn data(pid: PID, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![pid as u8];
+        packet.extend_from_slice(payload);
+        packet.extend_from_slice(&data_crc(payload).to_le_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_beagle_import() {
+        let get_device = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let descriptor = [18, 1, 0, 2, 0, 0, 0, 64, 0x50, 0x1d, 0x4b, 0x60,
+                          0, 1, 1, 2, 3, 1];
+        let ack = vec![PID::ACK as u8];
+        let transactions = [
+            ("SETUP txn", &get_device[..], vec![
+                token(PID::SETUP, 3),
+                data(PID::DATA0, &get_device),
+                ack.clone()]),
+            ("IN txn", &descriptor[..], vec![
+                token(PID::IN, 3),
+                data(PID::DATA1, &descriptor),
+                ack.clone()]),
+            ("OUT txn", &[], vec![
+                token(PID::OUT, 3),
+                data(PID::DATA1, &[]),
+                ack]),
+        ];
+        let mut csv = String::from(
+            "# Exported from Total Phase Data Center\n\
+             Level,Sp,Index,m:s.ms.us,Dur,Len,Err,Dev,Ep,Record,Data,\
+             Summary\n");
+        let mut index = 0;
+        for (record, payload, packets) in &transactions {
+            csv.push_str(&format!(
+                "1,HS,{},0:00.000.000,,{},,03,00,{},{},\"{}, with \"\"a\"\" \
+                 comma\"\n",
+                index, payload.len(), record, hex(payload), record));
+            index += 1;
+            for packet in packets {
+                let name = format!("{} packet", PID::from(packet[0]));
+                csv.push_str(&format!(
+                    "2,HS,{},0:00.000.000,,{},,03,00,{},{},\n",
+                    index, packet.len(), name, hex(packet)));
+                index += 1;
+            }
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.csv");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, &csv).unwrap();
+        assert!(is_beagle_export(path).unwrap());
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &[path]).unwrap();
+        drop(decoder);
+        assert!(cap.packet_index.len() == 9);
+        assert!(cap.transaction_index.len() == 3);
+        let control = cap.get_item(&None, 0);
+        assert!(cap.get_summary(&control) ==
+                "Getting device descriptor #0 for device 3, \
+                 reading 18 bytes");
+
+        std::fs::write(path, "Index,Data\n0,69 82 18\n").unwrap();
+        assert!(!is_beagle_export(path).unwrap());
+        assert!(split_fields("a,\"b,c\",d") == ["a", "b,c", "d"]);
+    }
+}
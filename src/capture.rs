@@ -1,7 +1,18 @@
-use std::ops::Range;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::ops::{Index, IndexMut, Range};
+use std::str::FromStr;
 
 use crate::file_vec::FileVec;
-use crate::hybrid_index::HybridIndex;
+use crate::grouping::{DeviceGroup, GroupMode, group_traffic};
+use crate::halts::{halt_started, is_halted, Halt};
+use crate::hid::{HID_CLASS, ReportDescriptor, ReportType, fmt_values};
+use crate::hybrid_index::{HybridIndex, HybridIndexError};
+use crate::labels::{LabelKey, Labels};
+use crate::msc::MscError;
+use crate::record::Record;
+use crate::storage::{CaptureStorage, MemoryLimits, memory_limits};
+use crate::usb_ids::fmt_device_id;
 use crate::usb::{
     PID,
     PacketFields,
@@ -10,21 +21,67 @@ use crate::usb::{
     DeviceDescriptor,
     Configuration,
     ControlTransfer,
+    PhyErrors,
+    Speed,
 };
 
-use bytemuck_derive::{Pod, Zeroable};
+use bisection::bisect_right;
 use num_enum::{IntoPrimitive, FromPrimitive};
 use num_format::{Locale, ToFormattedString};
 use humansize::{FileSize, file_size_opts as options};
+use thiserror::Error;
 
-#[derive(Clone)]
+/// An item in the traffic view, identified by the transfer index entry
+/// which starts its transfer, its transaction ID and its packet ID.
+///
+/// These are the same each time a capture is decoded, so an item can be
+/// referred to from outside a session, written as `transfer:T`,
+/// `transaction:T.X` or `packet:T.X.P`.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Item {
     Transfer(u64),
     Transaction(u64, u64),
     Packet(u64, u64, u64),
 }
 
-#[derive(Clone)]
+#[derive(Error, Debug, PartialEq)]
+pub enum ItemParseError {
+    #[error("invalid item '{0}', expected transfer:T, transaction:T.X or \
+             packet:T.X.P")]
+    InvalidItem(String),
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Item::*;
+        match self {
+            Transfer(t) => write!(f, "transfer:{}", t),
+            Transaction(t, x) => write!(f, "transaction:{}.{}", t, x),
+            Packet(t, x, p) => write!(f, "packet:{}.{}.{}", t, x, p),
+        }
+    }
+}
+
+impl FromStr for Item {
+    type Err = ItemParseError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || ItemParseError::InvalidItem(text.to_string());
+        let (kind, ids) = text.split_once(':').ok_or_else(invalid)?;
+        let ids = ids.split('.')
+            .map(|id| id.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|_| invalid())?;
+        match (kind, ids.as_slice()) {
+            ("transfer", [t]) => Ok(Item::Transfer(*t)),
+            ("transaction", [t, x]) => Ok(Item::Transaction(*t, *x)),
+            ("packet", [t, x, p]) => Ok(Item::Packet(*t, *x, *p)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum DeviceItem {
     Device(u64),
     DeviceDescriptor(u64),
@@ -35,35 +92,110 @@ pub enum DeviceItem {
     Interface(u64, u8, u8),
     InterfaceDescriptor(u64, u8, u8),
     InterfaceDescriptorField(u64, u8, u8, u8),
+    InterfaceAssociationDescriptor(u64, u8, u8),
+    InterfaceAssociationDescriptorField(u64, u8, u8, u8),
     EndpointDescriptor(u64, u8, u8, u8),
     EndpointDescriptorField(u64, u8, u8, u8, u8),
+    HidReportDescriptor(u64, u8, u8),
+    HidReportItem(u64, u8, u8, u16),
+}
+
+/// Items in the view of traffic grouped by device, interface and endpoint,
+/// which refer to groups by their positions.
+#[derive(Clone, Debug)]
+pub enum GroupItem {
+    Device(usize),
+    Interface(usize, usize),
+    Endpoint(usize, usize, usize),
+    Traffic(Item),
 }
 
-#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
-#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct Device {
     pub address: u8,
 }
 
+impl Record for Device {
+    const SIZE: usize = 1;
+
+    fn encode(&self, bytes: &mut [u8]) {
+        self.address.encode(bytes)
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        Device { address: u8::decode(bytes) }
+    }
+}
+
 bitfield! {
-    #[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
-    #[repr(C)]
+    #[derive(Copy, Clone, Debug, Default)]
     pub struct Endpoint(u64);
-    pub u64, device_id, set_device_id: 51, 0;
+    pub u64, device_id, set_device_id: 50, 0;
+    pub u8, _is_in, _set_is_in: 51, 51;
     pub u8, device_address, set_device_address: 58, 52;
     pub u8, number, set_number: 63, 59;
 }
 
+impl Endpoint {
+    pub fn is_in(&self) -> bool {
+        self._is_in() != 0
+    }
+    pub fn set_is_in(&mut self, value: bool) {
+        self._set_is_in(value as u8)
+    }
+    /// The address of the endpoint as given in descriptors, with the
+    /// direction in bit 7. Endpoint 0 carries control transfers in both
+    /// directions, so is kept as one endpoint with address 0.
+    pub fn endpoint_address(&self) -> u8 {
+        self.number() | if self.is_in() { 0x80 } else { 0 }
+    }
+}
+
+impl Record for Endpoint {
+    const SIZE: usize = 8;
+
+    fn encode(&self, bytes: &mut [u8]) {
+        self.0.encode(bytes)
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        Endpoint(u64::decode(bytes))
+    }
+}
+
+/// Format an endpoint address as its number and direction, e.g. "1 IN".
+/// Endpoint 0 has no direction.
+pub fn fmt_endpoint_address(address: u8) -> String {
+    match (address & 0x7F, address & 0x80 != 0) {
+        (0, _) => "0".to_string(),
+        (number, true) => format!("{} IN", number),
+        (number, false) => format!("{} OUT", number),
+    }
+}
+
+/// Position of an endpoint address in [DeviceData::endpoint_types], which
+/// holds the types of the OUT endpoints followed by the IN endpoints.
+pub fn endpoint_type_index(address: u8) -> usize {
+    (address & 0x0F) as usize | ((address & 0x80) >> 3) as usize
+}
+
+/// Bits of a `TransferIndexEntry` holding its endpoint ID.
+const ENDPOINT_ID_MSB: usize = 62;
+const ENDPOINT_ID_LSB: usize = 47;
+
 bitfield! {
-    #[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
-    #[repr(C)]
+    #[derive(Copy, Clone, Debug, Default)]
     pub struct TransferIndexEntry(u64);
-    pub u64, transfer_id, set_transfer_id: 51, 0;
-    pub u16, endpoint_id, set_endpoint_id: 62, 52;
+    pub u64, transfer_id, set_transfer_id: 46, 0;
+    pub u16, endpoint_id, set_endpoint_id: ENDPOINT_ID_MSB, ENDPOINT_ID_LSB;
     pub u8, _is_start, _set_is_start: 63, 63;
 }
 
 impl TransferIndexEntry {
+    /// Largest endpoint ID that can be stored in an entry.
+    pub const MAX_ENDPOINT_ID: usize =
+        (1 << (ENDPOINT_ID_MSB - ENDPOINT_ID_LSB + 1)) - 1;
+
     pub fn is_start(&self) -> bool {
         self._is_start() != 0
     }
@@ -72,6 +204,18 @@ impl TransferIndexEntry {
     }
 }
 
+impl Record for TransferIndexEntry {
+    const SIZE: usize = 8;
+
+    fn encode(&self, bytes: &mut [u8]) {
+        self.0.encode(bytes)
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        TransferIndexEntry(u64::decode(bytes))
+    }
+}
+
 #[derive(Copy, Clone, IntoPrimitive, FromPrimitive, PartialEq)]
 #[repr(u8)]
 pub enum EndpointState {
@@ -82,6 +226,62 @@ pub enum EndpointState {
     Ending = 3,
 }
 
+/// Number of endpoint states packed into each byte of `endpoint_states`.
+const STATES_PER_BYTE: usize = 4;
+
+/// Pack endpoint states into bytes, two bits per endpoint.
+pub fn pack_endpoint_states(states: &[u8]) -> Vec<u8> {
+    states.chunks(STATES_PER_BYTE).map(|chunk|
+        chunk.iter().enumerate().fold(0, |byte, (i, state)|
+            byte | (state & 0b11) << (2 * i))
+    ).collect()
+}
+
+/// Unpack the states of the first `count` endpoints from packed bytes.
+pub fn unpack_endpoint_states(bytes: &[u8], count: usize) -> Vec<u8> {
+    (0..count).map(|i| {
+        let byte = bytes[i / STATES_PER_BYTE];
+        (byte >> (2 * (i % STATES_PER_BYTE))) & 0b11
+    }).collect()
+}
+
+/// Number of missing frames after which the bus must have been suspended,
+/// since devices suspend after 3ms without bus activity.
+pub const SUSPEND_FRAMES: u16 = 3;
+
+/// What a gap in SOF frame numbers is likely to indicate.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SofGapKind {
+    /// Fewer than `SUSPEND_FRAMES` frames are missing from the capture.
+    DroppedFrames,
+    /// SOFs stopped for long enough to suspend the bus.
+    Suspend,
+    /// SOFs stopped, and then a device was enumerated at the default
+    /// address, so the device was disconnected or reset.
+    Disconnect,
+}
+
+/// A gap in SOF frame numbers, before the SOF packet it is stored against.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SofGap {
+    pub kind: SofGapKind,
+    pub missing_frames: u16,
+}
+
+impl SofGap {
+    pub fn description(&self) -> String {
+        use SofGapKind::*;
+        match (self.kind, self.missing_frames) {
+            (DroppedFrames, 1) => "after 1 dropped frame".to_string(),
+            (DroppedFrames, n) => format!("after {} dropped frames", n),
+            (Suspend, n) => format!(
+                "after suspend, {} frames missing", n),
+            (Disconnect, n) => format!(
+                "after disconnect or reset, {} frames missing", n),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, FromPrimitive)]
 #[repr(u8)]
 pub enum EndpointType {
@@ -100,39 +300,159 @@ pub struct EndpointTraffic {
     pub transfer_index: HybridIndex,
 }
 
+impl EndpointTraffic {
+    fn park(&mut self) -> Result<(), HybridIndexError> {
+        self.transaction_ids.park()?;
+        self.transfer_index.park()
+    }
+}
+
+/// The traffic of all endpoints, of which only those most recently used
+/// are kept active, so that memory use does not grow with the number of
+/// endpoints. Others are parked, and made active again when next indexed
+/// mutably.
+pub struct EndpointTrafficStore {
+    traffic: Vec<EndpointTraffic>,
+    /// IDs of the active endpoints, least recently used first.
+    active: VecDeque<usize>,
+    limit: usize,
+}
+
+impl EndpointTrafficStore {
+    pub fn new(limit: usize) -> EndpointTrafficStore {
+        EndpointTrafficStore {
+            traffic: Vec::new(),
+            active: VecDeque::new(),
+            limit: limit.max(1),
+        }
+    }
+
+    pub fn push(&mut self, traffic: EndpointTraffic) {
+        self.traffic.push(traffic);
+        self.touch(self.traffic.len() - 1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.traffic.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, EndpointTraffic> {
+        self.traffic.iter()
+    }
+
+    /// Number of endpoints currently active.
+    pub fn active_count(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Mark an endpoint as the most recently used, parking the least
+    /// recently used if this takes the active endpoints over the limit.
+    fn touch(&mut self, endpoint_id: usize) {
+        if self.active.back() == Some(&endpoint_id) {
+            return;
+        }
+        if let Some(position) =
+            self.active.iter().rposition(|&id| id == endpoint_id)
+        {
+            self.active.remove(position);
+        }
+        self.active.push_back(endpoint_id);
+        if self.active.len() > self.limit {
+            // The endpoint stays listed as active unless it is parked.
+            let oldest = *self.active.front().unwrap();
+            self.traffic[oldest].park().unwrap();
+            self.active.pop_front();
+        }
+    }
+}
+
+impl Index<usize> for EndpointTrafficStore {
+    type Output = EndpointTraffic;
+
+    fn index(&self, endpoint_id: usize) -> &EndpointTraffic {
+        &self.traffic[endpoint_id]
+    }
+}
+
+impl IndexMut<usize> for EndpointTrafficStore {
+    fn index_mut(&mut self, endpoint_id: usize) -> &mut EndpointTraffic {
+        self.touch(endpoint_id);
+        &mut self.traffic[endpoint_id]
+    }
+}
+
 pub struct DeviceData {
     pub device_descriptor: Option<DeviceDescriptor>,
     pub configurations: Vec<Option<Configuration>>,
     pub configuration_id: Option<usize>,
     pub endpoint_types: Vec<EndpointType>,
     pub strings: Vec<Option<Vec<u8>>>,
+    pub report_descriptors: Vec<Option<ReportDescriptor>>,
 }
 
 impl DeviceData {
-    pub fn endpoint_type(&self, number: usize) -> EndpointType {
+    /// The type of an endpoint, given its address with the direction in
+    /// bit 7.
+    pub fn endpoint_type(&self, address: u8) -> EndpointType {
         use EndpointType::*;
-        match number {
-            0 => Control,
+        match address {
+            0 | 0x80 => Control,
             0x10 => Framing,
             0x11 => Invalid,
-            _ => self.endpoint_types[number],
+            _ => self.endpoint_types[endpoint_type_index(address)],
+        }
+    }
+
+    /// The configuration the device is set to, or if that was not seen,
+    /// the first configuration read from it.
+    pub fn configuration(&self) -> Option<&Configuration> {
+        let current = self.configuration_id
+            .and_then(|id| self.configurations.get(id))
+            .and_then(|config| config.as_ref());
+        current.or_else(||
+            self.configurations.iter().find_map(|config| config.as_ref()))
+    }
+
+    /// The HID report descriptor of an interface, if it was read.
+    pub fn report_descriptor(&self, interface_number: u8)
+        -> Option<&ReportDescriptor>
+    {
+        self.report_descriptors.get(interface_number as usize)
+            .and_then(|desc| desc.as_ref())
+    }
+
+    /// The HID report descriptor describing the reports sent on an IN
+    /// endpoint, if known.
+    pub fn input_report_descriptor(&self, address: u8)
+        -> Option<&ReportDescriptor>
+    {
+        if address & 0x80 == 0 {
+            return None;
         }
+        let config = self.configuration()?;
+        let iface = config.interfaces.iter().find(|iface|
+            iface.descriptor.interface_class == HID_CLASS &&
+            iface.endpoint_descriptors.iter().any(|ep_desc|
+                ep_desc.endpoint_address == address))?;
+        self.report_descriptor(iface.descriptor.interface_number)
     }
 
     pub fn update_endpoint_types(&mut self) {
         match self.configuration_id {
-            Some(id) => match &self.configurations[id] {
-                Some(config) => {
+            // The configuration may not have been seen, if the capture
+            // started after enumeration.
+            Some(id) => match self.configurations.get(id) {
+                Some(Some(config)) => {
                     for iface in &config.interfaces {
                         for ep_desc in &iface.endpoint_descriptors {
-                            let number = ep_desc.endpoint_address & 0x0F;
-                            let index = number as usize;
+                            let index =
+                                endpoint_type_index(ep_desc.endpoint_address);
                             self.endpoint_types[index] =
                                 EndpointType::from(ep_desc.attributes & 0x03);
                         }
                     }
                 },
-                None => {},
+                _ => {},
             },
             None => {},
         }
@@ -142,15 +462,75 @@ impl DeviceData {
 pub struct Capture {
     pub item_index: HybridIndex,
     pub packet_index: HybridIndex,
+    /// Times at which packets were captured, in nanoseconds since the
+    /// Unix epoch. Empty if the capture had no timestamps, otherwise with
+    /// one for each packet. Times are not necessarily in order.
+    pub packet_times: FileVec<i64>,
     pub packet_data: FileVec<u8>,
     pub transaction_index: HybridIndex,
+    pub retry_index: HybridIndex,
+    /// IDs of the failed attempts retried by each transaction. Attempts
+    /// are claimed as each endpoint retries them, so the IDs are not in
+    /// order across transactions.
+    pub retry_ids: FileVec<u64>,
     pub transfer_index: FileVec<TransferIndexEntry>,
     pub devices: FileVec<Device>,
     pub device_data: Vec<DeviceData>,
     pub endpoints: FileVec<Endpoint>,
-    pub endpoint_traffic: Vec<EndpointTraffic>,
+    pub endpoint_traffic: EndpointTrafficStore,
     pub endpoint_states: FileVec<u8>,
     pub endpoint_state_index: HybridIndex,
+    pub endpoint_first_entry: Vec<u64>,
+    pub highlights: BTreeSet<u64>,
+    pub sof_gaps: BTreeMap<u64, SofGap>,
+    /// Errors reported by the capture hardware, for packets which had any.
+    pub phy_errors: BTreeMap<u64, PhyErrors>,
+    pub msc_errors: BTreeMap<u64, MscError>,
+    /// Periods in which endpoints were halted, in order of endpoint and
+    /// then of the start of the halt.
+    pub halts: Vec<Halt>,
+    /// Speed of the bus, if the capture recorded it.
+    pub speed: Option<Speed>,
+    /// Detail given in packet summaries.
+    pub verbosity: Verbosity,
+    /// How times are shown in the traffic view.
+    pub time_format: TimeFormat,
+    /// Labels given to devices and endpoints by the user.
+    pub labels: Labels,
+    /// Whether the capture was streamed in and has not been saved since,
+    /// so that it would be lost when closed.
+    pub unsaved: bool,
+    /// Where the capture's storage files are created.
+    pub storage: CaptureStorage,
+    /// Limits on the data held in memory.
+    pub limits: MemoryLimits,
+    /// How traffic is grouped in the grouped view.
+    group_mode: GroupMode,
+    /// Traffic grouped by device, interface and endpoint, found when first
+    /// needed.
+    traffic_groups: Option<Vec<DeviceGroup>>,
+    /// Payload totals of transfers, found when first summarized. Each is
+    /// kept with the number of transactions it covers, so that it is found
+    /// again if the transfer has grown since.
+    transfer_payloads: BTreeMap<u64, (u64, PayloadTotal)>,
+}
+
+/// Acknowledged payload of a transfer.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PayloadTotal {
+    pub bytes: u64,
+    pub packets: u64,
+}
+
+impl fmt::Display for PayloadTotal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.packets {
+            0 => write!(f, "no data"),
+            packets => write!(f, "{} bytes, average {} per packet",
+                              fmt_count(self.bytes),
+                              (self.bytes + packets / 2) / packets),
+        }
+    }
 }
 
 impl Default for Capture {
@@ -159,6 +539,90 @@ impl Default for Capture {
     }
 }
 
+/// How much detail is given in the summaries of packets.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Verbosity {
+    /// The PID and fields of each packet, without frame numbers or CRCs.
+    Short,
+    /// Frame numbers and CRCs as well.
+    Normal,
+    /// The packet's bytes as well, in hex.
+    Verbose,
+}
+
+impl Verbosity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Verbosity::Short => "short",
+            Verbosity::Normal => "normal",
+            Verbosity::Verbose => "verbose",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Verbosity> {
+        match name {
+            "short" => Some(Verbosity::Short),
+            "normal" => Some(Verbosity::Normal),
+            "verbose" => Some(Verbosity::Verbose),
+            _ => None,
+        }
+    }
+}
+
+/// How the time of each item is shown in the traffic view, for captures
+/// which have timestamps.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimeFormat {
+    /// No times are shown.
+    Hidden,
+    /// Time of day, in UTC.
+    Absolute,
+    /// Time since the first packet of the capture.
+    Relative,
+    /// Time since the previous sibling: the item shown before at the same
+    /// level, or for the first child of an item, that item. This is not
+    /// the row shown above when that is a child of an expanded item.
+    SiblingDelta,
+}
+
+impl TimeFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            TimeFormat::Hidden => "hidden",
+            TimeFormat::Absolute => "absolute",
+            TimeFormat::Relative => "relative",
+            TimeFormat::SiblingDelta => "sibling-delta",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TimeFormat> {
+        match name {
+            "hidden" => Some(TimeFormat::Hidden),
+            "absolute" => Some(TimeFormat::Absolute),
+            "relative" => Some(TimeFormat::Relative),
+            // Preferences saved before the mode was renamed.
+            "sibling-delta" | "delta" => Some(TimeFormat::SiblingDelta),
+            _ => None,
+        }
+    }
+}
+
+/// Format a number of nanoseconds as seconds, e.g. "12.000345000".
+pub fn fmt_nanos(nanos: i64) -> String {
+    let sign = if nanos < 0 { "-" } else { "" };
+    let nanos = nanos.unsigned_abs();
+    format!("{}{}.{:09}", sign, nanos / 1_000_000_000, nanos % 1_000_000_000)
+}
+
+/// Format a time in nanoseconds since the Unix epoch as the time of day
+/// in UTC, e.g. "13:05:42.000345000".
+fn fmt_time_of_day(time: i64) -> String {
+    let secs = time.div_euclid(1_000_000_000);
+    format!("{:02}:{:02}:{:02}.{:09}",
+            secs.rem_euclid(86400) / 3600, secs.rem_euclid(3600) / 60,
+            secs.rem_euclid(60), time.rem_euclid(1_000_000_000))
+}
+
 pub struct Transaction {
     pid: PID,
     packet_id_range: Range<u64>,
@@ -202,9 +666,7 @@ pub fn fmt_size(size: u64) -> String {
     size.file_size(options::BINARY).unwrap()
 }
 
-pub fn fmt_vec<T>(vec: &FileVec<T>) -> String
-    where T: bytemuck::Pod + Default
-{
+pub fn fmt_vec<T: Record>(vec: &FileVec<T>) -> String {
     format!("{} entries, {}", fmt_count(vec.len()), fmt_size(vec.size()))
 }
 
@@ -217,18 +679,49 @@ pub fn fmt_index(idx: &HybridIndex) -> String {
 
 impl Capture {
     pub fn new() -> Self {
+        Capture::with_storage(CaptureStorage::default())
+    }
+
+    /// Create a capture whose storage files are created in `storage`.
+    pub fn with_storage(storage: CaptureStorage) -> Self {
+        let limits = memory_limits();
+        let index = |purpose, min_width|
+            HybridIndex::new_in(&storage, purpose, min_width).unwrap();
         Capture {
-            item_index: HybridIndex::new(1).unwrap(),
-            packet_index: HybridIndex::new(2).unwrap(),
-            packet_data: FileVec::new().unwrap(),
-            transaction_index: HybridIndex::new(1).unwrap(),
-            transfer_index: FileVec::new().unwrap(),
-            devices: FileVec::new().unwrap(),
+            item_index: index("item_index", 1),
+            packet_index: index("packet_index", 2),
+            packet_times: FileVec::new_in(&storage, "packet_times")
+                .unwrap(),
+            packet_data: FileVec::new_in(&storage, "packet_data").unwrap(),
+            transaction_index: index("transaction_index", 1),
+            retry_index: index("retry_index", 1),
+            retry_ids: FileVec::new_in(&storage, "retry_ids").unwrap(),
+            transfer_index: FileVec::new_in(&storage, "transfer_index")
+                .unwrap(),
+            devices: FileVec::new_in(&storage, "devices").unwrap(),
             device_data: Vec::new(),
-            endpoints: FileVec::new().unwrap(),
-            endpoint_traffic: Vec::new(),
-            endpoint_states: FileVec::new().unwrap(),
-            endpoint_state_index: HybridIndex::new(1).unwrap(),
+            endpoints: FileVec::new_in(&storage, "endpoints").unwrap(),
+            endpoint_traffic:
+                EndpointTrafficStore::new(limits.active_endpoints),
+            endpoint_states: FileVec::new_in(&storage, "endpoint_states")
+                .unwrap(),
+            endpoint_state_index: index("endpoint_state_index", 1),
+            endpoint_first_entry: Vec::new(),
+            highlights: BTreeSet::new(),
+            sof_gaps: BTreeMap::new(),
+            phy_errors: BTreeMap::new(),
+            msc_errors: BTreeMap::new(),
+            halts: Vec::new(),
+            speed: None,
+            verbosity: Verbosity::Verbose,
+            time_format: TimeFormat::Hidden,
+            labels: Labels::default(),
+            unsaved: false,
+            storage: storage,
+            limits: limits,
+            group_mode: GroupMode::Interface,
+            traffic_groups: None,
+            transfer_payloads: BTreeMap::new(),
         }
     }
 
@@ -236,6 +729,8 @@ impl Capture {
         let mut overhead: u64 =
             self.packet_index.size() +
             self.transaction_index.size() +
+            self.retry_index.size() +
+            self.retry_ids.size() +
             self.transfer_index.size() +
             self.endpoint_states.size() +
             self.endpoint_state_index.size();
@@ -245,7 +740,7 @@ impl Capture {
         let mut xfr_count = 0;
         let mut xfr_entries = 0;
         let mut xfr_size = 0;
-        for ep_traf in &self.endpoint_traffic {
+        for ep_traf in self.endpoint_traffic.iter() {
             trx_count += ep_traf.transaction_ids.len();
             trx_entries += ep_traf.transaction_ids.entry_count();
             trx_size += ep_traf.transaction_ids.size();
@@ -261,6 +756,8 @@ impl Capture {
             "  Packet data: {}\n",
             "  Packet index: {}\n",
             "  Transaction index: {}\n",
+            "  Retry index: {}\n",
+            "  Retried transactions: {}\n",
             "  Transfer index: {}\n",
             "  Endpoint states: {}\n",
             "  Endpoint state index: {}\n",
@@ -270,6 +767,8 @@ impl Capture {
             fmt_size(self.packet_data.size()),
             fmt_index(&self.packet_index),
             fmt_index(&self.transaction_index),
+            fmt_index(&self.retry_index),
+            fmt_vec(&self.retry_ids),
             fmt_vec(&self.transfer_index),
             fmt_vec(&self.endpoint_states),
             fmt_index(&self.endpoint_state_index),
@@ -279,7 +778,19 @@ impl Capture {
         )
     }
 
+    pub fn set_highlighted(&mut self, transfer_index_id: u64) {
+        self.highlights.insert(transfer_index_id);
+    }
+
+    pub fn is_highlighted(&self, item: &Item) -> bool {
+        match item {
+            Item::Transfer(id) => self.highlights.contains(id),
+            _ => false
+        }
+    }
+
     pub fn get_item(&mut self, parent: &Option<Item>, index: u64) -> Item {
+        profile!("get_item");
         match parent {
             None => Item::Transfer(self.item_index.get(index).unwrap()),
             Some(item) => self.get_child(item, index)
@@ -300,7 +811,22 @@ impl Capture {
                 }),
             Transaction(transfer_index_id, transaction_id) =>
                 Packet(*transfer_index_id, *transaction_id, {
-                    self.transaction_index.get(*transaction_id).unwrap() + index}),
+                    // Packets of any retried attempts come first.
+                    let mut index = index;
+                    let mut packet_id = None;
+                    for retry_id in self.get_retries(*transaction_id) {
+                        let range = get_index_range(&mut self.transaction_index,
+                            self.packet_index.len(), retry_id);
+                        let count = range.end - range.start;
+                        if index < count {
+                            packet_id = Some(range.start + index);
+                            break;
+                        }
+                        index -= count;
+                    }
+                    packet_id.unwrap_or_else(|| self.transaction_index
+                        .get(*transaction_id).unwrap() + index)
+                }),
             Packet(..) => panic!("packets do not have children"),
         }
     }
@@ -327,6 +853,50 @@ impl Capture {
         }
     }
 
+    /// Find the position of an item in the traffic tree, as its index at
+    /// each level from the top, if it exists in this capture.
+    pub fn item_path(&mut self, item: &Item) -> Option<Vec<u64>> {
+        use Item::*;
+        let (parent, transfer_index_id) = match item {
+            Transfer(t) => (None, *t),
+            Transaction(t, _) => (Some(Transfer(*t)), *t),
+            Packet(t, x, _) => (Some(Transaction(*t, *x)), *t),
+        };
+        let mut path = match parent {
+            Some(parent) => self.item_path(&parent)?,
+            None => {
+                // Top level items are in order of their transfer index IDs.
+                let (mut start, mut end) = (0, self.item_index.len());
+                while start < end {
+                    let middle = (start + end) / 2;
+                    if self.item_index.get(middle).unwrap() < transfer_index_id
+                    {
+                        start = middle + 1;
+                    } else {
+                        end = middle;
+                    }
+                }
+                let found = start < self.item_index.len() &&
+                    self.item_index.get(start).unwrap() == transfer_index_id;
+                return found.then(|| vec![start]);
+            }
+        };
+        let parent = self.get_item_at(&path);
+        let index = (0..self.child_count(&parent))
+            .find(|index| self.get_child(&parent, *index) == *item)?;
+        path.push(index);
+        Some(path)
+    }
+
+    /// Get the item at a path found by `item_path`.
+    fn get_item_at(&mut self, path: &[u64]) -> Item {
+        let mut item = self.get_item(&None, path[0]);
+        for index in &path[1..] {
+            item = self.get_child(&item, *index);
+        }
+        item
+    }
+
     pub fn item_count(&mut self, parent: &Option<Item>) -> u64 {
         match parent {
             None => self.item_index.len(),
@@ -335,6 +905,7 @@ impl Capture {
     }
 
     pub fn child_count(&mut self, parent: &Item) -> u64 {
+        profile!("child_count");
         use Item::*;
         match parent {
             Transfer(id) => {
@@ -346,51 +917,221 @@ impl Capture {
                     0
                 }
             },
-            Transaction(..) => {
+            Transaction(_, transaction_id) => {
+                let mut count = 0;
+                for retry_id in self.get_retries(*transaction_id) {
+                    let range = get_index_range(&mut self.transaction_index,
+                        self.packet_index.len(), retry_id);
+                    count += range.end - range.start;
+                }
                 let range = self.item_range(parent);
-                range.end - range.start
+                count + range.end - range.start
             },
             Packet(..) => 0,
         }
     }
 
+    /// Find the ID of an endpoint of a device, given its address with the
+    /// direction in bit 7, if it has been seen.
+    pub fn find_endpoint(&mut self, device_id: u64, address: u8)
+        -> Option<u64>
+    {
+        let address = if address & 0x0F == 0 { 0 } else { address };
+        let count = self.endpoints.len();
+        let endpoints = self.endpoints.get_range(0..count).unwrap();
+        endpoints.iter()
+            .position(|ep| ep.device_id() == device_id &&
+                      ep.number() < 0x10 &&
+                      ep.endpoint_address() == address)
+            .map(|id| id as u64)
+    }
+
+    /// Get the IDs of all transactions on some endpoints of a device, given
+    /// their addresses, in capture order.
+    pub fn get_device_transaction_ids(&mut self, device_id: u64,
+                                      addresses: &[u8]) -> Vec<u64>
+    {
+        let mut transaction_ids = Vec::new();
+        for address in addresses {
+            if let Some(endpoint_id) = self.find_endpoint(device_id, *address)
+            {
+                let ids = &mut self.endpoint_traffic[endpoint_id as usize]
+                    .transaction_ids;
+                transaction_ids.extend(ids.get_range(0..ids.len()).unwrap());
+            }
+        }
+        transaction_ids.sort_unstable();
+        transaction_ids.dedup();
+        transaction_ids
+    }
+
+    /// Get the packets of a transaction, not including retried attempts.
+    pub fn get_transaction_packets(&mut self, transaction_id: u64)
+        -> Vec<Vec<u8>>
+    {
+        let range = get_index_range(&mut self.transaction_index,
+                                    self.packet_index.len(), transaction_id);
+        range.map(|packet_id| self.get_packet(packet_id)).collect()
+    }
+
+    /// Get the token PID and payload of a transaction, if it had a data
+    /// packet, whether or not the data was acknowledged.
+    pub fn get_payload(&mut self, transaction_id: u64)
+        -> Option<(PID, Vec<u8>)>
+    {
+        let transaction = self.get_transaction(&transaction_id);
+        let range = transaction.payload_byte_range?;
+        Some((transaction.pid, self.packet_data.get_range(range).unwrap()))
+    }
+
+    /// Get the token PID and payload of a transaction, if its data was
+    /// acknowledged.
+    pub fn get_acked_payload(&mut self, transaction_id: u64)
+        -> Option<(PID, Vec<u8>)>
+    {
+        let transaction = self.get_transaction(&transaction_id);
+        let last_packet_id = transaction.packet_id_range.end - 1;
+        match (self.get_packet_pid(last_packet_id),
+               transaction.payload_byte_range)
+        {
+            (PID::ACK | PID::NYET, Some(range)) =>
+                Some((transaction.pid,
+                      self.packet_data.get_range(range).unwrap())),
+            _ => None
+        }
+    }
+
+    /// Get the IDs of the failed attempts which a transaction retried.
+    pub fn get_retries(&mut self, transaction_id: u64) -> Vec<u64> {
+        let range = get_index_range(&mut self.retry_index,
+                                    self.retry_ids.len(), transaction_id);
+        self.retry_ids.get_range(range).unwrap()
+    }
+
+    /// The label given to a device, or to one of its endpoints, if any.
+    pub fn label(&self, device_id: usize, endpoint_address: Option<u8>)
+        -> Option<String>
+    {
+        let descriptor = self.device_data[device_id].device_descriptor?;
+        let key = LabelKey {
+            vendor_id: descriptor.vendor_id,
+            product_id: descriptor.product_id,
+            endpoint: endpoint_address,
+        };
+        self.labels.get(&key).map(str::to_string)
+    }
+
+    /// Summarize an item. Transfers are preceded by any label given to
+    /// their endpoint.
     pub fn get_summary(&mut self, item: &Item) -> String {
+        let summary = self.item_summary(item);
+        let transfer_index_id = match item {
+            Item::Transfer(transfer_index_id) => *transfer_index_id,
+            _ => return summary,
+        };
+        let entry = self.transfer_index.get(transfer_index_id).unwrap();
+        let endpoint = self.endpoints.get(entry.endpoint_id() as u64).unwrap();
+        let label = self.label(endpoint.device_id() as usize,
+                               Some(endpoint.endpoint_address()));
+        match label {
+            Some(label) => format!("{}: {}", label, summary),
+            None => summary,
+        }
+    }
+
+    fn item_summary(&mut self, item: &Item) -> String {
+        profile!("get_summary");
         use Item::*;
         match item {
             Packet(.., packet_id) => {
                 let packet = self.get_packet(*packet_id);
                 let pid = PID::from(packet[0]);
-                format!("{} packet{}: {:02X?}",
+                let short = self.verbosity == Verbosity::Short;
+                format!("{} packet{}{}{}",
                     pid,
                     match PacketFields::from_packet(&packet) {
                         PacketFields::SOF(sof) => format!(
-                            " with frame number {}, CRC {:02X}",
-                            sof.frame_number(),
-                            sof.crc()),
+                            "{}{}",
+                            if short {
+                                "".to_string()
+                            } else {
+                                format!(" with frame number {}, CRC {:02X}",
+                                        sof.frame_number(), sof.crc())
+                            },
+                            match self.sof_gaps.get(packet_id) {
+                                Some(gap) => format!(", {}",
+                                                     gap.description()),
+                                None => "".to_string()
+                            }),
                         PacketFields::Token(token) => format!(
-                            " on {}.{}, CRC {:02X}",
+                            " on {}.{}{}",
                             token.device_address(),
                             token.endpoint_number(),
-                            token.crc()),
+                            if short {
+                                "".to_string()
+                            } else {
+                                format!(", CRC {:02X}", token.crc())
+                            }),
                         PacketFields::Data(data) => format!(
-                            " with {} data bytes and CRC {:04X}",
+                            " with {} data bytes{}",
                             packet.len() - 3,
-                            data.crc),
+                            if short {
+                                "".to_string()
+                            } else {
+                                format!(" and CRC {:04X}", data.crc)
+                            }),
                         PacketFields::None => "".to_string()
                     },
-                    packet)
+                    match self.phy_errors.get(packet_id) {
+                        Some(errors) => format!(", {}", errors.description()),
+                        None => "".to_string()
+                    },
+                    match self.verbosity {
+                        Verbosity::Verbose => format!(": {:02X?}", packet),
+                        _ => "".to_string()
+                    })
             },
-            Transaction(_, transaction_id) => {
+            Transaction(transfer_index_id, transaction_id) => {
                 let transaction = self.get_transaction(transaction_id);
                 let count = transaction.packet_count();
+                let mut notes = match self.get_retries(*transaction_id).len() {
+                    0 => "".to_string(),
+                    1 => ", 1 retry".to_string(),
+                    n => format!(", {} retries", n),
+                };
+                match self.phy_errors
+                    .range(transaction.packet_id_range.clone())
+                    .count()
+                {
+                    0 => {},
+                    1 => notes.push_str(", 1 packet with PHY errors"),
+                    n => notes.push_str(
+                        &format!(", {} packets with PHY errors", n)),
+                }
+                if let Some(error) = self.msc_errors.get(transaction_id) {
+                    notes.push_str(&format!(", {}", error));
+                }
+                let endpoint_id = self.transfer_index
+                    .get(*transfer_index_id).unwrap().endpoint_id();
+                if halt_started(&self.halts, endpoint_id, *transaction_id) {
+                    notes.push_str(", endpoint halted");
+                }
+                if transaction.pid == PID::IN {
+                    if let Some(report) = self.fmt_input_report(
+                        *transfer_index_id, *transaction_id)
+                    {
+                        notes.push_str(&format!(": {}", report));
+                    }
+                }
                 match (transaction.pid, transaction.payload_size()) {
                     (PID::SOF, _) => format!(
-                        "{} SOF packets", count),
+                        "{} SOF packets{}", count,
+                        self.fmt_sof_gaps(transaction.packet_id_range)),
                     (pid, None) => format!(
-                        "{} transaction, {} packets", pid, count),
+                        "{} transaction, {} packets{}", pid, count, notes),
                     (pid, Some(size)) => format!(
-                        "{} transaction, {} packets with {} data bytes",
-                        pid, count, size)
+                        "{} transaction, {} packets with {} data bytes{}",
+                        pid, count, size, notes)
                 }
             },
             Transfer(transfer_index_id) => {
@@ -399,8 +1140,10 @@ impl Capture {
                 let endpoint = self.endpoints.get(endpoint_id as u64).unwrap();
                 let device_id = endpoint.device_id() as usize;
                 let dev_data = &self.device_data[device_id];
-                let num = endpoint.number() as usize;
-                let ep_type = dev_data.endpoint_type(num);
+                let ep_type = dev_data.endpoint_type(
+                    endpoint.endpoint_address());
+                let ep_name = format!("{}.{}", endpoint.device_address(),
+                    fmt_endpoint_address(endpoint.endpoint_address()));
                 if !entry.is_start() {
                     return match ep_type {
                         EndpointType::Invalid =>
@@ -408,8 +1151,8 @@ impl Capture {
                         EndpointType::Framing =>
                             "End of SOF groups".to_string(),
                         endpoint_type => format!(
-                            "{:?} transfer ending on endpoint {}.{}",
-                            endpoint_type, endpoint.device_address(), num)
+                            "{:?} transfer ending on endpoint {}",
+                            endpoint_type, ep_name)
                     }
                 }
                 let range = self.item_range(&item);
@@ -417,23 +1160,119 @@ impl Capture {
                 match ep_type {
                     EndpointType::Invalid => format!(
                         "{} invalid groups", count),
-                    EndpointType::Framing => format!(
-                        "{} SOF groups", count),
+                    EndpointType::Framing => {
+                        let packet_range =
+                            self.transfer_packet_range(endpoint_id, range);
+                        format!("{} SOF groups{}", count,
+                                self.fmt_sof_gaps(packet_range))
+                    },
                     EndpointType::Control => {
                         let transfer = self.get_control_transfer(
                             endpoint.device_address(), endpoint_id, range);
                         transfer.summary()
                     },
+                    EndpointType::Bulk | EndpointType::Interrupt => {
+                        let total = self.transfer_payload(
+                            *transfer_index_id, endpoint_id, range);
+                        format!(
+                            "{:?} transfer with {} transactions on endpoint \
+                             {}, {}", ep_type, count, ep_name, total)
+                    },
                     endpoint_type => format!(
-                        "{:?} transfer with {} transactions on endpoint {}.{}",
-                        endpoint_type, count,
-                        endpoint.device_address(), endpoint.number())
+                        "{:?} transfer with {} transactions on endpoint {}",
+                        endpoint_type, count, ep_name)
                 }
             }
         }
     }
 
+    /// Find the acknowledged payload of a range of an endpoint's
+    /// transactions, which make up the transfer started by a transfer
+    /// index entry.
+    fn transfer_payload(&mut self,
+                        transfer_index_id: u64,
+                        endpoint_id: u16,
+                        range: Range<u64>) -> PayloadTotal
+    {
+        let count = range.end - range.start;
+        if let Some((cached_count, total)) =
+            self.transfer_payloads.get(&transfer_index_id)
+        {
+            if *cached_count == count {
+                return *total;
+            }
+        }
+        let ep_traf = &mut self.endpoint_traffic[endpoint_id as usize];
+        let transaction_ids =
+            ep_traf.transaction_ids.get_range(range).unwrap();
+        let mut total = PayloadTotal::default();
+        for transaction_id in transaction_ids {
+            if let Some((_, payload)) = self.get_acked_payload(transaction_id)
+            {
+                total.bytes += payload.len() as u64;
+                total.packets += 1;
+            }
+        }
+        if self.transfer_payloads.len() >= self.limits.cached_payloads {
+            self.transfer_payloads.pop_first();
+        }
+        self.transfer_payloads.insert(transfer_index_id, (count, total));
+        total
+    }
+
+    /// Decode the HID input report carried by a transaction, if it is on
+    /// an endpoint whose report descriptor was seen.
+    fn fmt_input_report(&mut self, transfer_index_id: u64,
+                        transaction_id: u64) -> Option<String>
+    {
+        let entry = self.transfer_index.get(transfer_index_id).unwrap();
+        let endpoint = self.endpoints.get(entry.endpoint_id() as u64).unwrap();
+        let device_id = endpoint.device_id() as usize;
+        let address = endpoint.endpoint_address();
+        self.device_data[device_id].input_report_descriptor(address)?;
+        let (_, report) = self.get_acked_payload(transaction_id)?;
+        let descriptor = self.device_data[device_id]
+            .input_report_descriptor(address)?;
+        let values: Vec<_> = descriptor.decode(ReportType::Input, &report)?
+            .into_iter()
+            .filter(|value| value.value != 0)
+            .collect();
+        Some(if values.is_empty() {
+            "all zero".to_string()
+        } else {
+            fmt_values(&values)
+        })
+    }
+
+    /// Describe the SOF gaps within a range of packets, if any.
+    fn fmt_sof_gaps(&self, packet_range: Range<u64>) -> String {
+        let mut gaps = self.sof_gaps.range(packet_range)
+                                    .map(|(_, gap)| gap);
+        match (gaps.next(), gaps.count()) {
+            (None, _) => "".to_string(),
+            (Some(gap), 0) => format!(", {}", gap.description()),
+            (Some(_), more) => format!(
+                ", {} gaps in frame numbers", more + 1),
+        }
+    }
+
+    /// Find the range of packets spanned by a range of an endpoint's
+    /// transactions.
+    fn transfer_packet_range(&mut self,
+                             endpoint_id: u16,
+                             range: Range<u64>) -> Range<u64>
+    {
+        let ep_traf = &mut self.endpoint_traffic[endpoint_id as usize];
+        let first = ep_traf.transaction_ids.get(range.start).unwrap();
+        let last = ep_traf.transaction_ids.get(range.end - 1).unwrap();
+        let start = self.transaction_index.get(first).unwrap();
+        let end = get_index_range(&mut self.transaction_index,
+                                  self.packet_index.len(), last).end;
+        start..end
+    }
+
     pub fn get_connectors(&mut self, item: &Item) -> String {
+        profile!("get_connectors");
         use EndpointState::*;
         use Item::*;
         let endpoint_count = self.endpoints.len() as usize;
@@ -466,6 +1305,17 @@ impl Capture {
             }, _ => false
         };
         let last = last_transaction && !extended;
+        let halted: Vec<bool> = if self.halts.is_empty() {
+            vec![false; state_length]
+        } else {
+            let transaction_id = match item {
+                Transaction(_, id) | Packet(_, id, _) => *id,
+                Transfer(..) => self.get_entry_transaction(*transfer_index_id),
+            };
+            (0..state_length)
+                .map(|i| is_halted(&self.halts, i as u16, transaction_id))
+                .collect()
+        };
         let mut thru = false;
         for i in 0..state_length {
             let state = EndpointState::from(endpoint_state[i]);
@@ -476,21 +1326,26 @@ impl Capture {
                 (Transaction(..) | Packet(..), _, true) => on_endpoint,
                 _ => false,
             };
+            // A halted endpoint is drawn with a dashed line.
+            let (idle, line) = match halted[i] {
+                false => (' ', '│'),
+                true  => ('┆', '┆'),
+            };
             connectors.push(match item {
                 Transfer(..) => {
                     match (state, thru) {
-                        (Idle,     _    ) => ' ',
+                        (Idle,     _    ) => idle,
                         (Starting, _    ) => '○',
-                        (Ongoing,  false) => '│',
+                        (Ongoing,  false) => line,
                         (Ongoing,  true ) => '┼',
                         (Ending,   _    ) => '└',
                     }
                 },
                 Transaction(..) => {
                     match (on_endpoint, active, thru, last) {
-                        (false, false, false, _    ) => ' ',
+                        (false, false, false, _    ) => idle,
                         (false, false, true,  _    ) => '─',
-                        (false, true,  false, _    ) => '│',
+                        (false, true,  false, _    ) => line,
                         (false, true,  true,  _    ) => '┼',
                         (true,  _,     _,     false) => '├',
                         (true,  _,     _,     true ) => '└',
@@ -498,8 +1353,8 @@ impl Capture {
                 },
                 Packet(..) => {
                     match (on_endpoint, active, last) {
-                        (false, false, _    ) => ' ',
-                        (false, true,  _    ) => '│',
+                        (false, false, _    ) => idle,
+                        (false, true,  _    ) => line,
                         (true,  _,     false) => '│',
                         (true,  _,     true ) => ' ',
                     }
@@ -546,29 +1401,133 @@ impl Capture {
         let range = get_index_range(
             &mut self.endpoint_state_index,
             self.endpoint_states.len(), index);
-        self.endpoint_states.get_range(range).unwrap()
+        let bytes = self.endpoint_states.get_range(range).unwrap();
+        // Only endpoints seen before this entry have a state stored.
+        let count = bisect_right(&self.endpoint_first_entry, &index);
+        unpack_endpoint_states(&bytes, count)
     }
 
-    fn get_packet(&mut self, index: u64) -> Vec<u8> {
+    /// The time at which a packet was captured, in nanoseconds since the
+    /// Unix epoch, if the capture had timestamps.
+    pub fn packet_time(&mut self, packet_id: u64) -> Option<i64> {
+        if packet_id < self.packet_times.len() {
+            Some(self.packet_times.get(packet_id).unwrap())
+        } else {
+            None
+        }
+    }
+
+    /// The time of an item, which is that of its first packet. For a
+    /// transfer index entry which ends a transfer, this is the time of the
+    /// last transaction.
+    fn item_time(&mut self, item: &Item) -> Option<i64> {
+        let packet_id = match item {
+            Item::Transfer(transfer_index_id) => {
+                let transaction_id =
+                    self.get_entry_transaction(*transfer_index_id);
+                self.transaction_index.get(transaction_id).unwrap()
+            },
+            Item::Transaction(_, transaction_id) =>
+                self.transaction_index.get(*transaction_id).unwrap(),
+            Item::Packet(.., packet_id) => *packet_id,
+        };
+        self.packet_time(packet_id)
+    }
+
+    /// The time of an item as shown in the traffic view, in the current
+    /// `time_format`, or None if times are hidden or the capture has none.
+    /// `previous` is its previous sibling, which a delta is from.
+    pub fn get_time(&mut self, item: &Item, previous: Option<&Item>)
+        -> Option<String>
+    {
+        let time = self.item_time(item)?;
+        match self.time_format {
+            TimeFormat::Hidden => None,
+            TimeFormat::Absolute => Some(fmt_time_of_day(time)),
+            TimeFormat::Relative => {
+                let start = self.packet_time(0)?;
+                Some(fmt_nanos(time - start))
+            },
+            TimeFormat::SiblingDelta => {
+                let before = match previous {
+                    Some(previous) => self.item_time(previous)?,
+                    None => time,
+                };
+                Some(if time >= before {
+                    format!("+{}", fmt_nanos(time - before))
+                } else {
+                    fmt_nanos(time - before)
+                })
+            },
+        }
+    }
+
+    pub fn get_packet(&mut self, index: u64) -> Vec<u8> {
         let range = get_index_range(&mut self.packet_index,
                                     self.packet_data.len(), index);
         self.packet_data.get_range(range).unwrap()
     }
 
+    /// Get the first transaction of the transfer started by a transfer
+    /// index entry, or the last transaction of the transfer it ends.
+    pub fn get_entry_transaction(&mut self, transfer_index_id: u64) -> u64 {
+        let entry = self.transfer_index.get(transfer_index_id).unwrap();
+        let ep_traf = &mut self.endpoint_traffic[entry.endpoint_id() as usize];
+        let range = get_index_range(&mut ep_traf.transfer_index,
+                                    ep_traf.transaction_ids.len(),
+                                    entry.transfer_id());
+        let position = if entry.is_start() {
+            range.start
+        } else {
+            range.end - 1
+        };
+        ep_traf.transaction_ids.get(position).unwrap()
+    }
+
+    /// Get the PID of the last packet of a transaction, which is its
+    /// handshake if it had one.
+    pub fn get_last_pid(&mut self, transaction_id: u64) -> PID {
+        let range = get_index_range(&mut self.transaction_index,
+                                    self.packet_index.len(), transaction_id);
+        self.get_packet_pid(range.end - 1)
+    }
+
     fn get_packet_pid(&mut self, index: u64) -> PID {
         let offset = self.packet_index.get(index).unwrap();
         PID::from(self.packet_data.get(offset).unwrap())
     }
 
+    /// Whether a packet prefixes the one after it: PRE, sent at full speed
+    /// before each packet from the host to a low speed device, or SPLIT,
+    /// sent at high speed before a token for a low or full speed device.
+    pub fn is_prefix(&self, pid: PID) -> bool {
+        let low_or_full =
+            matches!(self.speed, Some(Speed::Low | Speed::Full));
+        match pid {
+            PID::ERR => low_or_full,
+            PID::SPLIT => !low_or_full,
+            _ => false,
+        }
+    }
+
+    /// Find the next packet in a range which is not a prefix.
+    fn skip_prefixes(&mut self, range: Range<u64>) -> Option<u64> {
+        range.into_iter().find(|id| {
+            let pid = self.get_packet_pid(*id);
+            !self.is_prefix(pid)
+        })
+    }
+
     fn get_transaction(&mut self, index: &u64) -> Transaction {
         let packet_id_range = get_index_range(&mut self.transaction_index,
                                               self.packet_index.len(), *index);
-        let packet_count = packet_id_range.end - packet_id_range.start;
-        let pid = self.get_packet_pid(packet_id_range.start);
+        let token_id = self.skip_prefixes(packet_id_range.clone())
+            .unwrap_or(packet_id_range.start);
+        let data_id = self.skip_prefixes(token_id + 1..packet_id_range.end);
+        let pid = self.get_packet_pid(token_id);
         use PID::*;
-        let payload_byte_range = match pid {
-            IN | OUT if packet_count >= 2 => {
-                let data_packet_id = packet_id_range.start + 1;
+        let payload_byte_range = match (pid, data_id) {
+            (IN | OUT, Some(data_packet_id)) => {
                 let packet_byte_range = get_index_range(
                     &mut self.packet_index,
                     self.packet_data.len(), data_packet_id);
@@ -589,6 +1548,28 @@ impl Capture {
         }
     }
 
+    /// Decode a transfer as a control transfer, if it is one.
+    pub fn control_transfer(&mut self, transfer_index_id: u64)
+        -> Option<ControlTransfer>
+    {
+        let entry = self.transfer_index.get(transfer_index_id).unwrap();
+        if !entry.is_start() {
+            return None;
+        }
+        let endpoint_id = entry.endpoint_id();
+        let endpoint = self.endpoints.get(endpoint_id as u64).unwrap();
+        let dev_data = &self.device_data[endpoint.device_id() as usize];
+        match dev_data.endpoint_type(endpoint.endpoint_address()) {
+            EndpointType::Control => {
+                let item = Item::Transfer(transfer_index_id);
+                let range = self.item_range(&item);
+                Some(self.get_control_transfer(
+                    endpoint.device_address(), endpoint_id, range))
+            },
+            _ => None
+        }
+    }
+
     fn get_control_transfer(&mut self,
                             address: u8,
                             endpoint_id: u16,
@@ -598,10 +1579,14 @@ impl Capture {
         let transaction_ids =
             ep_traf.transaction_ids.get_range(range).unwrap();
         let setup_transaction_id = transaction_ids[0];
-        let setup_packet_id =
-            self.transaction_index.get(setup_transaction_id)
-                                  .unwrap();
-        let data_packet_id = setup_packet_id + 1;
+        let setup_range = get_index_range(&mut self.transaction_index,
+                                          self.packet_index.len(),
+                                          setup_transaction_id);
+        let setup_packet_id = self.skip_prefixes(setup_range.clone())
+            .unwrap_or(setup_range.start);
+        let data_packet_id = self.skip_prefixes(
+            setup_packet_id + 1..setup_range.end)
+            .unwrap_or(setup_packet_id + 1);
         let data_packet = self.get_packet(data_packet_id);
         let fields = SetupFields::from_data_packet(&data_packet);
         let direction = fields.type_fields.direction();
@@ -614,8 +1599,7 @@ impl Capture {
             {
                 (Direction::In,  PID::IN,  Some(range)) |
                 (Direction::Out, PID::OUT, Some(range)) => {
-                    data.extend_from_slice(
-                        &self.packet_data.get_range(range).unwrap());
+                    data.extend(self.packet_data.get_range(range).unwrap());
                 },
                 (..) => {}
             };
@@ -627,6 +1611,124 @@ impl Capture {
         }
     }
 
+    fn traffic_groups(&mut self) -> &Vec<DeviceGroup> {
+        if self.traffic_groups.is_none() {
+            self.traffic_groups = Some(group_traffic(self, self.group_mode));
+        }
+        self.traffic_groups.as_ref().unwrap()
+    }
+
+    /// Set how traffic is grouped. Views of the previous grouping must not
+    /// be used after it changes.
+    pub fn set_group_mode(&mut self, mode: GroupMode) {
+        if mode != self.group_mode {
+            self.group_mode = mode;
+            self.traffic_groups = None;
+        }
+    }
+
+    pub fn group_item_count(&mut self, parent: &Option<GroupItem>) -> u64 {
+        use GroupItem::*;
+        let control = self.group_mode == GroupMode::Control;
+        let groups = self.traffic_groups();
+        (match parent {
+            None => groups.len(),
+            // Control transfers are shown directly under their device.
+            Some(Device(dev)) if control =>
+                groups[*dev].interfaces[0].endpoints[0].transfer_ids.len(),
+            Some(Device(dev)) => groups[*dev].interfaces.len(),
+            Some(Interface(dev, iface)) =>
+                groups[*dev].interfaces[*iface].endpoints.len(),
+            Some(Endpoint(dev, iface, ep)) =>
+                groups[*dev].interfaces[*iface].endpoints[*ep]
+                    .transfer_ids.len(),
+            Some(Traffic(item)) => return self.child_count(item),
+        }) as u64
+    }
+
+    pub fn get_group_item(&mut self, parent: &Option<GroupItem>, index: u64)
+        -> GroupItem
+    {
+        use GroupItem::*;
+        let index = index as usize;
+        match parent {
+            None => Device(index),
+            Some(Device(dev)) if self.group_mode == GroupMode::Control => {
+                let groups = self.traffic_groups();
+                let endpoint = &groups[*dev].interfaces[0].endpoints[0];
+                Traffic(Item::Transfer(endpoint.transfer_ids[index]))
+            },
+            Some(Device(dev)) => Interface(*dev, index),
+            Some(Interface(dev, iface)) => Endpoint(*dev, *iface, index),
+            Some(Endpoint(dev, iface, ep)) => {
+                let groups = self.traffic_groups();
+                let endpoint = &groups[*dev].interfaces[*iface].endpoints[*ep];
+                Traffic(Item::Transfer(endpoint.transfer_ids[index]))
+            },
+            Some(Traffic(item)) =>
+                Traffic(self.get_child(item, index as u64)),
+        }
+    }
+
+    pub fn get_group_summary(&mut self, item: &GroupItem) -> String {
+        use GroupItem::*;
+        let fmt_transfers = |count: usize| format!("{} transfer{}",
+            fmt_count(count as u64), if count == 1 {""} else {"s"});
+        match item {
+            Device(dev) => {
+                let group = &self.traffic_groups()[*dev];
+                let device_id = group.device_id;
+                let count = group.transfer_count();
+                let summary =
+                    self.get_device_summary(&DeviceItem::Device(device_id));
+                format!("{}, {}", summary, fmt_transfers(count))
+            },
+            Interface(dev, iface) => {
+                let device_id = self.traffic_groups()[*dev].device_id;
+                let group = &self.traffic_groups()[*dev].interfaces[*iface];
+                let count = group.transfer_count();
+                let number = match group.interface_number {
+                    Some(number) => number,
+                    None => return format!(
+                        "Device endpoints, {}", fmt_transfers(count)),
+                };
+                let class = self.device_data[device_id as usize]
+                    .configuration()
+                    .and_then(|config| config.interfaces.iter().find(|i|
+                        i.descriptor.interface_number == number))
+                    .and_then(|i| i.descriptor.class_triple().description());
+                match class {
+                    Some(class) => format!("Interface {}: {}, {}",
+                                           number, class, fmt_transfers(count)),
+                    None => format!("Interface {}, {}",
+                                    number, fmt_transfers(count)),
+                }
+            },
+            Endpoint(dev, iface, ep) => {
+                let device_id = self.traffic_groups()[*dev].device_id;
+                let group = &self.traffic_groups()[*dev].interfaces[*iface]
+                    .endpoints[*ep];
+                let count = group.transfer_ids.len();
+                let address = group.address;
+                let label = match self.label(device_id as usize, Some(address))
+                {
+                    Some(label) => format!(" ({})", label),
+                    None => String::new(),
+                };
+                match address {
+                    0x10 => format!("Framing packets, {} items",
+                                    fmt_count(count as u64)),
+                    0x11 => format!("Invalid packets, {} items",
+                                    fmt_count(count as u64)),
+                    address => format!("Endpoint {}{}, {}",
+                                       fmt_endpoint_address(address), label,
+                                       fmt_transfers(count)),
+                }
+            },
+            Traffic(item) => self.get_summary(item),
+        }
+    }
+
     pub fn get_device_item(&mut self, parent: &Option<DeviceItem>, index: u64)
         -> DeviceItem
     {
@@ -645,22 +1747,43 @@ impl Capture {
             },
             DeviceDescriptor(dev) =>
                 DeviceDescriptorField(*dev, index as u8),
-            Configuration(dev, conf) => match index {
-                0 => ConfigurationDescriptor(*dev, *conf),
-                n => Interface(*dev, *conf, (n - 1).try_into().unwrap()),
+            Configuration(dev, conf) => {
+                let config = self.device_data[*dev as usize]
+                    .configurations[*conf as usize].as_ref().unwrap();
+                let iface_count = config.interfaces.len() as u64;
+                match index {
+                    0 => ConfigurationDescriptor(*dev, *conf),
+                    n if n <= iface_count =>
+                        Interface(*dev, *conf, (n - 1).try_into().unwrap()),
+                    n => InterfaceAssociationDescriptor(*dev, *conf,
+                        (n - 1 - iface_count).try_into().unwrap()),
+                }
             },
             ConfigurationDescriptor(dev, conf) =>
                 ConfigurationDescriptorField(*dev, *conf, index as u8),
-            Interface(dev, conf, iface) => match index {
-                0 => InterfaceDescriptor(*dev, *conf, *iface),
-                n => EndpointDescriptor(*dev, *conf, *iface,
-                                        (n - 1).try_into().unwrap())
+            Interface(dev, conf, iface) => {
+                let config = self.device_data[*dev as usize]
+                    .configurations[*conf as usize].as_ref().unwrap();
+                let ep_count = config.interfaces[*iface as usize]
+                    .endpoint_descriptors.len() as u64;
+                match index {
+                    0 => InterfaceDescriptor(*dev, *conf, *iface),
+                    n if n <= ep_count =>
+                        EndpointDescriptor(*dev, *conf, *iface,
+                                           (n - 1).try_into().unwrap()),
+                    _ => HidReportDescriptor(*dev, *conf, *iface),
+                }
             },
             InterfaceDescriptor(dev, conf, iface) =>
                 InterfaceDescriptorField(*dev, *conf, *iface, index as u8),
+            InterfaceAssociationDescriptor(dev, conf, assoc) =>
+                InterfaceAssociationDescriptorField(*dev, *conf, *assoc,
+                                                    index as u8),
             EndpointDescriptor(dev, conf, iface, ep) =>
                  EndpointDescriptorField(*dev, *conf, *iface,
                                          *ep, index as u8),
+            HidReportDescriptor(dev, conf, iface) =>
+                HidReportItem(*dev, *conf, *iface, index as u16),
             _ => panic!("Item does not have children")
         }
     }
@@ -687,7 +1810,8 @@ impl Capture {
                 match data[*dev as usize]
                     .configurations[*conf as usize].as_ref()
                 {
-                    Some(conf) => 1 + conf.interfaces.len(),
+                    Some(conf) =>
+                        1 + conf.interfaces.len() + conf.associations.len(),
                     None => 0
                 },
             ConfigurationDescriptor(dev, conf) =>
@@ -701,26 +1825,48 @@ impl Capture {
                 match data[*dev as usize]
                     .configurations[*conf as usize].as_ref()
                 {
-                    Some(conf) => 1 + conf.interfaces[*iface as usize]
-                        .endpoint_descriptors.len(),
+                    Some(conf) => {
+                        let iface = &conf.interfaces[*iface as usize];
+                        let number = iface.descriptor.interface_number;
+                        1 + iface.endpoint_descriptors.len() +
+                            match data[*dev as usize]
+                                .report_descriptor(number)
+                            {
+                                Some(_) => 1,
+                                None => 0,
+                            }
+                    },
                     None => 0
                 },
             InterfaceDescriptor(..) => 9,
+            InterfaceAssociationDescriptor(..) => 8,
             EndpointDescriptor(..) => 6,
+            HidReportDescriptor(dev, conf, iface) => {
+                let dev_data = &data[*dev as usize];
+                let config = dev_data.configurations[*conf as usize]
+                    .as_ref().unwrap();
+                let number = config.interfaces[*iface as usize]
+                    .descriptor.interface_number;
+                dev_data.report_descriptor(number).unwrap().items.len()
+            },
             _ => 0
         }) as u64
     }
 
     pub fn get_device_summary(&mut self, item: &DeviceItem) -> String {
+        profile!("get_device_summary");
         use DeviceItem::*;
         match item {
             Device(dev) => {
-                let data = &self.device_data[*dev as usize];
                 let device = self.devices.get(*dev).unwrap();
-                format!("Device {}: {}", device.address,
+                let label = match self.label(*dev as usize, None) {
+                    Some(label) => format!(" ({})", label),
+                    None => String::new(),
+                };
+                let data = &self.device_data[*dev as usize];
+                format!("Device {}{}: {}", device.address, label,
                     match data.device_descriptor {
-                        Some(descriptor) => format!(
-                            "{:04X}:{:04X}",
+                        Some(descriptor) => fmt_device_id(
                             descriptor.vendor_id,
                             descriptor.product_id
                         ),
@@ -750,8 +1896,21 @@ impl Capture {
                 let config = config.as_ref().unwrap();
                 config.descriptor.field_text(*field, &data.strings)
             },
-            Interface(_, _, iface) => format!(
-                "Interface {}", iface),
+            Interface(dev, conf, iface) => {
+                let data = &self.device_data[*dev as usize];
+                let config = &data.configurations[*conf as usize];
+                let config = config.as_ref().unwrap();
+                let desc = &config.interfaces[*iface as usize].descriptor;
+                let name = match desc.alternate_setting {
+                    0 => format!("Interface {}", desc.interface_number),
+                    alt => format!("Interface {} alternate {}",
+                                   desc.interface_number, alt),
+                };
+                match desc.class_triple().description() {
+                    Some(description) => format!("{}: {}", name, description),
+                    None => name,
+                }
+            },
             InterfaceDescriptor(..) =>
                 "Interface descriptor".to_string(),
             InterfaceDescriptorField(dev, conf, iface, field) => {
@@ -761,6 +1920,28 @@ impl Capture {
                 let iface = &config.interfaces[*iface as usize];
                 iface.descriptor.field_text(*field, &data.strings)
             },
+            InterfaceAssociationDescriptor(dev, conf, assoc) => {
+                let data = &self.device_data[*dev as usize];
+                let config = &data.configurations[*conf as usize];
+                let config = config.as_ref().unwrap();
+                let desc = &config.associations[*assoc as usize];
+                let first = desc.first_interface;
+                let last = first.saturating_add(
+                    desc.interface_count.saturating_sub(1));
+                format!("Interface association for interfaces {}-{}{}",
+                    first, last,
+                    match desc.class_triple().description() {
+                        Some(description) => format!(": {}", description),
+                        None => "".to_string(),
+                    })
+            },
+            InterfaceAssociationDescriptorField(dev, conf, assoc, field) => {
+                let data = &self.device_data[*dev as usize];
+                let config = &data.configurations[*conf as usize];
+                let config = config.as_ref().unwrap();
+                let desc = &config.associations[*assoc as usize];
+                desc.field_text(*field, &data.strings)
+            },
             EndpointDescriptor(dev, conf, iface, ep) => {
                 let data = &self.device_data[*dev as usize];
                 let config = &data.configurations[*conf as usize];
@@ -779,6 +1960,24 @@ impl Capture {
                 let iface = &config.interfaces[*iface as usize];
                 let desc = iface.endpoint_descriptors[*ep as usize];
                 desc.field_text(*field)
+            },
+            HidReportDescriptor(dev, conf, iface) => {
+                let data = &self.device_data[*dev as usize];
+                let config = &data.configurations[*conf as usize];
+                let config = config.as_ref().unwrap();
+                let iface = &config.interfaces[*iface as usize];
+                let number = iface.descriptor.interface_number;
+                let desc = data.report_descriptor(number).unwrap();
+                format!("HID report descriptor, {} items", desc.items.len())
+            },
+            HidReportItem(dev, conf, iface, index) => {
+                let data = &self.device_data[*dev as usize];
+                let config = &data.configurations[*conf as usize];
+                let config = config.as_ref().unwrap();
+                let iface = &config.interfaces[*iface as usize];
+                let number = iface.descriptor.interface_number;
+                let desc = data.report_descriptor(number).unwrap();
+                desc.item_description(*index as usize)
             }
         }
     }
@@ -787,66 +1986,173 @@ impl Capture {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
-    use std::io::{BufReader, BufWriter, BufRead, Write};
-    use crate::decoder::Decoder;
+    use std::path::{Path, PathBuf};
+    use crate::corpus::{compare, find_cases, run_selftest, CORPUS_VAR};
 
-    fn write_item(cap: &mut Capture, item: &Item, depth: u8,
-                  writer: &mut dyn Write)
-    {
-        let summary = cap.get_summary(&item);
-        for _ in 0..depth {
-            writer.write(b" ").unwrap();
+    #[test]
+    fn test_captures() {
+        for case in find_cases(Path::new("./tests/")).unwrap() {
+            let output = case.decode().unwrap();
+            let reference =
+                std::fs::read_to_string(case.reference_path()).unwrap();
+            if let Err(err) = compare(&reference, &output) {
+                // Kept for comparison, in case the reference needs updating.
+                let path = tempfile::tempdir().unwrap().into_path()
+                    .join("output.txt");
+                std::fs::write(&path, &output).unwrap();
+                panic!("{}: {} (output written to {})",
+                       case.dir.display(), err, path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn test_external_corpus() {
+        let dir = match std::env::var_os(CORPUS_VAR) {
+            Some(dir) => PathBuf::from(dir),
+            None => return,
+        };
+        let mut output = Vec::new();
+        let failures = run_selftest(&dir, &mut output).unwrap();
+        assert!(failures == 0, "{}", String::from_utf8_lossy(&output));
+    }
+
+    #[test]
+    fn test_endpoint_limit() {
+        use crate::decoder::Decoder;
+        use crate::input::decode_files;
+        let decode = |limit| {
+            let mut cap = Capture::new();
+            cap.endpoint_traffic = EndpointTrafficStore::new(limit);
+            let mut decoder = Decoder::new(&mut cap);
+            decode_files(&mut decoder, &["./tests/mouse/capture.pcap"])
+                .unwrap();
+            drop(decoder);
+            cap
+        };
+        let mut limited = decode(1);
+        assert!(limited.endpoint_traffic.len() > 1);
+        assert!(limited.endpoint_traffic.active_count() == 1);
+        assert!(limited.endpoint_traffic.iter()
+                .filter(|traffic| traffic.transaction_ids.is_parked())
+                .count() == limited.endpoint_traffic.len() - 1);
+        let mut unlimited = decode(usize::MAX);
+        crate::verify::compare(&mut limited, &mut unlimited).unwrap();
+    }
+
+    #[test]
+    fn test_item_path() {
+        use crate::decoder::Decoder;
+        use crate::input::decode_files;
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let transfer = cap.get_item(&None, 5);
+        let transaction = cap.get_child(&transfer, 1);
+        let packet = cap.get_child(&transaction, 1);
+        assert!(cap.item_path(&transfer) == Some(vec![5]));
+        assert!(cap.item_path(&transaction) == Some(vec![5, 1]));
+        assert!(cap.item_path(&packet) == Some(vec![5, 1, 1]));
+        let text = packet.to_string();
+        assert!(text.starts_with("packet:"));
+        assert!(text.parse::<Item>() == Ok(packet));
+        if let Item::Transaction(t, x) = transaction {
+            assert!(cap.item_path(&Item::Transaction(t, x + 1000)).is_none());
+            assert!(cap.item_path(&Item::Transfer(t + 1000000)).is_none());
         }
-        writer.write(summary.as_bytes()).unwrap();
-        writer.write(b"\n").unwrap();
-        let num_children = cap.child_count(&item);
-        for child_id in 0..num_children {
-            let child = cap.get_child(&item, child_id);
-            write_item(cap, &child, depth + 1, writer);
+        assert!("transfer:1.2".parse::<Item>().is_err());
+        assert!("packet:x".parse::<Item>().is_err());
+        assert!("transaction:3.4".parse::<Item>() ==
+                Ok(Item::Transaction(3, 4)));
+    }
+
+    #[test]
+    fn test_verbosity() {
+        use crate::decoder::Decoder;
+        use crate::input::decode_files;
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let transfer = cap.get_item(&None, 1);
+        let transaction = cap.get_child(&transfer, 0);
+        let token = cap.get_child(&transaction, 0);
+        let data = cap.get_child(&transaction, 1);
+        for (verbosity, token_summary, data_summary) in [
+            (Verbosity::Short,
+             "SETUP packet on 0.0",
+             "DATA0 packet with 8 data bytes"),
+            (Verbosity::Normal,
+             "SETUP packet on 0.0, CRC 02",
+             "DATA0 packet with 8 data bytes and CRC 94DD"),
+            (Verbosity::Verbose,
+             "SETUP packet on 0.0, CRC 02: [2D, 00, 10]",
+             "DATA0 packet with 8 data bytes and CRC 94DD: [C3, 80, 06, \
+              00, 01, 00, 00, 40, 00, DD, 94]"),
+        ] {
+            cap.verbosity = verbosity;
+            assert!(cap.get_summary(&token) == token_summary);
+            assert!(cap.get_summary(&data) == data_summary);
+            assert!(Verbosity::from_name(verbosity.name()) == Some(verbosity));
         }
     }
 
     #[test]
-    fn test_captures() {
-        let test_dir = "./tests/";
-        for result in std::fs::read_dir(test_dir).unwrap() {
-            let entry = result.unwrap();
-            if entry.file_type().unwrap().is_dir() {
-                let path = entry.path();
-                let mut cap_path = path.clone();
-                let mut ref_path = path.clone();
-                let mut out_path = path.clone();
-                cap_path.push("capture.pcap");
-                ref_path.push("reference.txt");
-                out_path.push("output.txt");
-                {
-                    let mut pcap = pcap::Capture::from_file(cap_path).unwrap();
-                    let mut cap = Capture::new();
-                    let mut decoder = Decoder::new(&mut cap);
-                    while let Ok(packet) = pcap.next() {
-                        decoder.handle_raw_packet(&packet);
-                    }
-                    let out_file = File::create(out_path.clone()).unwrap();
-                    let mut out_writer = BufWriter::new(out_file);
-                    let num_items = cap.item_index.len();
-                    for item_id in 0 .. num_items {
-                        let item = cap.get_item(&None, item_id);
-                        write_item(&mut cap, &item, 0, &mut out_writer);
-                    }
-                }
-                let ref_file = File::open(ref_path).unwrap();
-                let out_file = File::open(out_path.clone()).unwrap();
-                let ref_reader = BufReader::new(ref_file);
-                let out_reader = BufReader::new(out_file);
-                let mut out_lines = out_reader.lines();
-                for line in ref_reader.lines() {
-                    let expected = line.unwrap();
-                    let actual = out_lines.next().unwrap().unwrap();
-                    assert!(actual == expected);
-                }
-            }
+    fn test_time_format() {
+        use crate::decoder::Decoder;
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        for (time, packet) in [
+            (1_000_000_000, &[0x2d, 0x00, 0x10][..]),
+            (1_000_004_000,
+             &[0xc3, 0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00,
+               0xdd, 0x94]),
+            (3_723_500_000_001, &[0xd2]),
+        ] {
+            decoder.handle_raw_packet_at(packet, Some(time)).unwrap();
         }
+        drop(decoder);
+        let transfer = cap.get_item(&None, 0);
+        let transaction = cap.get_child(&transfer, 0);
+        let data = cap.get_child(&transaction, 1);
+        let handshake = cap.get_child(&transaction, 2);
+        assert!(cap.get_time(&data, None).is_none());
+        cap.time_format = TimeFormat::Absolute;
+        assert!(cap.get_time(&data, None).unwrap() ==
+                "00:00:01.000004000");
+        assert!(cap.get_time(&handshake, None).unwrap() ==
+                "01:02:03.500000001");
+        cap.time_format = TimeFormat::Relative;
+        assert!(cap.get_time(&transfer, None).unwrap() == "0.000000000");
+        assert!(cap.get_time(&handshake, None).unwrap() ==
+                "3722.500000001");
+        cap.time_format = TimeFormat::SiblingDelta;
+        assert!(cap.get_time(&handshake, Some(&data)).unwrap() ==
+                "+3722.499996001");
+        assert!(cap.get_time(&data, Some(&handshake)).unwrap() ==
+                "-3722.499996001");
+        assert!(cap.get_time(&transaction, Some(&transfer)).unwrap() ==
+                "+0.000000000");
+        assert!(cap.get_time(&transfer, None).unwrap() == "+0.000000000");
+        assert!(fmt_time_of_day(-1) == "23:59:59.999999999");
+        assert!(fmt_nanos(-1_500_000_000) == "-1.500000000");
+        for format in [TimeFormat::Hidden, TimeFormat::Absolute,
+                       TimeFormat::Relative, TimeFormat::SiblingDelta]
+        {
+            assert!(TimeFormat::from_name(format.name()) == Some(format));
+        }
+        assert!(TimeFormat::from_name("delta") ==
+                Some(TimeFormat::SiblingDelta));
     }
-}
 
+    #[test]
+    fn test_endpoint_state_packing() {
+        let states = [3, 0, 1, 2, 2, 1];
+        let packed = pack_endpoint_states(&states);
+        assert!(packed == vec![0b10_01_00_11, 0b01_10]);
+        assert!(unpack_endpoint_states(&packed, 6) == states);
+        assert!(unpack_endpoint_states(&packed, 5) == states[..5]);
+        assert!(pack_endpoint_states(&[]).is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+//! Running the decoder over a corpus of captures with expected outputs.
+//!
+//! A corpus is a directory with a subdirectory for each test case, holding
+//! a `capture.pcap` and a `reference.txt` with the expected traffic tree,
+//! as written by the text exporter. This is the layout of the repository's
+//! own `tests` directory, and can be used for larger external corpora which
+//! are not committed to the repository.
+//!
+//! A corpus can also be checked against a baseline recorded by another
+//! build, such as a previous release, to find any changes in decoding
+//! beyond those covered by the references. Only the captures are needed
+//! for this.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::capture::Capture;
+use crate::decoder::Decoder;
+use crate::export::{write_tree, TextWriter};
+use crate::input::{decode_files, InputError};
+
+/// Environment variable giving an external corpus, for the test harness
+/// and as the default for the selftest command.
+pub const CORPUS_VAR: &str = "PACKETRY_TEST_CORPUS";
+
+#[derive(Error, Debug)]
+pub enum CorpusError {
+    #[error(transparent)]
+    InputError(#[from] InputError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error("line {line}: expected \"{expected}\", found \"{actual}\"")]
+    Mismatch { line: usize, expected: String, actual: String },
+    #[error("output has {output} lines, but the reference has {reference}")]
+    LengthMismatch { output: usize, reference: usize },
+}
+
+/// Number of lines shown from each side of a difference from a baseline.
+const DIFFERENCE_LINES: usize = 5;
+
+/// A capture, with the traffic tree expected from it if it has a reference.
+pub struct TestCase {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+impl TestCase {
+    pub fn capture_path(&self) -> PathBuf {
+        self.dir.join("capture.pcap")
+    }
+
+    pub fn reference_path(&self) -> PathBuf {
+        self.dir.join("reference.txt")
+    }
+
+    pub fn baseline_path(&self, baseline_dir: &Path) -> PathBuf {
+        baseline_dir.join(format!("{}.txt", self.name))
+    }
+
+    fn decode_capture(&self) -> Result<Capture, CorpusError> {
+        let path = self.capture_path();
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &[path.to_string_lossy()])?;
+        drop(decoder);
+        Ok(cap)
+    }
+
+    /// Decode the capture, returning its traffic tree as text.
+    pub fn decode(&self) -> Result<String, CorpusError> {
+        let mut cap = self.decode_capture()?;
+        tree_text(&mut cap)
+    }
+
+    /// Decode the capture, returning statistics on the decoded structure
+    /// followed by its traffic tree, for comparison between builds.
+    pub fn baseline(&self) -> Result<String, CorpusError> {
+        let mut cap = self.decode_capture()?;
+        Ok(format!(concat!(
+            "# Packets: {}\n",
+            "# Transactions: {}\n",
+            "# Top-level items: {}\n",
+            "# Devices: {}\n",
+            "# Endpoints: {}\n",
+            "{}"),
+            cap.packet_index.len(),
+            cap.transaction_index.len(),
+            cap.item_index.len(),
+            cap.devices.len(),
+            cap.endpoints.len(),
+            tree_text(&mut cap)?))
+    }
+
+    /// Decode the capture and compare its traffic tree to the reference.
+    pub fn check(&self) -> Result<(), CorpusError> {
+        let reference = std::fs::read_to_string(self.reference_path())?;
+        compare(&reference, &self.decode()?)
+    }
+}
+
+fn tree_text(cap: &mut Capture) -> Result<String, CorpusError> {
+    let mut output = Vec::new();
+    write_tree(cap, &mut TextWriter::new(&mut output))?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// Compare a traffic tree to a reference, line by line.
+pub fn compare(reference: &str, output: &str) -> Result<(), CorpusError> {
+    let mut output_lines = output.lines();
+    let mut count = 0;
+    for (i, expected) in reference.lines().enumerate() {
+        count = i + 1;
+        match output_lines.next() {
+            Some(actual) if actual == expected => {},
+            Some(actual) => return Err(CorpusError::Mismatch {
+                line: i + 1,
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+            }),
+            None => return Err(CorpusError::LengthMismatch {
+                output: i,
+                reference: reference.lines().count(),
+            }),
+        }
+    }
+    match output_lines.count() {
+        0 => Ok(()),
+        extra => Err(CorpusError::LengthMismatch {
+            output: count + extra,
+            reference: count,
+        }),
+    }
+}
+
+/// The lines which differ between two versions of a text, found by
+/// removing the lines they have in common at the start and end.
+#[derive(Debug, PartialEq)]
+pub struct Difference {
+    /// Line number of the first differing line.
+    pub line: usize,
+    pub removed: Vec<String>,
+    pub added: Vec<String>,
+}
+
+/// Find the region in which two texts differ, if they differ at all.
+pub fn difference(old: &str, new: &str) -> Option<Difference> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let start = old.iter().zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if start == old.len() && start == new.len() {
+        return None;
+    }
+    let end = old[start..].iter().rev().zip(new[start..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let lines = |text: &[&str]| text.iter()
+        .map(|line| line.to_string())
+        .collect();
+    Some(Difference {
+        line: start + 1,
+        removed: lines(&old[start..old.len() - end]),
+        added: lines(&new[start..new.len() - end]),
+    })
+}
+
+/// Find the captures in a corpus directory, in order of name.
+pub fn find_captures(dir: &Path) -> std::io::Result<Vec<TestCase>> {
+    let mut cases = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let case = TestCase {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            dir: entry.path(),
+        };
+        if entry.file_type()?.is_dir() && case.capture_path().is_file() {
+            cases.push(case);
+        }
+    }
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(cases)
+}
+
+/// Find the test cases in a corpus directory which have a reference, in
+/// order of name.
+pub fn find_cases(dir: &Path) -> std::io::Result<Vec<TestCase>> {
+    Ok(find_captures(dir)?.into_iter()
+        .filter(|case| case.reference_path().is_file())
+        .collect())
+}
+
+/// Check every case in a corpus, reporting each result to `output`.
+/// Returns the number of cases which failed.
+pub fn run_selftest(dir: &Path, output: &mut dyn Write)
+    -> std::io::Result<usize>
+{
+    let cases = find_cases(dir)?;
+    let mut failures = 0;
+    for case in &cases {
+        match case.check() {
+            Ok(()) => writeln!(output, "PASS {}", case.name)?,
+            Err(err) => {
+                failures += 1;
+                writeln!(output, "FAIL {}: {}", case.name, err)?;
+            }
+        }
+    }
+    writeln!(output, "{} of {} cases passed",
+             cases.len() - failures, cases.len())?;
+    Ok(failures)
+}
+
+/// Record a baseline for every capture in a corpus, as one file per
+/// capture in `baseline_dir`. Returns the number which failed to decode.
+pub fn record_baseline(dir: &Path, baseline_dir: &Path,
+                       output: &mut dyn Write) -> std::io::Result<usize>
+{
+    std::fs::create_dir_all(baseline_dir)?;
+    let mut failures = 0;
+    for case in find_captures(dir)? {
+        match case.baseline() {
+            Ok(text) => {
+                std::fs::write(case.baseline_path(baseline_dir), text)?;
+                writeln!(output, "RECORDED {}", case.name)?;
+            },
+            Err(err) => {
+                failures += 1;
+                writeln!(output, "FAIL {}: {}", case.name, err)?;
+            }
+        }
+    }
+    Ok(failures)
+}
+
+/// Compare every capture in a corpus against a baseline recorded by
+/// [record_baseline], reporting any differences to `output`. Returns the
+/// number of captures which changed or could not be compared.
+pub fn compare_baseline(dir: &Path, baseline_dir: &Path,
+                        output: &mut dyn Write) -> std::io::Result<usize>
+{
+    let cases = find_captures(dir)?;
+    let mut changes = 0;
+    for case in &cases {
+        let result = std::fs::read_to_string(case.baseline_path(baseline_dir))
+            .map_err(CorpusError::from)
+            .and_then(|old| Ok((old, case.baseline()?)));
+        let (old, new) = match result {
+            Ok(texts) => texts,
+            Err(err) => {
+                changes += 1;
+                writeln!(output, "FAIL {}: {}", case.name, err)?;
+                continue;
+            }
+        };
+        match difference(&old, &new) {
+            None => writeln!(output, "SAME {}", case.name)?,
+            Some(diff) => {
+                changes += 1;
+                writeln!(output,
+                         "CHANGED {}: from line {}, {} lines replaced by {}",
+                         case.name, diff.line,
+                         diff.removed.len(), diff.added.len())?;
+                for line in diff.removed.iter().take(DIFFERENCE_LINES) {
+                    writeln!(output, "  - {}", line)?;
+                }
+                for line in diff.added.iter().take(DIFFERENCE_LINES) {
+                    writeln!(output, "  + {}", line)?;
+                }
+            }
+        }
+    }
+    writeln!(output, "{} of {} captures unchanged",
+             cases.len() - changes, cases.len())?;
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        assert!(compare("a\nb\n", "a\nb\n").is_ok());
+        assert!(matches!(compare("a\nb\n", "a\nc\n"),
+            Err(CorpusError::Mismatch { line: 2, .. })));
+        assert!(matches!(compare("a\nb\n", "a\n"),
+            Err(CorpusError::LengthMismatch { output: 1, reference: 2 })));
+        assert!(matches!(compare("a\n", "a\nb\nc\n"),
+            Err(CorpusError::LengthMismatch { output: 3, reference: 1 })));
+    }
+
+    #[test]
+    fn test_find_cases() {
+        let cases = find_cases(Path::new("./tests")).unwrap();
+        let names: Vec<&str> = cases.iter()
+            .map(|case| case.name.as_str())
+            .collect();
+        assert!(names == ["hackrf-connect", "hackrf-restart-failure",
+                          "mouse"]);
+    }
+
+    #[test]
+    fn test_difference() {
+        assert!(difference("a\nb\n", "a\nb\n").is_none());
+        assert!(difference("a\nb\nc\nd\n", "a\nx\ny\nd\n") ==
+            Some(Difference {
+                line: 2,
+                removed: vec!["b".to_string(), "c".to_string()],
+                added: vec!["x".to_string(), "y".to_string()],
+            }));
+        assert!(difference("a\nb\n", "a\nc\nb\n") == Some(Difference {
+            line: 2,
+            removed: vec![],
+            added: vec!["c".to_string()],
+        }));
+        assert!(difference("a\na\n", "a\n") == Some(Difference {
+            line: 2,
+            removed: vec!["a".to_string()],
+            added: vec![],
+        }));
+    }
+
+    #[test]
+    fn test_baseline() {
+        let dir = Path::new("./tests");
+        let baseline_dir = tempfile::tempdir().unwrap();
+        let mut output = Vec::new();
+        assert!(record_baseline(dir, baseline_dir.path(), &mut output)
+            .unwrap() == 0);
+        assert!(compare_baseline(dir, baseline_dir.path(), &mut output)
+            .unwrap() == 0);
+        let mouse = baseline_dir.path().join("mouse.txt");
+        let text = std::fs::read_to_string(&mouse).unwrap();
+        assert!(text.starts_with("# Packets: "));
+        std::fs::write(&mouse, text.replacen("# Devices: ", "# Devices: 1",
+                                             1)).unwrap();
+        let mut output = Vec::new();
+        assert!(compare_baseline(dir, baseline_dir.path(), &mut output)
+            .unwrap() == 1);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("CHANGED mouse: from line 4, 1 lines \
+                                 replaced by 1"));
+    }
+}
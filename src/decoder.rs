@@ -1,4 +1,4 @@
-use std::mem::size_of;
+use thiserror::Error;
 
 use crate::usb::{
     PID,
@@ -12,6 +12,10 @@ use crate::usb::{
     DeviceDescriptor,
     ConfigDescriptor,
     Configuration,
+    PhyErrors,
+    Speed,
+    SplitFields,
+    TokenFields,
 };
 
 use crate::capture::{
@@ -20,12 +24,19 @@ use crate::capture::{
     DeviceData,
     Endpoint,
     EndpointType,
+    SofGap,
+    SofGapKind,
+    SUSPEND_FRAMES,
     EndpointState,
     EndpointTraffic,
     TransferIndexEntry,
+    endpoint_type_index,
+    pack_endpoint_states,
 };
 
+use crate::hid::{REPORT_DESCRIPTOR_TYPE, ReportDescriptor};
 use crate::hybrid_index::HybridIndex;
+use crate::record::Record;
 
 #[derive(PartialEq)]
 enum DecodeStatus {
@@ -37,12 +48,29 @@ enum DecodeStatus {
 
 struct EndpointData {
     device_id: usize,
-    number: usize,
+    /// Address of the endpoint, with the direction in bit 7.
+    address: u8,
+    transfer_index_id: u64,
     transaction_start: u64,
     transaction_count: u64,
     last: PID,
     setup: Option<SetupFields>,
     payload: Vec<u8>,
+    /// Failed attempts which may be retried by the next transaction.
+    pending_retries: Vec<u64>,
+    /// Token of the pending attempts.
+    last_attempt: PID,
+    /// The transfer is done, but the complete-split of its last
+    /// transaction is still to come.
+    ending: bool,
+}
+
+/// The halves of a split transaction, in which a high speed hub carries a
+/// transaction to a low or full speed device.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Split {
+    Start,
+    Complete,
 }
 
 #[derive(Default)]
@@ -54,6 +82,12 @@ struct TransactionState {
     endpoint_id: usize,
     setup: Option<SetupFields>,
     payload: Vec<u8>,
+    /// Failed attempts which this transaction is a retry of.
+    retries: Vec<u64>,
+    /// Number of PRE or SPLIT packets prefixing others in the transaction.
+    prefixes: u64,
+    /// Which half of a split transaction this is, if it is one.
+    split: Option<Split>,
 }
 
 impl TransactionState {
@@ -104,6 +138,14 @@ impl TransactionState {
             // OUT may also be completed by NAK or STALL.
             (OUT, DATA0 | DATA1, NAK | STALL) => DecodeStatus::DONE,
 
+            // A hub answers a start-split IN with ACK, and the
+            // complete-split with NYET or ERR until it has a response.
+            (IN, IN, ACK | NYET | ERR) if self.split.is_some() =>
+                DecodeStatus::DONE,
+            // A complete-split SETUP or OUT is answered with no data.
+            (SETUP | OUT, SETUP | OUT, ACK | NAK | STALL | NYET | ERR)
+                if self.split == Some(Split::Complete) => DecodeStatus::DONE,
+
             // Any other case is not a valid part of a transaction.
             _ => DecodeStatus::INVALID,
         }
@@ -112,53 +154,301 @@ impl TransactionState {
     fn completed(&self) -> bool {
         use PID::*;
         // A transaction is completed if it has 3 valid packets and is
-        // acknowledged with an ACK or NYET handshake.
-        match (self.count, self.last) {
+        // acknowledged with an ACK or NYET handshake. The data of a
+        // complete-split IN is not acknowledged.
+        match (self.count - self.prefixes, self.last) {
             (3, ACK | NYET) => true,
+            (2, DATA0 | DATA1) => self.first == IN &&
+                self.split == Some(Split::Complete),
             (..)            => false
         }
     }
+
+    /// Whether this is the half of a split transaction which carries only
+    /// the hub's handshake, and none of the transaction's data.
+    fn split_handshake(&self) -> bool {
+        use PID::*;
+        match (self.split, self.first, self.last) {
+            (Some(Split::Start), IN, _) => true,
+            (Some(Split::Complete), SETUP | OUT, last) => last != STALL,
+            (Some(Split::Complete), IN, NAK | NYET | ERR) => true,
+            _ => false,
+        }
+    }
+
+    fn ignored(&self) -> bool {
+        use PID::*;
+        // A token was ignored, or the response to it corrupted, if the
+        // transaction did not end with a handshake.
+        matches!(self.first, SETUP | IN | OUT) &&
+            !matches!(self.last, ACK | NAK | NYET | STALL)
+    }
+}
+
+/// Errors in the traffic that the decoder can detect and report.
+#[derive(Error, Copy, Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// A packet which could not be part of any valid transaction.
+    #[error("invalid transaction")]
+    InvalidTransaction,
+    /// A transaction which could not be part of any valid transfer.
+    #[error("invalid transfer")]
+    InvalidTransfer,
+}
+
+/// Events reported to observers as decoding progresses.
+#[derive(Copy, Clone, Debug)]
+pub enum DecoderEvent {
+    /// A device descriptor was successfully read from a device.
+    DeviceEnumerated {
+        address: u8,
+        descriptor: DeviceDescriptor,
+        transfer_index_id: u64,
+    },
+    /// A device was set to a configuration.
+    ConfigurationSet {
+        address: u8,
+        config_id: usize,
+        transfer_index_id: u64,
+    },
+    /// A transfer was completed. The transfer can be looked up as a top
+    /// level item using the ID of its starting transfer index entry.
+    TransferCompleted {
+        endpoint_id: usize,
+        transfer_index_id: u64,
+    },
+    /// An endpoint responded to a transaction with a STALL handshake.
+    EndpointStalled {
+        endpoint_id: usize,
+        transfer_index_id: u64,
+    },
+    /// An error was detected in the traffic on an endpoint.
+    ErrorDetected {
+        endpoint_id: usize,
+        packet_id: u64,
+        error: DecodeError,
+    },
+}
+
+/// Interface for components that want to react to decode events.
+pub trait DecoderObserver {
+    fn handle_event(&mut self, event: &DecoderEvent);
+}
+
+impl<F> DecoderObserver for F where F: FnMut(&DecoderEvent) {
+    fn handle_event(&mut self, event: &DecoderEvent) {
+        self(event)
+    }
 }
 
 const USB_MAX_DEVICES: usize = 128;
 const USB_MAX_ENDPOINTS: usize = 16;
 
+// Endpoints are found by device address and endpoint key, of which there
+// are USB_MAX_ENDPOINTS * 2, so along with the invalid and framing
+// endpoints, no capture can have more endpoints than a transfer index
+// entry can give IDs to.
+const _: () = assert!(2 + USB_MAX_DEVICES * USB_MAX_ENDPOINTS * 2 <=
+                      TransferIndexEntry::MAX_ENDPOINT_ID + 1);
+
+#[derive(Error, Debug)]
+pub enum DecoderError {
+    #[error("capture exceeds the limit of {0} endpoints")]
+    TooManyEndpoints(usize),
+}
+
 pub struct Decoder<'cap> {
     capture: &'cap mut Capture,
-    device_index: [i8; USB_MAX_DEVICES],
-    endpoint_index: [[i16; USB_MAX_ENDPOINTS]; USB_MAX_DEVICES],
+    observers: Vec<Box<dyn DecoderObserver>>,
+    device_index: [Option<usize>; USB_MAX_DEVICES],
+    /// Endpoint IDs by device address, and by endpoint number with 16
+    /// added for IN endpoints. Control endpoints are keyed by number alone.
+    endpoint_index: [[Option<usize>; USB_MAX_ENDPOINTS * 2]; USB_MAX_DEVICES],
     endpoint_data: Vec<EndpointData>,
     last_endpoint_state: Vec<u8>,
-    last_item_endpoint: i16,
+    last_item_endpoint: Option<usize>,
     transaction_state: TransactionState,
+    last_frame_number: Option<u16>,
+    /// Packet ID of the last SOF gap, if no other traffic has followed it.
+    last_sof_gap: Option<u64>,
+    /// Packet ID of the first of any PRE or SPLIT packets awaiting the
+    /// packet they prefix, and how many there are.
+    prefix: Option<(u64, u64)>,
+    /// The half of a split transaction announced by the last SPLIT.
+    pending_split: Option<Split>,
+    /// Time of the last packet, once any time is known.
+    last_time: Option<i64>,
 }
 
 impl<'cap> Decoder<'cap> {
     pub fn new(capture: &'cap mut Capture) -> Self {
         let mut decoder = Decoder {
             capture: capture,
-            device_index: [-1; USB_MAX_DEVICES],
+            observers: Vec::new(),
+            device_index: [None; USB_MAX_DEVICES],
             endpoint_data: Vec::new(),
-            endpoint_index: [[-1; USB_MAX_ENDPOINTS]; USB_MAX_DEVICES],
+            endpoint_index: [[None; USB_MAX_ENDPOINTS * 2]; USB_MAX_DEVICES],
             last_endpoint_state: Vec::new(),
-            last_item_endpoint: -1,
+            last_item_endpoint: None,
             transaction_state: TransactionState::default(),
+            last_frame_number: None,
+            last_sof_gap: None,
+            prefix: None,
+            pending_split: None,
+            last_time: None,
         };
-        decoder.add_endpoint(0, EndpointType::Invalid as usize);
-        decoder.add_endpoint(0, EndpointType::Framing as usize);
+        decoder.add_endpoint(0, EndpointType::Invalid as usize, false);
+        decoder.add_endpoint(0, EndpointType::Framing as usize, false);
         decoder 
     }
 
-    pub fn handle_raw_packet(&mut self, packet: &[u8]) {
-        self.transaction_update(packet);
+    /// Set the speed of the bus, if the capture records it. This decides
+    /// whether PID 0x3C is PRE or ERR.
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.capture.speed = Some(speed);
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn DecoderObserver>) {
+        self.observers.push(observer);
+    }
+
+    fn notify(&mut self, event: DecoderEvent) {
+        for observer in self.observers.iter_mut() {
+            observer.handle_event(&event);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn handle_raw_packet(&mut self, packet: &[u8])
+        -> Result<(), DecoderError>
+    {
+        self.handle_raw_packet_at(packet, None)
+    }
+
+    /// Handle a raw packet with the time it was captured, in nanoseconds
+    /// since the Unix epoch, if known.
+    pub fn handle_raw_packet_at(&mut self, packet: &[u8], time: Option<i64>)
+        -> Result<(), DecoderError>
+    {
+        self.handle_raw_packet_with_errors(packet, PhyErrors::default(), time)
+    }
+
+    /// Handle a raw packet along with any errors which the capture
+    /// hardware detected while receiving it. The packet is decoded as
+    /// normal, and the errors are kept to be shown with it.
+    ///
+    /// A packet which would need an endpoint ID beyond those a transfer
+    /// index entry can hold is not stored, and an error is returned.
+    pub fn handle_raw_packet_with_errors(&mut self, packet: &[u8],
+                                         errors: PhyErrors,
+                                         time: Option<i64>)
+        -> Result<(), DecoderError>
+    {
+        profile!("handle_raw_packet");
+        self.transaction_update(packet)?;
+        if !errors.is_empty() {
+            let packet_id = self.capture.packet_index.len();
+            self.capture.phy_errors.insert(packet_id, errors);
+        }
+        self.record_time(time);
+        self.frame_update(packet);
         self.capture.packet_index.push(
             self.capture.packet_data.len()).unwrap();
         self.capture.packet_data.append(packet).unwrap();
+        Ok(())
+    }
+
+    /// Record the time of the packet about to be stored.
+    ///
+    /// Times are only kept once one is known, and then for every packet.
+    /// Packets before the first time known are given that time, and those
+    /// after without one, the time of the packet before. Times are kept
+    /// as given, even if out of order, so that they can be exported as
+    /// they were captured.
+    fn record_time(&mut self, time: Option<i64>) {
+        let times = &mut self.capture.packet_times;
+        let time = match (time, self.last_time) {
+            (Some(time), Some(_)) => time,
+            (Some(time), None) => {
+                let earlier = self.capture.packet_index.len() as usize;
+                times.append(&vec![time; earlier]).unwrap();
+                time
+            },
+            (None, Some(last)) => last,
+            (None, None) => return,
+        };
+        times.push(&time).unwrap();
+        self.last_time = Some(time);
     }
 
-    fn transaction_update(&mut self, packet: &[u8]) {
+    fn frame_update(&mut self, packet: &[u8]) {
+        let packet_id = self.capture.packet_index.len();
+        match PacketFields::from_packet(packet) {
+            PacketFields::SOF(sof) => {
+                let frame_number = sof.frame_number();
+                if let Some(last) = self.last_frame_number {
+                    // Frame numbers are 11 bits and wrap around. At high
+                    // speed each frame number is repeated in 8 microframes.
+                    let delta = frame_number.wrapping_sub(last) & 0x7FF;
+                    if delta > 1 {
+                        let missing_frames = delta - 1;
+                        let kind = if missing_frames < SUSPEND_FRAMES {
+                            SofGapKind::DroppedFrames
+                        } else {
+                            SofGapKind::Suspend
+                        };
+                        self.capture.sof_gaps.insert(packet_id, SofGap {
+                            kind: kind,
+                            missing_frames: missing_frames,
+                        });
+                        self.last_sof_gap = Some(packet_id);
+                    }
+                }
+                self.last_frame_number = Some(frame_number);
+            },
+            PacketFields::Token(token) => {
+                // If the first transaction after a gap is a SETUP to the
+                // default address, a device was connected or reset.
+                let enumerating =
+                    PID::from(packet[0]) == PID::SETUP &&
+                    token.device_address() == 0;
+                if let Some(gap_packet_id) = self.last_sof_gap.take() {
+                    if enumerating {
+                        let gaps = &mut self.capture.sof_gaps;
+                        if let Some(gap) = gaps.get_mut(&gap_packet_id) {
+                            gap.kind = SofGapKind::Disconnect;
+                        }
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn transaction_update(&mut self, packet: &[u8])
+        -> Result<(), DecoderError>
+    {
+        profile!("transaction_update");
         let pid = PID::from(packet[0]);
-        match self.transaction_state.status(packet) {
+        if self.capture.is_prefix(pid) {
+            // Kept with whichever transaction the next packet is part of.
+            let packet_id = self.capture.packet_index.len();
+            self.prefix.get_or_insert((packet_id, 0)).1 += 1;
+            if pid == PID::SPLIT {
+                let fields = SplitFields::from_packet(packet);
+                self.pending_split = Some(if fields.is_complete() {
+                    Split::Complete
+                } else {
+                    Split::Start
+                });
+            }
+            return Ok(());
+        }
+        let status = self.transaction_state.status(packet);
+        if matches!(status, DecodeStatus::NEW | DecodeStatus::INVALID) {
+            self.check_endpoint_limit(packet)?;
+        }
+        match status {
             DecodeStatus::NEW => {
                 self.transaction_end();
                 self.transaction_start(packet);
@@ -173,15 +463,79 @@ impl<'cap> Decoder<'cap> {
             DecodeStatus::INVALID => {
                 self.transaction_end();
                 self.transaction_start(packet);
+                self.notify(DecoderEvent::ErrorDetected {
+                    endpoint_id: self.transaction_state.endpoint_id,
+                    packet_id: self.transaction_state.start,
+                    error: DecodeError::InvalidTransaction,
+                });
                 self.transaction_end();
             },
         };
+        Ok(())
+    }
+
+    /// Key of a token's endpoint in the endpoint index of its device,
+    /// along with its number and whether it is an IN endpoint.
+    fn endpoint_key(&self, pid: PID, token: &TokenFields)
+        -> (usize, bool, usize)
+    {
+        let num = token.endpoint_number() as usize;
+        // Control endpoints carry transfers in both directions.
+        let is_in = pid == PID::IN &&
+            !self.is_control_endpoint(token.device_address() as usize, num);
+        (num, is_in, num | (is_in as usize) << 4)
+    }
+
+    /// Whether an endpoint is known to be a control endpoint, from its
+    /// device's descriptors or from a SETUP token having been seen on it.
+    fn is_control_endpoint(&self, addr: usize, num: usize) -> bool {
+        if num == 0 {
+            return true;
+        }
+        match self.device_index[addr] {
+            Some(device_id) => {
+                let dev_data = &self.capture.device_data[device_id];
+                [num as u8, num as u8 | 0x80].iter().any(|&address|
+                    matches!(dev_data.endpoint_type(address),
+                             EndpointType::Control))
+            },
+            None => false,
+        }
+    }
+
+    /// Check that a packet starting a transaction can be given an endpoint
+    /// ID, before anything is changed for it.
+    ///
+    /// While endpoints are keyed by device address, this cannot fail, as
+    /// asserted with the limits above. It keeps the limit an error rather
+    /// than a corrupted index should endpoints ever be keyed otherwise,
+    /// such as by enumeration.
+    fn check_endpoint_limit(&self, packet: &[u8])
+        -> Result<(), DecoderError>
+    {
+        if let PacketFields::Token(token) = PacketFields::from_packet(packet) {
+            let addr = token.device_address() as usize;
+            let pid = PID::from(packet[0]);
+            let (_, _, key) = self.endpoint_key(pid, &token);
+            let endpoint_id = self.capture.endpoints.len() as usize;
+            if self.endpoint_index[addr][key].is_none() &&
+                endpoint_id > TransferIndexEntry::MAX_ENDPOINT_ID
+            {
+                return Err(DecoderError::TooManyEndpoints(
+                    TransferIndexEntry::MAX_ENDPOINT_ID + 1));
+            }
+        }
+        Ok(())
     }
 
     fn transaction_start(&mut self, packet: &[u8]) {
+        let (start, prefixes) = self.prefix.take()
+            .unwrap_or((self.capture.packet_index.len(), 0));
         let state = &mut self.transaction_state;
-        state.start = self.capture.packet_index.len();
-        state.count = 1;
+        state.start = start;
+        state.count = 1 + prefixes;
+        state.prefixes = prefixes;
+        state.split = self.pending_split.take();
         state.first = PID::from(packet[0]);
         state.last = state.first;
         match PacketFields::from_packet(&packet) {
@@ -190,14 +544,21 @@ impl<'cap> Decoder<'cap> {
             },
             PacketFields::Token(token) => {
                 let addr = token.device_address() as usize;
-                let num = token.endpoint_number() as usize;
-                if self.endpoint_index[addr][num] < 0 {
-                    let endpoint_id = self.capture.endpoints.len() as i16;
-                    self.endpoint_index[addr][num] = endpoint_id;
-                    self.add_endpoint(addr, num);
-                }
+                let pid = self.transaction_state.first;
+                let (num, is_in, key) = self.endpoint_key(pid, &token);
                 self.transaction_state.endpoint_id =
-                    self.endpoint_index[addr][num] as usize;
+                    match self.endpoint_index[addr][key] {
+                        Some(endpoint_id) => endpoint_id,
+                        None => {
+                            let endpoint_id =
+                                self.add_endpoint(addr, num, is_in);
+                            self.endpoint_index[addr][key] = Some(endpoint_id);
+                            endpoint_id
+                        }
+                    };
+                if pid == PID::SETUP {
+                    self.mark_control_endpoint(addr, num);
+                }
             },
             _ => {
                 self.transaction_state.endpoint_id = 0;
@@ -205,9 +566,25 @@ impl<'cap> Decoder<'cap> {
         }
     }
 
+    /// Record that an endpoint is a control endpoint, so that its traffic
+    /// in both directions is decoded as control transfers, even if its
+    /// descriptors were not seen.
+    fn mark_control_endpoint(&mut self, addr: usize, num: usize) {
+        if let Some(device_id) = self.device_index[addr] {
+            let dev_data = &mut self.capture.device_data[device_id];
+            for address in [num as u8, num as u8 | 0x80] {
+                dev_data.endpoint_types[endpoint_type_index(address)] =
+                    EndpointType::Control;
+            }
+        }
+    }
+
     fn transaction_append(&mut self, pid: PID) {
+        let prefixes = self.prefix.take().map_or(0, |(_, count)| count);
+        self.pending_split = None;
         let state = &mut self.transaction_state;
-        state.count += 1;
+        state.count += 1 + prefixes;
+        state.prefixes += prefixes;
         state.last = pid;
     }
 
@@ -218,6 +595,9 @@ impl<'cap> Decoder<'cap> {
         state.first = PID::Malformed;
         state.last = PID::Malformed;
         state.setup = None;
+        state.retries.clear();
+        state.prefixes = 0;
+        state.split = None;
     }
 
     fn add_transaction(&mut self) {
@@ -225,47 +605,83 @@ impl<'cap> Decoder<'cap> {
         self.transfer_update();
         self.capture.transaction_index.push(
             self.transaction_state.start).unwrap();
+        self.capture.retry_index.push(
+            self.capture.retry_ids.len()).unwrap();
+        self.capture.retry_ids.append(
+            &self.transaction_state.retries).unwrap();
+        if self.transaction_state.last == PID::STALL {
+            let endpoint_id = self.transaction_state.endpoint_id;
+            let ep_data = &self.endpoint_data[endpoint_id];
+            self.notify(DecoderEvent::EndpointStalled {
+                endpoint_id: endpoint_id,
+                transfer_index_id: ep_data.transfer_index_id,
+            });
+        }
     }
 
-    fn add_endpoint(&mut self, addr: usize, num: usize) {
-        if self.device_index[addr] == -1 {
-            self.device_index[addr] = self.capture.devices.size() as i8;
-            let device = Device { address: addr as u8 };
-            self.capture.devices.push(&device).unwrap();
-            let dev_data = DeviceData {
-                device_descriptor: None,
-                configurations: Vec::new(),
-                configuration_id: None,
-                endpoint_types: vec![
-                    EndpointType::Unidentified; USB_MAX_ENDPOINTS],
-                strings: Vec::new(),
-            };
-            self.capture.device_data.push(dev_data);
-        }
+    fn add_device(&mut self, addr: usize) -> usize {
+        let device_id = self.capture.devices.len() as usize;
+        self.device_index[addr] = Some(device_id);
+        let device = Device { address: addr as u8 };
+        self.capture.devices.push(&device).unwrap();
+        let dev_data = DeviceData {
+            device_descriptor: None,
+            configurations: Vec::new(),
+            configuration_id: None,
+            endpoint_types: vec![
+                EndpointType::Unidentified; USB_MAX_ENDPOINTS * 2],
+            strings: Vec::new(),
+            report_descriptors: Vec::new(),
+        };
+        self.capture.device_data.push(dev_data);
+        device_id
+    }
+
+    fn add_endpoint(&mut self, addr: usize, num: usize, is_in: bool)
+        -> usize
+    {
+        let endpoint_id = self.capture.endpoints.len() as usize;
+        let device_id = match self.device_index[addr] {
+            Some(device_id) => device_id,
+            None => self.add_device(addr),
+        };
         let ep_data = EndpointData {
-            number: num as usize,
-            device_id: self.device_index[addr] as usize,
+            address: num as u8 | if is_in { 0x80 } else { 0 },
+            device_id: device_id,
+            transfer_index_id: 0,
             transaction_start: 0,
             transaction_count: 0,
             last: PID::Malformed,
             setup: None,
             payload: Vec::new(),
+            pending_retries: Vec::new(),
+            last_attempt: PID::Malformed,
+            ending: false,
         };
         self.endpoint_data.push(ep_data);
         let mut endpoint = Endpoint::default();
-        endpoint.set_device_id(self.device_index[addr] as u64);
+        endpoint.set_device_id(device_id as u64);
         endpoint.set_device_address(addr as u8);
         endpoint.set_number(num as u8);
+        endpoint.set_is_in(is_in);
         self.capture.endpoints.push(&endpoint).unwrap();
+        let storage = &self.capture.storage;
+        let purpose = |name| format!("endpoint_{}_{}", endpoint_id, name);
         let ep_traf = EndpointTraffic {
-            transaction_ids: HybridIndex::new(1).unwrap(),
-            transfer_index: HybridIndex::new(1).unwrap(),
+            transaction_ids: HybridIndex::new_in(
+                storage, &purpose("transaction_ids"), 1).unwrap(),
+            transfer_index: HybridIndex::new_in(
+                storage, &purpose("transfer_index"), 1).unwrap(),
         };
         self.capture.endpoint_traffic.push(ep_traf);
+        self.capture.endpoint_first_entry.push(
+            self.capture.transfer_index.len());
         self.last_endpoint_state.push(EndpointState::Idle as u8);
+        endpoint_id
     }
 
     fn decode_request(&mut self) {
+        profile!("decode_request");
         let endpoint_id = self.transaction_state.endpoint_id;
         let ep_data = &self.endpoint_data[endpoint_id];
         let fields = ep_data.setup.as_ref().unwrap();
@@ -290,15 +706,23 @@ impl<'cap> Decoder<'cap> {
         let length = payload.len();
         match (recipient, desc_type) {
             (Recipient::Device, DescriptorType::Device) => {
-                if length == size_of::<DeviceDescriptor>() {
+                if length == DeviceDescriptor::SIZE {
                     let device_id = ep_data.device_id;
                     let dev_data = &mut self.capture.device_data[device_id];
-                    dev_data.device_descriptor =
-                        Some(DeviceDescriptor::from_bytes(payload));
+                    let descriptor = DeviceDescriptor::decode(payload);
+                    dev_data.device_descriptor = Some(descriptor);
+                    let transfer_index_id = ep_data.transfer_index_id;
+                    let device = self.capture.devices.get(device_id as u64)
+                                                     .unwrap();
+                    self.notify(DecoderEvent::DeviceEnumerated {
+                        address: device.address,
+                        descriptor: descriptor,
+                        transfer_index_id: transfer_index_id,
+                    });
                 }
             },
             (Recipient::Device, DescriptorType::Configuration) => {
-                let size = size_of::<ConfigDescriptor>();
+                let size = ConfigDescriptor::SIZE;
                 if length >= size {
                     let device_id = ep_data.device_id;
                     let dev_data = &mut self.capture.device_data[device_id];
@@ -327,6 +751,19 @@ impl<'cap> Decoder<'cap> {
                     strings[string_id] = Some(payload[2..length].to_vec());
                 }
             },
+            (Recipient::Interface, DescriptorType::Unknown)
+                if (fields.value >> 8) as u8 == REPORT_DESCRIPTOR_TYPE =>
+            {
+                let device_id = ep_data.device_id;
+                let descriptors =
+                    &mut self.capture.device_data[device_id].report_descriptors;
+                let interface_number = (fields.index & 0xFF) as usize;
+                while descriptors.len() <= interface_number {
+                    descriptors.push(None);
+                }
+                descriptors[interface_number] =
+                    Some(ReportDescriptor::from_bytes(payload));
+            },
             _ => {}
         }
     }
@@ -340,6 +777,13 @@ impl<'cap> Decoder<'cap> {
         let config_id = fields.value as usize;
         dev_data.configuration_id = Some(config_id);
         dev_data.update_endpoint_types();
+        let transfer_index_id = ep_data.transfer_index_id;
+        let device = self.capture.devices.get(device_id as u64).unwrap();
+        self.notify(DecoderEvent::ConfigurationSet {
+            address: device.address,
+            config_id: config_id,
+            transfer_index_id: transfer_index_id,
+        });
     }
 
     fn transfer_status(&mut self) -> DecodeStatus {
@@ -347,7 +791,7 @@ impl<'cap> Decoder<'cap> {
         let endpoint_id = self.transaction_state.endpoint_id;
         let ep_data = &mut self.endpoint_data[endpoint_id];
         let dev_data = &self.capture.device_data[ep_data.device_id];
-        let ep_type = &dev_data.endpoint_type(ep_data.number);
+        let ep_type = &dev_data.endpoint_type(ep_data.address);
         use PID::*;
         use EndpointType::*;
         use Direction::*;
@@ -423,15 +867,40 @@ impl<'cap> Decoder<'cap> {
     }
 
     fn transfer_update(&mut self) {
+        profile!("transfer_update");
+        // The half of a split transaction with only the hub's handshake
+        // is kept with the transfer, but does not move it on.
+        if self.transaction_state.split_handshake() {
+            let endpoint_id = self.transaction_state.endpoint_id;
+            let ep_data = &self.endpoint_data[endpoint_id];
+            if ep_data.transaction_count == 0 {
+                // Folded into the transaction which completes it.
+                self.hold_retry();
+            } else if ep_data.ending {
+                self.transfer_append(false);
+                self.transfer_end();
+            } else {
+                self.transfer_append(false);
+            }
+            return
+        }
         let status = self.transfer_status();
         let endpoint_id = self.transaction_state.endpoint_id;
         let ep_data = &mut self.endpoint_data[endpoint_id];
+        let dev_data = &self.capture.device_data[ep_data.device_id];
+        // Isochronous transactions have no handshake, and are not retried.
+        let isochronous = matches!(dev_data.endpoint_type(ep_data.address),
+                                   EndpointType::Isochronous);
         let retry_needed =
             ep_data.transaction_count > 0 &&
             status != DecodeStatus::INVALID &&
             !self.transaction_state.completed();
         if retry_needed {
-            self.transfer_append(false);
+            if self.transaction_state.ignored() && !isochronous {
+                self.hold_retry();
+            } else {
+                self.transfer_append(false);
+            }
             return
         }
         match status {
@@ -445,12 +914,22 @@ impl<'cap> Decoder<'cap> {
             },
             DecodeStatus::DONE => {
                 self.transfer_append(true);
-                self.transfer_end();
+                if self.transaction_state.split == Some(Split::Start) {
+                    let endpoint_id = self.transaction_state.endpoint_id;
+                    self.endpoint_data[endpoint_id].ending = true;
+                } else {
+                    self.transfer_end();
+                }
             },
             DecodeStatus::INVALID => {
                 self.transfer_end();
                 self.transfer_start();
                 self.transfer_append(false);
+                self.notify(DecoderEvent::ErrorDetected {
+                    endpoint_id: endpoint_id,
+                    packet_id: self.transaction_state.start,
+                    error: DecodeError::InvalidTransfer,
+                });
                 self.transfer_end();
             }
         }
@@ -460,16 +939,57 @@ impl<'cap> Decoder<'cap> {
         self.capture.item_index.push(
             self.capture.transfer_index.len()).unwrap();
         let endpoint_id = self.transaction_state.endpoint_id;
-        self.last_item_endpoint = endpoint_id as i16;
+        self.last_item_endpoint = Some(endpoint_id);
+        let transfer_index_id = self.capture.transfer_index.len();
         self.add_transfer_entry(endpoint_id, true);
         let ep_data = &mut self.endpoint_data[endpoint_id];
+        ep_data.transfer_index_id = transfer_index_id;
         let ep_traf = &mut self.capture.endpoint_traffic[endpoint_id];
         ep_data.transaction_start = ep_traf.transaction_ids.len();
         ep_data.transaction_count = 0;
         ep_traf.transfer_index.push(ep_data.transaction_start).unwrap();
     }
 
+    /// Hold back a failed attempt, to be folded into the transaction which
+    /// retries it, if the same token is repeated next.
+    fn hold_retry(&mut self) {
+        let endpoint_id = self.transaction_state.endpoint_id;
+        self.take_retries();
+        let transaction_id = self.capture.transaction_index.len();
+        let ep_data = &mut self.endpoint_data[endpoint_id];
+        ep_data.pending_retries.append(&mut self.transaction_state.retries);
+        ep_data.pending_retries.push(transaction_id);
+        ep_data.last_attempt = self.transaction_state.first;
+    }
+
+    /// Claim any pending attempts that the current transaction retries.
+    /// Pending attempts with a different token were not retried, so are
+    /// added to the transfer as transactions in their own right.
+    fn take_retries(&mut self) {
+        let endpoint_id = self.transaction_state.endpoint_id;
+        let ep_data = &mut self.endpoint_data[endpoint_id];
+        if ep_data.pending_retries.is_empty() {
+            return;
+        }
+        if ep_data.last_attempt == self.transaction_state.first {
+            self.transaction_state.retries.append(
+                &mut ep_data.pending_retries);
+        } else {
+            self.flush_retries(endpoint_id);
+        }
+    }
+
+    fn flush_retries(&mut self, endpoint_id: usize) {
+        let ep_data = &mut self.endpoint_data[endpoint_id];
+        let ep_traf = &mut self.capture.endpoint_traffic[endpoint_id];
+        for transaction_id in ep_data.pending_retries.drain(..) {
+            ep_traf.transaction_ids.push(transaction_id).unwrap();
+            ep_data.transaction_count += 1;
+        }
+    }
+
     fn transfer_append(&mut self, success: bool) {
+        self.take_retries();
         let endpoint_id = self.transaction_state.endpoint_id;
         let ep_data = &mut self.endpoint_data[endpoint_id];
         let ep_traf = &mut self.capture.endpoint_traffic[endpoint_id];
@@ -483,19 +1003,26 @@ impl<'cap> Decoder<'cap> {
 
     fn transfer_end(&mut self) {
         let endpoint_id = self.transaction_state.endpoint_id;
+        self.flush_retries(endpoint_id);
         let ep_data = &self.endpoint_data[endpoint_id];
+        let transfer_index_id = ep_data.transfer_index_id;
         if ep_data.transaction_count > 0 {
-            if self.last_item_endpoint != (endpoint_id as i16) {
+            if self.last_item_endpoint != Some(endpoint_id) {
                 self.capture.item_index.push(
                     self.capture.transfer_index.len()).unwrap();
-                self.last_item_endpoint = endpoint_id as i16;
+                self.last_item_endpoint = Some(endpoint_id);
             }
             self.add_transfer_entry(endpoint_id, false);
+            self.notify(DecoderEvent::TransferCompleted {
+                endpoint_id: endpoint_id,
+                transfer_index_id: transfer_index_id,
+            });
         }
         let ep_data = &mut self.endpoint_data[endpoint_id];
         ep_data.transaction_count = 0;
         ep_data.last = PID::Malformed;
         ep_data.payload.clear();
+        ep_data.ending = false;
     }
 
     fn add_transfer_entry(&mut self, endpoint_id: usize, start: bool) {
@@ -509,6 +1036,7 @@ impl<'cap> Decoder<'cap> {
     }
 
     fn add_endpoint_state(&mut self, endpoint_id: usize, start: bool) {
+        profile!("add_endpoint_state");
         let endpoint_count = self.capture.endpoints.len() as usize;
         for i in 0..endpoint_count {
             use EndpointState::*;
@@ -523,9 +1051,406 @@ impl<'cap> Decoder<'cap> {
                 }
             } as u8;
         }
-        let last_state = self.last_endpoint_state.as_slice();
+        let packed_state = pack_endpoint_states(&self.last_endpoint_state);
         let state_offset = self.capture.endpoint_states.len();
-        self.capture.endpoint_states.append(last_state).unwrap();
+        self.capture.endpoint_states.append(&packed_state).unwrap();
         self.capture.endpoint_state_index.push(state_offset).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_observer_events() {
+        let mut pcap =
+            pcap::Capture::from_file("./tests/mouse/capture.pcap").unwrap();
+        let mut cap = Capture::new();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut decoder = Decoder::new(&mut cap);
+        let log = events.clone();
+        decoder.add_observer(Box::new(move |event: &DecoderEvent| {
+            log.borrow_mut().push(*event);
+        }));
+        while let Ok(packet) = pcap.next() {
+            decoder.handle_raw_packet(&packet).unwrap();
+        }
+        let events = events.borrow();
+        let enumerated: Vec<(u8, u16)> = events.iter().filter_map(|event|
+            match event {
+                DecoderEvent::DeviceEnumerated { address, descriptor, .. } =>
+                    Some((*address, descriptor.vendor_id)),
+                _ => None
+            }).collect();
+        assert!(enumerated.len() == 2);
+        assert!(enumerated[0].0 == 0);
+        assert!(enumerated[1].0 == 4);
+        assert!(enumerated[0].1 == enumerated[1].1);
+        assert!(events.iter().any(|event| matches!(event,
+            DecoderEvent::ConfigurationSet { address: 4, config_id: 1, .. })));
+        assert!(events.iter().any(|event| matches!(event,
+            DecoderEvent::ErrorDetected {
+                error: DecodeError::InvalidTransaction, .. })));
+        let transfers = events.iter().filter(|event| matches!(event,
+            DecoderEvent::TransferCompleted { .. })).count();
+        assert!(transfers > 0);
+    }
+
+    #[test]
+    fn test_sof_gaps() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let mut packet_ids = Vec::new();
+        let mut sof = |decoder: &mut Decoder, frame_number: u16| {
+            let [lo, hi] = frame_number.to_le_bytes();
+            packet_ids.push(decoder.capture.packet_index.len());
+            decoder.handle_raw_packet(&[PID::SOF as u8, lo, hi]).unwrap();
+        };
+        let setup = |decoder: &mut Decoder, addr: u8| {
+            decoder.handle_raw_packet(&[PID::SETUP as u8, addr, 0]).unwrap();
+            decoder.handle_raw_packet(
+                &[PID::DATA0 as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+            decoder.handle_raw_packet(&[PID::ACK as u8]).unwrap();
+        };
+        for frame_number in [10, 10, 11, 13, 20, 2047, 0, 1] {
+            sof(&mut decoder, frame_number);
+        }
+        setup(&mut decoder, 4);
+        sof(&mut decoder, 5);
+        setup(&mut decoder, 0);
+        sof(&mut decoder, 6);
+        drop(decoder);
+        use SofGapKind::*;
+        let gaps: Vec<(u64, SofGapKind, u16)> = cap.sof_gaps.iter()
+            .map(|(id, gap)| (*id, gap.kind, gap.missing_frames))
+            .collect();
+        assert!(gaps == vec![
+            (packet_ids[3], DroppedFrames, 1),
+            (packet_ids[4], Suspend, 6),
+            (packet_ids[5], Suspend, 2026),
+            (packet_ids[8], Disconnect, 3),
+        ]);
+    }
+
+    #[test]
+    fn test_phy_errors() {
+        use crate::capture::Item;
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let mut babble = PhyErrors::default();
+        babble.set_babble(true);
+        decoder.handle_raw_packet(&[PID::IN as u8, 0x81, 0x00]).unwrap();
+        decoder.handle_raw_packet_with_errors(
+            &[PID::DATA0 as u8, 0x01, 0x7e, 0xc0], babble, None).unwrap();
+        decoder.handle_raw_packet(&[PID::ACK as u8]).unwrap();
+        drop(decoder);
+        assert!(cap.phy_errors.keys().eq([1].iter()));
+        let transfer = cap.get_item(&None, 0);
+        let transaction = cap.get_child(&transfer, 0);
+        assert!(cap.get_summary(&transaction).ends_with(
+            "1 packet with PHY errors"));
+        let packet = cap.get_child(&transaction, 1);
+        assert!(matches!(packet, Item::Packet(_, 0, 1)));
+        assert!(cap.get_summary(&packet).starts_with(
+            "DATA0 packet with 1 data bytes and CRC C07E, babble: "));
+    }
+
+    #[test]
+    fn test_retry_folding() {
+        use crate::capture::Item;
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let fields: u16 = 4 | 1 << 7;
+        let [lo, hi] = fields.to_le_bytes();
+        let packets: [&[u8]; 13] = [
+            &[PID::OUT as u8, lo, hi],
+            &[PID::DATA0 as u8, 1, 2, 0, 0],
+            &[PID::ACK as u8],
+            // Data corrupted, so not acknowledged by the device.
+            &[PID::OUT as u8, lo, hi],
+            &[PID::DATA1 as u8, 3, 4, 0, 0],
+            // No data sent by the host.
+            &[PID::OUT as u8, lo, hi],
+            // Successful retry.
+            &[PID::OUT as u8, lo, hi],
+            &[PID::DATA1 as u8, 3, 4, 0, 0],
+            &[PID::ACK as u8],
+            // Failed attempt, not retried. Only OUT endpoints can see a
+            // different token, as IN tokens have an endpoint of their own.
+            &[PID::OUT as u8, lo, hi],
+            &[PID::DATA0 as u8, 5, 6, 0, 0],
+            &[PID::SETUP as u8, lo, hi],
+            &[PID::SOF as u8, 0, 0],
+        ];
+        for packet in packets {
+            decoder.handle_raw_packet(packet).unwrap();
+        }
+        drop(decoder);
+        assert!(cap.get_retries(3) == vec![1, 2]);
+        assert!(cap.get_retries(4).is_empty());
+        let transfer = cap.get_item(&None, 0);
+        assert!(cap.child_count(&transfer) == 3);
+        let transaction = cap.get_child(&transfer, 1);
+        assert!(matches!(transaction, Item::Transaction(_, 3)));
+        assert!(cap.get_summary(&transaction).ends_with(", 2 retries"));
+        assert!(cap.child_count(&transaction) == 6);
+        let packet_ids: Vec<u64> = (0..6).map(|i|
+            match cap.get_child(&transaction, i) {
+                Item::Packet(.., packet_id) => packet_id,
+                _ => panic!("Expected a packet"),
+            }).collect();
+        assert!(packet_ids == vec![3, 4, 5, 6, 7, 8]);
+        assert!(matches!(cap.get_child(&transfer, 2),
+                         Item::Transaction(_, 4)));
+        crate::verify::check(&mut cap).unwrap();
+    }
+
+    #[test]
+    fn test_interleaved_retries() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let [a_lo, a_hi] = (4_u16 | 1 << 7).to_le_bytes();
+        let [b_lo, b_hi] = (4_u16 | 2 << 7).to_le_bytes();
+        let packets: [&[u8]; 17] = [
+            // Transfers started on endpoints 1 and 2.
+            &[PID::OUT as u8, a_lo, a_hi],
+            &[PID::DATA0 as u8, 1, 2, 0, 0],
+            &[PID::ACK as u8],
+            &[PID::OUT as u8, b_lo, b_hi],
+            &[PID::DATA0 as u8, 1, 2, 0, 0],
+            &[PID::ACK as u8],
+            // Failed attempts on each, not acknowledged.
+            &[PID::OUT as u8, a_lo, a_hi],
+            &[PID::DATA1 as u8, 3, 4, 0, 0],
+            &[PID::OUT as u8, b_lo, b_hi],
+            &[PID::DATA1 as u8, 3, 4, 0, 0],
+            // Endpoint 2 retries first, then endpoint 1.
+            &[PID::OUT as u8, b_lo, b_hi],
+            &[PID::DATA1 as u8, 3, 4, 0, 0],
+            &[PID::ACK as u8],
+            &[PID::OUT as u8, a_lo, a_hi],
+            &[PID::DATA1 as u8, 3, 4, 0, 0],
+            &[PID::ACK as u8],
+            &[PID::SOF as u8, 0, 0],
+        ];
+        for packet in packets {
+            decoder.handle_raw_packet(packet).unwrap();
+        }
+        drop(decoder);
+        assert!(cap.get_retries(4) == vec![3]);
+        assert!(cap.get_retries(5) == vec![2]);
+        crate::verify::check(&mut cap).unwrap();
+    }
+
+    #[test]
+    fn test_endpoint_directions() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let fields: u16 = 4 | 1 << 7;
+        let [lo, hi] = fields.to_le_bytes();
+        for pid in [PID::OUT, PID::IN, PID::OUT] {
+            decoder.handle_raw_packet(&[pid as u8, lo, hi]).unwrap();
+            decoder.handle_raw_packet(&[PID::DATA0 as u8, 1, 2, 0, 0]).unwrap();
+            decoder.handle_raw_packet(&[PID::ACK as u8]).unwrap();
+        }
+        drop(decoder);
+        // The IN and OUT endpoints are separate, after the two special
+        // endpoints for framing and invalid packets.
+        assert!(cap.endpoints.len() == 4);
+        assert!(cap.find_endpoint(1, 0x01) == Some(2));
+        assert!(cap.find_endpoint(1, 0x81) == Some(3));
+        let out_ep = cap.endpoints.get(2).unwrap();
+        let in_ep = cap.endpoints.get(3).unwrap();
+        assert!(!out_ep.is_in() && out_ep.endpoint_address() == 0x01);
+        assert!(in_ep.is_in() && in_ep.endpoint_address() == 0x81);
+        assert!(cap.get_device_transaction_ids(1, &[0x01]) == vec![0, 2]);
+        assert!(cap.get_device_transaction_ids(1, &[0x81]) == vec![1]);
+        let dev_data = &mut cap.device_data[1];
+        dev_data.endpoint_types[endpoint_type_index(0x81)] =
+            EndpointType::Bulk;
+        assert!(matches!(dev_data.endpoint_type(0x81), EndpointType::Bulk));
+        assert!(matches!(dev_data.endpoint_type(0x01),
+                         EndpointType::Unidentified));
+        crate::verify::check(&mut cap).unwrap();
+    }
+
+    #[test]
+    fn test_control_endpoint_directions() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let fields: u16 = 4 | 2 << 7;
+        let [lo, hi] = fields.to_le_bytes();
+        let setup = [PID::DATA0 as u8, 0x80, 0x06, 0x00, 0x01, 0x00, 0x00,
+                     0x12, 0x00, 0x00, 0x00];
+        let packets: [&[u8]; 9] = [
+            &[PID::SETUP as u8, lo, hi], &setup, &[PID::ACK as u8],
+            &[PID::IN as u8, lo, hi], &[PID::DATA1 as u8, 1, 2, 0, 0],
+            &[PID::ACK as u8],
+            &[PID::OUT as u8, lo, hi], &[PID::DATA1 as u8, 0, 0],
+            &[PID::ACK as u8],
+        ];
+        for packet in packets {
+            decoder.handle_raw_packet(packet).unwrap();
+        }
+        drop(decoder);
+        // A control endpoint other than 0 is kept as one endpoint, with
+        // its transfers in both directions.
+        assert!(cap.endpoints.len() == 3);
+        assert!(cap.find_endpoint(1, 0x02) == Some(2));
+        assert!(cap.find_endpoint(1, 0x82).is_none());
+        assert!(cap.get_device_transaction_ids(1, &[0x02]) == vec![0, 1, 2]);
+        assert!(cap.item_index.len() == 1);
+        crate::verify::check(&mut cap).unwrap();
+    }
+
+    #[test]
+    fn test_endpoint_limits() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        for addr in 0..USB_MAX_DEVICES {
+            for num in 0..USB_MAX_ENDPOINTS {
+                let fields = (addr | num << 7) as u16;
+                let [lo, hi] = fields.to_le_bytes();
+                let packets: [&[u8]; 3] = [
+                    &[PID::SETUP as u8, lo, hi],
+                    &[PID::DATA0 as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                    &[PID::ACK as u8],
+                ];
+                for packet in packets {
+                    decoder.handle_raw_packet(packet).unwrap();
+                }
+            }
+        }
+        drop(decoder);
+        let endpoint_count = USB_MAX_DEVICES * USB_MAX_ENDPOINTS + 2;
+        assert!(cap.endpoints.len() == endpoint_count as u64);
+        assert!(cap.devices.len() == USB_MAX_DEVICES as u64);
+        let last_entry = cap.transfer_index
+            .get(cap.transfer_index.len() - 1).unwrap();
+        assert!(last_entry.endpoint_id() as usize == endpoint_count - 1);
+        let last_endpoint = cap.endpoints
+            .get(last_entry.endpoint_id() as u64).unwrap();
+        assert!(last_endpoint.device_id() == USB_MAX_DEVICES as u64 - 1);
+        assert!(last_endpoint.number() == USB_MAX_ENDPOINTS as u8 - 1);
+        crate::verify::check(&mut cap).unwrap();
+    }
+
+    #[test]
+    fn test_endpoint_id_bound() {
+        // Use every key of two devices' endpoint indexes, twice over, with
+        // IN and OUT tokens and with SETUPs making each endpoint control.
+        let addresses = [1, USB_MAX_DEVICES - 1];
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        for _ in 0..2 {
+            for addr in addresses {
+                for num in 0..USB_MAX_ENDPOINTS {
+                    let fields = (addr | num << 7) as u16;
+                    let [lo, hi] = fields.to_le_bytes();
+                    for pid in [PID::IN, PID::OUT, PID::SETUP] {
+                        decoder.handle_raw_packet(&[pid as u8, lo, hi])
+                            .unwrap();
+                    }
+                }
+            }
+        }
+        drop(decoder);
+        // IN and OUT share the key of endpoint 0, the only key unused.
+        let per_device = USB_MAX_ENDPOINTS * 2 - 1;
+        let endpoint_count = 2 + addresses.len() * per_device;
+        assert!(cap.endpoints.len() == endpoint_count as u64);
+        assert!(2 + USB_MAX_DEVICES * per_device <=
+                TransferIndexEntry::MAX_ENDPOINT_ID + 1);
+    }
+
+    #[test]
+    fn test_split_transactions() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decoder.set_speed(Speed::High);
+        // Device 3 is on port 2 of the hub at address 1.
+        let start: &[u8] = &[PID::SPLIT as u8, 0x01, 0x02, 0x00];
+        let complete: &[u8] = &[PID::SPLIT as u8, 0x81, 0x02, 0x00];
+        let setup: &[u8] = &[PID::SETUP as u8, 3, 0];
+        let input: &[u8] = &[PID::IN as u8, 3, 0];
+        let output: &[u8] = &[PID::OUT as u8, 3, 0];
+        let ack: &[u8] = &[PID::ACK as u8];
+        let packets: [&[u8]; 23] = [
+            start, setup,
+            &[PID::DATA0 as u8, 0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12,
+              0x00, 0, 0],
+            ack,
+            complete, setup, ack,
+            start, input, ack,
+            complete, input, &[PID::NYET as u8],
+            complete, input,
+            &[PID::DATA1 as u8, 18, 1, 0, 2, 0, 0, 0, 64, 0x50, 0x1d, 0x4b,
+              0x60, 0, 1, 1, 2, 3, 1, 0, 0],
+            start, output, &[PID::DATA1 as u8, 0, 0], ack,
+            complete, output, ack,
+        ];
+        for packet in packets {
+            decoder.handle_raw_packet(packet).unwrap();
+        }
+        drop(decoder);
+        assert!(cap.transaction_index.len() == 7);
+        let transfer = cap.get_item(&None, 0);
+        assert!(cap.get_summary(&transfer) ==
+                "Getting device descriptor #0 for device 3, \
+                 reading 18 bytes");
+        assert!(cap.child_count(&transfer) == 7);
+        let transaction = cap.get_child(&transfer, 0);
+        assert!(cap.child_count(&transaction) == 4);
+        assert!(matches!(cap.get_child(&transaction, 0),
+                         crate::capture::Item::Packet(_, 0, 0)));
+        crate::verify::check(&mut cap).unwrap();
+    }
+
+    #[test]
+    fn test_pre_prefix() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decoder.set_speed(Speed::Full);
+        // Each packet from the host to a low speed device follows a PRE.
+        let pre: &[u8] = &[PID::ERR as u8];
+        let fields: u16 = 2 | 1 << 7;
+        let [lo, hi] = fields.to_le_bytes();
+        let packets: [&[u8]; 5] = [
+            pre,
+            &[PID::IN as u8, lo, hi],
+            &[PID::DATA0 as u8, 1, 2, 3, 0, 0],
+            pre,
+            &[PID::ACK as u8],
+        ];
+        for packet in packets {
+            decoder.handle_raw_packet(packet).unwrap();
+        }
+        drop(decoder);
+        assert!(cap.transaction_index.len() == 1);
+        let transfer = cap.get_item(&None, 0);
+        let transaction = cap.get_child(&transfer, 0);
+        assert!(cap.child_count(&transaction) == 5);
+        assert!(cap.get_summary(&transaction).starts_with("IN transaction"));
+        crate::verify::check(&mut cap).unwrap();
+    }
+
+    #[test]
+    fn test_packet_times() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let sof: &[u8] = &[PID::SOF as u8, 0x00, 0x10];
+        // Times are kept as given, before the epoch and out of order.
+        for time in [None, Some(-1_500_000_001), Some(7), Some(5), None] {
+            decoder.handle_raw_packet_at(sof, time).unwrap();
+        }
+        drop(decoder);
+        let times: Vec<Option<i64>> =
+            (0..5).map(|i| cap.packet_time(i)).collect();
+        assert!(times == [Some(-1_500_000_001), Some(-1_500_000_001),
+                          Some(7), Some(5), Some(5)]);
+        crate::verify::check(&mut cap).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+//! Links which open a capture at a particular item, with a filter applied,
+//! so that they can be given in bug reports or generated by scripts.
+//!
+//! A link has the form
+//! `packetry://open?file=PATH&goto=ITEM&filter=FILTER`, where `file` may be
+//! given more than once to join several files, and `goto` and `filter` are
+//! optional. They are the same as the `--goto` and `--filter` options.
+//! Values are percent-encoded.
+
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::capture::{Item, ItemParseError};
+use crate::filter::{Filter, FilterError};
+
+/// Scheme and path of links which open captures.
+pub const LINK_PREFIX: &str = "packetry://open";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum LinkError {
+    #[error("invalid link, expected packetry://open?file=PATH&goto=ITEM\
+             &filter=FILTER")]
+    InvalidLink,
+    #[error("invalid percent-encoding in link")]
+    InvalidEncoding,
+    #[error("unknown link parameter '{0}'")]
+    UnknownParameter(String),
+    #[error("link does not name a file")]
+    NoFile,
+    #[error(transparent)]
+    ItemParseError(#[from] ItemParseError),
+    #[error(transparent)]
+    FilterError(#[from] FilterError),
+}
+
+/// A capture to open, with the view to show of it.
+#[derive(Debug, PartialEq)]
+pub struct DeepLink {
+    pub files: Vec<String>,
+    pub goto: Option<Item>,
+    pub filter: String,
+}
+
+/// Whether a command line argument is a link, rather than a filename.
+pub fn is_link(arg: &str) -> bool {
+    arg.starts_with("packetry:")
+}
+
+fn decode(text: &str) -> Result<String, LinkError> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(0..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .ok_or(LinkError::InvalidEncoding)?;
+            bytes.push(u8::from_str_radix(hex, 16)
+                .map_err(|_| LinkError::InvalidEncoding)?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| LinkError::InvalidEncoding)
+}
+
+fn encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' |
+            b'-' | b'_' | b'.' | b'~' | b'/' | b':' =>
+                encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl FromStr for DeepLink {
+    type Err = LinkError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let query = text.strip_prefix(LINK_PREFIX)
+            .and_then(|rest| rest.strip_prefix('?'))
+            .ok_or(LinkError::InvalidLink)?;
+        let mut link = DeepLink {
+            files: Vec::new(),
+            goto: None,
+            filter: String::new(),
+        };
+        for parameter in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = parameter.split_once('=')
+                .ok_or(LinkError::InvalidLink)?;
+            let value = decode(value)?;
+            match name {
+                "file" => link.files.push(value),
+                "goto" => link.goto = Some(value.parse()?),
+                "filter" => {
+                    value.parse::<Filter>()?;
+                    link.filter = value;
+                },
+                _ => return Err(LinkError::UnknownParameter(name.to_string())),
+            }
+        }
+        if link.files.is_empty() {
+            return Err(LinkError::NoFile);
+        }
+        Ok(link)
+    }
+}
+
+impl fmt::Display for DeepLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parameters: Vec<String> = self.files.iter()
+            .map(|file| format!("file={}", encode(file)))
+            .collect();
+        if let Some(item) = &self.goto {
+            parameters.push(format!("goto={}", encode(&item.to_string())));
+        }
+        if !self.filter.is_empty() {
+            parameters.push(format!("filter={}", encode(&self.filter)));
+        }
+        write!(f, "{}?{}", LINK_PREFIX, parameters.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_link() {
+        let link: DeepLink = "packetry://open?file=/tmp/my%20capture.pcap\
+                              &goto=transaction:12.34&filter=4.1"
+            .parse().unwrap();
+        assert!(link.files == ["/tmp/my capture.pcap"]);
+        assert!(link.goto == Some(Item::Transaction(12, 34)));
+        assert!(link.filter == "4.1");
+        assert!(link.to_string().parse::<DeepLink>().unwrap() == link);
+
+        let link = DeepLink {
+            files: vec!["a&b.pcap".to_string(), "100%.pcap".to_string()],
+            goto: None,
+            filter: String::new(),
+        };
+        assert!(link.to_string() ==
+                "packetry://open?file=a%26b.pcap&file=100%25.pcap");
+        assert!(link.to_string().parse::<DeepLink>().unwrap() == link);
+
+        for (text, error) in [
+            ("packetry://close?file=a", LinkError::InvalidLink),
+            ("packetry://open?file=a%2", LinkError::InvalidEncoding),
+            ("packetry://open?file=a&page=2",
+             LinkError::UnknownParameter("page".to_string())),
+            ("packetry://open?goto=transfer:1", LinkError::NoFile),
+        ] {
+            assert!(text.parse::<DeepLink>().unwrap_err() == error);
+        }
+        assert!(matches!(
+            "packetry://open?file=a&goto=frame:1".parse::<DeepLink>(),
+            Err(LinkError::ItemParseError(_))));
+        assert!(matches!(
+            "packetry://open?file=a&filter=x".parse::<DeepLink>(),
+            Err(LinkError::FilterError(_))));
+        assert!(is_link("packetry://open?file=a"));
+        assert!(!is_link("capture.pcap"));
+    }
+}
@@ -0,0 +1,184 @@
+//! Capture-wide search for descriptor reads.
+//!
+//! Finds every GET_DESCRIPTOR request in the capture, grouped by device and
+//! by the descriptor requested, so that each read can be located in the
+//! traffic view and changes to a descriptor's contents can be spotted.
+
+use std::collections::BTreeMap;
+
+use crate::capture::Capture;
+use crate::usb::{
+    DescriptorType,
+    Direction,
+    RequestType,
+    StandardRequest,
+};
+
+/// One read of a descriptor, by a single control transfer.
+pub struct DescriptorRead {
+    /// Position of the transfer among the top-level items.
+    pub item_index: u64,
+    /// Which version of the descriptor contents was read, counting from 1.
+    pub version: usize,
+}
+
+/// All reads of one descriptor from one device.
+pub struct DescriptorReads {
+    pub device_id: u64,
+    pub address: u8,
+    pub descriptor_type: u8,
+    pub descriptor_index: u8,
+    pub language: u16,
+    pub reads: Vec<DescriptorRead>,
+    versions: Vec<Vec<u8>>,
+}
+
+impl DescriptorReads {
+    /// Number of distinct contents seen for this descriptor.
+    pub fn version_count(&self) -> usize {
+        self.versions.len()
+    }
+
+    /// Find the version matching some descriptor data, adding it if new.
+    ///
+    /// A shorter read of the same descriptor, such as the first 8 bytes of
+    /// a device descriptor, is counted as the same version as a longer one.
+    fn version(&mut self, data: &[u8]) -> usize {
+        for (i, known) in self.versions.iter_mut().enumerate() {
+            if known.starts_with(data) {
+                return i + 1;
+            } else if data.starts_with(known) {
+                *known = data.to_vec();
+                return i + 1;
+            }
+        }
+        self.versions.push(data.to_vec());
+        self.versions.len()
+    }
+
+    pub fn summary(&self) -> String {
+        let descriptor_type = DescriptorType::from(self.descriptor_type);
+        let name = match descriptor_type {
+            DescriptorType::Unknown => format!(
+                "Descriptor type 0x{:02x}", self.descriptor_type),
+            _ => {
+                let name = descriptor_type.description();
+                format!("{}{} descriptor",
+                        name[..1].to_uppercase(), &name[1..])
+            }
+        };
+        format!("Device {}: {} #{}{}, {} read{}, {} version{}",
+            self.address, name, self.descriptor_index,
+            match self.language {
+                0 => String::new(),
+                language => format!(", language 0x{:04x}", language),
+            },
+            self.reads.len(),
+            if self.reads.len() == 1 {""} else {"s"},
+            self.version_count(),
+            if self.version_count() == 1 {""} else {"s"})
+    }
+}
+
+/// Find all descriptor reads in the capture.
+///
+/// The result is ordered by device, then by descriptor type, index and
+/// language. The reads of each descriptor are in capture order.
+pub fn find_descriptor_reads(cap: &mut Capture) -> Vec<DescriptorReads> {
+    profile!("find_descriptor_reads");
+    let mut found = BTreeMap::new();
+    for item_index in 0..cap.item_index.len() {
+        let transfer_index_id = cap.item_index.get(item_index).unwrap();
+        let transfer = match cap.control_transfer(transfer_index_id) {
+            Some(transfer) => transfer,
+            None => continue,
+        };
+        let fields = &transfer.fields;
+        let is_get_descriptor =
+            matches!(fields.type_fields.request_type(), RequestType::Standard)
+            && matches!(fields.type_fields.direction(), Direction::In)
+            && matches!(StandardRequest::from(fields.request),
+                        StandardRequest::GetDescriptor);
+        if !is_get_descriptor {
+            continue;
+        }
+        let entry = cap.transfer_index.get(transfer_index_id).unwrap();
+        let endpoint = cap.endpoints.get(entry.endpoint_id() as u64).unwrap();
+        let device_id = endpoint.device_id();
+        let descriptor_type = (fields.value >> 8) as u8;
+        let descriptor_index = (fields.value & 0xFF) as u8;
+        let key = (device_id, descriptor_type, descriptor_index, fields.index);
+        let reads = found.entry(key).or_insert_with(|| DescriptorReads {
+            device_id: device_id,
+            address: transfer.address,
+            descriptor_type: descriptor_type,
+            descriptor_index: descriptor_index,
+            language: fields.index,
+            reads: Vec::new(),
+            versions: Vec::new(),
+        });
+        let version = reads.version(&transfer.data);
+        reads.reads.push(DescriptorRead {
+            item_index: item_index,
+            version: version,
+        });
+    }
+    found.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    #[test]
+    fn test_find_descriptor_reads() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let found = find_descriptor_reads(&mut cap);
+        let summaries: Vec<String> =
+            found.iter().map(DescriptorReads::summary).collect();
+        assert!(summaries == vec![
+            "Device 0: Device descriptor #0, 1 read, 1 version",
+            "Device 4: Device descriptor #0, 1 read, 1 version",
+            "Device 4: Configuration descriptor #0, 2 reads, 1 version",
+            "Device 4: String descriptor #0, 1 read, 1 version",
+            "Device 4: String descriptor #2, language 0x0409, 1 read, 1 version",
+            "Device 4: Descriptor type 0x22 #0, 1 read, 1 version",
+        ]);
+        let config = &found[2];
+        assert!(config.reads[0].version == config.reads[1].version);
+        assert!(config.reads[0].item_index < config.reads[1].item_index);
+        for reads in &found {
+            for read in &reads.reads {
+                let transfer_index_id =
+                    cap.item_index.get(read.item_index).unwrap();
+                let summary = cap.get_summary(
+                    &crate::capture::Item::Transfer(transfer_index_id));
+                assert!(summary.starts_with("Getting"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_descriptor_versions() {
+        let mut reads = DescriptorReads {
+            device_id: 1,
+            address: 1,
+            descriptor_type: 1,
+            descriptor_index: 0,
+            language: 0,
+            reads: Vec::new(),
+            versions: Vec::new(),
+        };
+        assert!(reads.version(&[1, 2]) == 1);
+        assert!(reads.version(&[1, 2, 3, 4]) == 1);
+        assert!(reads.version(&[1, 2, 3]) == 1);
+        assert!(reads.version(&[1, 2, 5, 4]) == 2);
+        assert!(reads.version(&[1, 2, 3, 4]) == 1);
+        assert!(reads.version_count() == 2);
+    }
+}
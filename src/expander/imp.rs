@@ -15,6 +15,7 @@ pub struct ExpanderWrapper {
     pub conn_label: RefCell<Label>,
     pub expander: RefCell<Expander>,
     pub handler: RefCell<Option<SignalHandlerId>>,
+    pub row_handler: RefCell<Option<(glib::Object, SignalHandlerId)>>,
 }
 
 // Basic declaration of our type for the GObject type system
@@ -1,16 +1,75 @@
 mod imp;
 
-use std::cell::RefMut;
 use gtk::{
     self,
     prelude::*,
     subclass::prelude::*,
     glib::{self, SignalHandlerId},
+    pango::{self, prelude::FontFamilyExt},
     Expander,
     Label,
     Orientation,
 };
 
+/// CSS class given to the connector labels, to set their font.
+pub const CONNECTORS_CLASS: &str = "connectors";
+
+/// Every character used in drawing connectors.
+const CONNECTOR_CHARS: [char; 9] =
+    [' ', '○', '│', '┼', '└', '├', '─', '□', '┆'];
+
+/// Fonts to try first for the connectors, in order of preference.
+const PREFERRED_FONTS: [&str; 9] = [
+    "Monospace",
+    "DejaVu Sans Mono",
+    "Noto Sans Mono",
+    "Liberation Mono",
+    "Ubuntu Mono",
+    "Cascadia Mono",
+    "Consolas",
+    "Menlo",
+    "Courier New",
+];
+
+/// Whether every connector character has the same width in a font,
+/// including any characters which must come from a fallback font.
+fn connectors_aligned(context: &pango::Context, family: &str) -> bool {
+    let mut description = pango::FontDescription::new();
+    description.set_family(family);
+    let layout = pango::Layout::new(context);
+    layout.set_font_description(Some(&description));
+    let mut widths = CONNECTOR_CHARS.iter().map(|c| {
+        layout.set_text(&c.to_string());
+        layout.size().0
+    });
+    let first = widths.next();
+    widths.all(|width| Some(width) == first)
+}
+
+/// Choose a monospace font for the connectors, independent of the font
+/// used for the rest of the interface, in which every connector character
+/// has the same width so that they line up between rows.
+///
+/// If no such font is found, the first monospace font is returned, or
+/// None if there are no monospace fonts at all.
+pub fn connector_font(context: &pango::Context) -> Option<String> {
+    let monospace: Vec<String> = context.list_families().iter()
+        .filter(|family| family.is_monospace())
+        .filter_map(|family| family.name())
+        .map(|name| name.to_string())
+        .collect();
+    let candidates = PREFERRED_FONTS.iter()
+        .map(|name| name.to_string())
+        .filter(|name| monospace.contains(name))
+        .chain(monospace.iter().cloned());
+    for family in candidates {
+        if connectors_aligned(context, &family) {
+            return Some(family);
+        }
+    }
+    monospace.into_iter().next()
+}
+
 glib::wrapper! {
     pub struct ExpanderWrapper(ObjectSubclass<imp::ExpanderWrapper>)
     @extends gtk::Box, gtk::Widget,
@@ -23,7 +82,9 @@ impl ExpanderWrapper {
             glib::Object::new(&[])
                          .expect("Failed to create new expander wrapper");
         wrapper.imp().text_label.replace(Label::new(None));
-        wrapper.imp().conn_label.replace(Label::new(None));
+        let conn_label = Label::new(None);
+        conn_label.add_css_class(CONNECTORS_CLASS);
+        wrapper.imp().conn_label.replace(conn_label);
         wrapper.imp().expander.replace(Expander::new(None));
         wrapper.append(&wrapper.imp().conn_label.borrow().clone());
         wrapper.append(&wrapper.imp().expander.borrow().clone());
@@ -33,24 +94,49 @@ impl ExpanderWrapper {
         wrapper
     }
 
-    pub fn expander(&self) -> RefMut<Expander> {
-        self.imp().expander.borrow_mut()
+    pub fn expander(&self) -> Expander {
+        self.imp().expander.borrow().clone()
+    }
+
+    /// The label showing the connectors for the row.
+    pub fn connectors_label(&self) -> Label {
+        self.imp().conn_label.borrow().clone()
     }
 
+    /// Set the handler connected to the expander, disconnecting any
+    /// previous one which was not cleared.
+    ///
+    /// GTK does not guarantee that every bind of a recycled row is followed
+    /// by exactly one unbind, so this must not assume any particular order.
     pub fn set_handler(&self, handler: SignalHandlerId) {
+        self.clear_expander_handler();
         self.imp().handler.replace(Some(handler));
     }
 
-    pub fn take_handler(&self) -> Option<SignalHandlerId> {
-        self.imp().handler.take().take()
+    /// Set the handler connected to the row shown, disconnecting any
+    /// previous one which was not cleared.
+    pub fn set_row_handler(&self, row: &glib::Object, handler: SignalHandlerId)
+    {
+        self.clear_row_handler();
+        self.imp().row_handler.replace(Some((row.clone(), handler)));
+    }
+
+    /// Disconnect the handlers connected to the expander and the row shown,
+    /// if there are any.
+    pub fn clear_handler(&self) {
+        self.clear_expander_handler();
+        self.clear_row_handler();
+    }
+
+    fn clear_expander_handler(&self) {
+        if let Some(handler) = self.imp().handler.take() {
+            self.expander().disconnect(handler);
+        }
     }
 
-    pub fn set_connectors(&self, connectors: Option<String>) {
-        match connectors {
-            Some(text) =>
-                self.imp().conn_label.borrow_mut().set_markup(
-                    format!("<tt>{}</tt>", text).as_str()),
-            None => {}
-        };
+    fn clear_row_handler(&self) {
+        if let Some((row, handler)) = self.imp().row_handler.take() {
+            row.disconnect(handler);
+        }
     }
 }
@@ -0,0 +1,852 @@
+//! Export of the traffic tree to text and HTML, of the transactions in a
+//! capture to CSV, of its control requests to a CSV log, of its packets to
+//! pcapng or a selection of them to pcap, and of the whole capture to JSON
+//! for other tools to read.
+
+use std::io::{Result, Write};
+use std::ops::Range;
+
+use crate::capture::{Capture, Item};
+use crate::filter::Filter;
+use crate::usb::{
+    PID,
+    PacketFields,
+    RequestType,
+    Speed,
+    StandardRequest,
+};
+
+/// Output format which the traffic tree can be written in.
+pub trait TreeWriter {
+    /// Called before the start of the tree.
+    fn start(&mut self) -> Result<()> { Ok(()) }
+
+    /// Called for each item, before any of its children.
+    fn start_item(&mut self, cap: &mut Capture, item: &Item, depth: usize,
+                  has_children: bool) -> Result<()>;
+
+    /// Called for each item, after all of its children.
+    fn end_item(&mut self, _depth: usize, _has_children: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after the end of the tree.
+    fn end(&mut self) -> Result<()> { Ok(()) }
+}
+
+fn write_item(cap: &mut Capture, item: &Item, depth: usize,
+              writer: &mut dyn TreeWriter) -> Result<()>
+{
+    let child_count = cap.child_count(item);
+    let has_children = child_count > 0;
+    writer.start_item(cap, item, depth, has_children)?;
+    for child_id in 0..child_count {
+        let child = cap.get_child(item, child_id);
+        write_item(cap, &child, depth + 1, writer)?;
+    }
+    writer.end_item(depth, has_children)
+}
+
+/// Walk the whole traffic tree in display order, passing it to a writer.
+pub fn write_tree(cap: &mut Capture, writer: &mut dyn TreeWriter)
+    -> Result<()>
+{
+    writer.start()?;
+    for item_id in 0..cap.item_count(&None) {
+        let item = cap.get_item(&None, item_id);
+        write_item(cap, &item, 0, writer)?;
+    }
+    writer.end()
+}
+
+/// Writes each item's summary on its own line, indented by its depth.
+pub struct TextWriter<'w> {
+    output: &'w mut dyn Write,
+}
+
+impl<'w> TextWriter<'w> {
+    pub fn new(output: &'w mut dyn Write) -> Self {
+        TextWriter { output: output }
+    }
+}
+
+impl TreeWriter for TextWriter<'_> {
+    fn start_item(&mut self, cap: &mut Capture, item: &Item, depth: usize,
+                  _has_children: bool) -> Result<()>
+    {
+        let summary = cap.get_summary(item);
+        writeln!(self.output, "{:depth$}{}", "", summary, depth = depth)
+    }
+}
+
+const HTML_HEADER: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Packetry capture</title>
+<style>
+body { font-family: monospace; }
+details > :not(summary) { margin-left: 2em; }
+div.item { margin-left: 1.2em; }
+.transfer { color: #204a87; }
+.transaction { color: #4e9a06; }
+.packet { color: #555753; }
+.packet[title] { cursor: help; }
+.highlighted { background-color: #fce94f; }
+</style>
+</head>
+<body>
+"#;
+
+const HTML_FOOTER: &str = "</body>\n</html>\n";
+
+/// Writes a self-contained HTML page, with the tree made collapsible using
+/// `<details>` elements, and packet contents shown in a hex tooltip.
+pub struct HtmlWriter<'w> {
+    output: &'w mut dyn Write,
+}
+
+impl<'w> HtmlWriter<'w> {
+    pub fn new(output: &'w mut dyn Write) -> Self {
+        HtmlWriter { output: output }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Format bytes as hex, sixteen to a line.
+pub fn hex_dump(data: &[u8]) -> String {
+    data.chunks(16)
+        .map(|line| line.iter()
+             .map(|byte| format!("{:02x}", byte))
+             .collect::<Vec<String>>()
+             .join(" "))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+impl TreeWriter for HtmlWriter<'_> {
+    fn start(&mut self) -> Result<()> {
+        self.output.write_all(HTML_HEADER.as_bytes())
+    }
+
+    fn start_item(&mut self, cap: &mut Capture, item: &Item, _depth: usize,
+                  has_children: bool) -> Result<()>
+    {
+        let mut class = match item {
+            Item::Transfer(..) => "transfer",
+            Item::Transaction(..) => "transaction",
+            Item::Packet(..) => "packet",
+        }.to_string();
+        if cap.is_highlighted(item) {
+            class.push_str(" highlighted");
+        }
+        let title = match item {
+            Item::Packet(.., packet_id) => format!(" title=\"{}\"",
+                escape_html(&hex_dump(&cap.get_packet(*packet_id)))
+                    .replace('\n', "&#10;")),
+            _ => String::new(),
+        };
+        let summary = escape_html(&cap.get_summary(item));
+        if has_children {
+            writeln!(self.output,
+                     "<details><summary class=\"{}\"{}>{}</summary>",
+                     class, title, summary)
+        } else {
+            writeln!(self.output, "<div class=\"item {}\"{}>{}</div>",
+                     class, title, summary)
+        }
+    }
+
+    fn end_item(&mut self, _depth: usize, has_children: bool) -> Result<()> {
+        if has_children {
+            writeln!(self.output, "</details>")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn end(&mut self) -> Result<()> {
+        self.output.write_all(HTML_FOOTER.as_bytes())
+    }
+}
+
+/// Default number of payload bytes written to each row of a CSV export.
+pub const CSV_PAYLOAD_LIMIT: usize = 64;
+
+const CSV_HEADER: &str =
+    "transaction,device,endpoint,pids,length,status,payload";
+
+/// List the PIDs of a transaction's packets, giving runs of the same PID
+/// as a count, e.g. "SOF x12".
+fn fmt_pids(packets: &[Vec<u8>]) -> String {
+    let mut runs: Vec<(PID, usize)> = Vec::new();
+    for packet in packets {
+        let pid = PID::from(packet[0]);
+        match runs.last_mut() {
+            Some((last, count)) if *last == pid => *count += 1,
+            _ => runs.push((pid, 1)),
+        }
+    }
+    runs.iter()
+        .map(|(pid, count)| match count {
+            1 => pid.to_string(),
+            n => format!("{} x{}", pid, n),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Write one CSV row per transaction, in capture order, giving the
+/// payload in hex up to `payload_limit` bytes. The length column always
+/// gives the full payload length.
+///
+/// Rows are identified by their transaction number.
+pub fn write_csv(cap: &mut Capture, output: &mut dyn Write,
+                 payload_limit: usize) -> Result<()>
+{
+    writeln!(output, "{}", CSV_HEADER)?;
+    for transaction_id in 0..cap.transaction_index.len() {
+        let packets: Vec<Vec<u8>> = cap.get_transaction_packets(
+            transaction_id)
+            .into_iter()
+            .filter(|packet| !cap.is_prefix(PID::from(packet[0])))
+            .collect();
+        let (device, endpoint) = match PacketFields::from_packet(&packets[0])
+        {
+            PacketFields::Token(token) => (
+                token.device_address().to_string(),
+                token.endpoint_number().to_string()),
+            _ => (String::new(), String::new()),
+        };
+        let payload = match packets.get(1) {
+            Some(packet) if packet.len() >= 3 &&
+                matches!(PID::from(packet[0]), PID::DATA0 | PID::DATA1) =>
+                Some(&packet[1..packet.len() - 2]),
+            _ => None,
+        };
+        let status = match packets.last().map(|packet| PID::from(packet[0]))
+        {
+            Some(pid @ (PID::ACK | PID::NAK | PID::STALL | PID::NYET)) =>
+                pid.to_string(),
+            _ => String::new(),
+        };
+        writeln!(output, "{},{},{},{},{},{},{}",
+                 transaction_id, device, endpoint, fmt_pids(&packets),
+                 payload.map_or(String::new(), |data| data.len().to_string()),
+                 status,
+                 payload.map_or(String::new(), |data| data.iter()
+                     .take(payload_limit)
+                     .map(|byte| format!("{:02x}", byte))
+                     .collect()))?;
+    }
+    Ok(())
+}
+
+/// Ending of paths to which the control request log is exported, rather
+/// than the transaction CSV.
+pub const REQUEST_LOG_SUFFIX: &str = ".requests.csv";
+
+const REQUEST_LOG_HEADER: &str =
+    "packet,device,type,recipient,request,value,index,length,status,data,\
+     summary";
+
+/// Quote a CSV field if it holds a comma or quote.
+fn csv_field(text: &str) -> String {
+    if text.contains(',') || text.contains('"') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// Write one CSV row per control transfer, across all devices, in capture
+/// order. This lists the requests of a device's control protocol with
+/// the data of each, as is needed when documenting a vendor protocol.
+///
+/// Rows are identified by the number of their first packet. Standard
+/// requests are named, others
+/// given by number. The status is the handshake of the last transaction
+/// seen, so is STALL for a rejected request, and empty if the transfer was
+/// not finished.
+pub fn write_request_log(cap: &mut Capture, output: &mut dyn Write)
+    -> Result<()>
+{
+    writeln!(output, "{}", REQUEST_LOG_HEADER)?;
+    for item_index in 0..cap.item_index.len() {
+        let transfer_index_id = cap.item_index.get(item_index).unwrap();
+        let transfer = match cap.control_transfer(transfer_index_id) {
+            Some(transfer) => transfer,
+            None => continue,
+        };
+        let item = Item::Transfer(transfer_index_id);
+        let first_transaction_id = match cap.get_child(&item, 0) {
+            Item::Transaction(_, transaction_id) => transaction_id,
+            _ => unreachable!(),
+        };
+        let packet_id =
+            cap.transaction_index.get(first_transaction_id).unwrap();
+        let transaction_count = cap.child_count(&item);
+        let last = cap.get_child(&item, transaction_count - 1);
+        let status = match last {
+            Item::Transaction(_, transaction_id) =>
+                match cap.get_transaction_packets(transaction_id).last()
+                    .map(|packet| PID::from(packet[0]))
+                {
+                    Some(pid @ (PID::ACK | PID::STALL)) => pid.to_string(),
+                    _ => String::new(),
+                },
+            _ => unreachable!(),
+        };
+        let fields = &transfer.fields;
+        let request_type = fields.type_fields.request_type();
+        let request = match (request_type,
+                             StandardRequest::from(fields.request))
+        {
+            (RequestType::Standard, StandardRequest::Unknown) =>
+                fields.request.to_string(),
+            (RequestType::Standard, request) => format!("{:?}", request),
+            (..) => fields.request.to_string(),
+        };
+        writeln!(output, "{},{},{:?},{:?},{},0x{:04x},0x{:04x},{},{},{},{}",
+                 packet_id,
+                 transfer.address,
+                 request_type,
+                 fields.type_fields.recipient(),
+                 request,
+                 fields.value,
+                 fields.index,
+                 fields.length,
+                 status,
+                 transfer.data.iter()
+                     .map(|byte| format!("{:02x}", byte))
+                     .collect::<String>(),
+                 csv_field(&transfer.summary()))?;
+    }
+    Ok(())
+}
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+const LINKTYPE_USB_2_0: u16 = 288;
+const LINKTYPE_USB_2_0_LOW_SPEED: u16 = 293;
+const LINKTYPE_USB_2_0_FULL_SPEED: u16 = 294;
+const LINKTYPE_USB_2_0_HIGH_SPEED: u16 = 295;
+
+/// Link type to write for a capture, which records the speed of the bus if
+/// it is known.
+fn linktype(speed: Option<Speed>) -> u16 {
+    match speed {
+        None => LINKTYPE_USB_2_0,
+        Some(Speed::Low) => LINKTYPE_USB_2_0_LOW_SPEED,
+        Some(Speed::Full) => LINKTYPE_USB_2_0_FULL_SPEED,
+        Some(Speed::High) => LINKTYPE_USB_2_0_HIGH_SPEED,
+    }
+}
+
+/// Write one pcapng block, padding its body to a multiple of 4 bytes.
+fn write_block(output: &mut dyn Write, block_type: u32, body: &[u8])
+    -> Result<()>
+{
+    let padding = (4 - (body.len() & 3)) & 3;
+    let length = (12 + body.len() + padding) as u32;
+    output.write_all(&block_type.to_le_bytes())?;
+    output.write_all(&length.to_le_bytes())?;
+    output.write_all(body)?;
+    output.write_all(&[0; 3][..padding])?;
+    output.write_all(&length.to_le_bytes())
+}
+
+/// Write every packet in the capture to a pcapng file, with the USB 2.0
+/// link type used for captures from hardware analyzers, so that it can be
+/// opened in Wireshark. The link type gives the speed of the bus, if the
+/// capture recorded it.
+///
+/// Packets are given the times at which they were captured, or zero
+/// timestamps if the capture had none. Times before the epoch, which the
+/// format cannot hold, are given as the epoch.
+pub fn write_pcapng(cap: &mut Capture, output: &mut dyn Write) -> Result<()> {
+    let mut section = Vec::new();
+    section.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+    section.extend_from_slice(&1u16.to_le_bytes());
+    section.extend_from_slice(&0u16.to_le_bytes());
+    // Section length not given.
+    section.extend_from_slice(&(-1i64).to_le_bytes());
+    write_block(output, PCAPNG_SECTION_HEADER, &section)?;
+    let mut interface = Vec::new();
+    interface.extend_from_slice(&linktype(cap.speed).to_le_bytes());
+    interface.extend_from_slice(&0u16.to_le_bytes());
+    // No limit on packet length.
+    interface.extend_from_slice(&0u32.to_le_bytes());
+    // Timestamps in nanoseconds, then the end of the options.
+    interface.extend_from_slice(&PCAPNG_OPTION_TSRESOL.to_le_bytes());
+    interface.extend_from_slice(&1u16.to_le_bytes());
+    interface.extend_from_slice(&[9, 0, 0, 0]);
+    interface.extend_from_slice(&[0; 4]);
+    write_block(output, PCAPNG_INTERFACE_DESCRIPTION, &interface)?;
+    for index in 0..cap.packet_index.len() {
+        let packet = cap.get_packet(index);
+        let nanos = cap.packet_time(index).unwrap_or(0).max(0) as u64;
+        let length = packet.len() as u32;
+        let mut body = Vec::with_capacity(20 + packet.len());
+        // Interface ID, then the high and low words of the timestamp.
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(nanos as u32).to_le_bytes());
+        body.extend_from_slice(&length.to_le_bytes());
+        body.extend_from_slice(&length.to_le_bytes());
+        body.extend_from_slice(&packet);
+        write_block(output, PCAPNG_ENHANCED_PACKET, &body)?;
+    }
+    Ok(())
+}
+
+/// Magic number of a pcap file with nanosecond timestamps.
+const PCAP_NANOS_MAGIC: u32 = 0xA1B23C4D;
+
+/// Write the packets of a capture which pass a filter, and fall within a
+/// range of packet numbers, to a pcap file. Returns the number written.
+///
+/// Each transaction is kept or dropped whole, by the device address and
+/// endpoint of its token. Transactions without one, such as SOFs, are only
+/// kept if the filter is empty. Packets are given the times at which they
+/// were captured, in nanoseconds, or zero timestamps if the capture had
+/// none. Times before the epoch are given as the epoch.
+pub fn write_pcap(cap: &mut Capture, output: &mut dyn Write, filter: &Filter,
+                  range: Range<u64>) -> Result<u64>
+{
+    let mut header = Vec::new();
+    header.extend_from_slice(&PCAP_NANOS_MAGIC.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // Timezone offset and timestamp accuracy, then the snapshot length.
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&65535u32.to_le_bytes());
+    header.extend_from_slice(&(linktype(cap.speed) as u32).to_le_bytes());
+    output.write_all(&header)?;
+    let packet_count = cap.packet_index.len();
+    let transaction_count = cap.transaction_index.len();
+    let mut written = 0;
+    for transaction_id in 0..transaction_count {
+        let start = cap.transaction_index.get(transaction_id).unwrap();
+        let end = if transaction_id + 1 < transaction_count {
+            cap.transaction_index.get(transaction_id + 1).unwrap()
+        } else {
+            packet_count
+        };
+        if end <= range.start || start >= range.end {
+            continue;
+        }
+        let packets: Vec<Vec<u8>> = (start..end)
+            .map(|packet_id| cap.get_packet(packet_id))
+            .collect();
+        let token = packets.iter()
+            .find(|packet| !cap.is_prefix(PID::from(packet[0])))
+            .map(|packet| PacketFields::from_packet(packet));
+        let selected = match token {
+            _ if filter.is_empty() => true,
+            Some(PacketFields::Token(token)) => filter.matches(
+                token.device_address(), token.endpoint_number()),
+            _ => false,
+        };
+        if !selected {
+            continue;
+        }
+        for (packet_id, packet) in (start..end).zip(packets) {
+            if !range.contains(&packet_id) {
+                continue;
+            }
+            let length = packet.len() as u32;
+            let time = cap.packet_time(packet_id).unwrap_or(0).max(0);
+            // Timestamp seconds and nanoseconds, then the lengths.
+            output.write_all(
+                &((time / 1_000_000_000) as u32).to_le_bytes())?;
+            output.write_all(
+                &((time % 1_000_000_000) as u32).to_le_bytes())?;
+            output.write_all(&length.to_le_bytes())?;
+            output.write_all(&length.to_le_bytes())?;
+            output.write_all(&packet)?;
+            written += 1;
+        }
+    }
+    Ok(written)
+}
+
+/// Version of the JSON export schema. It is raised when a field is removed
+/// or changes meaning, but not when fields are added.
+pub const JSON_SCHEMA_VERSION: u64 = 1;
+
+/// Quote a string for JSON.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 =>
+                quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Write one top-level item of the traffic tree as JSON, with its
+/// transactions and their packets.
+fn write_json_transfer(cap: &mut Capture, output: &mut dyn Write,
+                       transfer_index_id: u64) -> Result<()>
+{
+    let transfer = Item::Transfer(transfer_index_id);
+    let entry = cap.transfer_index.get(transfer_index_id).unwrap();
+    let endpoint = cap.endpoints.get(entry.endpoint_id() as u64).unwrap();
+    let address = endpoint.endpoint_address();
+    let ep_type = cap.device_data[endpoint.device_id() as usize]
+        .endpoint_type(address);
+    write!(output,
+           "\n    {{\"id\": {}, \"device_address\": {}, \"endpoint\": {}, \
+            \"type\": {},\n     \"summary\": {},\n     \"transactions\": [",
+           json_string(&transfer.to_string()),
+           endpoint.device_address(),
+           address,
+           json_string(&format!("{:?}", ep_type)),
+           json_string(&cap.get_summary(&transfer)))?;
+    for transaction_id in 0..cap.child_count(&transfer) {
+        let transaction = cap.get_child(&transfer, transaction_id);
+        write!(output,
+               "{}\n      {{\"id\": {}, \"summary\": {}, \"packets\": [",
+               if transaction_id == 0 {""} else {","},
+               json_string(&transaction.to_string()),
+               json_string(&cap.get_summary(&transaction)))?;
+        for index in 0..cap.child_count(&transaction) {
+            let packet = cap.get_child(&transaction, index);
+            let packet_id = match packet {
+                Item::Packet(.., packet_id) => packet_id,
+                _ => continue,
+            };
+            let start = cap.packet_index.get(packet_id).unwrap();
+            let end = if packet_id + 1 < cap.packet_index.len() {
+                cap.packet_index.get(packet_id + 1).unwrap()
+            } else {
+                cap.packet_data.len()
+            };
+            let pid = PID::from(cap.packet_data.get(start).unwrap());
+            write!(output,
+                   "{}\n        {{\"id\": {}, \"pid\": {}, \"offset\": {}, \
+                    \"length\": {}}}",
+                   if index == 0 {""} else {","},
+                   json_string(&packet.to_string()),
+                   json_string(&pid.to_string()),
+                   start,
+                   end - start)?;
+        }
+        write!(output, "\n      ]}}")?;
+    }
+    write!(output, "\n    ]}}")
+}
+
+/// Write the whole capture as JSON, in a schema which other tools can rely
+/// on across releases. The top-level object has:
+///
+/// - `schema`, always "packetry-export", and `version`, which is
+///   [JSON_SCHEMA_VERSION].
+/// - `capture`, with the `speed` of the bus ("low", "full" or "high") or
+///   null if unknown, and the numbers of `packets` and `transactions`.
+/// - `devices`, each with its `id`, `address`, and the `vendor_id` and
+///   `product_id` from its descriptor, or null if that was not seen.
+/// - `transfers`, each top-level item of the traffic tree, with its `id`
+///   as given to `--goto`, `device_address`, `endpoint` address with the
+///   direction in bit 7, endpoint `type`, `summary` and `transactions`.
+///   Each transaction has an `id`, `summary` and `packets`, and each packet
+///   an `id`, `pid`, and the `offset` and `length` of its bytes in
+///   `packet_data`.
+/// - `packet_data`, the bytes of every packet in capture order, in hex.
+///
+/// Summaries are the text shown in the traffic view, which may change
+/// between releases.
+pub fn write_json(cap: &mut Capture, output: &mut dyn Write) -> Result<()> {
+    let speed = match cap.speed {
+        None => "null",
+        Some(Speed::Low) => "\"low\"",
+        Some(Speed::Full) => "\"full\"",
+        Some(Speed::High) => "\"high\"",
+    };
+    writeln!(output, "{{")?;
+    writeln!(output, "  \"schema\": \"packetry-export\",")?;
+    writeln!(output, "  \"version\": {},", JSON_SCHEMA_VERSION)?;
+    writeln!(output,
+             "  \"capture\": {{\"speed\": {}, \"packets\": {}, \
+              \"transactions\": {}}},",
+             speed, cap.packet_index.len(), cap.transaction_index.len())?;
+    write!(output, "  \"devices\": [")?;
+    let device_count = cap.devices.len();
+    let devices = cap.devices.get_range(0..device_count).unwrap();
+    for (device_id, device) in devices.iter().enumerate() {
+        let (vendor_id, product_id) =
+            match cap.device_data[device_id].device_descriptor {
+                Some(descriptor) => (descriptor.vendor_id.to_string(),
+                                     descriptor.product_id.to_string()),
+                None => ("null".to_string(), "null".to_string()),
+            };
+        write!(output,
+               "{}\n    {{\"id\": {}, \"address\": {}, \"vendor_id\": {}, \
+                \"product_id\": {}}}",
+               if device_id == 0 {""} else {","},
+               device_id, device.address, vendor_id, product_id)?;
+    }
+    writeln!(output, "\n  ],")?;
+    write!(output, "  \"transfers\": [")?;
+    for item_id in 0..cap.item_count(&None) {
+        if item_id > 0 {
+            write!(output, ",")?;
+        }
+        let transfer_index_id = cap.item_index.get(item_id).unwrap();
+        write_json_transfer(cap, output, transfer_index_id)?;
+    }
+    writeln!(output, "\n  ],")?;
+    write!(output, "  \"packet_data\": \"")?;
+    let length = cap.packet_data.len();
+    for chunk in cap.packet_data.iter_range(0..length) {
+        for byte in chunk.map_err(std::io::Error::other)? {
+            write!(output, "{:02x}", byte)?;
+        }
+    }
+    writeln!(output, "\"\n}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    #[test]
+    fn test_html_export() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        cap.set_highlighted(0);
+        let mut text = Vec::new();
+        write_tree(&mut cap, &mut TextWriter::new(&mut text)).unwrap();
+        let mut html = Vec::new();
+        write_tree(&mut cap, &mut HtmlWriter::new(&mut html)).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.ends_with("</html>\n"));
+        // Every item appears once, and every expandable one is closed.
+        let items = text.lines().count();
+        let opened = html.matches("<details>").count();
+        assert!(opened == html.matches("</details>").count());
+        assert!(opened + html.matches("<div class=\"item").count() == items);
+        assert!(html.matches("class=\"transfer highlighted\"").count() == 1);
+        assert!(html.contains("title=\"2d 00 10\""));
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        assert!(hex_dump(&[]) == "");
+        assert!(hex_dump(&[0x2d, 0x00, 0x10]) == "2d 00 10");
+        let data: Vec<u8> = (0..18).collect();
+        assert!(hex_dump(&data) == concat!(
+            "00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n",
+            "10 11"));
+    }
+    #[test]
+    fn test_csv_export() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let mut csv = Vec::new();
+        write_csv(&mut cap, &mut csv, 4).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert!(rows[0] == CSV_HEADER);
+        assert!(rows.len() as u64 == cap.transaction_index.len() + 1);
+        assert!(rows.iter().all(|row| row.matches(',').count() == 6));
+        assert!(rows[2] == "1,0,0,SETUP DATA0 ACK,8,ACK,80060001");
+        assert!(rows[3] == "2,0,0,IN NAK,,NAK,");
+        assert!(rows[11] == "10,0,0,OUT DATA1 ACK,0,ACK,");
+    }
+
+    #[test]
+    fn test_request_log() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let mut log = Vec::new();
+        write_request_log(&mut cap, &mut log).unwrap();
+        let log = String::from_utf8(log).unwrap();
+        let rows: Vec<&str> = log.lines().collect();
+        assert!(rows[0] == REQUEST_LOG_HEADER);
+        assert!(rows.len() == 11);
+        assert!(rows[1].starts_with(
+            "1,0,Standard,Device,GetDescriptor,0x0100,0x0000,64,ACK,12010002"));
+        assert!(rows[1].ends_with(",\"Getting device descriptor #0 for \
+                                   device 0, reading 18 of 64 requested \
+                                   bytes\""));
+        assert!(rows.iter().skip(1)
+            .all(|row| row.split(',').nth(8) == Some("ACK")));
+        assert!(csv_field("a \"b\"") == "\"a \"\"b\"\"\"");
+        assert!(csv_field("ab") == "ab");
+    }
+
+    #[test]
+    fn test_pcapng_export() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let mut output = Vec::new();
+        write_pcapng(&mut cap, &mut output).unwrap();
+        let word = |offset: usize| u32::from_le_bytes(
+            output[offset..offset + 4].try_into().unwrap());
+        let mut offset = 0;
+        let mut blocks = Vec::new();
+        while offset < output.len() {
+            let length = word(offset + 4) as usize;
+            assert!(length & 3 == 0);
+            assert!(word(offset + length - 4) as usize == length);
+            blocks.push((word(offset), offset));
+            offset += length;
+        }
+        assert!(offset == output.len());
+        assert!(blocks[0].0 == PCAPNG_SECTION_HEADER);
+        assert!(word(8) == PCAPNG_BYTE_ORDER_MAGIC);
+        assert!(blocks[1].0 == PCAPNG_INTERFACE_DESCRIPTION);
+        assert!(output[blocks[1].1 + 8..][..2] ==
+                LINKTYPE_USB_2_0.to_le_bytes());
+        assert!(blocks.len() as u64 == cap.packet_index.len() + 2);
+        assert!(blocks[2..].iter()
+            .all(|(block_type, _)| *block_type == PCAPNG_ENHANCED_PACKET));
+        let (_, offset) = blocks[3];
+        assert!(word(offset + 20) == 3);
+        assert!(output[offset + 28..][..3] == cap.get_packet(1)[..]);
+    }
+
+    #[test]
+    fn test_pcapng_timestamps() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        // Nanoseconds, and times out of order, are kept on export.
+        let mut times = Vec::new();
+        for packet_id in 0..cap.packet_index.len() {
+            let time = cap.packet_time(packet_id).unwrap();
+            times.push(time + (packet_id as i64 % 7) * 123);
+        }
+        times.swap(1, 2);
+        cap.packet_times = crate::file_vec::FileVec::new().unwrap();
+        cap.packet_times.append(&times).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.pcapng");
+        let path = path.to_str().unwrap();
+        let mut output = Vec::new();
+        write_pcapng(&mut cap, &mut output).unwrap();
+        std::fs::write(path, output).unwrap();
+        let mut loaded = Capture::new();
+        let mut decoder = Decoder::new(&mut loaded);
+        decode_files(&mut decoder, &[path]).unwrap();
+        drop(decoder);
+        assert!(loaded.packet_times.len() == times.len() as u64);
+        for (packet_id, &time) in times.iter().enumerate() {
+            assert!(loaded.packet_time(packet_id as u64) == Some(time));
+        }
+    }
+
+    #[test]
+    fn test_pcap_export() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let dir = tempfile::tempdir().unwrap();
+        let export = |cap: &mut Capture, filter: &str, range: Range<u64>| {
+            let path = dir.path().join("export.pcap");
+            let mut output = Vec::new();
+            let filter: Filter = filter.parse().unwrap();
+            let count = write_pcap(cap, &mut output, &filter, range).unwrap();
+            std::fs::write(&path, output).unwrap();
+            let mut packets = Vec::new();
+            let mut pcap = pcap::Capture::from_file(&path).unwrap();
+            while let Ok(packet) = pcap.next() {
+                packets.push(packet.data.to_vec());
+            }
+            assert!(packets.len() as u64 == count);
+            packets
+        };
+
+        let packet_count = cap.packet_index.len();
+        let all = export(&mut cap, "", 0..packet_count);
+        assert!(all.len() as u64 == packet_count);
+        assert!(all[1] == cap.get_packet(1));
+
+        let first = export(&mut cap, "", 10..20);
+        assert!(first.len() == 10);
+        assert!(first[0] == cap.get_packet(10));
+
+        // Only whole transactions to endpoint 4.1 are kept.
+        let mouse = export(&mut cap, "4.1", 0..packet_count);
+        assert!(!mouse.is_empty());
+        assert!(mouse.len() < all.len());
+        let mut tokens = 0;
+        for packet in &mouse {
+            if let PacketFields::Token(token) =
+                PacketFields::from_packet(packet)
+            {
+                assert!(token.device_address() == 4);
+                assert!(token.endpoint_number() == 1);
+                tokens += 1;
+            }
+        }
+        assert!(tokens > 0);
+        assert!(export(&mut cap, "9", 0..packet_count).is_empty());
+    }
+
+    #[test]
+    fn test_json_export() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let mut output = Vec::new();
+        write_json(&mut cap, &mut output).unwrap();
+        let json = String::from_utf8(output).unwrap();
+        assert!(json.starts_with("{\n  \"schema\": \"packetry-export\",\n  \
+                                  \"version\": 1,\n"));
+        assert!(json.contains("\"capture\": {\"speed\": null, "));
+        assert!(json.matches("\"pid\": ").count() as u64 ==
+                cap.packet_index.len());
+        assert!(json.matches("\"address\": ").count() == 2);
+        // The first packet is a lone malformed byte at the start.
+        assert!(json.contains(
+            "{\"id\": \"packet:0.0.0\", \"pid\": \"Malformed\", \
+             \"offset\": 0, \"length\": 1}"));
+        let data = json.split("\"packet_data\": \"").nth(1).unwrap();
+        assert!(data.len() as u64 == cap.packet_data.len() * 2 + 4);
+        assert!(data.starts_with("ff2d0010"));
+        assert!(json_string("a \"b\"\\\n\u{1}") ==
+                "\"a \\\"b\\\"\\\\\\n\\u0001\"");
+    }
+}
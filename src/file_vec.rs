@@ -1,30 +1,47 @@
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::SeekFrom;
+use std::cmp::{min, max};
 use std::marker::PhantomData;
 use std::ops::Range;
 
 use bufreaderwriter::BufReaderWriter;
-use bytemuck::{bytes_of, bytes_of_mut, Pod};
-use tempfile::tempfile;
 use thiserror::Error;
 
+use crate::record::{Record, decode_all, encode_all};
+use crate::storage::{
+    CaptureStorage, copy_contents, read_exact_at, read_u64};
+
+/// Size of the chunks read by `FileVec::iter_range`.
+const CHUNK_BYTES: usize = 0x10000;
+
 #[derive(Error, Debug)]
 pub enum FileVecError {
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 }
 
-pub struct FileVec<T> where T: Pod + Default {
+/// A vector of items kept in a storage file. Items are stored in the form
+/// given by their `Record` implementation, so that the file and saved
+/// contents are the same on every host.
+pub struct FileVec<T> where T: Record {
     _marker: PhantomData<T>,
     file: BufReaderWriter<File>,
     file_length: u64,
     item_count: u64,
 }
 
-impl<T: Pod + Default> FileVec<T> {
+impl<T: Record> FileVec<T> {
    pub fn new() -> Result<Self, FileVecError> {
-        let file = tempfile()?;
+        FileVec::new_in(&CaptureStorage::default(), "")
+   }
+
+   /// Create a FileVec in a capture's storage, with a file named for its
+   /// purpose.
+   pub fn new_in(storage: &CaptureStorage, purpose: &str)
+       -> Result<Self, FileVecError>
+   {
+        let file = storage.create_file(purpose)?;
         Ok(Self{
             _marker: PhantomData,
             file: BufReaderWriter::new_writer(file),
@@ -33,44 +50,55 @@ impl<T: Pod + Default> FileVec<T> {
         })
     }
 
-    pub fn push(&mut self, item: &T) -> Result<(), FileVecError> where T: Pod {
-        let data= bytes_of(item);
-        self.file.write_all(data)?;
+    pub fn push(&mut self, item: &T) -> Result<(), FileVecError> {
+        profile!("FileVec::push");
+        let mut data = vec![0; T::SIZE];
+        item.encode(&mut data);
+        self.file.write_all(&data)?;
         self.file_length += data.len() as u64;
         self.item_count += 1;
         Ok(())
     }
 
-    pub fn append(&mut self, items: &[T]) -> Result<(), FileVecError> where T: Pod {
-        for item in items {
-            let data = bytes_of(item);
-            self.file.write_all(data)?;
-            self.file_length += data.len() as u64;
-        }
+    pub fn append(&mut self, items: &[T]) -> Result<(), FileVecError> {
+        profile!("FileVec::append");
+        let data = encode_all(items);
+        self.file.write_all(&data)?;
+        self.file_length += data.len() as u64;
         self.item_count += items.len() as u64;
         Ok(())
     }
 
     pub fn get(&mut self, index: u64) -> Result<T, FileVecError> {
-        let mut result: T = Default::default();
-        let start = index * std::mem::size_of::<T>() as u64;
-        self.file.seek(SeekFrom::Start(start as u64))?;
-        self.file.read_exact(bytes_of_mut(&mut result))?;
+        profile!("FileVec::get");
+        let mut data = vec![0; T::SIZE];
+        let start = index * T::SIZE as u64;
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.read_exact(&mut data)?;
         self.file.seek(SeekFrom::Start(self.file_length))?;
-        Ok(result)
+        Ok(T::decode(&data))
     }
 
     pub fn get_range(&mut self, range: Range<u64>) -> Result<Vec<T>, FileVecError> {
-        let mut buf: T = Default::default();
-        let mut result = Vec::new();
-        let start = range.start * std::mem::size_of::<T>() as u64;
-        self.file.seek(SeekFrom::Start(start as u64))?;
-        for _ in range {
-            self.file.read_exact(bytes_of_mut(&mut buf))?;
-            result.push(buf);
-        }
+        profile!("FileVec::get_range");
+        let count = range.end.saturating_sub(range.start) as usize;
+        let mut data = vec![0; count * T::SIZE];
+        let start = range.start * T::SIZE as u64;
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.read_exact(&mut data)?;
         self.file.seek(SeekFrom::Start(self.file_length))?;
-        Ok(result)
+        Ok(decode_all(&data))
+    }
+
+    /// Iterate over a range of items, reading them in chunks of at most
+    /// `CHUNK_BYTES` so that the whole range is never held in memory.
+    pub fn iter_range(&mut self, range: Range<u64>) -> FileVecChunks<'_, T> {
+        let chunk_items = max(1, CHUNK_BYTES / T::SIZE);
+        FileVecChunks {
+            vec: self,
+            range: range,
+            chunk_items: chunk_items as u64,
+        }
     }
 
     pub fn len(&self) -> u64 {
@@ -80,20 +108,132 @@ impl<T: Pod + Default> FileVec<T> {
     pub fn size(&self) -> u64 {
        self.file_length
     }
+
+    /// Write the items to `output`, in a form which can be read back by
+    /// `FileVec::load`.
+    pub fn save(&mut self, output: &mut dyn Write)
+        -> Result<(), FileVecError>
+    {
+        self.file.flush()?;
+        output.write_all(&self.item_count.to_le_bytes())?;
+        copy_contents(self.file.get_ref(), self.file_length, output)?;
+        Ok(())
+    }
+
+    /// Load items saved by `FileVec::save` into a new FileVec.
+    pub fn load(input: &mut dyn Read) -> Result<Self, FileVecError> {
+        FileVec::load_in(&CaptureStorage::default(), "", input)
+    }
+
+    /// Load items saved by `FileVec::save` into a new FileVec in a
+    /// capture's storage, with a file named for its purpose.
+    pub fn load_in(storage: &CaptureStorage, purpose: &str,
+                   input: &mut dyn Read) -> Result<Self, FileVecError>
+    {
+        let item_count = read_u64(input)?;
+        let file_length = item_count * T::SIZE as u64;
+        let file = storage.create_file_from(purpose, input, file_length)?;
+        Ok(Self{
+            _marker: PhantomData,
+            file: BufReaderWriter::new_writer(file),
+            file_length: file_length,
+            item_count: item_count,
+        })
+    }
+
+    /// Create a read-only handle to the items currently stored, which can
+    /// be used independently of this FileVec, including from other threads.
+    pub fn reader(&mut self) -> Result<FileVecReader<T>, FileVecError> {
+        self.file.flush()?;
+        Ok(FileVecReader {
+            _marker: PhantomData,
+            file: self.file.get_ref().try_clone()?,
+            item_count: self.item_count,
+        })
+    }
+}
+
+/// Read-only handle to a snapshot of a `FileVec`.
+///
+/// Reads are positional, so on Unix they do not disturb the file offset
+/// that is shared with the original FileVec. On Windows they move it, so
+/// the original should not be written to while a reader is in use.
+pub struct FileVecReader<T> where T: Record {
+    _marker: PhantomData<T>,
+    file: File,
+    item_count: u64,
+}
+
+impl<T: Record> FileVecReader<T> {
+    pub fn get(&self, index: u64) -> Result<T, FileVecError> {
+        let mut data = vec![0; T::SIZE];
+        let start = index * T::SIZE as u64;
+        read_exact_at(&self.file, &mut data, start)?;
+        Ok(T::decode(&data))
+    }
+
+    pub fn get_range(&self, range: Range<u64>) -> Result<Vec<T>, FileVecError> {
+        let count = range.end.saturating_sub(range.start) as usize;
+        let mut data = vec![0; count * T::SIZE];
+        let start = range.start * T::SIZE as u64;
+        read_exact_at(&self.file, &mut data, start)?;
+        Ok(decode_all(&data))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.item_count
+    }
+}
+
+/// Iterator over chunks of a range of items in a `FileVec`.
+pub struct FileVecChunks<'a, T> where T: Record {
+    vec: &'a mut FileVec<T>,
+    range: Range<u64>,
+    chunk_items: u64,
+}
+
+impl<'a, T: Record> Iterator
+    for FileVecChunks<'a, T>
+{
+    type Item = Result<Vec<T>, FileVecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.range.start >= self.range.end {
+            return None;
+        }
+        let end = min(self.range.end, self.range.start + self.chunk_items);
+        let chunk_range = self.range.start..end;
+        self.range.start = end;
+        Some(self.vec.get_range(chunk_range))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bytemuck_derive::{Pod, Zeroable};
 
-    #[derive(Copy, Clone, Debug, Default, PartialEq, Pod, Zeroable)]
-    #[repr(C)]
+    #[derive(Copy, Clone, Debug, Default, PartialEq)]
     struct Foo {
         bar: u32,
         baz: u32,
     }
 
+    impl Record for Foo {
+        const SIZE: usize = 8;
+
+        fn encode(&self, bytes: &mut [u8]) {
+            self.bar.encode(&mut bytes[0..4]);
+            self.baz.encode(&mut bytes[4..8]);
+        }
+
+        fn decode(bytes: &[u8]) -> Self {
+            Foo {
+                bar: u32::decode(&bytes[0..4]),
+                baz: u32::decode(&bytes[4..8]),
+            }
+        }
+    }
+
     #[test]
     fn test_file_vec_push() {
         let mut v = FileVec::new().unwrap();
@@ -122,4 +262,70 @@ mod tests {
         let vec: Vec<_> = file_vec.get_range(0..100).unwrap();
         assert!(vec == data);
     }
+
+    #[test]
+    fn test_file_vec_iter_range() {
+        let mut file_vec = FileVec::new().unwrap();
+        let data: Vec<u8> = (0..200000).map(|i| i as u8).collect();
+        file_vec.append(&data).unwrap();
+        let chunks: Vec<Vec<u8>> = file_vec.iter_range(5..150000)
+            .map(|chunk| chunk.unwrap()).collect();
+        assert!(chunks.len() == 3);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= CHUNK_BYTES));
+        assert!(chunks.concat() == &data[5..150000]);
+        assert!(file_vec.iter_range(10..10).next().is_none());
+        // Writes must still go to the end after reading.
+        file_vec.push(&42).unwrap();
+        assert!(file_vec.get(200000).unwrap() == 42);
+    }
+
+    #[test]
+    fn test_file_vec_save_load() {
+        let mut file_vec = FileVec::new().unwrap();
+        let data: Vec<Foo> = (0..100).map(|i| Foo { bar: i, baz: i }).collect();
+        file_vec.append(&data).unwrap();
+        file_vec.get(10).unwrap();
+        let mut saved = Vec::new();
+        file_vec.save(&mut saved).unwrap();
+        let mut loaded = FileVec::<Foo>::load(&mut saved.as_slice()).unwrap();
+        assert!(loaded.len() == 100);
+        assert!(loaded.get_range(0..100).unwrap() == data);
+        // The loaded copy can be appended to.
+        loaded.push(&Foo { bar: 100, baz: 100 }).unwrap();
+        assert!(loaded.get(100).unwrap().bar == 100);
+        assert!(FileVec::<Foo>::load(&mut &saved[..100]).is_err());
+    }
+
+    #[test]
+    fn test_file_vec_byte_order() {
+        let mut file_vec = FileVec::new().unwrap();
+        file_vec.push(&Foo { bar: 0x01020304, baz: 5 }).unwrap();
+        let mut saved = Vec::new();
+        file_vec.save(&mut saved).unwrap();
+        // Saved contents are little-endian whatever the host.
+        assert!(saved == [1, 0, 0, 0, 0, 0, 0, 0,
+                          4, 3, 2, 1, 5, 0, 0, 0]);
+        let reader = file_vec.reader().unwrap();
+        assert!(reader.get(0).unwrap().bar == 0x01020304);
+        assert!(reader.get_range(0..1).unwrap()[0].baz == 5);
+    }
+
+    #[test]
+    fn test_file_vec_reader() {
+        let mut file_vec = FileVec::new().unwrap();
+        let data: Vec<Foo> = (0..100).map(|i| Foo { bar: i, baz: i }).collect();
+        file_vec.append(&data).unwrap();
+        let reader = file_vec.reader().unwrap();
+        file_vec.push(&Foo { bar: 100, baz: 100 }).unwrap();
+        let expected = data.clone();
+        let thread = std::thread::spawn(move || {
+            assert!(reader.len() == 100);
+            assert!(reader.get(42).unwrap() == expected[42]);
+            assert!(reader.get_range(10..90).unwrap() == &expected[10..90]);
+        });
+        thread.join().unwrap();
+        assert!(file_vec.len() == 101);
+        assert!(file_vec.get(100).unwrap().bar == 100);
+        assert!(file_vec.get_range(0..100).unwrap() == data);
+    }
 }
@@ -0,0 +1,214 @@
+//! Filtering of the top-level items shown in the traffic view.
+
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::capture::Capture;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum FilterError {
+    #[error("invalid filter '{0}', expected ADDRESS or ADDRESS.ENDPOINT")]
+    InvalidFilter(String),
+}
+
+/// Selects the transfers on a device, or on one endpoint of a device.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub address: Option<u8>,
+    pub endpoint: Option<u8>,
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    /// Parse a filter, e.g. "4" for device address 4, or "4.1" for its
+    /// endpoint 1. An empty string matches everything.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(Filter::default());
+        }
+        let invalid = || FilterError::InvalidFilter(text.to_string());
+        let (address, endpoint) = match text.split_once('.') {
+            Some((address, endpoint)) => (address, Some(endpoint)),
+            None => (text, None),
+        };
+        let address = address.parse::<u8>().ok()
+            .filter(|address| *address < 128)
+            .ok_or_else(invalid)?;
+        let endpoint = match endpoint {
+            Some(endpoint) => Some(endpoint.parse::<u8>().ok()
+                .filter(|endpoint| *endpoint < 16)
+                .ok_or_else(invalid)?),
+            None => None,
+        };
+        Ok(Filter {
+            address: Some(address),
+            endpoint: endpoint,
+        })
+    }
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.address.is_none() && self.endpoint.is_none()
+    }
+
+    /// Whether traffic on an endpoint of a device passes this filter.
+    pub fn matches(&self, address: u8, endpoint: u8) -> bool {
+        (self.address.is_none() || self.address == Some(address)) &&
+        (self.endpoint.is_none() || self.endpoint == Some(endpoint))
+    }
+
+    /// Find the top-level items which pass this filter, by item index.
+    pub fn apply(&self, cap: &mut Capture) -> Vec<u64> {
+        let item_count = cap.item_index.len();
+        if self.is_empty() {
+            return (0..item_count).collect();
+        }
+        let endpoint_count = cap.endpoints.len();
+        let endpoints = cap.endpoints.get_range(0..endpoint_count).unwrap();
+        let matching: Vec<bool> = endpoints.iter().map(|endpoint| {
+            // Invalid and framing packets are not on any real endpoint.
+            endpoint.number() < 16 &&
+            self.matches(endpoint.device_address(), endpoint.number())
+        }).collect();
+        let item_ids = cap.item_index.get_range(0..item_count).unwrap();
+        let mut items = Vec::new();
+        for (i, transfer_index_id) in item_ids.into_iter().enumerate() {
+            let entry = cap.transfer_index.get(transfer_index_id).unwrap();
+            if matching[entry.endpoint_id() as usize] {
+                items.push(i as u64);
+            }
+        }
+        items
+    }
+}
+
+/// A change to a list: at `position`, `removed` items were replaced by
+/// `added` new ones, as for `gio::ListModel::items_changed`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ItemsChanged {
+    pub position: u32,
+    pub removed: u32,
+    pub added: u32,
+}
+
+impl ItemsChanged {
+    /// Apply the change to a list, taking the items added from `new`, the
+    /// list which the changes from `diff` lead to.
+    pub fn apply(&self, list: &mut Vec<u64>, new: &[u64]) {
+        let start = self.position as usize;
+        let end = start + self.removed as usize;
+        // Everything before the change is already as in the new list.
+        let added = &new[start..start + self.added as usize];
+        list.splice(start..end, added.iter().cloned());
+    }
+}
+
+/// Find the minimal changes that turn one sorted list into another.
+///
+/// Positions are given relative to the list as updated by the preceding
+/// changes, so the changes can be signalled in order.
+pub fn diff(old: &[u64], new: &[u64]) -> Vec<ItemsChanged> {
+    let mut changes = Vec::new();
+    let mut run: Option<ItemsChanged> = None;
+    let mut position = 0;
+    let mut i = 0;
+    let mut j = 0;
+    loop {
+        let (removed, added) = match (old.get(i), new.get(j)) {
+            (Some(x), Some(y)) if x == y => {
+                if let Some(change) = run.take() {
+                    position += change.added;
+                    changes.push(change);
+                }
+                position += 1;
+                i += 1;
+                j += 1;
+                continue;
+            },
+            (Some(x), Some(y)) if x < y => (1, 0),
+            (Some(_), None) => (1, 0),
+            (_, Some(_)) => (0, 1),
+            (None, None) => break,
+        };
+        let change = run.get_or_insert(ItemsChanged {
+            position: position,
+            removed: 0,
+            added: 0,
+        });
+        change.removed += removed;
+        change.added += added;
+        i += removed as usize;
+        j += added as usize;
+    }
+    changes.extend(run);
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    /// Apply changes to a list, as a ListModel's consumer would see them.
+    fn apply(old: &[u64], new: &[u64]) -> Vec<u64> {
+        let mut list = old.to_vec();
+        for change in diff(old, new) {
+            change.apply(&mut list, new);
+        }
+        list
+    }
+
+    #[test]
+    fn test_diff() {
+        assert!(diff(&[], &[]).is_empty());
+        assert!(diff(&[1, 2, 3], &[1, 2, 3]).is_empty());
+        assert!(diff(&[], &[1, 2]) == vec![
+            ItemsChanged { position: 0, removed: 0, added: 2 }]);
+        assert!(diff(&[1, 2, 3, 4, 5], &[1, 4, 5, 6]) == vec![
+            ItemsChanged { position: 1, removed: 2, added: 0 },
+            ItemsChanged { position: 3, removed: 0, added: 1 }]);
+        assert!(diff(&[1, 3, 5], &[2, 3, 4]) == vec![
+            ItemsChanged { position: 0, removed: 1, added: 1 },
+            ItemsChanged { position: 2, removed: 1, added: 1 }]);
+        let old = [0, 2, 3, 7, 8, 9, 12];
+        let new = [1, 2, 4, 5, 8, 12, 13];
+        assert!(apply(&old, &new) == new);
+        assert!(apply(&new, &old) == old);
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        assert!("".parse::<Filter>().unwrap().is_empty());
+        assert!(" 4 ".parse::<Filter>().unwrap() ==
+            Filter { address: Some(4), endpoint: None });
+        assert!("4.1".parse::<Filter>().unwrap() ==
+            Filter { address: Some(4), endpoint: Some(1) });
+        for text in ["x", "128", "4.16", "4.", ".1"] {
+            assert!(text.parse::<Filter>().unwrap_err() ==
+                FilterError::InvalidFilter(text.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_apply_filter() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let all = Filter::default().apply(&mut cap);
+        assert!(all.len() as u64 == cap.item_index.len());
+        let device = Filter::from_str("4").unwrap().apply(&mut cap);
+        let control = Filter::from_str("4.0").unwrap().apply(&mut cap);
+        let interrupt = Filter::from_str("4.1").unwrap().apply(&mut cap);
+        assert!(!control.is_empty());
+        assert!(!interrupt.is_empty());
+        assert!(control.len() + interrupt.len() == device.len());
+        assert!(device.len() < all.len());
+        assert!(Filter::from_str("5").unwrap().apply(&mut cap).is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+//! Grouping of traffic by device, interface and endpoint.
+//!
+//! Each endpoint is assigned to the interface whose descriptors list it, in
+//! the device's current configuration. The default control endpoint, and
+//! any endpoint not found in the descriptors, belong to the device itself.
+//!
+//! The control traffic of each device can also be grouped on its own, so
+//! that the endpoint zero transfers of devices on a shared bus can be read
+//! without the others' interleaved with them.
+
+use std::collections::BTreeMap;
+
+use crate::capture::Capture;
+
+/// How traffic is grouped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GroupMode {
+    /// All traffic, by device, interface and endpoint.
+    Interface,
+    /// Only the traffic on each device's default control endpoint, by
+    /// device.
+    Control,
+}
+
+/// The top-level transfers on one endpoint.
+pub struct EndpointGroup {
+    /// Address of the endpoint, with the direction in bit 7.
+    pub address: u8,
+    /// Transfer index IDs, in capture order.
+    pub transfer_ids: Vec<u64>,
+}
+
+/// The endpoints of one interface, or those belonging to no interface.
+pub struct InterfaceGroup {
+    pub interface_number: Option<u8>,
+    pub endpoints: Vec<EndpointGroup>,
+}
+
+impl InterfaceGroup {
+    pub fn transfer_count(&self) -> usize {
+        self.endpoints.iter()
+            .map(|endpoint| endpoint.transfer_ids.len())
+            .sum()
+    }
+}
+
+/// The interfaces of one device which had traffic.
+pub struct DeviceGroup {
+    pub device_id: u64,
+    pub interfaces: Vec<InterfaceGroup>,
+}
+
+impl DeviceGroup {
+    pub fn transfer_count(&self) -> usize {
+        self.interfaces.iter()
+            .map(|iface| iface.transfer_count())
+            .sum()
+    }
+}
+
+/// Group the top-level transfers in a capture by device, interface and
+/// endpoint. Devices, interfaces and endpoints without traffic are left
+/// out, as are all endpoints but endpoint zero in [GroupMode::Control].
+pub fn group_traffic(cap: &mut Capture, mode: GroupMode)
+    -> Vec<DeviceGroup>
+{
+    profile!("group_traffic");
+    let endpoint_count = cap.endpoints.len();
+    let endpoints = cap.endpoints.get_range(0..endpoint_count).unwrap();
+    let mut transfer_ids = vec![Vec::new(); endpoint_count as usize];
+    let item_count = cap.item_index.len();
+    for transfer_index_id in cap.item_index.get_range(0..item_count).unwrap() {
+        let entry = cap.transfer_index.get(transfer_index_id).unwrap();
+        transfer_ids[entry.endpoint_id() as usize].push(transfer_index_id);
+    }
+    type Interfaces = BTreeMap<Option<u8>, Vec<EndpointGroup>>;
+    let mut devices: BTreeMap<u64, Interfaces> = BTreeMap::new();
+    for (endpoint_id, ids) in transfer_ids.into_iter().enumerate() {
+        if ids.is_empty() {
+            continue;
+        }
+        let endpoint = &endpoints[endpoint_id];
+        let device_id = endpoint.device_id();
+        let address = endpoint.endpoint_address();
+        if mode == GroupMode::Control && address != 0 {
+            continue;
+        }
+        let interface_number = cap.device_data[device_id as usize]
+            .configuration()
+            .filter(|_| address != 0)
+            .and_then(|config| config.interfaces.iter().find(|iface|
+                iface.endpoint_descriptors.iter().any(|ep_desc|
+                    ep_desc.endpoint_address == address)))
+            .map(|iface| iface.descriptor.interface_number);
+        devices.entry(device_id)
+            .or_default()
+            .entry(interface_number)
+            .or_default()
+            .push(EndpointGroup {
+                address: address,
+                transfer_ids: ids,
+            });
+    }
+    devices.into_iter()
+        .map(|(device_id, interfaces)| DeviceGroup {
+            device_id: device_id,
+            interfaces: interfaces.into_iter()
+                .map(|(interface_number, endpoints)| InterfaceGroup {
+                    interface_number: interface_number,
+                    endpoints: endpoints,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    #[test]
+    fn test_group_mouse_capture() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let groups = group_traffic(&mut cap, GroupMode::Interface);
+        let total: usize = groups.iter()
+            .map(|device| device.transfer_count())
+            .sum();
+        assert!(total as u64 == cap.item_index.len());
+        let mouse = groups.iter()
+            .find(|device|
+                cap.devices.get(device.device_id).unwrap().address == 4)
+            .unwrap();
+        let numbers: Vec<Option<u8>> = mouse.interfaces.iter()
+            .map(|iface| iface.interface_number)
+            .collect();
+        assert!(numbers == [None, Some(0)]);
+        let control = &mouse.interfaces[0].endpoints;
+        assert!(control.len() == 1 && control[0].address == 0);
+        let interrupt = &mouse.interfaces[1].endpoints;
+        assert!(interrupt.len() == 1 && interrupt[0].address == 0x81);
+
+        // Each device's control transfers are kept apart.
+        let groups = group_traffic(&mut cap, GroupMode::Control);
+        let addresses: Vec<u8> = groups.iter()
+            .map(|device|
+                cap.devices.get(device.device_id).unwrap().address)
+            .collect();
+        assert!(addresses == [0, 4]);
+        for device in &groups {
+            assert!(device.interfaces.len() == 1);
+            let endpoints = &device.interfaces[0].endpoints;
+            assert!(endpoints.len() == 1 && endpoints[0].address == 0);
+            for transfer_index_id in &endpoints[0].transfer_ids {
+                let entry = cap.transfer_index.get(*transfer_index_id)
+                    .unwrap();
+                let endpoint = cap.endpoints.get(entry.endpoint_id() as u64)
+                    .unwrap();
+                assert!(endpoint.device_id() == device.device_id);
+            }
+        }
+        let total: usize = groups.iter()
+            .map(|device| device.transfer_count())
+            .sum();
+        assert!(total == 10);
+    }
+}
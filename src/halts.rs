@@ -0,0 +1,238 @@
+//! Tracking of halted endpoints.
+//!
+//! An endpoint other than a control endpoint which responds to a
+//! transaction with STALL is halted, and remains halted until the host
+//! clears the condition with a CLEAR_FEATURE(ENDPOINT_HALT) request. Any
+//! further STALLs before then are part of the same halt.
+
+use crate::capture::{Capture, EndpointType, fmt_endpoint_address};
+use crate::usb::{
+    PID,
+    Recipient,
+    RequestType,
+    StandardFeature,
+    StandardRequest,
+};
+
+/// A period in which an endpoint was halted.
+pub struct Halt {
+    pub endpoint_id: u16,
+    pub address: u8,
+    /// Address of the endpoint, with the direction in bit 7.
+    pub endpoint_address: u8,
+    /// The first transaction answered with STALL.
+    pub transaction_id: u64,
+    /// The SETUP transaction of the request which cleared the halt, if one
+    /// was seen.
+    pub end: Option<u64>,
+}
+
+impl Halt {
+    /// Whether the endpoint was halted at a transaction, between the one
+    /// which stalled and the request which cleared the halt.
+    pub fn covers(&self, transaction_id: u64) -> bool {
+        transaction_id > self.transaction_id && match self.end {
+            Some(end) => transaction_id < end,
+            None => true,
+        }
+    }
+
+    pub fn description(&self) -> String {
+        format!("Endpoint {}.{} halted at transaction {}, {}",
+                self.address, fmt_endpoint_address(self.endpoint_address),
+                self.transaction_id,
+                match self.end {
+                    Some(_) => "later cleared",
+                    None => "not cleared by the end of the capture",
+                })
+    }
+}
+
+/// The halts of one endpoint, from a list of halts in order of endpoint and
+/// then of the start of the halt, as found by `find_halts`.
+fn endpoint_halts(halts: &[Halt], endpoint_id: u16) -> &[Halt] {
+    let start = halts.partition_point(|halt| halt.endpoint_id < endpoint_id);
+    let count = halts[start..]
+        .partition_point(|halt| halt.endpoint_id == endpoint_id);
+    &halts[start..start + count]
+}
+
+/// Whether an endpoint was halted at a transaction, given the halts found
+/// by `find_halts`.
+pub fn is_halted(halts: &[Halt], endpoint_id: u16, transaction_id: u64)
+    -> bool
+{
+    // The halts of an endpoint do not overlap, so only the last one to
+    // start before the transaction can cover it.
+    let halts = endpoint_halts(halts, endpoint_id);
+    let before = halts
+        .partition_point(|halt| halt.transaction_id < transaction_id);
+    before > 0 && halts[before - 1].covers(transaction_id)
+}
+
+/// Whether a halt of an endpoint started at a transaction, given the halts
+/// found by `find_halts`.
+pub fn halt_started(halts: &[Halt], endpoint_id: u16, transaction_id: u64)
+    -> bool
+{
+    endpoint_halts(halts, endpoint_id)
+        .binary_search_by_key(&transaction_id, |halt| halt.transaction_id)
+        .is_ok()
+}
+
+/// Find the SETUP transactions of all requests clearing an endpoint halt,
+/// with the device ID and endpoint address they cleared.
+fn find_clears(cap: &mut Capture) -> Vec<(u64, u64, u8)> {
+    let mut clears = Vec::new();
+    for item_index in 0..cap.item_index.len() {
+        let transfer_index_id = cap.item_index.get(item_index).unwrap();
+        let transfer = match cap.control_transfer(transfer_index_id) {
+            Some(transfer) => transfer,
+            None => continue,
+        };
+        let fields = &transfer.fields;
+        let is_clear_halt =
+            matches!(fields.type_fields.request_type(), RequestType::Standard)
+            && matches!(fields.type_fields.recipient(), Recipient::Endpoint)
+            && matches!(StandardRequest::from(fields.request),
+                        StandardRequest::ClearFeature)
+            && matches!(StandardFeature::from(fields.value),
+                        StandardFeature::EndpointHalt);
+        if is_clear_halt {
+            let entry = cap.transfer_index.get(transfer_index_id).unwrap();
+            let endpoint =
+                cap.endpoints.get(entry.endpoint_id() as u64).unwrap();
+            clears.push((cap.get_entry_transaction(transfer_index_id),
+                         endpoint.device_id(), (fields.index & 0x8F) as u8));
+        }
+    }
+    clears
+}
+
+/// Find every period in which an endpoint was halted, in order of
+/// endpoint and then of the start of the halt.
+pub fn find_halts(cap: &mut Capture) -> Vec<Halt> {
+    profile!("find_halts");
+    let clears = find_clears(cap);
+    let endpoint_count = cap.endpoints.len();
+    let endpoints = cap.endpoints.get_range(0..endpoint_count).unwrap();
+    let mut halts: Vec<Halt> = Vec::new();
+    for (endpoint_id, endpoint) in endpoints.iter().enumerate() {
+        let device_id = endpoint.device_id();
+        let endpoint_address = endpoint.endpoint_address();
+        let dev_data = &cap.device_data[device_id as usize];
+        match dev_data.endpoint_type(endpoint_address) {
+            EndpointType::Control |
+            EndpointType::Framing |
+            EndpointType::Invalid => continue,
+            _ => {}
+        }
+        let ep_traf = &mut cap.endpoint_traffic[endpoint_id];
+        let transaction_ids = ep_traf.transaction_ids
+            .get_range(0..ep_traf.transaction_ids.len())
+            .unwrap();
+        let mut current: Option<usize> = None;
+        for transaction_id in transaction_ids {
+            if cap.get_last_pid(transaction_id) != PID::STALL {
+                continue;
+            }
+            if let Some(index) = current {
+                if halts[index].covers(transaction_id) {
+                    continue;
+                }
+            }
+            let end = clears.iter()
+                .find(|(clear, clear_device_id, clear_address)|
+                    *clear > transaction_id &&
+                    *clear_device_id == device_id &&
+                    *clear_address == endpoint_address)
+                .map(|(clear, ..)| *clear);
+            halts.push(Halt {
+                endpoint_id: endpoint_id as u16,
+                address: endpoint.device_address(),
+                endpoint_address: endpoint_address,
+                transaction_id: transaction_id,
+                end: end,
+            });
+            current = Some(halts.len() - 1);
+        }
+    }
+    halts
+}
+
+/// Find all endpoint halts in a capture and store them with it. Returns
+/// the number found.
+pub fn check_capture(cap: &mut Capture) -> usize {
+    cap.halts = find_halts(cap);
+    cap.halts.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::usb::data_crc;
+
+    fn token(pid: u8, address: u8, number: u8) -> Vec<u8> {
+        let fields = address as u16 | (number as u16) << 7;
+        let bytes = fields.to_le_bytes();
+        vec![pid, bytes[0], bytes[1]]
+    }
+
+    fn data(pid: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![pid];
+        packet.extend_from_slice(payload);
+        packet.extend_from_slice(&data_crc(payload).to_le_bytes());
+        packet
+    }
+
+    const ACK: u8 = 0xd2;
+    const STALL: u8 = 0x1e;
+
+    fn bulk_in(pid: u8) -> Vec<Vec<u8>> {
+        vec![token(0x69, 1, 1), data(pid, &[1, 2, 3]), vec![ACK]]
+    }
+
+    fn stall() -> Vec<Vec<u8>> {
+        vec![token(0x69, 1, 1), vec![STALL]]
+    }
+
+    fn clear_halt() -> Vec<Vec<u8>> {
+        vec![token(0x2d, 1, 0),
+             data(0xc3, &[0x02, 0x01, 0x00, 0x00, 0x81, 0x00, 0x00, 0x00]),
+             vec![ACK],
+             token(0x69, 1, 0), data(0x4b, &[]), vec![ACK]]
+    }
+
+    #[test]
+    fn test_find_halts() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let traffic = [bulk_in(0xc3), stall(), stall(), clear_halt(),
+                       bulk_in(0xc3), stall()];
+        for packet in traffic.iter().flatten() {
+            decoder.handle_raw_packet(packet).unwrap();
+        }
+        drop(decoder);
+        assert!(check_capture(&mut cap) == 2);
+        let halts = &cap.halts;
+        assert!(halts[0].address == 1 && halts[0].endpoint_address == 0x81);
+        assert!(halts[0].transaction_id == 1 && halts[0].end == Some(3));
+        assert!(!halts[0].covers(1) && halts[0].covers(2));
+        assert!(!halts[0].covers(3) && !halts[0].covers(6));
+        let endpoint_id = halts[0].endpoint_id;
+        assert!(halts[1].endpoint_id == endpoint_id);
+        assert!(halt_started(halts, endpoint_id, 1));
+        assert!(!halt_started(halts, endpoint_id, 2));
+        assert!(!halt_started(halts, endpoint_id + 1, 1));
+        assert!(!is_halted(halts, endpoint_id, 1));
+        assert!(is_halted(halts, endpoint_id, 2));
+        assert!(!is_halted(halts, endpoint_id, 4));
+        assert!(is_halted(halts, endpoint_id, 7));
+        assert!(!is_halted(halts, endpoint_id - 1, 7));
+        assert!(halts[1].transaction_id == 6);
+        assert!(halts[1].end.is_none());
+        assert!(halts[1].description() == "Endpoint 1.1 IN halted at \
+            transaction 6, not cleared by the end of the capture");
+    }
+}
@@ -0,0 +1,178 @@
+//! Import of packets written out as hex, such as those pasted into bug
+//! reports or printed in firmware logs.
+//!
+//! A hex dump has a line for each packet, holding its bytes from the PID
+//! to the CRC in hex, either run together or separated by spaces, with or
+//! without a 0x prefix on each. A line may start with a timestamp in
+//! seconds, written with a decimal point and optionally in brackets or
+//! followed by a colon, e.g. `[12.000345]` or `12.000345:`. Blank lines,
+//! and anything after a `#`, are ignored.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+use thiserror::Error;
+
+/// Number of bytes read from a file to find whether it is a hex dump.
+const PROBE_LENGTH: u64 = 4096;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum HexDumpError {
+    #[error("line {0}: invalid hex '{1}'")]
+    InvalidHex(usize, String),
+    #[error("line {0}: invalid timestamp '{1}'")]
+    InvalidTimestamp(usize, String),
+}
+
+/// A packet read from a hex dump, with its timestamp as seconds and
+/// nanoseconds if it had one.
+pub type HexPacket = (Option<(i64, i64)>, Vec<u8>);
+
+/// Parse a timestamp in seconds, such as `12.000345`, to seconds and
+/// nanoseconds.
+fn parse_timestamp(text: &str) -> Option<(i64, i64)> {
+    let (secs, fraction) = text.split_once('.')?;
+    let secs = secs.parse().ok()?;
+    if fraction.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = format!("{:0<9}", &fraction[..fraction.len().min(9)])
+        .parse().ok()?;
+    Some((secs, nanos))
+}
+
+/// Parse bytes written in hex, two digits to a byte.
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.strip_prefix("0x").unwrap_or(text);
+    if text.is_empty() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| text.get(i..i + 2)
+            .and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Parse a line of a hex dump, numbered from 1, returning `None` if it
+/// holds no packet.
+pub fn parse_line(number: usize, line: &str)
+    -> Result<Option<HexPacket>, HexDumpError>
+{
+    let line = match line.split_once('#') {
+        Some((before, _)) => before,
+        None => line,
+    };
+    let mut words = line.split_whitespace().peekable();
+    let timestamp = match words.peek() {
+        Some(word) if word.contains('.') => {
+            let text = word.trim_start_matches('[')
+                .trim_end_matches(':')
+                .trim_end_matches(']');
+            let timestamp = parse_timestamp(text).ok_or_else(||
+                HexDumpError::InvalidTimestamp(number, word.to_string()))?;
+            words.next();
+            Some(timestamp)
+        },
+        _ => None,
+    };
+    let mut packet = Vec::new();
+    for word in words {
+        let bytes = parse_hex(word).ok_or_else(||
+            HexDumpError::InvalidHex(number, word.to_string()))?;
+        packet.extend(bytes);
+    }
+    if packet.is_empty() {
+        // A timestamp must be followed by the packet it is for.
+        return match timestamp {
+            Some(_) => Err(HexDumpError::InvalidHex(number, String::new())),
+            None => Ok(None),
+        };
+    }
+    Ok(Some((timestamp, packet)))
+}
+
+/// Parse the packets of a hex dump, stopping at the first invalid line.
+pub fn parse_packets(text: &str) -> Result<Vec<HexPacket>, HexDumpError> {
+    let mut packets = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if let Some(packet) = parse_line(index + 1, line)? {
+            packets.push(packet);
+        }
+    }
+    Ok(packets)
+}
+
+/// Whether a file is a hex dump, judged by whether its first line with a
+/// packet can be parsed.
+pub fn is_hex_dump(path: &str) -> std::io::Result<bool> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file.take(PROBE_LENGTH));
+    for (index, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        let line = match std::str::from_utf8(&line) {
+            Ok(line) => line,
+            Err(_) => return Ok(false),
+        };
+        match parse_line(index + 1, line) {
+            Ok(Some(_)) => return Ok(true),
+            Ok(None) => continue,
+            Err(_) => return Ok(false),
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{Capture, Item};
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    #[test]
+    fn test_parse_line() {
+        let setup = vec![0x2d, 0x00, 0x10];
+        for line in ["2d0010", "2d 00 10", "0x2d 0x00 0x10", " 2D 0010 # x"] {
+            assert!(parse_line(1, line) == Ok(Some((None, setup.clone()))));
+        }
+        for line in ["[1.5] 2d0010", "1.500000: 2d 00 10", "1.5 2d0010"] {
+            assert!(parse_line(1, line) ==
+                    Ok(Some((Some((1, 500_000_000)), setup.clone()))));
+        }
+        assert!(parse_line(1, "   # comment") == Ok(None));
+        assert!(parse_line(2, "2d 0") ==
+                Err(HexDumpError::InvalidHex(2, "0".into())));
+        assert!(parse_line(3, "2d zz") ==
+                Err(HexDumpError::InvalidHex(3, "zz".into())));
+        assert!(parse_line(4, "1.x 2d") ==
+                Err(HexDumpError::InvalidTimestamp(4, "1.x".into())));
+        assert!(parse_line(5, "[1.5]") ==
+                Err(HexDumpError::InvalidHex(5, String::new())));
+        assert!(parse_packets("2d0010\n\nbad\n") ==
+                Err(HexDumpError::InvalidHex(3, "bad".into())));
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.txt");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "\
+            # GET_DESCRIPTOR(Device) to address 0\n\
+            [0.000100] 2d 00 10\n\
+            [0.000104] c3 80 06 00 01 00 00 40 00 dd 94\n\
+            [0.000110] d2\n").unwrap();
+        assert!(is_hex_dump(path).unwrap());
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &[path]).unwrap();
+        drop(decoder);
+        assert!(cap.packet_index.len() == 3);
+        assert!(cap.packet_time(0) == Some(100_000));
+        assert!(cap.packet_time(2) == Some(110_000));
+        let transfer_index_id = cap.item_index.get(0).unwrap();
+        let summary = cap.get_summary(&Item::Transfer(transfer_index_id));
+        assert!(summary.contains("device descriptor"), "{}", summary);
+    }
+}
@@ -0,0 +1,670 @@
+//! Parsing of HID report descriptors, and decoding of the reports they
+//! describe.
+
+use std::collections::BTreeMap;
+
+use crate::capture::Capture;
+use crate::hid_usages::{fmt_page, fmt_usage, fmt_usage_name};
+use crate::usb::PID;
+
+/// Interface class code for HID.
+pub const HID_CLASS: u8 = 0x03;
+
+/// Interface subclass for HID devices supporting the boot protocol.
+pub const BOOT_SUBCLASS: u8 = 0x01;
+
+/// Descriptor type of a HID report descriptor.
+pub const REPORT_DESCRIPTOR_TYPE: u8 = 0x22;
+
+/// Largest usage range which will be expanded into individual usages.
+const MAX_USAGE_RANGE: u32 = 0x400;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ItemType {
+    Main,
+    Global,
+    Local,
+    Reserved,
+}
+
+/// A short item from a report descriptor.
+#[derive(Copy, Clone, Debug)]
+pub struct Item {
+    pub item_type: ItemType,
+    pub tag: u8,
+    pub size: u8,
+    pub data: u32,
+}
+
+impl Item {
+    /// The item data as a signed value.
+    fn signed(&self) -> i32 {
+        match self.size {
+            1 => self.data as u8 as i8 as i32,
+            2 => self.data as u16 as i16 as i32,
+            _ => self.data as i32,
+        }
+    }
+
+    fn name(&self) -> Option<&'static str> {
+        use ItemType::*;
+        Some(match (self.item_type, self.tag) {
+            (Main, 0x8) => "Input",
+            (Main, 0x9) => "Output",
+            (Main, 0xA) => "Collection",
+            (Main, 0xB) => "Feature",
+            (Main, 0xC) => "End Collection",
+            (Global, 0x0) => "Usage Page",
+            (Global, 0x1) => "Logical Minimum",
+            (Global, 0x2) => "Logical Maximum",
+            (Global, 0x3) => "Physical Minimum",
+            (Global, 0x4) => "Physical Maximum",
+            (Global, 0x5) => "Unit Exponent",
+            (Global, 0x6) => "Unit",
+            (Global, 0x7) => "Report Size",
+            (Global, 0x8) => "Report ID",
+            (Global, 0x9) => "Report Count",
+            (Global, 0xA) => "Push",
+            (Global, 0xB) => "Pop",
+            (Local, 0x0) => "Usage",
+            (Local, 0x1) => "Usage Minimum",
+            (Local, 0x2) => "Usage Maximum",
+            (Local, 0x3) => "Designator Index",
+            (Local, 0x4) => "Designator Minimum",
+            (Local, 0x5) => "Designator Maximum",
+            (Local, 0x7) => "String Index",
+            (Local, 0x8) => "String Minimum",
+            (Local, 0x9) => "String Maximum",
+            (Local, 0xA) => "Delimiter",
+            _ => return None,
+        })
+    }
+
+    /// Describe this item, given the usage page in effect.
+    pub fn description(&self, usage_page: u16) -> String {
+        use ItemType::*;
+        let name = match self.name() {
+            Some(name) => name,
+            None => return format!("{:?} item 0x{:X} (0x{:X})",
+                                   self.item_type, self.tag, self.data),
+        };
+        let value = match (self.item_type, self.tag) {
+            (Main, 0x8 | 0x9 | 0xB) => fmt_main_flags(self.tag, self.data),
+            (Main, 0xA) => match self.data {
+                0x00 => "Physical".to_string(),
+                0x01 => "Application".to_string(),
+                0x02 => "Logical".to_string(),
+                0x03 => "Report".to_string(),
+                0x04 => "Named Array".to_string(),
+                0x05 => "Usage Switch".to_string(),
+                0x06 => "Usage Modifier".to_string(),
+                0x80..=0xFF => format!("Vendor-defined 0x{:02X}", self.data),
+                _ => format!("0x{:02X}", self.data),
+            },
+            (Main, 0xC) | (Global, 0xA | 0xB) => return name.to_string(),
+            (Global, 0x0) => fmt_page(self.data as u16),
+            (Global, 0x1..=0x4) => self.signed().to_string(),
+            (Global, 0x5 | 0x6) => format!("0x{:X}", self.data),
+            (Local, 0x0..=0x2) if self.size == 4 => fmt_usage(
+                (self.data >> 16) as u16, self.data as u16),
+            (Local, 0x0..=0x2) => fmt_usage(usage_page, self.data as u16),
+            _ => self.data.to_string(),
+        };
+        format!("{} ({})", name, value)
+    }
+}
+
+fn fmt_main_flags(tag: u8, flags: u32) -> String {
+    let mut parts = vec![
+        if flags & 0x01 == 0 {"Data"} else {"Constant"},
+        if flags & 0x02 == 0 {"Array"} else {"Variable"},
+        if flags & 0x04 == 0 {"Absolute"} else {"Relative"},
+    ];
+    for (bit, name) in [
+        (0x08, "Wrap"),
+        (0x10, "Non Linear"),
+        (0x20, "No Preferred State"),
+        (0x40, "Null State"),
+        (0x100, "Buffered Bytes"),
+    ] {
+        if flags & bit != 0 {
+            parts.push(name);
+        }
+    }
+    // Input items have no volatile flag.
+    if tag != 0x8 && flags & 0x80 != 0 {
+        parts.push("Volatile");
+    }
+    parts.join(", ")
+}
+
+/// Split report descriptor data into its short items.
+///
+/// Long items are skipped, and parsing stops at any truncated item.
+pub fn parse_items(bytes: &[u8]) -> Vec<Item> {
+    let mut items = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let prefix = bytes[offset];
+        if prefix == 0xFE {
+            match bytes.get(offset + 1) {
+                Some(size) => offset += 3 + *size as usize,
+                None => break,
+            }
+            continue;
+        }
+        let size = [0, 1, 2, 4][(prefix & 0x03) as usize];
+        let start = offset + 1;
+        let end = start + size;
+        if end > bytes.len() {
+            break;
+        }
+        let mut data = 0;
+        for (i, byte) in bytes[start..end].iter().enumerate() {
+            data |= (*byte as u32) << (8 * i);
+        }
+        items.push(Item {
+            item_type: match (prefix >> 2) & 0x03 {
+                0 => ItemType::Main,
+                1 => ItemType::Global,
+                2 => ItemType::Local,
+                _ => ItemType::Reserved,
+            },
+            tag: prefix >> 4,
+            size: size as u8,
+            data: data,
+        });
+        offset = end;
+    }
+    items
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReportType {
+    Input,
+    Output,
+    Feature,
+}
+
+/// A field within a report, holding one or more values.
+#[derive(Clone, Debug)]
+pub struct ReportField {
+    pub report_type: ReportType,
+    pub report_id: u8,
+    /// Position of the field, in bits after any report ID.
+    pub bit_offset: usize,
+    pub bit_size: usize,
+    pub count: usize,
+    /// Flags from the main item.
+    pub flags: u32,
+    /// Usages with their pages, as (page << 16) | id.
+    pub usages: Vec<u32>,
+    pub logical_minimum: i32,
+    pub logical_maximum: i32,
+}
+
+impl ReportField {
+    pub fn is_constant(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    pub fn is_variable(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+
+    pub fn is_relative(&self) -> bool {
+        self.flags & 0x04 != 0
+    }
+
+    /// Read the value at some index within this field.
+    fn value(&self, data: &[u8], index: usize) -> Option<i32> {
+        let start = self.bit_offset + index * self.bit_size;
+        if self.bit_size == 0 || self.bit_size > 32 ||
+            start + self.bit_size > data.len() * 8
+        {
+            return None;
+        }
+        let mut raw: u32 = 0;
+        for bit in 0..self.bit_size {
+            let position = start + bit;
+            if data[position / 8] & (1 << (position % 8)) != 0 {
+                raw |= 1 << bit;
+            }
+        }
+        Some(if self.logical_minimum < 0 && self.bit_size < 32 {
+            let shift = 32 - self.bit_size;
+            ((raw << shift) as i32) >> shift
+        } else {
+            raw as i32
+        })
+    }
+}
+
+/// A value decoded from a report.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ReportValue {
+    /// Usage with its page, as (page << 16) | id.
+    pub usage: u32,
+    pub value: i32,
+    /// Whether this is a usage selected by an array field, rather than
+    /// the value of a variable.
+    pub selected: bool,
+}
+
+impl ReportValue {
+    pub fn usage_page(&self) -> u16 {
+        (self.usage >> 16) as u16
+    }
+
+    pub fn usage_id(&self) -> u16 {
+        self.usage as u16
+    }
+}
+
+/// Describe a list of report values, e.g. "Button 1=1, X=-3, Y=2".
+pub fn fmt_values(values: &[ReportValue]) -> String {
+    let parts: Vec<String> = values.iter().map(|value| {
+        let name = fmt_usage_name(value.usage_page(), value.usage_id());
+        if value.selected {
+            name
+        } else {
+            format!("{}={}", name, value.value)
+        }
+    }).collect();
+    parts.join(", ")
+}
+
+#[derive(Clone, Default)]
+struct GlobalState {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    report_size: usize,
+    report_id: u8,
+    report_count: usize,
+}
+
+/// A parsed HID report descriptor.
+#[derive(Clone, Debug)]
+pub struct ReportDescriptor {
+    pub items: Vec<Item>,
+    /// The usage page in effect at each item.
+    pub usage_pages: Vec<u16>,
+    /// Collection nesting depth at each item.
+    pub depths: Vec<usize>,
+    pub fields: Vec<ReportField>,
+    /// Whether reports are prefixed with a report ID byte.
+    pub report_ids: bool,
+}
+
+impl ReportDescriptor {
+    /// Encode the items of the descriptor, which `from_bytes` parses back
+    /// into the same descriptor.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for item in &self.items {
+            let item_type = match item.item_type {
+                ItemType::Main => 0,
+                ItemType::Global => 1,
+                ItemType::Local => 2,
+                ItemType::Reserved => 3,
+            };
+            let (size, size_code) = match item.size {
+                0 => (0, 0),
+                1 => (1, 1),
+                2 => (2, 2),
+                _ => (4, 3),
+            };
+            bytes.push(item.tag << 4 | item_type << 2 | size_code);
+            bytes.extend_from_slice(&item.data.to_le_bytes()[..size]);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> ReportDescriptor {
+        use ItemType::*;
+        let items = parse_items(bytes);
+        let mut usage_pages = Vec::with_capacity(items.len());
+        let mut depths = Vec::with_capacity(items.len());
+        let mut fields = Vec::new();
+        let mut report_ids = false;
+        let mut global = GlobalState::default();
+        let mut stack = Vec::new();
+        let mut usages = Vec::new();
+        let mut usage_minimum = None;
+        let mut depth: usize = 0;
+        let mut offsets = BTreeMap::new();
+        let extend = |page: u16, item: &Item| {
+            if item.size == 4 {
+                item.data
+            } else {
+                (page as u32) << 16 | (item.data & 0xFFFF)
+            }
+        };
+        for item in &items {
+            if item.item_type == Main && item.tag == 0xC {
+                depth = depth.saturating_sub(1);
+            }
+            usage_pages.push(global.usage_page);
+            depths.push(depth);
+            match (item.item_type, item.tag) {
+                (Main, tag @ (0x8 | 0x9 | 0xB)) => {
+                    let report_type = match tag {
+                        0x8 => ReportType::Input,
+                        0x9 => ReportType::Output,
+                        _ => ReportType::Feature,
+                    };
+                    let key = (tag, global.report_id);
+                    let offset = offsets.entry(key).or_insert(0);
+                    let bit_size = global.report_size * global.report_count;
+                    fields.push(ReportField {
+                        report_type: report_type,
+                        report_id: global.report_id,
+                        bit_offset: *offset,
+                        bit_size: global.report_size,
+                        count: global.report_count,
+                        flags: item.data,
+                        usages: std::mem::take(&mut usages),
+                        logical_minimum: global.logical_minimum,
+                        logical_maximum: global.logical_maximum,
+                    });
+                    *offset += bit_size;
+                    usage_minimum = None;
+                },
+                (Main, 0xA) => {
+                    depth += 1;
+                    usages.clear();
+                    usage_minimum = None;
+                },
+                (Main, _) => {
+                    usages.clear();
+                    usage_minimum = None;
+                },
+                (Global, 0x0) => global.usage_page = item.data as u16,
+                (Global, 0x1) => global.logical_minimum = item.signed(),
+                (Global, 0x2) => global.logical_maximum =
+                    if global.logical_minimum < 0 {
+                        item.signed()
+                    } else {
+                        item.data as i32
+                    },
+                (Global, 0x7) => global.report_size = item.data as usize,
+                (Global, 0x8) => {
+                    global.report_id = item.data as u8;
+                    report_ids = true;
+                },
+                (Global, 0x9) => global.report_count = item.data as usize,
+                (Global, 0xA) => stack.push(global.clone()),
+                (Global, 0xB) => if let Some(state) = stack.pop() {
+                    global = state;
+                },
+                (Local, 0x0) => usages.push(extend(global.usage_page, item)),
+                (Local, 0x1) =>
+                    usage_minimum = Some(extend(global.usage_page, item)),
+                (Local, 0x2) => {
+                    let maximum = extend(global.usage_page, item);
+                    if let Some(minimum) = usage_minimum.take() {
+                        if minimum <= maximum &&
+                            maximum - minimum < MAX_USAGE_RANGE
+                        {
+                            usages.extend(minimum..=maximum);
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+        ReportDescriptor {
+            items: items,
+            usage_pages: usage_pages,
+            depths: depths,
+            fields: fields,
+            report_ids: report_ids,
+        }
+    }
+
+    /// Describe an item, indented by its collection depth.
+    pub fn item_description(&self, index: usize) -> String {
+        format!("{}{}", "  ".repeat(self.depths[index]),
+                self.items[index].description(self.usage_pages[index]))
+    }
+
+    /// Decode the values in a report.
+    ///
+    /// Constant fields and unused array entries are omitted. Returns None
+    /// if the report is not described by this descriptor.
+    pub fn decode(&self, report_type: ReportType, report: &[u8])
+        -> Option<Vec<ReportValue>>
+    {
+        let (report_id, data) = match (self.report_ids, report.split_first()) {
+            (true, Some((id, data))) => (*id, data),
+            (true, None) => return None,
+            (false, _) => (0, report),
+        };
+        let mut values = Vec::new();
+        let mut found = false;
+        for field in &self.fields {
+            if field.report_type != report_type ||
+                field.report_id != report_id
+            {
+                continue;
+            }
+            found = true;
+            if field.is_constant() {
+                continue;
+            }
+            for index in 0..field.count {
+                let value = match field.value(data, index) {
+                    Some(value) => value,
+                    None => break,
+                };
+                if field.is_variable() {
+                    // Any remaining values share the last usage.
+                    let usage = match field.usages.get(index)
+                        .or_else(|| field.usages.last())
+                    {
+                        Some(usage) => *usage,
+                        None => continue,
+                    };
+                    values.push(ReportValue {
+                        usage: usage,
+                        value: value,
+                        selected: false,
+                    });
+                } else {
+                    if value < field.logical_minimum ||
+                        value > field.logical_maximum
+                    {
+                        continue;
+                    }
+                    let position = (value - field.logical_minimum) as usize;
+                    match field.usages.get(position) {
+                        // Usage ID zero means no selection.
+                        Some(usage) if *usage & 0xFFFF != 0 =>
+                            values.push(ReportValue {
+                                usage: *usage,
+                                value: 1,
+                                selected: true,
+                            }),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        if found {
+            Some(values)
+        } else {
+            None
+        }
+    }
+}
+
+/// A HID interface seen in a capture.
+pub struct HidInterface {
+    pub address: u8,
+    pub interface_number: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// The interface's report descriptor, if it was read.
+    pub descriptor: Option<ReportDescriptor>,
+    /// IDs of the transactions on the interface's IN endpoints, in order.
+    pub transaction_ids: Vec<u64>,
+}
+
+impl HidInterface {
+    /// Whether the interface supports a boot protocol, such as for a
+    /// keyboard or mouse.
+    pub fn is_boot(&self, protocol: u8) -> bool {
+        self.subclass == BOOT_SUBCLASS && self.protocol == protocol
+    }
+
+    /// Whether the reports described include a usage on some page.
+    pub fn has_input_page(&self, page: u16) -> bool {
+        self.descriptor.iter()
+            .flat_map(|desc| desc.fields.iter())
+            .filter(|field| field.report_type == ReportType::Input)
+            .flat_map(|field| field.usages.iter())
+            .any(|usage| (usage >> 16) as u16 == page)
+    }
+
+    /// The input reports sent by the interface, in capture order.
+    pub fn input_reports(&self, cap: &mut Capture) -> Vec<Vec<u8>> {
+        self.transaction_ids.iter()
+            .filter_map(|id| match cap.get_acked_payload(*id) {
+                Some((PID::IN, data)) => Some(data),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Find the HID interfaces of all devices in the capture.
+pub fn find_interfaces(cap: &mut Capture) -> Vec<HidInterface> {
+    let mut interfaces = Vec::new();
+    for device_id in 0..cap.device_data.len() {
+        let dev_data = &cap.device_data[device_id];
+        let config = match dev_data.configuration() {
+            Some(config) => config,
+            None => continue,
+        };
+        let mut found = Vec::new();
+        for iface in &config.interfaces {
+            let desc = &iface.descriptor;
+            if desc.interface_class != HID_CLASS {
+                continue;
+            }
+            let addresses: Vec<u8> = iface.endpoint_descriptors.iter()
+                .filter(|ep_desc| ep_desc.endpoint_address & 0x80 != 0)
+                .map(|ep_desc| ep_desc.endpoint_address)
+                .collect();
+            found.push((HidInterface {
+                address: 0,
+                interface_number: desc.interface_number,
+                subclass: desc.interface_subclass,
+                protocol: desc.interface_protocol,
+                descriptor: dev_data
+                    .report_descriptor(desc.interface_number).cloned(),
+                transaction_ids: Vec::new(),
+            }, addresses));
+        }
+        let address = cap.devices.get(device_id as u64).unwrap().address;
+        for (mut iface, addresses) in found {
+            iface.address = address;
+            iface.transaction_ids =
+                cap.get_device_transaction_ids(device_id as u64, &addresses);
+            interfaces.push(iface);
+        }
+    }
+    interfaces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Report descriptor of the mouse in tests/mouse.
+    const MOUSE: [u8; 75] = [
+        0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0x85, 0x01,
+        0x09, 0x01, 0xA1, 0x00, 0x05, 0x09, 0x19, 0x01,
+        0x29, 0x05, 0x15, 0x00, 0x25, 0x01, 0x95, 0x05,
+        0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x03,
+        0x81, 0x03, 0x05, 0x01, 0x16, 0x01, 0xF8, 0x26,
+        0xFF, 0x07, 0x75, 0x0C, 0x95, 0x02, 0x09, 0x30,
+        0x09, 0x31, 0x81, 0x06, 0x15, 0x81, 0x25, 0x7F,
+        0x75, 0x08, 0x95, 0x01, 0x09, 0x38, 0x81, 0x06,
+        0xC0, 0x05, 0x0C, 0x0A, 0x38, 0x02, 0x95, 0x01,
+        0x81, 0x06, 0xC0,
+    ];
+
+    // Boot protocol keyboard report descriptor, from the HID specification.
+    const KEYBOARD: [u8; 63] = [
+        0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x05, 0x07,
+        0x19, 0xE0, 0x29, 0xE7, 0x15, 0x00, 0x25, 0x01,
+        0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01,
+        0x75, 0x08, 0x81, 0x01, 0x95, 0x05, 0x75, 0x01,
+        0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02,
+        0x95, 0x01, 0x75, 0x03, 0x91, 0x01, 0x95, 0x06,
+        0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07,
+        0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xC0,
+    ];
+
+    #[test]
+    fn test_parse_items() {
+        let desc = ReportDescriptor::from_bytes(&MOUSE);
+        assert!(desc.items.len() == 37);
+        assert!(desc.report_ids);
+        let lines: Vec<String> = (0..6)
+            .map(|i| desc.item_description(i))
+            .collect();
+        assert!(lines == vec![
+            "Usage Page (Generic Desktop)",
+            "Usage (Generic Desktop / Mouse)",
+            "Collection (Application)",
+            "  Report ID (1)",
+            "  Usage (Generic Desktop / Pointer)",
+            "  Collection (Physical)",
+        ]);
+        assert!(desc.item_description(7) ==
+                "    Usage Minimum (Button / Button 1)");
+        assert!(desc.item_description(18) == "    Logical Minimum (-2047)");
+        assert!(desc.item_description(24) ==
+                "    Input (Data, Variable, Relative)");
+        assert!(desc.item_description(31) == "  End Collection");
+        assert!(desc.item_description(33) == "  Usage (Consumer / AC Pan)");
+        assert!(desc.item_description(36) == "End Collection");
+        let input: Vec<(usize, usize, usize)> = desc.fields.iter()
+            .map(|f| (f.bit_offset, f.bit_size, f.count))
+            .collect();
+        assert!(input == vec![(0, 1, 5), (5, 3, 1), (8, 12, 2),
+                              (32, 8, 1), (40, 8, 1)]);
+        assert!(desc.to_bytes() == MOUSE);
+    }
+
+    #[test]
+    fn test_decode_mouse() {
+        let desc = ReportDescriptor::from_bytes(&MOUSE);
+        let report = [0x01, 0x05, 0xF9, 0xFF, 0x00, 0x01, 0xFF];
+        let values = desc.decode(ReportType::Input, &report).unwrap();
+        assert!(fmt_values(&values) ==
+            "Button 1=1, Button 2=0, Button 3=1, Button 4=0, Button 5=0, \
+             X=-7, Y=15, Wheel=1, AC Pan=-1");
+        assert!(desc.decode(ReportType::Input, &[0x02, 0x00]).is_none());
+        assert!(desc.decode(ReportType::Output, &report).is_none());
+    }
+
+    #[test]
+    fn test_decode_keyboard() {
+        let desc = ReportDescriptor::from_bytes(&KEYBOARD);
+        assert!(!desc.report_ids);
+        let report = [0x02, 0x00, 0x04, 0x27, 0x00, 0x00, 0x00, 0x00];
+        let values = desc.decode(ReportType::Input, &report).unwrap();
+        let pressed: Vec<ReportValue> = values.into_iter()
+            .filter(|v| v.value != 0)
+            .collect();
+        assert!(fmt_values(&pressed) ==
+            "Keyboard LeftShift=1, Keyboard a and A, Keyboard 0 and )");
+        let leds = desc.decode(ReportType::Output, &[0x02]).unwrap();
+        assert!(fmt_values(&leds) ==
+            "Num Lock=0, Caps Lock=1, Scroll Lock=0, Compose=0, Kana=0");
+    }
+}
@@ -0,0 +1,379 @@
+//! Names of HID usage pages and usages, from the HID Usage Tables.
+//!
+//! Only the pages and usages commonly seen in practice are included. Any
+//! others are shown by number.
+
+const USAGE_PAGES: &[(u16, &str)] = &[
+    (0x01, "Generic Desktop"),
+    (0x02, "Simulation Controls"),
+    (0x03, "VR Controls"),
+    (0x04, "Sport Controls"),
+    (0x05, "Game Controls"),
+    (0x06, "Generic Device Controls"),
+    (0x07, "Keyboard/Keypad"),
+    (0x08, "LED"),
+    (0x09, "Button"),
+    (0x0A, "Ordinal"),
+    (0x0B, "Telephony Device"),
+    (0x0C, "Consumer"),
+    (0x0D, "Digitizers"),
+    (0x0E, "Haptics"),
+    (0x0F, "Physical Input Device"),
+    (0x10, "Unicode"),
+    (0x12, "Eye and Head Trackers"),
+    (0x14, "Auxiliary Display"),
+    (0x20, "Sensors"),
+    (0x40, "Medical Instrument"),
+    (0x41, "Braille Display"),
+    (0x59, "Lighting and Illumination"),
+    (0x80, "Monitor"),
+    (0x81, "Monitor Enumerated"),
+    (0x82, "VESA Virtual Controls"),
+    (0x84, "Power"),
+    (0x85, "Battery System"),
+    (0x8C, "Barcode Scanner"),
+    (0x8D, "Scales"),
+    (0x8E, "Magnetic Stripe Reader"),
+    (0x90, "Camera Control"),
+    (0x91, "Arcade"),
+    (0x92, "Gaming Device"),
+    (0xF1D0, "FIDO Alliance"),
+];
+
+pub const GENERIC_DESKTOP: u16 = 0x01;
+pub const KEYBOARD: u16 = 0x07;
+pub const LED: u16 = 0x08;
+pub const BUTTON: u16 = 0x09;
+pub const ORDINAL: u16 = 0x0A;
+pub const CONSUMER: u16 = 0x0C;
+pub const DIGITIZERS: u16 = 0x0D;
+
+const GENERIC_DESKTOP_USAGES: &[(u16, &str)] = &[
+    (0x01, "Pointer"),
+    (0x02, "Mouse"),
+    (0x04, "Joystick"),
+    (0x05, "Gamepad"),
+    (0x06, "Keyboard"),
+    (0x07, "Keypad"),
+    (0x08, "Multi-axis Controller"),
+    (0x09, "Tablet PC System Controls"),
+    (0x30, "X"),
+    (0x31, "Y"),
+    (0x32, "Z"),
+    (0x33, "Rx"),
+    (0x34, "Ry"),
+    (0x35, "Rz"),
+    (0x36, "Slider"),
+    (0x37, "Dial"),
+    (0x38, "Wheel"),
+    (0x39, "Hat Switch"),
+    (0x3A, "Counted Buffer"),
+    (0x3B, "Byte Count"),
+    (0x3C, "Motion Wakeup"),
+    (0x3D, "Start"),
+    (0x3E, "Select"),
+    (0x40, "Vx"),
+    (0x41, "Vy"),
+    (0x42, "Vz"),
+    (0x43, "Vbrx"),
+    (0x44, "Vbry"),
+    (0x45, "Vbrz"),
+    (0x46, "Vno"),
+    (0x47, "Feature Notification"),
+    (0x48, "Resolution Multiplier"),
+    (0x80, "System Control"),
+    (0x81, "System Power Down"),
+    (0x82, "System Sleep"),
+    (0x83, "System Wake Up"),
+    (0x84, "System Context Menu"),
+    (0x85, "System Main Menu"),
+    (0x86, "System App Menu"),
+    (0x87, "System Menu Help"),
+    (0x88, "System Menu Exit"),
+    (0x89, "System Menu Select"),
+    (0x8A, "System Menu Right"),
+    (0x8B, "System Menu Left"),
+    (0x8C, "System Menu Up"),
+    (0x8D, "System Menu Down"),
+    (0x90, "D-pad Up"),
+    (0x91, "D-pad Down"),
+    (0x92, "D-pad Right"),
+    (0x93, "D-pad Left"),
+];
+
+/// Keyboard/Keypad usages 0x00 to 0x67, in order.
+const KEYBOARD_USAGES: [&str; 0x68] = [
+    "No Event",
+    "Keyboard ErrorRollOver",
+    "Keyboard POSTFail",
+    "Keyboard ErrorUndefined",
+    "Keyboard a and A",
+    "Keyboard b and B",
+    "Keyboard c and C",
+    "Keyboard d and D",
+    "Keyboard e and E",
+    "Keyboard f and F",
+    "Keyboard g and G",
+    "Keyboard h and H",
+    "Keyboard i and I",
+    "Keyboard j and J",
+    "Keyboard k and K",
+    "Keyboard l and L",
+    "Keyboard m and M",
+    "Keyboard n and N",
+    "Keyboard o and O",
+    "Keyboard p and P",
+    "Keyboard q and Q",
+    "Keyboard r and R",
+    "Keyboard s and S",
+    "Keyboard t and T",
+    "Keyboard u and U",
+    "Keyboard v and V",
+    "Keyboard w and W",
+    "Keyboard x and X",
+    "Keyboard y and Y",
+    "Keyboard z and Z",
+    "Keyboard 1 and !",
+    "Keyboard 2 and @",
+    "Keyboard 3 and #",
+    "Keyboard 4 and $",
+    "Keyboard 5 and %",
+    "Keyboard 6 and ^",
+    "Keyboard 7 and &",
+    "Keyboard 8 and *",
+    "Keyboard 9 and (",
+    "Keyboard 0 and )",
+    "Keyboard Return (ENTER)",
+    "Keyboard ESCAPE",
+    "Keyboard DELETE (Backspace)",
+    "Keyboard Tab",
+    "Keyboard Spacebar",
+    "Keyboard - and _",
+    "Keyboard = and +",
+    "Keyboard [ and {",
+    "Keyboard ] and }",
+    "Keyboard \\ and |",
+    "Keyboard Non-US # and ~",
+    "Keyboard ; and :",
+    "Keyboard ' and \"",
+    "Keyboard Grave Accent and Tilde",
+    "Keyboard , and <",
+    "Keyboard . and >",
+    "Keyboard / and ?",
+    "Keyboard Caps Lock",
+    "Keyboard F1",
+    "Keyboard F2",
+    "Keyboard F3",
+    "Keyboard F4",
+    "Keyboard F5",
+    "Keyboard F6",
+    "Keyboard F7",
+    "Keyboard F8",
+    "Keyboard F9",
+    "Keyboard F10",
+    "Keyboard F11",
+    "Keyboard F12",
+    "Keyboard PrintScreen",
+    "Keyboard Scroll Lock",
+    "Keyboard Pause",
+    "Keyboard Insert",
+    "Keyboard Home",
+    "Keyboard PageUp",
+    "Keyboard Delete Forward",
+    "Keyboard End",
+    "Keyboard PageDown",
+    "Keyboard RightArrow",
+    "Keyboard LeftArrow",
+    "Keyboard DownArrow",
+    "Keyboard UpArrow",
+    "Keypad Num Lock and Clear",
+    "Keypad /",
+    "Keypad *",
+    "Keypad -",
+    "Keypad +",
+    "Keypad ENTER",
+    "Keypad 1 and End",
+    "Keypad 2 and Down Arrow",
+    "Keypad 3 and PageDn",
+    "Keypad 4 and Left Arrow",
+    "Keypad 5",
+    "Keypad 6 and Right Arrow",
+    "Keypad 7 and Home",
+    "Keypad 8 and Up Arrow",
+    "Keypad 9 and PageUp",
+    "Keypad 0 and Insert",
+    "Keypad . and Delete",
+    "Keyboard Non-US \\ and |",
+    "Keyboard Application",
+    "Keyboard Power",
+    "Keypad =",
+];
+
+/// Keyboard/Keypad usages 0xE0 to 0xE7, the modifier keys.
+const KEYBOARD_MODIFIERS: [&str; 8] = [
+    "Keyboard LeftControl",
+    "Keyboard LeftShift",
+    "Keyboard LeftAlt",
+    "Keyboard Left GUI",
+    "Keyboard RightControl",
+    "Keyboard RightShift",
+    "Keyboard RightAlt",
+    "Keyboard Right GUI",
+];
+
+const LED_USAGES: &[(u16, &str)] = &[
+    (0x01, "Num Lock"),
+    (0x02, "Caps Lock"),
+    (0x03, "Scroll Lock"),
+    (0x04, "Compose"),
+    (0x05, "Kana"),
+    (0x06, "Power"),
+    (0x07, "Shift"),
+    (0x08, "Do Not Disturb"),
+    (0x09, "Mute"),
+];
+
+const CONSUMER_USAGES: &[(u16, &str)] = &[
+    (0x001, "Consumer Control"),
+    (0x002, "Numeric Key Pad"),
+    (0x003, "Programmable Buttons"),
+    (0x004, "Microphone"),
+    (0x005, "Headphone"),
+    (0x006, "Graphic Equalizer"),
+    (0x030, "Power"),
+    (0x040, "Menu"),
+    (0x0B0, "Play"),
+    (0x0B1, "Pause"),
+    (0x0B2, "Record"),
+    (0x0B3, "Fast Forward"),
+    (0x0B4, "Rewind"),
+    (0x0B5, "Scan Next Track"),
+    (0x0B6, "Scan Previous Track"),
+    (0x0B7, "Stop"),
+    (0x0B8, "Eject"),
+    (0x0CD, "Play/Pause"),
+    (0x0E0, "Volume"),
+    (0x0E2, "Mute"),
+    (0x0E9, "Volume Increment"),
+    (0x0EA, "Volume Decrement"),
+    (0x183, "AL Consumer Control Configuration"),
+    (0x18A, "AL Email Reader"),
+    (0x192, "AL Calculator"),
+    (0x194, "AL Local Machine Browser"),
+    (0x221, "AC Search"),
+    (0x223, "AC Home"),
+    (0x224, "AC Back"),
+    (0x225, "AC Forward"),
+    (0x226, "AC Stop"),
+    (0x227, "AC Refresh"),
+    (0x22A, "AC Bookmarks"),
+    (0x238, "AC Pan"),
+];
+
+const DIGITIZER_USAGES: &[(u16, &str)] = &[
+    (0x01, "Digitizer"),
+    (0x02, "Pen"),
+    (0x03, "Light Pen"),
+    (0x04, "Touch Screen"),
+    (0x05, "Touch Pad"),
+    (0x0E, "Device Configuration"),
+    (0x20, "Stylus"),
+    (0x22, "Finger"),
+    (0x23, "Device Settings"),
+    (0x30, "Tip Pressure"),
+    (0x31, "Barrel Pressure"),
+    (0x32, "In Range"),
+    (0x33, "Touch"),
+    (0x34, "Untouch"),
+    (0x35, "Tap"),
+    (0x3D, "X Tilt"),
+    (0x3E, "Y Tilt"),
+    (0x42, "Tip Switch"),
+    (0x44, "Barrel Switch"),
+    (0x45, "Eraser"),
+    (0x47, "Confidence"),
+    (0x48, "Width"),
+    (0x49, "Height"),
+    (0x51, "Contact Identifier"),
+    (0x52, "Device Mode"),
+    (0x54, "Contact Count"),
+    (0x55, "Contact Count Maximum"),
+    (0x56, "Scan Time"),
+    (0x5B, "Transducer Serial Number"),
+];
+
+fn lookup(table: &[(u16, &'static str)], id: u16) -> Option<&'static str> {
+    table.iter().find(|(i, _)| *i == id).map(|(_, name)| *name)
+}
+
+fn is_vendor_page(page: u16) -> bool {
+    page >= 0xFF00
+}
+
+/// Name of a usage page, if known.
+pub fn page_name(page: u16) -> Option<&'static str> {
+    lookup(USAGE_PAGES, page)
+}
+
+/// Name of a usage page, or its number if not known.
+pub fn fmt_page(page: u16) -> String {
+    match page_name(page) {
+        Some(name) => name.to_string(),
+        None if is_vendor_page(page) =>
+            format!("Vendor-defined 0x{:04X}", page),
+        None => format!("Page 0x{:04X}", page),
+    }
+}
+
+/// Name of a usage within a page, if known.
+pub fn usage_name(page: u16, id: u16) -> Option<String> {
+    let name = match page {
+        GENERIC_DESKTOP => lookup(GENERIC_DESKTOP_USAGES, id),
+        KEYBOARD => match id {
+            0x00..=0x67 => Some(KEYBOARD_USAGES[id as usize]),
+            0x68..=0x73 => return Some(format!("Keyboard F{}", id - 0x68 + 13)),
+            0xE0..=0xE7 => Some(KEYBOARD_MODIFIERS[(id - 0xE0) as usize]),
+            _ => None,
+        },
+        LED => lookup(LED_USAGES, id),
+        BUTTON => return Some(match id {
+            0 => "No Button Pressed".to_string(),
+            n => format!("Button {}", n),
+        }),
+        ORDINAL => return Some(format!("Instance {}", id)),
+        CONSUMER => lookup(CONSUMER_USAGES, id),
+        DIGITIZERS => lookup(DIGITIZER_USAGES, id),
+        _ => None,
+    };
+    name.map(str::to_string)
+}
+
+/// Name of a usage within its page, or its number if not known.
+pub fn fmt_usage_name(page: u16, id: u16) -> String {
+    usage_name(page, id).unwrap_or_else(|| format!("0x{:04X}", id))
+}
+
+/// Full name of a usage, including its page, e.g. "Generic Desktop / X".
+pub fn fmt_usage(page: u16, id: u16) -> String {
+    format!("{} / {}", fmt_page(page), fmt_usage_name(page, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_names() {
+        assert!(fmt_usage(0x01, 0x30) == "Generic Desktop / X");
+        assert!(fmt_usage_name(0x07, 0x04) == "Keyboard a and A");
+        assert!(fmt_usage_name(0x07, 0x27) == "Keyboard 0 and )");
+        assert!(fmt_usage_name(0x07, 0x67) == "Keypad =");
+        assert!(fmt_usage_name(0x07, 0x73) == "Keyboard F24");
+        assert!(fmt_usage_name(0x07, 0xE1) == "Keyboard LeftShift");
+        assert!(fmt_usage_name(0x09, 3) == "Button 3");
+        assert!(fmt_usage_name(0x0C, 0x238) == "AC Pan");
+        assert!(fmt_usage(0x01, 0xFF) == "Generic Desktop / 0x00FF");
+        assert!(fmt_usage(0xFF00, 0x01) == "Vendor-defined 0xFF00 / 0x0001");
+        assert!(fmt_usage(0x00AB, 0x01) == "Page 0x00AB / 0x0001");
+    }
+}
@@ -3,12 +3,15 @@ use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::ops::Range;
 use std::cmp::{min, max};
+use std::mem::size_of;
 
 use bufreaderwriter::BufReaderWriter;
-use tempfile::tempfile;
 use thiserror::Error;
 use bisection::bisect_right;
 
+use crate::storage::{
+    CaptureStorage, copy_contents, read_exact_at, read_u64, write_all_at};
+
 #[derive(Error, Debug)]
 pub enum HybridIndexError {
     #[error(transparent)]
@@ -16,20 +19,64 @@ pub enum HybridIndexError {
 }
 
 bitfield! {
+    #[derive(Copy, Clone)]
     pub struct IncrementFields(u64);
     u64, count, set_count: 59, 0;
     u8, width, set_width: 63, 60;
 }
 
+#[derive(Clone)]
 struct Entry {
     base_value: u64,
     file_offset: u64,
     increments: IncrementFields,
 }
 
+/// Bytes taken by an entry and its start in the index when written out.
+const ENTRY_BYTES: u64 = 4 * size_of::<u64>() as u64;
+
+/// Write entries, each with its start in the index.
+fn write_entries(entries: &[Entry], index: &[u64], output: &mut dyn Write)
+    -> std::io::Result<()>
+{
+    for (entry, start) in entries.iter().zip(index) {
+        for value in [entry.base_value, entry.file_offset,
+                      entry.increments.0, *start]
+        {
+            output.write_all(&value.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Read back entries written by `write_entries`.
+fn read_entries(input: &mut dyn Read, count: u64)
+    -> std::io::Result<(Vec<Entry>, Vec<u64>)>
+{
+    let mut entries = Vec::new();
+    let mut index = Vec::new();
+    for _ in 0..count {
+        entries.push(Entry {
+            base_value: read_u64(input)?,
+            file_offset: read_u64(input)?,
+            increments: IncrementFields(read_u64(input)?),
+        });
+        index.push(read_u64(input)?);
+    }
+    Ok((entries, index))
+}
+
+/// An index's file, and where its entries are held.
+enum IndexFile {
+    /// Buffered, with the entries in memory.
+    Active(BufReaderWriter<File>),
+    /// Unbuffered, with this many entries written after the increments.
+    Parked(File, u64),
+}
+
 pub struct HybridIndex {
     min_width: u8,
-    file: BufReaderWriter<File>,
+    file: IndexFile,
     file_length: u64,
     total_count: u64,
     entries: Vec<Entry>,
@@ -40,10 +87,18 @@ pub struct HybridIndex {
 
 impl HybridIndex {
     pub fn new(min_width: u8) -> Result<Self, HybridIndexError> {
-        let file = tempfile()?;
+        HybridIndex::new_in(&CaptureStorage::default(), "", min_width)
+    }
+
+    /// Create an index in a capture's storage, with a file named for its
+    /// purpose.
+    pub fn new_in(storage: &CaptureStorage, purpose: &str, min_width: u8)
+        -> Result<Self, HybridIndexError>
+    {
+        let file = storage.create_file(purpose)?;
         Ok(Self{
             min_width: min_width,
-            file: BufReaderWriter::new_writer(file),
+            file: IndexFile::Active(BufReaderWriter::new_writer(file)),
             file_length: 0,
             total_count: 0,
             entries: Vec::new(),
@@ -54,6 +109,38 @@ impl HybridIndex {
     }
 
     pub fn push(&mut self, value: u64) -> Result<(), HybridIndexError> {
+        profile!("HybridIndex::push");
+        self.unpark()?;
+        if let Some((bytes, width)) = self.encode(value, self.file_length) {
+            self.write(&bytes[0..width])?;
+        }
+        Ok(())
+    }
+
+    /// Push a batch of values, writing their encoding in one operation.
+    pub fn push_slice(&mut self, values: &[u64])
+        -> Result<(), HybridIndexError>
+    {
+        profile!("HybridIndex::push_slice");
+        self.unpark()?;
+        let mut buffer = Vec::with_capacity(
+            values.len() * self.min_width as usize);
+        for &value in values {
+            let file_offset = self.file_length + buffer.len() as u64;
+            if let Some((bytes, width)) = self.encode(value, file_offset) {
+                buffer.extend_from_slice(&bytes[0..width]);
+            }
+        }
+        self.write(&buffer)
+    }
+
+    /// Add a value to the entries, returning any increment bytes which
+    /// must then be written at `file_offset`, and their width.
+    fn encode(&mut self, value: u64, file_offset: u64)
+        -> Option<([u8; 8], usize)>
+    {
+        self.total_count += 1;
+        self.last_value = value;
         if self.entries.len() == 0 {
             let first_entry = Entry {
                 base_value: value,
@@ -62,90 +149,61 @@ impl HybridIndex {
             };
             self.entries.push(first_entry);
             self.index.push(0);
+            return None;
+        }
+        let last_entry = self.entries.last_mut().unwrap();
+        let increment = value - last_entry.base_value;
+        let width = max(byte_width(increment), self.min_width);
+        let count = last_entry.increments.count();
+        if count > 0 && width > last_entry.increments.width() {
+            let new_entry = Entry {
+                base_value: value,
+                file_offset: file_offset,
+                increments: IncrementFields(0),
+            };
+            self.entries.push(new_entry);
+            self.index.push(self.total_count - 1);
+            None
         } else {
-            let last_entry = self.entries.last_mut().unwrap();
-            let increment = value - last_entry.base_value;
-            let width = max(byte_width(increment), self.min_width);
-            let count = last_entry.increments.count();
-            if count > 0 && width > last_entry.increments.width() {
-                let new_entry = Entry {
-                    base_value: value,
-                    file_offset: self.file_length,
-                    increments: IncrementFields(0),
-                };
-                self.entries.push(new_entry);
-                self.index.push(self.total_count);
-            } else {
-                if last_entry.increments.width() == 0 {
-                    last_entry.increments.set_width(width);
-                }
-                let bytes = increment.to_le_bytes();
-                if !self.at_end {
-                   self.file.seek(SeekFrom::Start(self.file_length))?;
-                   self.at_end = true;
-                }
-                self.file.write_all(&bytes[0..width as usize])?;
-                self.file_length += width as u64;
-                last_entry.increments.set_count(count + 1);
+            if last_entry.increments.width() == 0 {
+                last_entry.increments.set_width(width);
             }
+            last_entry.increments.set_count(count + 1);
+            Some((increment.to_le_bytes(), width as usize))
         }
-        self.total_count += 1;
-        self.last_value = value;
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), HybridIndexError> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let file_length = self.file_length;
+        if !self.at_end {
+           self.file().seek(SeekFrom::Start(file_length))?;
+           self.at_end = true;
+        }
+        self.file().write_all(bytes)?;
+        self.file_length += bytes.len() as u64;
         Ok(())
     }
 
     pub fn get(&mut self, i: u64) -> Result<u64, HybridIndexError> {
-        let entry_id = bisect_right(self.index.as_slice(), &i) - 1;
-        let entry = &self.entries[entry_id];
-        let increment_id = i - self.index[entry_id];
-        if increment_id == 0 {
-            Ok(entry.base_value)
-        } else {
-            let width = entry.increments.width();
-            let start = entry.file_offset + (increment_id - 1) * width as u64;
-            let mut bytes = [0 as u8; 8];
-            self.file.seek(SeekFrom::Start(start))?;
-            self.at_end = false;
-            self.file.read_exact(&mut bytes[0..width as usize])?;
-            let increment = u64::from_le_bytes(bytes);
-            let value = entry.base_value + increment;
-            Ok(value)
-        }
+        profile!("HybridIndex::get");
+        Ok(self.get_range(i..(i + 1))?[0])
     }
 
     pub fn get_range(&mut self, range: Range<u64>) -> Result<Vec<u64>, HybridIndexError> {
-        let mut result = Vec::new();
-        let mut i = range.start;
-        while i < range.end {
-            let entry_id = bisect_right(self.index.as_slice(), &i) - 1;
-            let entry = &self.entries[entry_id];
-            let mut increment_id = i - self.index[entry_id];
-            if increment_id == 0 {
-                result.push(entry.base_value);
-                i += 1;
-            } else {
-                increment_id -= 1;
-            }
-            let available = entry.increments.count() - increment_id;
-            let needed = range.end - i;
-            let read_count = min(available, needed);
-            if read_count == 0 {
-                continue;
-            }
-            let width = entry.increments.width();
-            let start = entry.file_offset + increment_id * width as u64;
-            self.file.seek(SeekFrom::Start(start))?;
-            self.at_end = false;
-            let mut bytes = [0 as u8; 8];
-            for _ in 0..read_count {
-                self.file.read_exact(&mut bytes[0..width as usize])?;
-                let increment = u64::from_le_bytes(bytes);
-                let value = entry.base_value + increment;
-                result.push(value);
-            }
-            i += read_count;
-        }
-        Ok(result)
+        profile!("HybridIndex::get_range");
+        self.unpark()?;
+        let file = match &mut self.file {
+            IndexFile::Active(file) => file,
+            IndexFile::Parked(..) => unreachable!("index was just unparked"),
+        };
+        self.at_end = false;
+        read_values(&self.entries, &self.index, range, |buffer, start| {
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(buffer)
+        })
     }
 
     pub fn len(&self) -> u64 {
@@ -153,14 +211,198 @@ impl HybridIndex {
     }
 
     pub fn entry_count(&self) -> u64 {
-        self.entries.len() as u64
+        match self.file {
+            IndexFile::Active(_) => self.entries.len() as u64,
+            IndexFile::Parked(_, count) => count,
+        }
     }
 
     pub fn size(&self) -> u64 {
        self.file_length +
-           self.entries.len() as u64 * std::mem::size_of::<Entry>() as u64 +
-           self.index.len() as u64 * std::mem::size_of::<u64>() as u64
+           self.entry_count() * size_of::<Entry>() as u64 +
+           self.entry_count() * size_of::<u64>() as u64
+    }
+
+    /// Whether the index is parked, holding nothing in memory.
+    pub fn is_parked(&self) -> bool {
+        matches!(self.file, IndexFile::Parked(..))
+    }
+
+    /// Release the memory held by the index, its file's buffer and its
+    /// entries, by writing the entries to its file after the increments.
+    /// They are read back when the index is next used.
+    pub fn park(&mut self) -> Result<(), HybridIndexError> {
+        let file = match &mut self.file {
+            IndexFile::Active(file) => {
+                file.flush()?;
+                file.get_ref().try_clone()?
+            },
+            IndexFile::Parked(..) => return Ok(()),
+        };
+        let mut bytes = Vec::with_capacity(
+            self.entries.len() * ENTRY_BYTES as usize);
+        write_entries(&self.entries, &self.index, &mut bytes)?;
+        write_all_at(&file, &bytes, self.file_length)?;
+        let count = self.entries.len() as u64;
+        self.entries = Vec::new();
+        self.index = Vec::new();
+        self.file = IndexFile::Parked(file, count);
+        Ok(())
+    }
+
+    /// Read back the entries of a parked index, and buffer its file again.
+    fn unpark(&mut self) -> Result<(), HybridIndexError> {
+        let (file, count) = match &self.file {
+            IndexFile::Active(_) => return Ok(()),
+            IndexFile::Parked(file, count) => (file, *count),
+        };
+        let mut bytes = vec![0; (count * ENTRY_BYTES) as usize];
+        read_exact_at(file, &mut bytes, self.file_length)?;
+        (self.entries, self.index) =
+            read_entries(&mut bytes.as_slice(), count)?;
+        file.set_len(self.file_length)?;
+        let file = file.try_clone()?;
+        self.file = IndexFile::Active(BufReaderWriter::new_writer(file));
+        // The file offset is wherever parking left it.
+        self.at_end = false;
+        Ok(())
     }
+
+    /// The buffered file of an index which is not parked.
+    fn file(&mut self) -> &mut BufReaderWriter<File> {
+        match &mut self.file {
+            IndexFile::Active(file) => file,
+            IndexFile::Parked(..) => panic!("HybridIndex used while parked"),
+        }
+    }
+
+    /// Write the index to `output`, in a form which can be read back by
+    /// `HybridIndex::load`.
+    pub fn save(&mut self, output: &mut dyn Write)
+        -> Result<(), HybridIndexError>
+    {
+        self.unpark()?;
+        self.file().flush()?;
+        output.write_all(&[self.min_width])?;
+        for value in [self.total_count, self.last_value,
+                      self.entries.len() as u64, self.file_length]
+        {
+            output.write_all(&value.to_le_bytes())?;
+        }
+        write_entries(&self.entries, &self.index, output)?;
+        let file_length = self.file_length;
+        copy_contents(self.file().get_ref(), file_length, output)?;
+        Ok(())
+    }
+
+    /// Load an index saved by `HybridIndex::save` into a new index.
+    pub fn load(input: &mut dyn Read) -> Result<Self, HybridIndexError> {
+        HybridIndex::load_in(&CaptureStorage::default(), "", input)
+    }
+
+    /// Load an index saved by `HybridIndex::save` into a new index in a
+    /// capture's storage, with a file named for its purpose.
+    pub fn load_in(storage: &CaptureStorage, purpose: &str,
+                   input: &mut dyn Read) -> Result<Self, HybridIndexError>
+    {
+        let mut min_width = [0];
+        input.read_exact(&mut min_width)?;
+        let total_count = read_u64(input)?;
+        let last_value = read_u64(input)?;
+        let entry_count = read_u64(input)?;
+        let file_length = read_u64(input)?;
+        let (entries, index) = read_entries(input, entry_count)?;
+        let file = storage.create_file_from(purpose, input, file_length)?;
+        Ok(Self{
+            min_width: min_width[0],
+            file: IndexFile::Active(BufReaderWriter::new_writer(file)),
+            file_length: file_length,
+            total_count: total_count,
+            entries: entries,
+            index: index,
+            last_value: last_value,
+            at_end: true,
+        })
+    }
+
+    /// Create a read-only handle to the values currently stored, which can
+    /// be used independently of this index, including from other threads.
+    pub fn reader(&mut self) -> Result<HybridIndexReader, HybridIndexError> {
+        self.unpark()?;
+        self.file().flush()?;
+        Ok(HybridIndexReader {
+            file: self.file().get_ref().try_clone()?,
+            total_count: self.total_count,
+            entries: self.entries.clone(),
+            index: self.index.clone(),
+        })
+    }
+}
+
+/// Read-only handle to a snapshot of a `HybridIndex`.
+///
+/// Reads are positional, so on Unix they do not disturb the file offset
+/// that is shared with the original index. On Windows they move it, so
+/// the original should not be used while a reader is in use.
+pub struct HybridIndexReader {
+    file: File,
+    total_count: u64,
+    entries: Vec<Entry>,
+    index: Vec<u64>,
+}
+
+impl HybridIndexReader {
+    pub fn get(&self, i: u64) -> Result<u64, HybridIndexError> {
+        Ok(self.get_range(i..(i + 1))?[0])
+    }
+
+    pub fn get_range(&self, range: Range<u64>) -> Result<Vec<u64>, HybridIndexError> {
+        read_values(&self.entries, &self.index, range, |buffer, start| {
+            read_exact_at(&self.file, buffer, start)
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.total_count
+    }
+}
+
+/// Look up a range of values in an index's entries, using `read` to fill
+/// a buffer with the increments stored from a given file offset.
+fn read_values<F>(entries: &[Entry], index: &[u64], range: Range<u64>,
+                  mut read: F) -> Result<Vec<u64>, HybridIndexError>
+    where F: FnMut(&mut [u8], u64) -> std::io::Result<()>
+{
+    let mut result = Vec::new();
+    let mut i = range.start;
+    while i < range.end {
+        let entry_id = bisect_right(index, &i) - 1;
+        let entry = &entries[entry_id];
+        let mut increment_id = i - index[entry_id];
+        if increment_id == 0 {
+            result.push(entry.base_value);
+            i += 1;
+        } else {
+            increment_id -= 1;
+        }
+        let available = entry.increments.count() - increment_id;
+        let needed = range.end - i;
+        let read_count = min(available, needed);
+        if read_count == 0 {
+            continue;
+        }
+        let width = entry.increments.width() as usize;
+        let start = entry.file_offset + increment_id * width as u64;
+        let mut buffer = vec![0 as u8; read_count as usize * width];
+        read(&mut buffer, start)?;
+        for increment_bytes in buffer.chunks(width) {
+            let mut bytes = [0 as u8; 8];
+            bytes[0..width].copy_from_slice(increment_bytes);
+            result.push(entry.base_value + u64::from_le_bytes(bytes));
+        }
+        i += read_count;
+    }
+    Ok(result)
 }
 
 fn byte_width(value: u64) -> u8 {
@@ -226,4 +468,99 @@ mod tests {
             assert!(vr == xr);
         }
     }
+
+    #[test]
+    fn test_push_slice() {
+        let mut single = HybridIndex::new(1).unwrap();
+        let mut batched = HybridIndex::new(1).unwrap();
+        let mut values = Vec::<u64>::new();
+        let mut x = 5;
+        for i in 0..1000 {
+            // Include jumps which force new entries to be started.
+            x += if i % 100 == 99 { 0x12345 } else { i % 7 };
+            values.push(x);
+            single.push(x).unwrap();
+        }
+        for chunk in values.chunks(37) {
+            batched.push_slice(chunk).unwrap();
+        }
+        batched.push_slice(&[]).unwrap();
+        assert!(batched.len() == single.len());
+        assert!(batched.entry_count() == single.entry_count());
+        assert!(batched.size() == single.size());
+        let n = values.len() as u64;
+        assert!(batched.get_range(0..n).unwrap() == values);
+        for i in 0..n {
+            assert!(batched.get(i).unwrap() == values[i as usize]);
+        }
+    }
+
+    #[test]
+    fn test_hybrid_index_save_load() {
+        let mut v = HybridIndex::new(1).unwrap();
+        let mut values: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        v.push_slice(&values).unwrap();
+        v.get(500).unwrap();
+        let mut saved = Vec::new();
+        v.save(&mut saved).unwrap();
+        let mut loaded = HybridIndex::load(&mut saved.as_slice()).unwrap();
+        assert!(loaded.len() == v.len());
+        assert!(loaded.entry_count() == v.entry_count());
+        assert!(loaded.get_range(0..1000).unwrap() == values);
+        // The loaded copy continues from where the original left off.
+        values.push(1000 * 1000);
+        v.push(1000 * 1000).unwrap();
+        loaded.push(1000 * 1000).unwrap();
+        assert!(loaded.size() == v.size());
+        assert!(loaded.get_range(0..1001).unwrap() == values);
+    }
+
+    #[test]
+    fn test_park() {
+        let mut v = HybridIndex::new(1).unwrap();
+        let mut values: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        v.push_slice(&values).unwrap();
+        let (entry_count, size) = (v.entry_count(), v.size());
+        v.park().unwrap();
+        assert!(v.is_parked());
+        assert!(v.entries.is_empty());
+        assert!(v.len() == 1000);
+        assert!(v.entry_count() == entry_count);
+        assert!(v.size() == size);
+        // The index is read back when used, and continues where it was.
+        assert!(v.get(999).unwrap() == 999 * 999);
+        assert!(!v.is_parked());
+        v.park().unwrap();
+        values.push(1000 * 1000);
+        v.push(1000 * 1000).unwrap();
+        assert!(v.get_range(0..1001).unwrap() == values);
+        assert!(v.size() > size);
+        v.park().unwrap();
+        let mut saved = Vec::new();
+        v.save(&mut saved).unwrap();
+        let mut loaded = HybridIndex::load(&mut saved.as_slice()).unwrap();
+        assert!(loaded.get_range(0..1001).unwrap() == values);
+    }
+
+    #[test]
+    fn test_hybrid_index_reader() {
+        let mut v = HybridIndex::new(1).unwrap();
+        let expected: Vec<u64> = (0..1000).map(|i| i * i).collect();
+        v.push_slice(&expected).unwrap();
+        let reader = v.reader().unwrap();
+        v.push(1000 * 1000).unwrap();
+        let values = expected.clone();
+        let thread = std::thread::spawn(move || {
+            assert!(reader.len() == 1000);
+            for i in 0..1000 {
+                assert!(reader.get(i).unwrap() == values[i as usize]);
+            }
+            assert!(reader.get_range(0..1000).unwrap() == values);
+            assert!(reader.get_range(500..510).unwrap() == &values[500..510]);
+        });
+        thread.join().unwrap();
+        assert!(v.len() == 1001);
+        assert!(v.get(1000).unwrap() == 1000 * 1000);
+        assert!(v.get_range(0..1000).unwrap() == expected);
+    }
 }
@@ -0,0 +1,1098 @@
+//! Reading packets from capture files into the decoder.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+use flate2::read::MultiGzDecoder;
+use ruzstd::decoding::StreamingDecoder;
+use tempfile::NamedTempFile;
+use thiserror::Error;
+
+use crate::beagle::{is_beagle_export, is_comment, BeagleImporter};
+use crate::decoder::{Decoder, DecoderError};
+use crate::hexdump::{is_hex_dump, parse_packets, HexDumpError};
+use crate::project;
+use crate::storage;
+use crate::trigger::Trigger;
+use crate::urb::RecordImporter;
+use crate::usb::Speed;
+use crate::usbmon::{is_text_capture, UsbmonImporter};
+use crate::usbpcap::UsbpcapImporter;
+
+/// Link type of captures of the packets on the bus.
+const LINKTYPE_USB_2_0: i32 = 288;
+
+/// Link types of captures of the packets on a bus of a known speed.
+const LINKTYPE_USB_2_0_LOW_SPEED: i32 = 293;
+const LINKTYPE_USB_2_0_FULL_SPEED: i32 = 294;
+const LINKTYPE_USB_2_0_HIGH_SPEED: i32 = 295;
+
+#[derive(Error, Debug)]
+pub enum FileError {
+    #[error(transparent)]
+    PcapError(#[from] pcap::Error),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error("unsupported file format, expected a pcap, pcapng or usbmon \
+             text capture, a Total Phase CSV export or a hex dump")]
+    UnsupportedFormat,
+    #[error("unsupported link type {0}, expected USB packets (288, \
+             293-295), usbmon (189, 220) or USBPcap (249)")]
+    UnsupportedLinktype(i32),
+    #[error("a project can only be opened on its own")]
+    Project,
+    #[error("text captures cannot be merged")]
+    TextMerge,
+    #[error("text captures cannot be recorded to pcap")]
+    TextRecord,
+    #[error(transparent)]
+    HexDumpError(#[from] HexDumpError),
+    #[error(transparent)]
+    DecoderError(#[from] DecoderError),
+}
+
+#[derive(Error, Debug)]
+#[error("failed to read {filename}: {error}")]
+pub struct InputError {
+    pub filename: String,
+    pub error: FileError,
+}
+
+/// Formats of file which can be opened, as found from their first bytes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FileFormat {
+    Pcap,
+    Pcapng,
+    UsbmonText,
+    BeagleCsv,
+    HexDump,
+    Project,
+}
+
+/// Magic numbers of pcap files, in both byte orders and with microsecond
+/// or nanosecond timestamps.
+const PCAP_MAGICS: [[u8; 4]; 4] = [
+    [0xD4, 0xC3, 0xB2, 0xA1],
+    [0xA1, 0xB2, 0xC3, 0xD4],
+    [0x4D, 0x3C, 0xB2, 0xA1],
+    [0xA1, 0xB2, 0x3C, 0x4D],
+];
+
+/// Block type of the section header block which starts a pcapng file.
+const PCAPNG_MAGIC: [u8; 4] = [0x0A, 0x0D, 0x0D, 0x0A];
+
+/// Magic numbers of compressed files, which are decompressed when read.
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// A file to be read, by way of a decompressed copy if it was compressed.
+struct InputFile {
+    path: String,
+    /// Decompressed copy of the file, which is removed when dropped.
+    _decompressed: Option<NamedTempFile>,
+}
+
+/// Prepare a file for reading, decompressing it first if it was compressed
+/// with gzip or zstd.
+///
+/// The file is decompressed in full to a storage file, because libpcap and
+/// the text importers read files by name.
+fn open_input(filename: &str) -> Result<InputFile, FileError> {
+    let plain = InputFile {
+        path: filename.to_string(),
+        _decompressed: None,
+    };
+    if filename == STDIN_FILENAME {
+        return Ok(plain);
+    }
+    let mut magic = Vec::new();
+    File::open(filename)?.take(4).read_to_end(&mut magic)?;
+    let file = BufReader::new(File::open(filename)?);
+    let mut input: Box<dyn Read> = if magic.starts_with(&GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(file))
+    } else if magic == ZSTD_MAGIC {
+        Box::new(StreamingDecoder::new(file)
+            .map_err(std::io::Error::other)?)
+    } else {
+        return Ok(plain);
+    };
+    let mut decompressed = storage::create_named_file()?;
+    std::io::copy(&mut input, &mut decompressed)?;
+    Ok(InputFile {
+        path: decompressed.path().to_string_lossy().into_owned(),
+        _decompressed: Some(decompressed),
+    })
+}
+
+/// Find the format of a file from its first bytes, if it is supported.
+pub fn detect_format(path: &str) -> std::io::Result<Option<FileFormat>> {
+    let mut magic = Vec::new();
+    File::open(path)?.take(8).read_to_end(&mut magic)?;
+    let prefix: Option<[u8; 4]> =
+        magic.get(0..4).map(|prefix| prefix.try_into().unwrap());
+    Ok(match prefix {
+        _ if magic == project::MAGIC => Some(FileFormat::Project),
+        Some(prefix) if PCAP_MAGICS.contains(&prefix) =>
+            Some(FileFormat::Pcap),
+        Some(PCAPNG_MAGIC) => Some(FileFormat::Pcapng),
+        _ if is_text_capture(path)? => Some(FileFormat::UsbmonText),
+        _ if is_beagle_export(path)? => Some(FileFormat::BeagleCsv),
+        _ if is_hex_dump(path)? => Some(FileFormat::HexDump),
+        _ => None,
+    })
+}
+
+/// How much of the end of a capture to keep, for long captures of which
+/// only the most recent traffic is of interest.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Retention {
+    /// Keep the last packets adding up to this many bytes.
+    Bytes(u64),
+    /// Keep the packets timestamped within this many seconds of the last.
+    Seconds(i64),
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("invalid limit '{0}', expected a number of megabytes or minutes, \
+         e.g. 100MB or 30min")]
+pub struct RetentionError(String);
+
+impl FromStr for Retention {
+    type Err = RetentionError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let error = || RetentionError(text.to_string());
+        let (count, scale) = if let Some(count) = text.strip_suffix("MB") {
+            (count, Retention::Bytes(1 << 20))
+        } else if let Some(count) = text.strip_suffix("min") {
+            (count, Retention::Seconds(60))
+        } else {
+            return Err(error());
+        };
+        let count: u32 = count.parse().map_err(|_| error())?;
+        match scale {
+            _ if count == 0 => Err(error()),
+            Retention::Bytes(size) => Ok(Retention::Bytes(count as u64 * size)),
+            Retention::Seconds(secs) =>
+                Ok(Retention::Seconds(count as i64 * secs)),
+        }
+    }
+}
+
+/// Packets in a file were timestamped earlier than those before them.
+#[derive(Debug, PartialEq)]
+pub struct TimestampWarning {
+    pub filename: String,
+    /// Index of the first out-of-order packet within the file.
+    pub packet: u64,
+    /// Total number of out-of-order packets in the file.
+    pub count: u64,
+}
+
+impl fmt::Display for TimestampWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} packet(s) out of timestamp order, first at packet {}",
+               self.filename, self.count, self.packet)
+    }
+}
+
+/// Filename which reads a capture from standard input.
+pub const STDIN_FILENAME: &str = "-";
+
+/// Find the importer for a host-side capture with the given link type, if
+/// it is one. Returns `None` for captures of USB packets, which need no
+/// importer, and an error for any other link type.
+fn record_importer(linktype: pcap::Linktype)
+    -> Result<Option<Box<dyn RecordImporter>>, FileError>
+{
+    if let Some(importer) = UsbmonImporter::new(linktype) {
+        return Ok(Some(Box::new(importer)));
+    }
+    if let Some(importer) = UsbpcapImporter::new(linktype) {
+        return Ok(Some(Box::new(importer)));
+    }
+    match linktype.0 {
+        LINKTYPE_USB_2_0 |
+        LINKTYPE_USB_2_0_LOW_SPEED |
+        LINKTYPE_USB_2_0_FULL_SPEED |
+        LINKTYPE_USB_2_0_HIGH_SPEED => Ok(None),
+        other => Err(FileError::UnsupportedLinktype(other)),
+    }
+}
+
+/// Speed of the bus captured, if the link type records it.
+fn linktype_speed(linktype: pcap::Linktype) -> Option<Speed> {
+    match linktype.0 {
+        LINKTYPE_USB_2_0_LOW_SPEED => Some(Speed::Low),
+        LINKTYPE_USB_2_0_FULL_SPEED => Some(Speed::Full),
+        LINKTYPE_USB_2_0_HIGH_SPEED => Some(Speed::High),
+        _ => None,
+    }
+}
+
+/// Find the format of a file to be decoded. Standard input is read by
+/// libpcap, so must be a pcap or pcapng stream.
+fn input_format(filename: &str) -> Result<FileFormat, FileError> {
+    if filename == STDIN_FILENAME {
+        return Ok(FileFormat::Pcap);
+    }
+    match detect_format(filename)? {
+        Some(FileFormat::Project) => Err(FileError::Project),
+        Some(format) => Ok(format),
+        None => Err(FileError::UnsupportedFormat),
+    }
+}
+
+/// Timestamp of a packet, as seconds and nanoseconds.
+type Timestamp = (i64, i64);
+
+/// The timestamp of a packet read through [open_pcap], whose timeval holds
+/// nanoseconds in place of microseconds.
+fn timestamp(packet: &pcap::Packet) -> Timestamp {
+    let ts = packet.header.ts;
+    (ts.tv_sec as i64, ts.tv_usec as i64)
+}
+
+/// A timestamp as nanoseconds since the Unix epoch, as the capture keeps
+/// them.
+fn nanos(timestamp: Option<Timestamp>) -> Option<i64> {
+    timestamp.map(|(secs, nanos)| secs * 1_000_000_000 + nanos)
+}
+
+/// Open a pcap or pcapng file, or standard input, with timestamps given
+/// in nanoseconds whatever the resolution of the file.
+fn open_pcap(path: &str) -> Result<pcap::Capture<pcap::Offline>, pcap::Error>
+{
+    pcap::Capture::from_file_with_precision(path, pcap::Precision::Nano)
+}
+
+/// Destination of the packets read from files.
+trait PacketSink {
+    /// Set the speed of the bus the packets were captured from.
+    fn set_speed(&mut self, speed: Speed);
+
+    /// Take a packet, with its timestamp if the file has them.
+    fn packet(&mut self, timestamp: Option<Timestamp>, packet: &[u8])
+        -> Result<(), FileError>;
+}
+
+impl PacketSink for Decoder<'_> {
+    fn set_speed(&mut self, speed: Speed) {
+        Decoder::set_speed(self, speed);
+    }
+
+    fn packet(&mut self, timestamp: Option<Timestamp>, packet: &[u8])
+        -> Result<(), FileError>
+    {
+        Ok(self.handle_raw_packet_at(packet, nanos(timestamp))?)
+    }
+}
+
+/// Size of the header spooled before each packet by a [RollingBuffer]:
+/// the seconds and nanoseconds of its timestamp, with seconds of
+/// `i64::MIN` if it had none, then its length.
+const SPOOL_HEADER: usize = 24;
+
+/// Number of bytes read at once from the oldest end of a spool.
+const SPOOL_READ: usize = 0x10000;
+
+/// Spooled bytes which must have been dropped before the spool is
+/// compacted.
+const SPOOL_COMPACT: u64 = 1 << 20;
+
+/// Holds the packets within a [Retention] limit, dropping older ones as
+/// newer ones arrive.
+///
+/// Packets are spooled to a storage file, each after a header, and only
+/// the range of the file still kept is held in memory, so memory use does
+/// not grow with the limit. A size limit covers the headers as well as
+/// the packets. The file is compacted once more of it has been dropped
+/// than is kept, so that it stays within twice the size of the packets
+/// kept.
+struct RollingBuffer {
+    retention: Retention,
+    speed: Option<Speed>,
+    spool: NamedTempFile,
+    writer: BufWriter<File>,
+    /// Handle to the spool with its own offset, for reading the oldest
+    /// packets while the writer appends.
+    reader: File,
+    /// Offset of the oldest packet kept.
+    start: u64,
+    /// Offset at which the next packet is written.
+    end: u64,
+    /// Bytes read from the spool at `head_offset`.
+    head: Vec<u8>,
+    head_offset: u64,
+    /// Number of packets kept.
+    count: u64,
+    dropped: u64,
+    /// The first error writing to or reading from the spool, after which
+    /// packets are ignored.
+    error: Option<std::io::Error>,
+}
+
+impl RollingBuffer {
+    fn new(retention: Retention) -> std::io::Result<RollingBuffer> {
+        let spool = storage::create_named_file()?;
+        Ok(RollingBuffer {
+            retention: retention,
+            speed: None,
+            writer: BufWriter::new(spool.reopen()?),
+            reader: spool.reopen()?,
+            spool: spool,
+            start: 0,
+            end: 0,
+            head: Vec::new(),
+            head_offset: 0,
+            count: 0,
+            dropped: 0,
+            error: None,
+        })
+    }
+
+    /// Read `length` bytes of the spool at `offset`, from those read ahead
+    /// if possible.
+    fn read_at(&mut self, offset: u64, length: usize)
+        -> std::io::Result<&[u8]>
+    {
+        let head_end = self.head_offset + self.head.len() as u64;
+        if offset < self.head_offset || offset + length as u64 > head_end {
+            let size = (self.end - offset).min(SPOOL_READ as u64) as usize;
+            let size = size.max(length);
+            let written = self.end - self.writer.buffer().len() as u64;
+            if offset + size as u64 > written {
+                self.writer.flush()?;
+            }
+            self.head.resize(size, 0);
+            self.reader.seek(SeekFrom::Start(offset))?;
+            self.reader.read_exact(&mut self.head)?;
+            self.head_offset = offset;
+        }
+        let start = (offset - self.head_offset) as usize;
+        Ok(&self.head[start..start + length])
+    }
+
+    /// The timestamp and spooled size of the oldest packet kept.
+    fn oldest(&mut self) -> std::io::Result<(Option<Timestamp>, u64)> {
+        let header = self.read_at(self.start, SPOOL_HEADER)?;
+        let field = |i: usize| i64::from_le_bytes(
+            header[i * 8..i * 8 + 8].try_into().unwrap());
+        let timestamp = match field(0) {
+            i64::MIN => None,
+            secs => Some((secs, field(1))),
+        };
+        Ok((timestamp, SPOOL_HEADER as u64 + field(2) as u64))
+    }
+
+    /// Whether the oldest packet is outside the limit. Packets without
+    /// timestamps are only dropped by a size limit.
+    fn expired(&mut self, latest: Option<Timestamp>)
+        -> std::io::Result<bool>
+    {
+        if self.count == 0 {
+            return Ok(false);
+        }
+        Ok(match (self.retention, latest) {
+            (Retention::Bytes(limit), _) => self.end - self.start > limit,
+            (Retention::Seconds(limit), Some(latest)) =>
+                match self.oldest()?.0 {
+                    Some(oldest) => (latest.0 - oldest.0) * 1_000_000_000 +
+                        (latest.1 - oldest.1) > limit * 1_000_000_000,
+                    None => false,
+                },
+            (Retention::Seconds(_), None) => false,
+        })
+    }
+
+    /// Move the packets kept to the start of a new spool.
+    fn compact(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        let spool = storage::create_named_file()?;
+        let mut writer = BufWriter::new(spool.reopen()?);
+        self.reader.seek(SeekFrom::Start(self.start))?;
+        std::io::copy(&mut (&self.reader).take(self.end - self.start),
+                      &mut writer)?;
+        self.end -= self.start;
+        self.start = 0;
+        self.head.clear();
+        self.head_offset = 0;
+        self.reader = spool.reopen()?;
+        self.writer = writer;
+        self.spool = spool;
+        Ok(())
+    }
+
+    fn push(&mut self, timestamp: Option<Timestamp>, packet: &[u8])
+        -> std::io::Result<()>
+    {
+        let (secs, nanos) = timestamp.unwrap_or((i64::MIN, 0));
+        self.writer.write_all(&secs.to_le_bytes())?;
+        self.writer.write_all(&nanos.to_le_bytes())?;
+        self.writer.write_all(&(packet.len() as u64).to_le_bytes())?;
+        self.writer.write_all(packet)?;
+        self.end += (SPOOL_HEADER + packet.len()) as u64;
+        self.count += 1;
+        while self.expired(timestamp)? {
+            let (_, size) = self.oldest()?;
+            self.start += size;
+            self.count -= 1;
+            self.dropped += 1;
+        }
+        if self.start >= SPOOL_COMPACT && self.start > self.end - self.start {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Pass the packets kept on to a sink, oldest first.
+    fn replay(&mut self, sink: &mut dyn PacketSink)
+        -> Result<(), FileError>
+    {
+        if let Some(speed) = self.speed {
+            sink.set_speed(speed);
+        }
+        self.writer.flush()?;
+        self.reader.seek(SeekFrom::Start(self.start))?;
+        let mut reader = BufReader::new(&self.reader);
+        let mut header = [0; SPOOL_HEADER];
+        let mut packet = Vec::new();
+        for _ in 0..self.count {
+            reader.read_exact(&mut header)?;
+            let field = |i: usize| i64::from_le_bytes(
+                header[i * 8..i * 8 + 8].try_into().unwrap());
+            let timestamp = match field(0) {
+                i64::MIN => None,
+                secs => Some((secs, field(1))),
+            };
+            packet.resize(field(2) as usize, 0);
+            reader.read_exact(&mut packet)?;
+            sink.packet(timestamp, &packet)?;
+        }
+        Ok(())
+    }
+}
+
+impl PacketSink for RollingBuffer {
+    fn set_speed(&mut self, speed: Speed) {
+        self.speed = Some(speed);
+    }
+
+    fn packet(&mut self, timestamp: Option<Timestamp>, packet: &[u8])
+        -> Result<(), FileError>
+    {
+        if self.error.is_none() {
+            if let Err(err) = self.push(timestamp, packet) {
+                self.error = Some(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Passes packets on to a decoder once a [Trigger] has matched, keeping
+/// only its history of packets until then.
+struct TriggeredSink<'t, 'd, 'c> {
+    trigger: &'t mut Trigger,
+    decoder: &'d mut Decoder<'c>,
+    history: VecDeque<(Option<Timestamp>, Vec<u8>)>,
+    /// Number of packets read before the match, or up to now if none.
+    position: u64,
+    triggered: bool,
+}
+
+impl PacketSink for TriggeredSink<'_, '_, '_> {
+    fn set_speed(&mut self, speed: Speed) {
+        self.decoder.set_speed(speed);
+    }
+
+    fn packet(&mut self, timestamp: Option<Timestamp>, packet: &[u8])
+        -> Result<(), FileError>
+    {
+        if self.triggered {
+            return self.decoder.packet(timestamp, packet);
+        }
+        if self.trigger.check(packet) {
+            self.triggered = true;
+            for (timestamp, packet) in self.history.drain(..) {
+                self.decoder.packet(timestamp, &packet)?;
+            }
+            return self.decoder.packet(timestamp, packet);
+        }
+        self.position += 1;
+        if self.trigger.history > 0 {
+            if self.history.len() == self.trigger.history {
+                self.history.pop_front();
+            }
+            self.history.push_back((timestamp, packet.to_vec()));
+        }
+        Ok(())
+    }
+}
+
+/// Decode a sequence of files from the point where a [Trigger] matches,
+/// along with the packets before it in the trigger's history.
+///
+/// This is for recording only the traffic of interest from a long
+/// stream, such as standard input. Returns the position of the packet
+/// which matched, or `None` if it never did, in which case nothing is
+/// decoded.
+pub fn decode_files_triggered<S: AsRef<str>>(decoder: &mut Decoder,
+                                             filenames: &[S],
+                                             trigger: &mut Trigger)
+    -> Result<(Vec<TimestampWarning>, Option<u64>), InputError>
+{
+    let mut sink = TriggeredSink {
+        trigger: trigger,
+        decoder: decoder,
+        history: VecDeque::new(),
+        position: 0,
+        triggered: false,
+    };
+    let warnings = read_files(&mut sink, filenames, None)?;
+    let position = if sink.triggered {
+        Some(sink.position)
+    } else {
+        None
+    };
+    Ok((warnings, position))
+}
+
+/// Decode a sequence of files as a single capture.
+///
+/// All files are fed through the same decoder, so transactions and transfers
+/// which are split across a file boundary are joined back together. Files
+/// captured on a host, with Linux usbmon or Windows USBPcap, are converted
+/// to packets as they are read.
+/// The timestamps of usbmon text captures and Total Phase exports are not
+/// checked. Files compressed with gzip or zstd, such as `.pcap.gz`, are
+/// decompressed before they are read.
+///
+/// A filename of [STDIN_FILENAME] reads a pcap stream from standard input,
+/// decoding each packet as it arrives, until the stream ends.
+pub fn decode_files<S: AsRef<str>>(decoder: &mut Decoder, filenames: &[S])
+    -> Result<Vec<TimestampWarning>, InputError>
+{
+    read_files(decoder, filenames, None)
+}
+
+/// Decode a sequence of files as [decode_files] does, while also writing
+/// the records read from them to a pcap file as they arrive.
+///
+/// This is for streams from standard input, so that a session is kept on
+/// disk even if Packetry stops before it can be saved. The file is flushed
+/// after each record, and has the link type of the input, so the input
+/// must be a single pcap or pcapng stream.
+pub fn decode_files_recording<S: AsRef<str>>(decoder: &mut Decoder,
+                                             filenames: &[S],
+                                             record: &str)
+    -> Result<Vec<TimestampWarning>, InputError>
+{
+    read_files(decoder, filenames, Some(record))
+}
+
+/// Decode only the end of a sequence of files, as given by a [Retention]
+/// limit, returning the number of packets dropped before it.
+///
+/// This is for long captures, such as soak tests streamed to standard
+/// input, which would otherwise fill the disk. Packets are spooled to a
+/// storage file until the input ends, dropping the oldest as the limit is
+/// passed, and only those kept are decoded. The capture therefore starts
+/// partway through the traffic, as if it had been started then.
+pub fn decode_files_keeping<S: AsRef<str>>(decoder: &mut Decoder,
+                                           filenames: &[S],
+                                           retention: Retention)
+    -> Result<(Vec<TimestampWarning>, u64), InputError>
+{
+    let mut buffer = RollingBuffer::new(retention)
+        .map_err(|err| spool_error("spool", err))?;
+    let warnings = read_files(&mut buffer, filenames, None)?;
+    let spool = buffer.spool.path().display().to_string();
+    if let Some(err) = buffer.error.take() {
+        return Err(spool_error(&spool, err));
+    }
+    buffer.replay(decoder).map_err(|err| spool_error(&spool, err))?;
+    Ok((warnings, buffer.dropped))
+}
+
+/// An error from the spool of a [RollingBuffer], or from decoding what
+/// was read back from it, given as one reading the spool.
+fn spool_error<E: Into<FileError>>(spool: &str, err: E) -> InputError {
+    InputError {
+        filename: spool.to_string(),
+        error: err.into(),
+    }
+}
+
+/// Read the packets of a sequence of files, in order, into a sink, and
+/// write their records to a pcap file if one is given to record to.
+fn read_files<S: AsRef<str>>(sink: &mut dyn PacketSink, filenames: &[S],
+                             record: Option<&str>)
+    -> Result<Vec<TimestampWarning>, InputError>
+{
+    let mut warnings = Vec::new();
+    let mut last_timestamp = (i64::MIN, i64::MIN);
+    for filename in filenames {
+        let filename = filename.as_ref();
+        let error = |error: FileError| InputError {
+            filename: filename.to_string(),
+            error: error,
+        };
+        let input = open_input(filename).map_err(error)?;
+        let format = input_format(&input.path).map_err(error)?;
+        let text = matches!(format,
+                            FileFormat::UsbmonText |
+                            FileFormat::BeagleCsv |
+                            FileFormat::HexDump);
+        if text && record.is_some() {
+            return Err(error(FileError::TextRecord));
+        }
+        if format == FileFormat::UsbmonText {
+            let text = std::fs::read_to_string(&input.path)
+                .map_err(|err| error(err.into()))?;
+            let mut importer = UsbmonImporter::text();
+            for line in text.lines() {
+                for packet in importer.text_packets(line) {
+                    sink.packet(None, &packet).map_err(error)?;
+                }
+            }
+            continue;
+        }
+        if format == FileFormat::BeagleCsv {
+            let text = std::fs::read_to_string(&input.path)
+                .map_err(|err| error(err.into()))?;
+            let mut lines = text.lines().filter(|line| !is_comment(line));
+            let importer = lines.next()
+                .and_then(BeagleImporter::new)
+                .ok_or_else(|| error(FileError::UnsupportedFormat))?;
+            for packet in lines.filter_map(|line| importer.packet(line)) {
+                sink.packet(None, &packet).map_err(error)?;
+            }
+            continue;
+        }
+        if format == FileFormat::HexDump {
+            let text = std::fs::read_to_string(&input.path)
+                .map_err(|err| error(err.into()))?;
+            let packets = parse_packets(&text)
+                .map_err(|err| error(err.into()))?;
+            for (timestamp, packet) in packets {
+                sink.packet(timestamp, &packet).map_err(error)?;
+            }
+            continue;
+        }
+        // libpcap reads standard input when given its filename.
+        let mut pcap = open_pcap(&input.path)
+            .map_err(|err| error(err.into()))?;
+        let mut importer = record_importer(pcap.get_datalink())
+            .map_err(error)?;
+        if let Some(speed) = linktype_speed(pcap.get_datalink()) {
+            sink.set_speed(speed);
+        }
+        let mut savefile = record
+            .map(|path| pcap.savefile(path))
+            .transpose()
+            .map_err(|err| error(err.into()))?;
+        let mut warning: Option<TimestampWarning> = None;
+        let mut packet_count = 0;
+        while let Ok(packet) = pcap.next() {
+            if let Some(savefile) = savefile.as_mut() {
+                savefile.write(&packet);
+                savefile.flush().map_err(|err| error(err.into()))?;
+            }
+            let timestamp = timestamp(&packet);
+            if timestamp < last_timestamp {
+                match warning.as_mut() {
+                    Some(warning) => warning.count += 1,
+                    None => warning = Some(TimestampWarning {
+                        filename: filename.to_string(),
+                        packet: packet_count,
+                        count: 1,
+                    }),
+                }
+            } else {
+                last_timestamp = timestamp;
+            }
+            match importer.as_mut() {
+                Some(importer) => {
+                    for packet in importer.packets(&packet) {
+                        sink.packet(Some(timestamp), &packet).map_err(error)?;
+                    }
+                },
+                None => sink.packet(Some(timestamp), &packet)
+                    .map_err(error)?,
+            }
+            packet_count += 1;
+        }
+        warnings.extend(warning);
+    }
+    Ok(warnings)
+}
+
+/// A file being merged, with the next packet to be taken from it.
+struct MergeSource {
+    _input: InputFile,
+    pcap: pcap::Capture<pcap::Offline>,
+    importer: Option<Box<dyn RecordImporter>>,
+    next: Option<((i64, i64), Vec<u8>)>,
+}
+
+impl MergeSource {
+    fn advance(&mut self) {
+        self.next = self.pcap.next().ok()
+            .map(|packet| (timestamp(&packet), packet.data.to_vec()));
+    }
+}
+
+/// Decode a set of files as a single capture, merging their packets in
+/// timestamp order.
+///
+/// This allows the files of a session which was split up to be given in
+/// any order. Packets with the same timestamp are taken from the file
+/// last read from, so that a file's packets stay together where they meet
+/// the next. Text captures cannot be merged, as their packets are not
+/// timestamped.
+pub fn merge_files<S: AsRef<str>>(decoder: &mut Decoder, filenames: &[S])
+    -> Result<(), InputError>
+{
+    let mut sources = Vec::new();
+    for filename in filenames {
+        let filename = filename.as_ref();
+        let error = |error: FileError| InputError {
+            filename: filename.to_string(),
+            error: error,
+        };
+        let input = open_input(filename).map_err(error)?;
+        match input_format(&input.path).map_err(error)? {
+            FileFormat::UsbmonText |
+            FileFormat::BeagleCsv |
+            FileFormat::HexDump =>
+                return Err(error(FileError::TextMerge)),
+            _ => {},
+        }
+        let pcap = open_pcap(&input.path)
+            .map_err(|err| error(err.into()))?;
+        if let Some(speed) = linktype_speed(pcap.get_datalink()) {
+            decoder.set_speed(speed);
+        }
+        let mut source = MergeSource {
+            _input: input,
+            importer: record_importer(pcap.get_datalink()).map_err(error)?,
+            pcap: pcap,
+            next: None,
+        };
+        source.advance();
+        sources.push(source);
+    }
+    let mut current = 0;
+    loop {
+        let earliest = sources.iter()
+            .enumerate()
+            .filter_map(|(i, source)| source.next.as_ref()
+                .map(|(timestamp, _)| ((*timestamp, i != current), i)))
+            .min();
+        current = match earliest {
+            Some((_, i)) => i,
+            None => break,
+        };
+        let source = &mut sources[current];
+        let (timestamp, packet) = source.next.take().unwrap();
+        let time = nanos(Some(timestamp));
+        let error = |error: DecoderError| InputError {
+            filename: filenames[current].as_ref().to_string(),
+            error: error.into(),
+        };
+        match source.importer.as_mut() {
+            Some(importer) => {
+                for packet in importer.packets(&packet) {
+                    decoder.handle_raw_packet_at(&packet, time)
+                        .map_err(error)?;
+                }
+            },
+            None => decoder.handle_raw_packet_at(&packet, time)
+                .map_err(error)?,
+        }
+        source.advance();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Capture;
+    use crate::verify::{check, compare};
+
+    fn decode(filenames: &[&str]) -> (Capture, Vec<TimestampWarning>) {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let warnings = decode_files(&mut decoder, filenames).unwrap();
+        drop(decoder);
+        (cap, warnings)
+    }
+
+    #[test]
+    fn test_split_files() {
+        let source = "./tests/mouse/capture.pcap";
+        let dir = tempfile::tempdir().unwrap();
+        let part1 = dir.path().join("part1.pcap");
+        let part2 = dir.path().join("part2.pcap");
+        {
+            let mut pcap = pcap::Capture::from_file(source).unwrap();
+            let mut file1 = pcap.savefile(&part1).unwrap();
+            let mut file2 = pcap.savefile(&part2).unwrap();
+            let mut count = 0;
+            while let Ok(packet) = pcap.next() {
+                // Split between the data and handshake of a transaction.
+                if count < 100 {
+                    file1.write(&packet);
+                } else {
+                    file2.write(&packet);
+                }
+                count += 1;
+            }
+        }
+        let part1 = part1.to_str().unwrap();
+        let part2 = part2.to_str().unwrap();
+
+        let (mut single, warnings) = decode(&[source]);
+        assert!(warnings.is_empty());
+        let (mut joined, warnings) = decode(&[part1, part2]);
+        assert!(warnings.is_empty());
+        check(&mut joined).unwrap();
+        compare(&mut single, &mut joined).unwrap();
+
+        let (_, warnings) = decode(&[part2, part1]);
+        assert!(warnings.len() == 1);
+        assert!(warnings[0].filename == part1);
+        assert!(warnings[0].packet == 0);
+        assert!(warnings[0].count == 100);
+
+        // Merging puts the parts back in order.
+        let mut merged = Capture::new();
+        let mut decoder = Decoder::new(&mut merged);
+        merge_files(&mut decoder, &[part2, part1]).unwrap();
+        drop(decoder);
+        check(&mut merged).unwrap();
+        compare(&mut single, &mut merged).unwrap();
+
+        let missing = dir.path().join("missing.pcap");
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let result = decode_files(&mut decoder, &[missing.to_str().unwrap()]);
+        assert!(result.unwrap_err().filename == missing.to_str().unwrap());
+    }
+
+    #[test]
+    fn test_speed_linktype() {
+        let source = "./tests/mouse/capture.pcap";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("full-speed.pcap");
+        {
+            let mut pcap = pcap::Capture::from_file(source).unwrap();
+            let dead = pcap::Capture::dead(
+                pcap::Linktype(LINKTYPE_USB_2_0_FULL_SPEED)).unwrap();
+            let mut file = dead.savefile(&path).unwrap();
+            while let Ok(packet) = pcap.next() {
+                file.write(&packet);
+            }
+        }
+        let (mut original, _) = decode(&[source]);
+        assert!(original.speed.is_none());
+        let (mut cap, _) = decode(&[path.to_str().unwrap()]);
+        assert!(cap.speed == Some(Speed::Full));
+        check(&mut cap).unwrap();
+        compare(&mut original, &mut cap).unwrap();
+    }
+
+    #[test]
+    fn test_retention() {
+        assert!("100MB".parse() == Ok(Retention::Bytes(100 << 20)));
+        assert!("30min".parse() == Ok(Retention::Seconds(1800)));
+        for text in ["", "MB", "0min", "-1MB", "30s", "1.5MB"] {
+            assert!(text.parse::<Retention>().is_err());
+        }
+
+        let source = "./tests/mouse/capture.pcap";
+        let (mut all, _) = decode(&[source]);
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let (_, dropped) = decode_files_keeping(
+            &mut decoder, &[source], Retention::Bytes(1 << 30)).unwrap();
+        drop(decoder);
+        assert!(dropped == 0);
+        compare(&mut all, &mut cap).unwrap();
+
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let (_, dropped) = decode_files_keeping(
+            &mut decoder, &[source], Retention::Bytes(1000)).unwrap();
+        drop(decoder);
+        assert!(dropped > 0);
+        assert!(cap.packet_index.len() + dropped == all.packet_index.len());
+        assert!(cap.packet_data.len() <= 1000);
+        check(&mut cap).unwrap();
+
+        let mut buffer = RollingBuffer::new(Retention::Seconds(60)).unwrap();
+        for (secs, usecs) in [(0, 0), (29, 5), (60, 0), (60, 6), (90, 0)] {
+            buffer.packet(Some((secs, usecs)), &[0xA5]).unwrap();
+        }
+        assert!(buffer.dropped == 2);
+        assert!(buffer.oldest().unwrap() == (Some((60, 0)), 25));
+        buffer.packet(None, &[0xA5]).unwrap();
+        assert!(buffer.count == 4);
+        assert!(buffer.error.is_none());
+    }
+
+    /// Collects the packets passed to it.
+    #[derive(Default)]
+    struct PacketList(Vec<(Option<Timestamp>, Vec<u8>)>);
+
+    impl PacketSink for PacketList {
+        fn set_speed(&mut self, _speed: Speed) {}
+
+        fn packet(&mut self, timestamp: Option<Timestamp>, packet: &[u8])
+            -> Result<(), FileError>
+        {
+            self.0.push((timestamp, packet.to_vec()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_rolling_buffer_compaction() {
+        // Each packet takes 28 bytes with its header, so 100 are kept.
+        let mut buffer = RollingBuffer::new(Retention::Bytes(2800)).unwrap();
+        let count: u32 = 100000;
+        for i in 0..count {
+            buffer.packet(Some((i as i64, 0)), &i.to_le_bytes()).unwrap();
+        }
+        assert!(buffer.error.is_none());
+        assert!(buffer.count == 100);
+        assert!(buffer.dropped == count as u64 - 100);
+        // The spool was compacted, so holds little more than is kept.
+        assert!(buffer.end <= 2 * SPOOL_COMPACT);
+        buffer.writer.flush().unwrap();
+        assert!(buffer.spool.as_file().metadata().unwrap().len() ==
+                buffer.end);
+        let mut list = PacketList::default();
+        buffer.replay(&mut list).unwrap();
+        let expected: Vec<_> = (count - 100..count)
+            .map(|i| (Some((i as i64, 0)), i.to_le_bytes().to_vec()))
+            .collect();
+        assert!(list.0 == expected);
+    }
+
+    #[test]
+    fn test_trigger() {
+        use crate::trigger::TriggerCondition;
+        let source = "./tests/mouse/capture.pcap";
+        let (mut all, _) = decode(&[source]);
+        let decode_triggered = |condition: &str, history| {
+            let condition: TriggerCondition = condition.parse().unwrap();
+            let mut trigger = Trigger::new(condition, history);
+            let mut cap = Capture::new();
+            let mut decoder = Decoder::new(&mut cap);
+            let (_, position) = decode_files_triggered(
+                &mut decoder, &[source], &mut trigger).unwrap();
+            drop(decoder);
+            (cap, position)
+        };
+
+        // The device is addressed as 4 after SET_ADDRESS.
+        let (mut cap, position) = decode_triggered("address 4", 10);
+        let position = position.unwrap();
+        assert!(position > 10);
+        assert!(cap.packet_index.len() + position - 10 ==
+                all.packet_index.len());
+        assert!(cap.get_packet(10) == all.get_packet(position));
+        check(&mut cap).unwrap();
+
+        // With no history, decoding starts at the packet which matched.
+        let (mut cap, start) = decode_triggered("address 4", 0);
+        assert!(start == Some(position));
+        assert!(cap.get_packet(0) == all.get_packet(position));
+
+        let (cap, position) = decode_triggered("address 99", 10);
+        assert!(position.is_none());
+        assert!(cap.packet_index.len() == 0);
+    }
+
+    #[test]
+    fn test_compressed_files() {
+        use std::io::Write;
+        let source = "./tests/mouse/capture.pcap";
+        let contents = std::fs::read(source).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let gzip = dir.path().join("capture.pcap.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            File::create(&gzip).unwrap(), flate2::Compression::default());
+        encoder.write_all(&contents).unwrap();
+        encoder.finish().unwrap();
+        let zstd = dir.path().join("capture.pcap.zst");
+        std::fs::write(&zstd, ruzstd::encoding::compress_to_vec(
+            &contents[..], ruzstd::encoding::CompressionLevel::Fastest))
+            .unwrap();
+        let (mut expected, _) = decode(&[source]);
+        for path in [gzip, zstd] {
+            let (mut cap, warnings) = decode(&[path.to_str().unwrap()]);
+            assert!(warnings.is_empty());
+            compare(&mut cap, &mut expected).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let source = "./tests/mouse/capture.pcap";
+        let dir = tempfile::tempdir().unwrap();
+        let record = dir.path().join("record.pcap");
+        let record = record.to_str().unwrap();
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files_recording(&mut decoder, &[source], record).unwrap();
+        drop(decoder);
+        let (mut expected, _) = decode(&[source]);
+        compare(&mut cap, &mut expected).unwrap();
+        let (mut recorded, _) = decode(&[record]);
+        compare(&mut recorded, &mut expected).unwrap();
+
+        let text = dir.path().join("usbmon.txt");
+        std::fs::write(&text, "d5ea89a0 3575914555 S Ci:1:003:0 s 80 06 \
+                               0100 0000 0012 18 <\n").unwrap();
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let err = decode_files_recording(
+            &mut decoder, &[text.to_str().unwrap()], record).unwrap_err();
+        assert!(matches!(err.error, FileError::TextRecord));
+    }
+
+    #[test]
+    fn test_detect_format() {
+        use FileFormat::*;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let path = path.to_str().unwrap();
+        let text = "d5ea89a0 3575914555 S Ci:1:003:0 s 80 06 0100 0000 \
+                    0012 18 <\n";
+        for (contents, format) in [
+            (&[0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0][..], Some(Pcap)),
+            (&[0xA1, 0xB2, 0x3C, 0x4D, 0, 2, 0, 4][..], Some(Pcap)),
+            (&[0x0A, 0x0D, 0x0D, 0x0A, 28, 0, 0, 0][..], Some(Pcapng)),
+            (&project::MAGIC[..], Some(Project)),
+            (text.as_bytes(), Some(UsbmonText)),
+            (b"Index,Record,Data\n", Some(BeagleCsv)),
+            (b"# SETUP\n[0.5] 2d 00 10\n", Some(HexDump)),
+            (b"GIF89a", None),
+            (&[], None),
+        ] {
+            std::fs::write(path, contents).unwrap();
+            assert!(detect_format(path).unwrap() == format);
+        }
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        let err = decode_files(&mut decoder, &[path]).unwrap_err();
+        assert!(matches!(err.error, FileError::UnsupportedFormat));
+    }
+}
@@ -0,0 +1,322 @@
+//! Reconstruction of typed text from HID keyboard traffic.
+//!
+//! Each keyboard input report lists the keys currently held down. Keys
+//! which appear in a report but not in the one before are new presses,
+//! which are converted to the characters they would type, taking account
+//! of the Shift modifiers and Caps Lock.
+
+use crate::capture::Capture;
+use crate::hid::{ReportType, ReportValue, find_interfaces};
+use crate::hid_usages::{KEYBOARD, fmt_usage_name};
+
+const KEYBOARD_PROTOCOL: u8 = 0x01;
+
+const ERROR_ROLL_OVER: u16 = 0x01;
+const CAPS_LOCK: u16 = 0x39;
+const FIRST_MODIFIER: u16 = 0xE0;
+const LAST_MODIFIER: u16 = 0xE7;
+
+const CTRL: u8 = 0x11;
+const SHIFT: u8 = 0x22;
+const ALT: u8 = 0x44;
+const GUI: u8 = 0x88;
+
+/// Characters typed by usages 0x1E to 0x38, without and with Shift.
+const DIGITS_AND_SYMBOLS: [(char, char); 27] = [
+    ('1', '!'), ('2', '@'), ('3', '#'), ('4', '$'), ('5', '%'),
+    ('6', '^'), ('7', '&'), ('8', '*'), ('9', '('), ('0', ')'),
+    ('\n', '\n'), ('\x1B', '\x1B'), ('\x08', '\x08'), ('\t', '\t'),
+    (' ', ' '), ('-', '_'), ('=', '+'), ('[', '{'), (']', '}'),
+    ('\\', '|'), ('#', '~'), (';', ':'), ('\'', '"'), ('`', '~'),
+    (',', '<'), ('.', '>'), ('/', '?'),
+];
+
+/// The keys held down according to one input report.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyReport {
+    /// Modifier bits, in boot protocol order from Left Control.
+    pub modifiers: u8,
+    /// Usage IDs of the other keys held down.
+    pub keys: Vec<u16>,
+}
+
+impl KeyReport {
+    /// Read a boot protocol keyboard report. Returns None for a report
+    /// signalling that too many keys are held down to list.
+    pub fn from_boot_report(data: &[u8]) -> Option<KeyReport> {
+        if data.len() < 3 {
+            return None;
+        }
+        let keys: Vec<u16> = data[2..].iter()
+            .map(|key| *key as u16)
+            .filter(|key| *key != 0)
+            .collect();
+        if keys.contains(&ERROR_ROLL_OVER) {
+            return None;
+        }
+        Some(KeyReport {
+            modifiers: data[0],
+            keys: keys,
+        })
+    }
+
+    /// Collect the keyboard usages from a decoded report. Returns None if
+    /// the report contains no keyboard usages, or signals roll over.
+    pub fn from_values(values: &[ReportValue]) -> Option<KeyReport> {
+        let mut report = KeyReport::default();
+        let mut found = false;
+        for value in values {
+            if value.usage_page() != KEYBOARD {
+                continue;
+            }
+            found = true;
+            let id = value.usage_id();
+            if value.value == 0 {
+                continue;
+            } else if id == ERROR_ROLL_OVER {
+                return None;
+            } else if (FIRST_MODIFIER..=LAST_MODIFIER).contains(&id) {
+                report.modifiers |= 1 << (id - FIRST_MODIFIER);
+            } else if id > 0x03 {
+                report.keys.push(id);
+            }
+        }
+        if found {
+            Some(report)
+        } else {
+            None
+        }
+    }
+}
+
+/// Tracks the keys held on a keyboard, to find new key presses.
+#[derive(Default)]
+pub struct KeyboardState {
+    keys: Vec<u16>,
+    caps_lock: bool,
+}
+
+impl KeyboardState {
+    /// Update the state from a new report, returning the text typed.
+    pub fn update(&mut self, report: &KeyReport) -> String {
+        let mut text = String::new();
+        for key in &report.keys {
+            if self.keys.contains(key) {
+                continue;
+            }
+            if *key == CAPS_LOCK {
+                self.caps_lock = !self.caps_lock;
+            } else {
+                text.push_str(&self.key_text(*key, report.modifiers));
+            }
+        }
+        self.keys = report.keys.clone();
+        text
+    }
+
+    /// The text typed by pressing a key with some modifiers held.
+    fn key_text(&self, key: u16, modifiers: u8) -> String {
+        let shift = modifiers & SHIFT != 0;
+        let character = match key {
+            0x04..=0x1D => {
+                let letter = (b'a' + (key - 0x04) as u8) as char;
+                Some(if shift != self.caps_lock {
+                    letter.to_ascii_uppercase()
+                } else {
+                    letter
+                })
+            },
+            0x1E..=0x38 => {
+                let (plain, shifted) =
+                    DIGITS_AND_SYMBOLS[(key - 0x1E) as usize];
+                Some(if shift {shifted} else {plain})
+                    .filter(|c| !c.is_control() || c.is_whitespace())
+            },
+            0x54 => Some('/'),
+            0x55 => Some('*'),
+            0x56 => Some('-'),
+            0x57 => Some('+'),
+            0x58 => Some('\n'),
+            0x59..=0x61 => Some((b'1' + (key - 0x59) as u8) as char),
+            0x62 => Some('0'),
+            0x63 => Some('.'),
+            0x64 => Some(if shift {'|'} else {'\\'}),
+            0x67 => Some('='),
+            _ => None,
+        };
+        let combination = modifiers & (CTRL | ALT | GUI) != 0;
+        match character {
+            Some(c) if !combination => c.to_string(),
+            _ => {
+                let mut parts = Vec::new();
+                for (mask, name) in [(CTRL, "Ctrl"), (ALT, "Alt"),
+                                     (GUI, "GUI")]
+                {
+                    if modifiers & mask != 0 {
+                        parts.push(name.to_string());
+                    }
+                }
+                if combination && shift {
+                    parts.push("Shift".to_string());
+                }
+                parts.push(match character {
+                    Some(c) if c.is_ascii_graphic() =>
+                        c.to_ascii_uppercase().to_string(),
+                    _ => key_name(key),
+                });
+                format!("[{}]", parts.join("+"))
+            }
+        }
+    }
+}
+
+/// Name of a key, for keys which do not type a character.
+fn key_name(key: u16) -> String {
+    match key {
+        0x28 => "Enter".to_string(),
+        0x29 => "Esc".to_string(),
+        0x2A => "Backspace".to_string(),
+        0x2B => "Tab".to_string(),
+        0x2C => "Space".to_string(),
+        0x3A..=0x45 => format!("F{}", key - 0x3A + 1),
+        0x4C => "Delete".to_string(),
+        0x4F => "Right".to_string(),
+        0x50 => "Left".to_string(),
+        0x51 => "Down".to_string(),
+        0x52 => "Up".to_string(),
+        _ => {
+            let name = fmt_usage_name(KEYBOARD, key);
+            match name.strip_prefix("Keyboard ") {
+                Some(short) => short.to_string(),
+                None => name,
+            }
+        }
+    }
+}
+
+/// Text typed on one keyboard interface.
+pub struct Transcript {
+    pub address: u8,
+    pub interface_number: u8,
+    /// Number of reports in which new keys were pressed.
+    pub keystrokes: usize,
+    pub text: String,
+}
+
+impl Transcript {
+    pub fn summary(&self) -> String {
+        format!("Device {}, interface {}: {} keystroke{}",
+                self.address, self.interface_number, self.keystrokes,
+                if self.keystrokes == 1 {""} else {"s"})
+    }
+}
+
+/// Reconstruct the text typed on each keyboard in the capture.
+///
+/// Reports are decoded using the interface's report descriptor if it was
+/// read, and otherwise as boot protocol reports if the interface supports
+/// the boot protocol.
+pub fn find_keyboard_text(cap: &mut Capture) -> Vec<Transcript> {
+    profile!("find_keyboard_text");
+    let mut transcripts = Vec::new();
+    for iface in find_interfaces(cap) {
+        let keyboard = match iface.descriptor {
+            Some(_) => iface.has_input_page(KEYBOARD),
+            None => iface.is_boot(KEYBOARD_PROTOCOL),
+        };
+        if !keyboard {
+            continue;
+        }
+        let mut state = KeyboardState::default();
+        let mut transcript = Transcript {
+            address: iface.address,
+            interface_number: iface.interface_number,
+            keystrokes: 0,
+            text: String::new(),
+        };
+        for data in iface.input_reports(cap) {
+            let report = match &iface.descriptor {
+                Some(descriptor) => descriptor
+                    .decode(ReportType::Input, &data)
+                    .and_then(|values| KeyReport::from_values(&values)),
+                None => KeyReport::from_boot_report(&data),
+            };
+            if let Some(report) = report {
+                let text = state.update(&report);
+                if !text.is_empty() {
+                    transcript.keystrokes += 1;
+                    transcript.text.push_str(&text);
+                }
+            }
+        }
+        transcripts.push(transcript);
+    }
+    transcripts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_reports(reports: &[[u8; 8]]) -> String {
+        let mut state = KeyboardState::default();
+        reports.iter()
+            .filter_map(|data| KeyReport::from_boot_report(data))
+            .map(|report| state.update(&report))
+            .collect()
+    }
+
+    #[test]
+    fn test_boot_reports() {
+        assert!(type_reports(&[
+            // Shift+H, then i with H still held.
+            [0x02, 0, 0x0B, 0, 0, 0, 0, 0],
+            [0x00, 0, 0x0B, 0x0C, 0, 0, 0, 0],
+            [0x00, 0, 0, 0, 0, 0, 0, 0],
+            // Right Shift+1, Enter.
+            [0x20, 0, 0x1E, 0, 0, 0, 0, 0],
+            [0x00, 0, 0x28, 0, 0, 0, 0, 0],
+            [0x00, 0, 0, 0, 0, 0, 0, 0],
+        ]) == "Hi!\n");
+    }
+
+    #[test]
+    fn test_caps_lock_and_combinations() {
+        assert!(type_reports(&[
+            [0x00, 0, 0x39, 0, 0, 0, 0, 0],
+            [0x00, 0, 0, 0, 0, 0, 0, 0],
+            [0x00, 0, 0x04, 0, 0, 0, 0, 0],
+            [0x02, 0, 0x05, 0, 0, 0, 0, 0],
+            [0x00, 0, 0x39, 0, 0, 0, 0, 0],
+            [0x00, 0, 0, 0, 0, 0, 0, 0],
+            // Too many keys held, which must not count as new presses.
+            [0x00, 0, 0x06, 0, 0, 0, 0, 0],
+            [0x00, 0, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01],
+            [0x00, 0, 0x06, 0, 0, 0, 0, 0],
+            [0x01, 0, 0x06, 0, 0, 0, 0, 0],
+            [0x00, 0, 0, 0, 0, 0, 0, 0],
+            [0x01, 0, 0x06, 0, 0, 0, 0, 0],
+            [0x05, 0, 0x4C, 0, 0, 0, 0, 0],
+            [0x00, 0, 0x2A, 0, 0, 0, 0, 0],
+            [0x00, 0, 0x3B, 0, 0, 0, 0, 0],
+        ]) == "Abc[Ctrl+C][Ctrl+Alt+Delete][Backspace][F2]");
+    }
+
+    #[test]
+    fn test_report_values() {
+        let value = |id, value, selected| ReportValue {
+            usage: (KEYBOARD as u32) << 16 | id,
+            value: value,
+            selected: selected,
+        };
+        let report = KeyReport::from_values(&[
+            value(0xE0, 0, false),
+            value(0xE1, 1, false),
+            value(0x04, 1, true),
+            value(0x27, 1, true),
+        ]).unwrap();
+        assert!(report == KeyReport { modifiers: 0x02, keys: vec![4, 0x27] });
+        assert!(KeyReport::from_values(&[value(0x01, 1, true)]).is_none());
+        assert!(KeyReport::from_values(&[]).is_none());
+    }
+}
@@ -0,0 +1,173 @@
+//! Labels given by the user to devices and endpoints, such as "sensor bulk
+//! IN", which are shown alongside them in summaries.
+//!
+//! Labels are keyed by the VID:PID of the device, and the address of the
+//! endpoint, so that they apply again to new captures of the same
+//! hardware. They are kept as "KEY = LABEL" lines in a file in the user's
+//! configuration directory, and saved with projects.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::preferences::config_dir;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum LabelError {
+    #[error("invalid label key '{0}', expected VID:PID or VID:PID.ENDPOINT \
+             in hex")]
+    InvalidKey(String),
+}
+
+/// Identifies a device by its VID:PID, or an endpoint of it by address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LabelKey {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Endpoint address, with the direction in bit 7.
+    pub endpoint: Option<u8>,
+}
+
+impl fmt::Display for LabelKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor_id, self.product_id)?;
+        match self.endpoint {
+            Some(address) => write!(f, ".{:02x}", address),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for LabelKey {
+    type Err = LabelError;
+
+    /// Parse a key, e.g. "1d50:615b" for a device or "1d50:615b.81" for
+    /// its endpoint 1 IN.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || LabelError::InvalidKey(text.to_string());
+        let hex16 = |hex: &str| u16::from_str_radix(hex, 16)
+            .map_err(|_| invalid());
+        let (device, endpoint) = match text.split_once('.') {
+            Some((device, endpoint)) => (device, Some(endpoint)),
+            None => (text, None),
+        };
+        let (vid, pid) = device.split_once(':').ok_or_else(invalid)?;
+        let endpoint = match endpoint {
+            Some(hex) => Some(u8::from_str_radix(hex, 16)
+                .map_err(|_| invalid())?),
+            None => None,
+        };
+        Ok(LabelKey {
+            vendor_id: hex16(vid)?,
+            product_id: hex16(pid)?,
+            endpoint: endpoint,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Labels {
+    labels: BTreeMap<LabelKey, String>,
+}
+
+impl Labels {
+    pub fn get(&self, key: &LabelKey) -> Option<&str> {
+        self.labels.get(key).map(String::as_str)
+    }
+
+    /// Set the label of a device or endpoint, or remove it if empty.
+    pub fn set(&mut self, key: LabelKey, label: &str) {
+        let label = label.trim();
+        if label.is_empty() {
+            self.labels.remove(&key);
+        } else {
+            self.labels.insert(key, label.to_string());
+        }
+    }
+
+    /// Add the labels of another set, replacing any with the same key.
+    pub fn merge(&mut self, other: &Labels) {
+        for (key, label) in &other.labels {
+            self.labels.insert(*key, label.clone());
+        }
+    }
+
+    /// Parse labels, ignoring any lines which are not valid.
+    pub fn parse(text: &str) -> Labels {
+        let mut labels = Labels::default();
+        for line in text.lines() {
+            if let Some((key, label)) = line.split_once('=') {
+                if let Ok(key) = key.trim().parse() {
+                    labels.set(key, label);
+                }
+            }
+        }
+        labels
+    }
+
+    pub fn to_text(&self) -> String {
+        self.labels.iter()
+            .map(|(key, label)| format!("{} = {}\n", key, label))
+            .collect()
+    }
+
+    fn path() -> PathBuf {
+        config_dir().join("labels")
+    }
+
+    /// Load the labels saved by the user.
+    pub fn load() -> Labels {
+        match std::fs::read_to_string(Labels::path()) {
+            Ok(text) => Labels::parse(&text),
+            Err(_) => Labels::default(),
+        }
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = Labels::path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.to_text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_labels() {
+        let device = LabelKey {
+            vendor_id: 0x1d50,
+            product_id: 0x615b,
+            endpoint: None,
+        };
+        let endpoint = LabelKey { endpoint: Some(0x81), ..device };
+        assert!("1d50:615b".parse() == Ok(device));
+        assert!("1D50:615B.81".parse() == Ok(endpoint));
+        assert!(endpoint.to_string() == "1d50:615b.81");
+        for text in ["", "1d50", "1d50:615b.", "1d50:615b.100", "x:1"] {
+            assert!(text.parse::<LabelKey>() ==
+                    Err(LabelError::InvalidKey(text.to_string())));
+        }
+
+        let mut labels = Labels::default();
+        labels.set(device, " Bootloader ");
+        labels.set(endpoint, "sensor bulk IN");
+        assert!(labels.get(&device) == Some("Bootloader"));
+        assert!(Labels::parse(&labels.to_text()) == labels);
+        assert!(Labels::parse("1d50:615b.81 = sensor = 1\nbad = 2\nx\n")
+            .get(&endpoint) == Some("sensor = 1"));
+
+        let mut other = Labels::default();
+        other.set(device, "Application");
+        labels.merge(&other);
+        assert!(labels.get(&device) == Some("Application"));
+        labels.set(endpoint, "");
+        assert!(labels.get(&endpoint).is_none());
+    }
+}
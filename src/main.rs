@@ -1,11 +1,17 @@
 #[macro_use]
 extern crate bitfield;
 
+#[macro_use]
+mod profiling;
+
 mod model;
 pub mod row_data;
 mod expander;
 
+use std::io::Write;
+use std::ops::Range;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
 
 use gtk::gio::ListModel;
 use gtk::glib::Object;
@@ -19,36 +25,99 @@ use gtk::{
     SignalListItemFactory,
     SingleSelection,
     Orientation,
+    Widget,
 };
 use row_data::GenericRowData;
 use model::GenericModel;
 use expander::ExpanderWrapper;
 
+mod anonymize;
+mod audio;
+mod batch;
+mod beagle;
+
 mod capture;
-use capture::Capture;
+use capture::{Capture, fmt_count, fmt_size};
+
+mod corpus;
 
 mod decoder;
 use decoder::Decoder;
 
+mod deeplink;
+
+mod descriptors;
+
+mod export;
+use export::{
+    hex_dump,
+    write_csv,
+    write_json,
+    write_pcap,
+    write_pcapng,
+    write_request_log,
+    write_tree,
+    CSV_PAYLOAD_LIMIT,
+    REQUEST_LOG_SUFFIX,
+    HtmlWriter,
+    TextWriter,
+    TreeWriter,
+};
+
 mod file_vec;
+mod filter;
+mod grouping;
+use grouping::GroupMode;
+mod halts;
+mod hexdump;
+mod hid;
+mod hid_usages;
 mod hybrid_index;
+mod input;
+mod keyboard;
+mod labels;
+mod msc;
+mod network;
+mod pointer;
+mod preferences;
+mod project;
+mod quality;
+mod queries;
+mod record;
+mod rules;
+mod scheduling;
+mod screenshot;
+mod storage;
+mod summary;
+mod trigger;
+use trigger::Trigger;
+mod urb;
 mod usb;
+mod usb_ids;
+mod usbmon;
+mod usbpcap;
+mod verify;
+mod video;
+mod watch;
+
+use rules::{Action, Rule, RuleEngine};
 
 fn create_view<Item, Model, RowData>(capture: &Arc<Mutex<Capture>>)
-        -> ListView
+        -> (ListView, Model)
     where
-        Model: GenericModel<Item> + IsA<ListModel>,
+        Model: GenericModel<Item> + IsA<ListModel> + Clone,
         RowData: GenericRowData<Item> + IsA<Object>
 {
     let cap = capture.clone();
     let model = Model::new(cap, None);
     let cap = capture.clone();
+    let root = model.clone();
     let tree_model = TreeListModel::new(&model, false, false, move |o| {
         let row = o.downcast_ref::<RowData>().unwrap();
         match row.child_count(&mut cap.lock().unwrap()) {
             0 => None,
             _ => Some(
-                Model::new(cap.clone(), row.get_item())
+                root.child_model(row.get_item())
                     .upcast::<ListModel>()
             )
         }
@@ -56,15 +125,33 @@ fn create_view<Item, Model, RowData>(capture: &Arc<Mutex<Capture>>)
     let selection_model = SingleSelection::new(Some(&tree_model));
     let factory = SignalListItemFactory::new();
     factory.connect_setup(move |_, list_item| {
-        let text_label = Label::new(None);
-        if RowData::CONNECTORS {
+        let text_label = if RowData::CONNECTORS {
             let expander = ExpanderWrapper::new();
             list_item.set_child(Some(&expander));
+            list_item
+                .property_expression_weak("item")
+                .chain_property::<TreeListRow>("item")
+                .chain_property::<RowData>("connectors")
+                .bind(&expander.connectors_label(), "label", Widget::NONE);
+            expander
+                .last_child()
+                .expect("The child has to exist")
+                .downcast::<Label>()
+                .expect("The child must be a Label.")
         } else {
+            let text_label = Label::new(None);
             let expander = TreeExpander::new();
             expander.set_child(Some(&text_label));
             list_item.set_child(Some(&expander));
-        }
+            text_label
+        };
+        // The label follows the summary of whichever row is bound. The list
+        // item is held weakly, as the label it owns holds the expression.
+        list_item
+            .property_expression_weak("item")
+            .chain_property::<TreeListRow>("item")
+            .chain_property::<RowData>("summary")
+            .bind(&text_label, "label", Widget::NONE);
     });
     factory.connect_bind(move |_, list_item| {
         let treelistrow = list_item
@@ -83,21 +170,25 @@ fn create_view<Item, Model, RowData>(capture: &Arc<Mutex<Capture>>)
             .child()
             .expect("The child has to exist");
 
-        let text_label = container
-            .last_child()
-            .expect("The child has to exist")
-            .downcast::<Label>()
-            .expect("The child must be a Label.");
-
-        let summary = row.get_summary();
-        text_label.set_text(&summary);
+        set_highlighted(&container, row.is_highlighted());
 
         if RowData::CONNECTORS {
             let expander_wrapper = container
                 .downcast::<ExpanderWrapper>()
                 .expect("The child must be a ExpanderWrapper.");
 
-            expander_wrapper.set_connectors(row.get_connectors());
+            // Disconnect any handlers left from a previous binding before
+            // updating the expander, so the old row is not affected.
+            expander_wrapper.clear_handler();
+            let weak = expander_wrapper.downgrade();
+            let handler = row.connect_notify_local(
+                Some("highlighted"), move |row, _| {
+                    if let Some(container) = weak.upgrade() {
+                        set_highlighted(container.upcast_ref(),
+                                        row.is_highlighted());
+                    }
+                });
+            expander_wrapper.set_row_handler(row.upcast_ref(), handler);
             let expander = expander_wrapper.expander();
             expander.set_visible(treelistrow.is_expandable());
             expander.set_expanded(treelistrow.is_expanded());
@@ -123,11 +214,7 @@ fn create_view<Item, Model, RowData>(capture: &Arc<Mutex<Capture>>)
                 .downcast::<ExpanderWrapper>()
                 .expect("The child must be a ExpanderWrapper.");
 
-            let expander = expander_wrapper.expander();
-            match expander_wrapper.take_handler() {
-                Some(handler) => expander.disconnect(handler),
-                None => panic!("Handler was not set")
-            };
+            expander_wrapper.clear_handler();
         } else {
             let tree_expander = container
                 .downcast::<TreeExpander>()
@@ -136,63 +223,2410 @@ fn create_view<Item, Model, RowData>(capture: &Arc<Mutex<Capture>>)
             tree_expander.set_list_row(None);
         }
     });
-    ListView::new(Some(&selection_model), Some(&factory))
+    (ListView::new(Some(&selection_model), Some(&factory)), model)
+}
+
+/// Show whether a row matched a highlight rule.
+fn set_highlighted(container: &Widget, highlighted: bool) {
+    if highlighted {
+        container.add_css_class("highlighted");
+    } else {
+        container.remove_css_class("highlighted");
+    }
+}
+
+/// Restore any streamed captures left unsaved by crashed sessions, then
+/// remove the rest of the storage files left behind by them. Sessions are
+/// only found to have crashed once their locks are released, so nothing
+/// in use is touched. Returns the paths of the captures restored.
+fn clean_stale_sessions() -> Vec<String> {
+    let mut restored = Vec::new();
+    let stale = storage::find_stale_sessions(&storage::base_dir());
+    for session in &stale {
+        let recovery = match &session.recovery {
+            Some(recovery) => recovery,
+            None => continue,
+        };
+        match storage::restore(session, &storage::restored_dir()) {
+            Ok(path) => {
+                println!("Restored an unsaved capture ({}) streamed by '{}' \
+                          to {}", fmt_size(recovery.size), recovery.command,
+                         path.display());
+                restored.push(path.to_string_lossy().into_owned());
+            },
+            Err(err) => eprintln!("Failed to restore {}: {}",
+                                  recovery.path.display(), err),
+        }
+    }
+    let stale = storage::find_stale_sessions(&storage::base_dir());
+    if stale.is_empty() {
+        return restored;
+    }
+    let file_count: u64 = stale.iter().map(|s| s.file_count).sum();
+    let size: u64 = stale.iter().map(|s| s.size).sum();
+    println!("Removing {} storage files ({}) left by previous sessions",
+             fmt_count(file_count), fmt_size(size));
+    for session in stale {
+        if let Err(err) = std::fs::remove_dir_all(&session.path) {
+            eprintln!("Failed to remove {}: {}",
+                      session.path.display(), err);
+        }
+    }
+    restored
+}
+
+/// Export the traffic tree to a file, as HTML or plain text according to
+/// the file extension, the capture's transactions or control requests to a
+/// CSV file, its packets to a pcapng file, those selected by a filter and
+/// range of packet numbers to a pcap file, the whole capture as JSON for
+/// other tools, or as a project.
+fn export_file(cap: &mut Capture, path: &str, filter: &filter::Filter,
+               packets: Option<Range<u64>>) -> std::io::Result<()>
+{
+    let file = std::fs::File::create(path)?;
+    let mut output = std::io::BufWriter::new(file);
+    if path.ends_with(project::PROJECT_EXTENSION) {
+        project::save_project(cap, &mut output)
+            .map_err(std::io::Error::other)?;
+        return output.flush();
+    }
+    if path.ends_with(REQUEST_LOG_SUFFIX) {
+        write_request_log(cap, &mut output)?;
+        return output.flush();
+    }
+    if path.ends_with(".csv") {
+        write_csv(cap, &mut output, CSV_PAYLOAD_LIMIT)?;
+        return output.flush();
+    }
+    if path.ends_with(".pcapng") {
+        write_pcapng(cap, &mut output)?;
+        return output.flush();
+    }
+    if path.ends_with(".json") {
+        write_json(cap, &mut output)?;
+        return output.flush();
+    }
+    if path.ends_with(".pcap") {
+        let packets = packets.unwrap_or(0..cap.packet_index.len());
+        write_pcap(cap, &mut output, filter, packets)?;
+        return output.flush();
+    }
+    let html = path.ends_with(".html") || path.ends_with(".htm");
+    let mut writer: Box<dyn TreeWriter> = if html {
+        Box::new(HtmlWriter::new(&mut output))
+    } else {
+        Box::new(TextWriter::new(&mut output))
+    };
+    write_tree(cap, writer.as_mut())?;
+    drop(writer);
+    output.flush()
+}
+
+/// Select a top-level row of a tree view, and scroll to show it.
+fn select_top_level_row(view: &ListView, position: u32) {
+    select_path(view, &[position]);
+}
+
+/// Select a row of a tree view, given its position at each level from the
+/// top, expanding the rows above it and scrolling to show it.
+fn select_path(view: &ListView, path: &[u32]) -> bool {
+    let selection = view.model().unwrap()
+        .downcast::<SingleSelection>().unwrap();
+    let tree_model = selection.model()
+        .downcast::<TreeListModel>().unwrap();
+    let mut row = match tree_model.child_row(path[0]) {
+        Some(row) => row,
+        None => return false,
+    };
+    for index in &path[1..] {
+        row.set_expanded(true);
+        row = match row.child_row(*index) {
+            Some(row) => row,
+            None => return false,
+        };
+    }
+    let position = row.position();
+    selection.set_selected(position);
+    let _ = view.activate_action("list.scroll-to-item",
+                                 Some(&position.to_variant()));
+    true
+}
+
+/// Select an item in the traffic view. Returns false if it is not in the
+/// capture, or is hidden by the current filter.
+fn select_item(view: &ListView,
+               traffic_model: &model::Model,
+               capture: &Arc<Mutex<Capture>>,
+               item: &capture::Item) -> bool
+{
+    let path = match capture.lock().unwrap().item_path(item) {
+        Some(path) => path,
+        None => return false,
+    };
+    let mut positions = match traffic_model.item_position(path[0]) {
+        Some(position) => vec![position],
+        None => return false,
+    };
+    positions.extend(path[1..].iter().map(|index| *index as u32));
+    select_path(view, &positions)
+}
+
+/// Show a window listing all descriptor reads, grouped by device and
+/// descriptor. Activating a read selects its transfer in the traffic view,
+/// and its device in the device view.
+fn show_descriptor_reads(window: &gtk::ApplicationWindow,
+                         capture: &Arc<Mutex<Capture>>,
+                         traffic_view: &ListView,
+                         traffic_model: &model::Model,
+                         device_view: &ListView)
+{
+    let list = gtk::ListBox::new();
+    let mut targets = Vec::new();
+    {
+        let mut cap = capture.lock().unwrap();
+        for reads in descriptors::find_descriptor_reads(&mut cap) {
+            let label = Label::new(Some(&reads.summary()));
+            label.set_xalign(0.0);
+            let row = gtk::ListBoxRow::builder()
+                .child(&label)
+                .activatable(false)
+                .selectable(false)
+                .build();
+            list.append(&row);
+            targets.push(None);
+            for read in &reads.reads {
+                let transfer_index_id =
+                    cap.item_index.get(read.item_index).unwrap();
+                let item = capture::Item::Transfer(transfer_index_id);
+                let label = Label::new(Some(&format!(
+                    "    Version {}: {}", read.version, cap.get_summary(&item))));
+                label.set_xalign(0.0);
+                list.append(&label);
+                targets.push(Some((read.item_index, reads.device_id)));
+            }
+        }
+    }
+    let traffic_view = traffic_view.clone();
+    let traffic_model = traffic_model.clone();
+    let device_view = device_view.clone();
+    list.connect_row_activated(move |_, row| {
+        if let Some((item_index, device_id)) = targets[row.index() as usize] {
+            // The transfer may be hidden by the current filter.
+            if let Some(position) = traffic_model.item_position(item_index) {
+                select_top_level_row(&traffic_view, position);
+            }
+            // Device 0 is a placeholder, not shown in the device view.
+            if device_id > 0 {
+                select_top_level_row(&device_view, (device_id - 1) as u32);
+            }
+        }
+    });
+    let scrolled_window = gtk::ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Automatic)
+        .min_content_height(360)
+        .min_content_width(640)
+        .child(&list)
+        .build();
+    let descriptor_window = gtk::Window::builder()
+        .title("Descriptor traffic")
+        .transient_for(window)
+        .child(&scrolled_window)
+        .build();
+    descriptor_window.show();
+}
+
+/// Show a window for saving the current filter with a search as a named
+/// query, and running saved queries, listing the transfers they match.
+///
+/// Running a query also applies its filter to the traffic view, so that
+/// the transfers found can be selected there.
+fn show_queries(window: &gtk::ApplicationWindow,
+                capture: &Arc<Mutex<Capture>>,
+                traffic_view: &ListView,
+                traffic_model: &model::Model,
+                filter_entry: &gtk::SearchEntry)
+{
+    use queries::{add_query, load_queries, save_queries, Query};
+    let saved = gtk::ComboBoxText::new();
+    for query in load_queries() {
+        saved.append(Some(&query.name), &query.name);
+    }
+    saved.set_active(Some(0));
+    let run_button = gtk::Button::with_label("Run");
+    let delete_button = gtk::Button::with_label("Delete");
+    let name = gtk::Entry::builder().placeholder_text("Name").build();
+    let search = gtk::Entry::builder()
+        .placeholder_text("Search summaries")
+        .hexpand(true)
+        .build();
+    let save_button = gtk::Button::with_label("Save with current filter");
+    let status = Label::new(None);
+    status.set_xalign(0.0);
+    let results = gtk::ListBox::new();
+    let targets: std::rc::Rc<std::cell::RefCell<Vec<u64>>> =
+        std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let grid = gtk::Grid::builder()
+        .row_spacing(6)
+        .column_spacing(12)
+        .margin_top(12)
+        .margin_bottom(12)
+        .margin_start(12)
+        .margin_end(12)
+        .build();
+    grid.attach(&saved, 0, 0, 2, 1);
+    grid.attach(&run_button, 2, 0, 1, 1);
+    grid.attach(&delete_button, 3, 0, 1, 1);
+    grid.attach(&name, 0, 1, 1, 1);
+    grid.attach(&search, 1, 1, 1, 1);
+    grid.attach(&save_button, 2, 1, 2, 1);
+    grid.attach(&status, 0, 2, 4, 1);
+    let scrolled_window = gtk::ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Automatic)
+        .min_content_height(360)
+        .min_content_width(640)
+        .vexpand(true)
+        .child(&results)
+        .build();
+    grid.attach(&scrolled_window, 0, 3, 4, 1);
+
+    let save_window = window.clone();
+    let save_saved = saved.clone();
+    let save_filter = filter_entry.clone();
+    save_button.connect_clicked(move |_| {
+        let query = Query::new(&name.text(), &save_filter.text(),
+                               &search.text());
+        let query = match query {
+            Ok(query) => query,
+            Err(err) => {
+                show_message(&save_window, "Invalid query",
+                             &err.to_string());
+                return;
+            }
+        };
+        let mut queries = load_queries();
+        let query_name = query.name.clone();
+        add_query(&mut queries, query);
+        if let Err(err) = save_queries(&queries) {
+            show_message(&save_window, "Failed to save query",
+                         &err.to_string());
+            return;
+        }
+        save_saved.remove_all();
+        for query in &queries {
+            save_saved.append(Some(&query.name), &query.name);
+        }
+        save_saved.set_active_id(Some(&query_name));
+    });
+
+    let delete_window = window.clone();
+    let delete_saved = saved.clone();
+    delete_button.connect_clicked(move |_| {
+        let name = match delete_saved.active_id() {
+            Some(name) => name,
+            None => return,
+        };
+        let mut queries = load_queries();
+        queries.retain(|query| query.name != name.as_str());
+        if let Err(err) = save_queries(&queries) {
+            show_message(&delete_window, "Failed to save queries",
+                         &err.to_string());
+            return;
+        }
+        if let Some(position) = delete_saved.active() {
+            delete_saved.remove(position as i32);
+        }
+        delete_saved.set_active(Some(0));
+    });
+
+    let run_window = window.clone();
+    let run_capture = capture.clone();
+    let run_filter = filter_entry.clone();
+    let run_results = results.clone();
+    let run_targets = targets.clone();
+    run_button.connect_clicked(move |_| {
+        let query = saved.active_id().and_then(|name| load_queries()
+            .into_iter()
+            .find(|query| query.name == name.as_str()));
+        let query = match query {
+            Some(query) => query,
+            None => return,
+        };
+        run_filter.set_text(&query.filter);
+        run_filter.emit_activate();
+        while let Some(row) = run_results.row_at_index(0) {
+            run_results.remove(&row);
+        }
+        let mut cap = run_capture.lock().unwrap();
+        let found = match query.run(&mut cap) {
+            Ok(found) => found,
+            Err(err) => {
+                show_message(&run_window, "Failed to run query",
+                             &err.to_string());
+                return;
+            }
+        };
+        for item_index in &found {
+            let transfer_index_id = cap.item_index.get(*item_index).unwrap();
+            let item = capture::Item::Transfer(transfer_index_id);
+            let label = Label::new(Some(&cap.get_summary(&item)));
+            label.set_xalign(0.0);
+            run_results.append(&label);
+        }
+        status.set_text(&format!("{}: {} matching transfers",
+                                 query.name, fmt_count(found.len() as u64)));
+        *run_targets.borrow_mut() = found;
+    });
+
+    let traffic_view = traffic_view.clone();
+    let traffic_model = traffic_model.clone();
+    results.connect_row_activated(move |_, row| {
+        let item_index = targets.borrow()[row.index() as usize];
+        if let Some(position) = traffic_model.item_position(item_index) {
+            select_top_level_row(&traffic_view, position);
+        }
+    });
+
+    let queries_window = gtk::Window::builder()
+        .title("Saved queries")
+        .transient_for(window)
+        .child(&grid)
+        .build();
+    queries_window.show();
+}
+
+/// Find the item selected in a traffic view, if any.
+fn selected_item(view: &ListView) -> Option<capture::Item> {
+    let selection = view.model()?.downcast::<SingleSelection>().ok()?;
+    let row = selection.selected_item()?.downcast::<TreeListRow>().ok()?;
+    row.item()?.downcast::<row_data::RowData>().ok()?.get_item()
+}
+
+/// Describe an item in full: its summary, followed by its data in hex.
+fn item_details(cap: &mut Capture, item: &capture::Item) -> String {
+    use capture::Item::*;
+    let mut summary =
+        format!("{}\nItem ID: {}", cap.get_summary(item), item);
+    if let Packet(.., packet_id) = item {
+        if let Some(time) = cap.packet_time(*packet_id) {
+            summary += &format!("\nTime: {}", capture::fmt_nanos(time));
+        }
+    }
+    let data = match item {
+        Transfer(transfer_index_id) => cap.control_transfer(*transfer_index_id)
+            .map(|transfer| transfer.data),
+        Transaction(_, transaction_id) => cap.get_payload(*transaction_id)
+            .map(|(_, data)| data),
+        Packet(.., packet_id) => Some(cap.get_packet(*packet_id)),
+    };
+    match data {
+        Some(data) if !data.is_empty() =>
+            format!("{}\n\n{}", summary, hex_dump(&data)),
+        _ => summary,
+    }
+}
+
+/// Show a window with the details of the item selected in the traffic
+/// view, which follows the selection as it changes.
+fn show_details(window: &gtk::ApplicationWindow,
+                capture: &Arc<Mutex<Capture>>,
+                traffic_view: &ListView)
+{
+    let label = Label::builder()
+        .xalign(0.0)
+        .yalign(0.0)
+        .selectable(true)
+        .wrap(true)
+        .build();
+    label.add_css_class("monospace");
+    let update = {
+        let label = label.clone();
+        let capture = capture.clone();
+        let traffic_view = traffic_view.clone();
+        move || label.set_text(&match selected_item(&traffic_view) {
+            Some(item) => item_details(&mut capture.lock().unwrap(), &item),
+            None => "No item selected".to_string(),
+        })
+    };
+    update();
+    let selection = traffic_view.model().unwrap()
+        .downcast::<SingleSelection>().unwrap();
+    let handler = selection.connect_selected_notify(move |_| update());
+    let scrolled_window = gtk::ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Never)
+        .min_content_height(240)
+        .min_content_width(480)
+        .child(&label)
+        .build();
+    let details_window = gtk::Window::builder()
+        .title("Selected item")
+        .transient_for(window)
+        .child(&scrolled_window)
+        .build();
+    let handler = std::cell::Cell::new(Some(handler));
+    details_window.connect_close_request(move |_| {
+        if let Some(handler) = handler.take() {
+            selection.disconnect(handler);
+        }
+        gtk::Inhibit(false)
+    });
+    details_window.show();
+}
+
+/// Show a window with the text typed on each keyboard in the capture.
+fn show_keyboard_text(window: &gtk::ApplicationWindow,
+                      capture: &Arc<Mutex<Capture>>)
+{
+    let list = gtk::Box::new(Orientation::Vertical, 6);
+    let transcripts = keyboard::find_keyboard_text(
+        &mut capture.lock().unwrap());
+    if transcripts.is_empty() {
+        list.append(&Label::new(Some("No keyboard traffic found")));
+    }
+    for transcript in transcripts {
+        let heading = Label::new(Some(&transcript.summary()));
+        heading.set_xalign(0.0);
+        heading.add_css_class("heading");
+        list.append(&heading);
+        let text = Label::new(Some(&transcript.text));
+        text.set_xalign(0.0);
+        text.set_wrap(true);
+        text.set_selectable(true);
+        list.append(&text);
+    }
+    let scrolled_window = gtk::ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Never)
+        .min_content_height(360)
+        .min_content_width(480)
+        .child(&list)
+        .build();
+    let keyboard_window = gtk::Window::builder()
+        .title("Keyboard text")
+        .transient_for(window)
+        .child(&scrolled_window)
+        .build();
+    keyboard_window.show();
+}
+
+/// Draw a pointer path, scaled to fit the area available.
+fn draw_pointer_path(path: &pointer::PointerPath, context: &gtk::cairo::Context,
+                     width: i32, height: i32)
+{
+    const MARGIN: f64 = 10.0;
+    let ((min_x, min_y), (max_x, max_y)) = match path.bounds() {
+        Some(bounds) => bounds,
+        None => return,
+    };
+    let range_x = (max_x - min_x).max(1) as f64;
+    let range_y = (max_y - min_y).max(1) as f64;
+    let scale = f64::min((width as f64 - 2.0 * MARGIN) / range_x,
+                         (height as f64 - 2.0 * MARGIN) / range_y);
+    let position = |(x, y): (i64, i64)| (
+        MARGIN + (x - min_x) as f64 * scale,
+        MARGIN + (y - min_y) as f64 * scale);
+    context.set_source_rgb(0.2, 0.4, 0.8);
+    context.set_line_width(1.5);
+    for (i, point) in path.points.iter().enumerate() {
+        let (x, y) = position(*point);
+        if i == 0 {
+            context.move_to(x, y);
+        } else {
+            context.line_to(x, y);
+        }
+    }
+    let _ = context.stroke();
+    // Mark the start in green and the end in red.
+    let ends = [(path.points[0], (0.0, 0.7, 0.0)),
+                (*path.points.last().unwrap(), (0.8, 0.0, 0.0))];
+    for (point, (r, g, b)) in ends {
+        let (x, y) = position(point);
+        context.set_source_rgb(r, g, b);
+        context.arc(x, y, 4.0, 0.0, 2.0 * std::f64::consts::PI);
+        let _ = context.fill();
+    }
+}
+
+/// Show a window plotting the movement of each pointing device in the
+/// capture, with a tab for each device.
+fn show_pointer_paths(window: &gtk::ApplicationWindow,
+                      capture: &Arc<Mutex<Capture>>)
+{
+    let notebook = gtk::Notebook::new();
+    let paths = pointer::find_pointer_paths(&mut capture.lock().unwrap());
+    if paths.is_empty() {
+        notebook.append_page(
+            &Label::new(Some("No mouse or digitizer traffic found")),
+            Some(&Label::new(Some("Pointer movement"))));
+    }
+    for path in paths {
+        let tab_label = Label::new(Some(&format!(
+            "Device {}.{}", path.address, path.interface_number)));
+        let summary = Label::new(Some(&path.summary()));
+        let area = gtk::DrawingArea::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .build();
+        area.set_draw_func(move |_, context, width, height| {
+            draw_pointer_path(&path, context, width, height);
+        });
+        let page = gtk::Box::new(Orientation::Vertical, 6);
+        page.append(&summary);
+        page.append(&area);
+        notebook.append_page(&page, Some(&tab_label));
+    }
+    let analysis_window = gtk::Window::builder()
+        .title("Pointer movement")
+        .transient_for(window)
+        .default_width(640)
+        .default_height(480)
+        .child(&notebook)
+        .build();
+    analysis_window.show();
+}
+
+/// Layout of the main window.
+const WINDOW_UI: &str = include_str!("../data/window.ui");
+
+/// Get an object defined in a UI definition.
+fn ui_object<T: IsA<Object>>(builder: &gtk::Builder, id: &str) -> T {
+    builder.object(id)
+        .unwrap_or_else(|| panic!("No object '{}' in UI definition", id))
+}
+
+/// Close a popover menu when any button in it is clicked.
+fn close_on_click(popover: &gtk::Popover) {
+    let mut child = popover.child().and_then(|menu| menu.first_child());
+    while let Some(widget) = child {
+        if let Some(button) = widget.downcast_ref::<gtk::Button>() {
+            let popover = popover.clone();
+            button.connect_clicked(move |_| popover.popdown());
+        }
+        child = widget.next_sibling();
+    }
+}
+
+/// Ask for a filename, then save an image of the traffic view to it.
+fn save_view(window: &gtk::ApplicationWindow, view: &gtk::ScrolledWindow) {
+    let dialog = gtk::FileChooserNative::new(
+        Some("Save view as PNG or PDF"),
+        Some(window),
+        gtk::FileChooserAction::Save,
+        Some("Save"),
+        None);
+    dialog.set_current_name("packetry.png");
+    let window = window.clone();
+    let view = view.clone();
+    dialog.connect_response(move |dialog, response| {
+        let path = dialog.file().and_then(|file| file.path());
+        dialog.destroy();
+        if response != gtk::ResponseType::Accept {
+            return;
+        }
+        if let Some(path) = path {
+            if let Err(err) = screenshot::render_to_file(&view, &path) {
+                show_message(&window, "Failed to save view", &err.to_string());
+            }
+        }
+    });
+    dialog.show();
+}
+
+/// Ask for a capture file, then open it in place of the window's capture.
+fn choose_capture(window: &gtk::ApplicationWindow) {
+    let dialog = gtk::FileChooserNative::new(
+        Some("Open capture"),
+        Some(window),
+        gtk::FileChooserAction::Open,
+        Some("Open"),
+        None);
+    let window = window.clone();
+    dialog.connect_response(move |dialog, response| {
+        let file = dialog.file();
+        dialog.destroy();
+        if response != gtk::ResponseType::Accept {
+            return;
+        }
+        if let Some(file) = file {
+            open_in_place(&window, &file);
+        }
+    });
+    dialog.show();
+}
+
+/// Ask for packets as hex, pasted in one to a line, then open them in
+/// place of the window's capture.
+fn import_hex_dump(window: &gtk::ApplicationWindow) {
+    let text_view = gtk::TextView::builder()
+        .monospace(true)
+        .build();
+    let scrolled_window = gtk::ScrolledWindow::builder()
+        .min_content_height(360)
+        .min_content_width(480)
+        .vexpand(true)
+        .child(&text_view)
+        .build();
+    let import_button = gtk::Button::with_label("Import");
+    let content = gtk::Box::new(Orientation::Vertical, 6);
+    content.append(&Label::new(Some(
+        "Paste packets as hex, one to a line, each optionally after a \
+         timestamp in seconds")));
+    content.append(&scrolled_window);
+    content.append(&import_button);
+    let import_window = gtk::Window::builder()
+        .title("Import from hex dump")
+        .transient_for(window)
+        .child(&content)
+        .build();
+    let window = window.clone();
+    let close_window = import_window.clone();
+    import_button.connect_clicked(move |_| {
+        let application = match window.application() {
+            Some(application) => application,
+            None => return,
+        };
+        let buffer = text_view.buffer();
+        let text = buffer.text(&buffer.start_iter(), &buffer.end_iter(), false);
+        let result = storage::create_named_file()
+            .and_then(|mut file| file.write_all(text.as_bytes()).map(|_| file))
+            .map_err(|err| err.to_string())
+            .and_then(|file| {
+                let filename = file.path().to_string_lossy().into_owned();
+                open_capture(&[filename], Vec::new(), false, None, None, None)
+            });
+        match result {
+            Ok((cap, _)) => {
+                open_window(&application, &Arc::new(Mutex::new(cap)),
+                            "Hex dump", "", None);
+                close_window.close();
+                window.destroy();
+            },
+            Err(err) => show_message(&window, "Failed to import", &err),
+        }
+    });
+    import_window.show();
+}
+
+/// Ask for a filename, then save the loaded capture to it as pcapng. If
+/// `close` is set, the window is closed once the capture is saved.
+fn save_pcapng(window: &gtk::ApplicationWindow,
+               capture: &Arc<Mutex<Capture>>,
+               close: bool)
+{
+    let dialog = gtk::FileChooserNative::new(
+        Some("Save capture as pcapng"),
+        Some(window),
+        gtk::FileChooserAction::Save,
+        Some("Save"),
+        None);
+    dialog.set_current_name("capture.pcapng");
+    let window = window.clone();
+    let capture = capture.clone();
+    dialog.connect_response(move |dialog, response| {
+        let path = dialog.file().and_then(|file| file.path());
+        dialog.destroy();
+        if response != gtk::ResponseType::Accept {
+            return;
+        }
+        if let Some(path) = path {
+            let mut cap = capture.lock().unwrap();
+            let result = std::fs::File::create(&path)
+                .and_then(|file| {
+                    let mut output = std::io::BufWriter::new(file);
+                    write_pcapng(&mut cap, &mut output)?;
+                    output.flush()
+                });
+            let saved = match result {
+                Ok(()) => {
+                    cap.unsaved = false;
+                    true
+                },
+                Err(err) => {
+                    show_message(&window, "Failed to save capture",
+                                 &err.to_string());
+                    false
+                }
+            };
+            drop(cap);
+            if close && saved {
+                window.destroy();
+            }
+        }
+    });
+    dialog.show();
+}
+
+/// Ask whether to save a capture which would otherwise be lost, before
+/// closing its window.
+fn confirm_close(window: &gtk::ApplicationWindow,
+                 capture: &Arc<Mutex<Capture>>)
+{
+    let dialog = gtk::MessageDialog::builder()
+        .transient_for(window)
+        .modal(true)
+        .text("Save the capture before closing?")
+        .secondary_text("The capture was streamed from standard input, and \
+                         will be lost if it is not saved.")
+        .build();
+    dialog.add_button("Cancel", gtk::ResponseType::Cancel);
+    dialog.add_button("Close Without Saving", gtk::ResponseType::Reject);
+    dialog.add_button("Save", gtk::ResponseType::Accept);
+    dialog.set_default_response(gtk::ResponseType::Accept);
+    let window = window.clone();
+    let capture = capture.clone();
+    dialog.connect_response(move |dialog, response| {
+        dialog.destroy();
+        match response {
+            gtk::ResponseType::Accept => save_pcapng(&window, &capture, true),
+            gtk::ResponseType::Reject => window.destroy(),
+            _ => {},
+        }
+    });
+    dialog.show();
+}
+
+/// Ask for a filename, then save the packets of the loaded capture which
+/// pass a filter to it as pcap.
+fn save_filtered_pcap(window: &gtk::ApplicationWindow,
+                      capture: &Arc<Mutex<Capture>>,
+                      filter: filter::Filter)
+{
+    let dialog = gtk::FileChooserNative::new(
+        Some("Save filtered packets as pcap"),
+        Some(window),
+        gtk::FileChooserAction::Save,
+        Some("Save"),
+        None);
+    dialog.set_current_name("filtered.pcap");
+    let window = window.clone();
+    let capture = capture.clone();
+    dialog.connect_response(move |dialog, response| {
+        let path = dialog.file().and_then(|file| file.path());
+        dialog.destroy();
+        if response != gtk::ResponseType::Accept {
+            return;
+        }
+        if let Some(path) = path {
+            let mut cap = capture.lock().unwrap();
+            let packets = 0..cap.packet_index.len();
+            let result = std::fs::File::create(&path)
+                .and_then(|file| {
+                    let mut output = std::io::BufWriter::new(file);
+                    write_pcap(&mut cap, &mut output, &filter, packets)?;
+                    output.flush()
+                });
+            if let Err(err) = result {
+                show_message(&window, "Failed to save packets",
+                             &err.to_string());
+            }
+        }
+    });
+    dialog.show();
+}
+
+/// Ask for a filename, then save a log of the loaded capture's control
+/// requests to it.
+fn save_request_log(window: &gtk::ApplicationWindow,
+                    capture: &Arc<Mutex<Capture>>)
+{
+    let dialog = gtk::FileChooserNative::new(
+        Some("Export control requests"),
+        Some(window),
+        gtk::FileChooserAction::Save,
+        Some("Export"),
+        None);
+    dialog.set_current_name(&format!("capture{}", REQUEST_LOG_SUFFIX));
+    let window = window.clone();
+    let capture = capture.clone();
+    dialog.connect_response(move |dialog, response| {
+        let path = dialog.file().and_then(|file| file.path());
+        dialog.destroy();
+        if response != gtk::ResponseType::Accept {
+            return;
+        }
+        if let Some(path) = path {
+            let mut cap = capture.lock().unwrap();
+            let result = std::fs::File::create(&path)
+                .and_then(|file| {
+                    let mut output = std::io::BufWriter::new(file);
+                    write_request_log(&mut cap, &mut output)?;
+                    output.flush()
+                });
+            if let Err(err) = result {
+                show_message(&window, "Failed to export control requests",
+                             &err.to_string());
+            }
+        }
+    });
+    dialog.show();
+}
+
+/// Ask for a filename, then save the loaded capture to it as a project.
+fn save_project(window: &gtk::ApplicationWindow,
+                capture: &Arc<Mutex<Capture>>)
+{
+    let dialog = gtk::FileChooserNative::new(
+        Some("Save capture as project"),
+        Some(window),
+        gtk::FileChooserAction::Save,
+        Some("Save"),
+        None);
+    dialog.set_current_name(
+        &format!("capture{}", project::PROJECT_EXTENSION));
+    let window = window.clone();
+    let capture = capture.clone();
+    dialog.connect_response(move |dialog, response| {
+        let path = dialog.file().and_then(|file| file.path());
+        dialog.destroy();
+        if response != gtk::ResponseType::Accept {
+            return;
+        }
+        if let Some(path) = path {
+            let mut cap = capture.lock().unwrap();
+            let result = std::fs::File::create(&path)
+                .map_err(project::ProjectError::from)
+                .and_then(|file| {
+                    let mut output = std::io::BufWriter::new(file);
+                    project::save_project(&mut cap, &mut output)?;
+                    Ok(output.flush()?)
+                });
+            match result {
+                Ok(()) => cap.unsaved = false,
+                Err(err) => show_message(&window, "Failed to save project",
+                                         &err.to_string()),
+            }
+        }
+    });
+    dialog.show();
+}
+
+/// Ask for a filename, then export the capture's audio streams as WAV.
+fn export_audio(window: &gtk::ApplicationWindow,
+                capture: &Arc<Mutex<Capture>>)
+{
+    let dialog = gtk::FileChooserNative::new(
+        Some("Export audio as WAV"),
+        Some(window),
+        gtk::FileChooserAction::Save,
+        Some("Export"),
+        None);
+    dialog.set_current_name("audio.wav");
+    let window = window.clone();
+    let capture = capture.clone();
+    dialog.connect_response(move |dialog, response| {
+        let path = dialog.file().and_then(|file| file.path());
+        dialog.destroy();
+        if response != gtk::ResponseType::Accept {
+            return;
+        }
+        if let Some(path) = path {
+            let mut cap = capture.lock().unwrap();
+            match audio::export_wav(&mut cap, &path) {
+                Ok(streams) => {
+                    let lines: Vec<String> = streams.iter()
+                        .map(|(summary, path)|
+                            format!("{}\n  to {}", summary, path.display()))
+                        .collect();
+                    show_message(&window, "Audio exported",
+                                 &lines.join("\n"));
+                },
+                Err(err) => show_message(&window, "Failed to export audio",
+                                         &err.to_string()),
+            }
+        }
+    });
+    dialog.show();
+}
+
+/// Ask for a folder, then export the frames of the capture's video
+/// streams into it as image files.
+fn export_video(window: &gtk::ApplicationWindow,
+                capture: &Arc<Mutex<Capture>>)
+{
+    let dialog = gtk::FileChooserNative::new(
+        Some("Export video frames to folder"),
+        Some(window),
+        gtk::FileChooserAction::SelectFolder,
+        Some("Export"),
+        None);
+    let window = window.clone();
+    let capture = capture.clone();
+    dialog.connect_response(move |dialog, response| {
+        let path = dialog.file().and_then(|file| file.path());
+        dialog.destroy();
+        if response != gtk::ResponseType::Accept {
+            return;
+        }
+        if let Some(path) = path {
+            let mut cap = capture.lock().unwrap();
+            match video::export_frames(&mut cap, &path) {
+                Ok(streams) => {
+                    let lines: Vec<String> = streams.iter()
+                        .map(|(summary, count)|
+                            format!("{}: {} frames", summary, count))
+                        .collect();
+                    show_message(&window, "Video exported",
+                                 &lines.join("\n"));
+                },
+                Err(err) => show_message(&window, "Failed to export video",
+                                         &err.to_string()),
+            }
+        }
+    });
+    dialog.show();
+}
+
+/// Ask for a filename, then export the Ethernet frames sent to and from
+/// CDC networking devices as a pcap file.
+fn export_ethernet(window: &gtk::ApplicationWindow,
+                   capture: &Arc<Mutex<Capture>>)
+{
+    let dialog = gtk::FileChooserNative::new(
+        Some("Export as Ethernet pcap"),
+        Some(window),
+        gtk::FileChooserAction::Save,
+        Some("Export"),
+        None);
+    dialog.set_current_name("ethernet.pcap");
+    let window = window.clone();
+    let capture = capture.clone();
+    dialog.connect_response(move |dialog, response| {
+        let path = dialog.file().and_then(|file| file.path());
+        dialog.destroy();
+        if response != gtk::ResponseType::Accept {
+            return;
+        }
+        if let Some(path) = path {
+            let mut cap = capture.lock().unwrap();
+            match network::export_ethernet(&mut cap, &path) {
+                Ok(count) => show_message(&window, "Ethernet frames exported",
+                    &format!("{} frames written to {}",
+                             count, path.display())),
+                Err(err) => show_message(&window,
+                                         "Failed to export Ethernet frames",
+                                         &err.to_string()),
+            }
+        }
+    });
+    dialog.show();
+}
+
+/// Ask for a filename, then export the blocks read from and written to
+/// mass storage devices as disk images.
+fn export_disk_image(window: &gtk::ApplicationWindow,
+                     capture: &Arc<Mutex<Capture>>)
+{
+    let dialog = gtk::FileChooserNative::new(
+        Some("Export disk image"),
+        Some(window),
+        gtk::FileChooserAction::Save,
+        Some("Export"),
+        None);
+    dialog.set_current_name("disk.img");
+    let window = window.clone();
+    let capture = capture.clone();
+    dialog.connect_response(move |dialog, response| {
+        let path = dialog.file().and_then(|file| file.path());
+        dialog.destroy();
+        if response != gtk::ResponseType::Accept {
+            return;
+        }
+        if let Some(path) = path {
+            let mut cap = capture.lock().unwrap();
+            match msc::export_images(&mut cap, &path) {
+                Ok(images) => {
+                    let lines: Vec<String> = images.iter()
+                        .map(|(summary, path)|
+                            format!("{}\n  to {}", summary, path.display()))
+                        .collect();
+                    show_message(&window, "Disk images exported",
+                                 &lines.join("\n"));
+                },
+                Err(err) => show_message(&window,
+                                         "Failed to export disk images",
+                                         &err.to_string()),
+            }
+        }
+    });
+    dialog.show();
+}
+
+/// Show a window for changing the view preferences, which are applied
+/// through a CSS provider as they are changed, and saved. A change to the
+/// detail of summaries, or to how times are shown, is applied to the
+/// window's capture, and its traffic view redrawn.
+fn show_view_settings(window: &gtk::ApplicationWindow,
+                      provider: &gtk::CssProvider,
+                      capture: &Arc<Mutex<Capture>>,
+                      traffic_model: &model::Model)
+{
+    use capture::{TimeFormat, Verbosity};
+    use preferences::{Preferences, RowDensity, MIN_FONT_SIZE, MAX_FONT_SIZE};
+    let prefs = Preferences::load();
+    let default_font = gtk::CheckButton::with_label("Default font size");
+    default_font.set_active(prefs.font_size.is_none());
+    let font_size = gtk::SpinButton::with_range(
+        MIN_FONT_SIZE as f64, MAX_FONT_SIZE as f64, 1.0);
+    font_size.set_value(prefs.font_size.unwrap_or(10) as f64);
+    font_size.set_sensitive(prefs.font_size.is_some());
+    let density = gtk::ComboBoxText::new();
+    for name in [RowDensity::Compact.name(), RowDensity::Comfortable.name()] {
+        density.append(Some(name), name);
+    }
+    density.set_active_id(Some(prefs.row_density.name()));
+    let verbosity = gtk::ComboBoxText::new();
+    for level in [Verbosity::Short, Verbosity::Normal, Verbosity::Verbose] {
+        verbosity.append(Some(level.name()), level.name());
+    }
+    verbosity.set_active_id(Some(prefs.verbosity.name()));
+    let time_format = gtk::ComboBoxText::new();
+    for format in [TimeFormat::Hidden, TimeFormat::Absolute,
+                   TimeFormat::Relative, TimeFormat::SiblingDelta]
+    {
+        time_format.append(Some(format.name()), format.name());
+    }
+    time_format.set_active_id(Some(prefs.time_format.name()));
+    let apply = {
+        let provider = provider.clone();
+        let capture = capture.clone();
+        let traffic_model = traffic_model.clone();
+        let default_font = default_font.clone();
+        let font_size = font_size.clone();
+        let density = density.clone();
+        let verbosity = verbosity.clone();
+        let time_format = time_format.clone();
+        move || {
+            font_size.set_sensitive(!default_font.is_active());
+            let prefs = Preferences {
+                font_size: if default_font.is_active() {
+                    None
+                } else {
+                    Some(font_size.value_as_int() as u32)
+                },
+                row_density: match density.active_id().as_deref() {
+                    Some("comfortable") => RowDensity::Comfortable,
+                    _ => RowDensity::Compact,
+                },
+                verbosity: verbosity.active_id()
+                    .and_then(|name| Verbosity::from_name(&name))
+                    .unwrap_or(Verbosity::Verbose),
+                time_format: time_format.active_id()
+                    .and_then(|name| TimeFormat::from_name(&name))
+                    .unwrap_or(TimeFormat::Hidden),
+            };
+            provider.load_from_data(prefs.css().as_bytes());
+            let changed = {
+                let mut cap = capture.lock().unwrap();
+                let changed = cap.verbosity != prefs.verbosity ||
+                    cap.time_format != prefs.time_format;
+                cap.verbosity = prefs.verbosity;
+                cap.time_format = prefs.time_format;
+                changed
+            };
+            if changed {
+                traffic_model.refresh();
+            }
+            if let Err(err) = prefs.save() {
+                eprintln!("Failed to save preferences: {}", err);
+            }
+        }
+    };
+    let apply_font = apply.clone();
+    default_font.connect_toggled(move |_| apply_font());
+    let apply_size = apply.clone();
+    font_size.connect_value_changed(move |_| apply_size());
+    let apply_density = apply.clone();
+    density.connect_changed(move |_| apply_density());
+    let apply_verbosity = apply.clone();
+    verbosity.connect_changed(move |_| apply_verbosity());
+    time_format.connect_changed(move |_| apply());
+    let grid = gtk::Grid::builder()
+        .row_spacing(6)
+        .column_spacing(12)
+        .margin_top(12)
+        .margin_bottom(12)
+        .margin_start(12)
+        .margin_end(12)
+        .build();
+    grid.attach(&default_font, 0, 0, 2, 1);
+    grid.attach(&Label::new(Some("Font size")), 0, 1, 1, 1);
+    grid.attach(&font_size, 1, 1, 1, 1);
+    grid.attach(&Label::new(Some("Row spacing")), 0, 2, 1, 1);
+    grid.attach(&density, 1, 2, 1, 1);
+    grid.attach(&Label::new(Some("Packet detail")), 0, 3, 1, 1);
+    grid.attach(&verbosity, 1, 3, 1, 1);
+    grid.attach(&Label::new(Some("Times")), 0, 4, 1, 1);
+    grid.attach(&time_format, 1, 4, 1, 1);
+    let settings_window = gtk::Window::builder()
+        .title("View settings")
+        .transient_for(window)
+        .child(&grid)
+        .build();
+    settings_window.show();
+}
+
+/// Show a window for labelling the devices in the capture and their
+/// endpoints. Labels are saved for the device's VID:PID, so that they are
+/// shown again in other captures of the same device.
+fn show_labels(window: &gtk::ApplicationWindow,
+               capture: &Arc<Mutex<Capture>>,
+               traffic_model: &model::Model,
+               device_model: &model::DeviceModel)
+{
+    use labels::{LabelKey, Labels};
+    let grid = gtk::Grid::builder()
+        .row_spacing(6)
+        .column_spacing(12)
+        .margin_top(12)
+        .margin_bottom(12)
+        .margin_start(12)
+        .margin_end(12)
+        .build();
+    let mut entries = Vec::new();
+    {
+        let mut cap = capture.lock().unwrap();
+        let device_count = cap.devices.len();
+        let endpoint_count = cap.endpoints.len();
+        let devices = cap.devices.get_range(0..device_count).unwrap();
+        let endpoints = cap.endpoints.get_range(0..endpoint_count).unwrap();
+        for (device_id, device) in devices.iter().enumerate() {
+            let descriptor = match cap.device_data[device_id].device_descriptor
+            {
+                Some(descriptor) => descriptor,
+                None => continue,
+            };
+            let key = LabelKey {
+                vendor_id: descriptor.vendor_id,
+                product_id: descriptor.product_id,
+                endpoint: None,
+            };
+            let mut rows = vec![(key, format!(
+                "Device {} ({})", device.address, key))];
+            for endpoint in endpoints.iter().filter(|endpoint|
+                endpoint.device_id() as usize == device_id &&
+                endpoint.number() < 16)
+            {
+                let address = endpoint.endpoint_address();
+                rows.push((LabelKey { endpoint: Some(address), ..key },
+                           format!("    Endpoint {}",
+                                   capture::fmt_endpoint_address(address))));
+            }
+            for (key, name) in rows {
+                let label = Label::new(Some(&name));
+                label.set_xalign(0.0);
+                let entry = gtk::Entry::new();
+                entry.set_text(cap.labels.get(&key).unwrap_or(""));
+                let row = entries.len() as i32;
+                grid.attach(&label, 0, row, 1, 1);
+                grid.attach(&entry, 1, row, 1, 1);
+                entries.push((key, entry));
+            }
+        }
+    }
+    if entries.is_empty() {
+        show_message(window, "No devices to label",
+                     "Labels are given to devices whose descriptors were \
+                      seen in the capture.");
+        return;
+    }
+    let save_button = gtk::Button::with_label("Save");
+    grid.attach(&save_button, 1, entries.len() as i32, 1, 1);
+    let labels_window = gtk::Window::builder()
+        .title("Labels")
+        .transient_for(window)
+        .child(&grid)
+        .build();
+    let window = window.clone();
+    let capture = capture.clone();
+    let traffic_model = traffic_model.clone();
+    let device_model = device_model.clone();
+    let close_window = labels_window.clone();
+    save_button.connect_clicked(move |_| {
+        let mut saved = Labels::load();
+        {
+            let mut cap = capture.lock().unwrap();
+            for (key, entry) in &entries {
+                saved.set(*key, &entry.text());
+                cap.labels.set(*key, &entry.text());
+            }
+        }
+        if let Err(err) = saved.save() {
+            show_message(&window, "Failed to save labels", &err.to_string());
+        }
+        traffic_model.refresh();
+        device_model.refresh();
+        close_window.close();
+    });
+    labels_window.show();
+}
+
+/// Show a message in a dialog.
+fn show_message(window: &gtk::ApplicationWindow, text: &str, detail: &str) {
+    let message = gtk::MessageDialog::builder()
+        .transient_for(window)
+        .modal(true)
+        .buttons(gtk::ButtonsType::Close)
+        .text(text)
+        .secondary_text(detail)
+        .build();
+    message.connect_response(|message, _| message.close());
+    message.show();
+}
+
+/// Decode a capture from a sequence of files, or a set of files to be
+/// merged, or load it from a project, then run the post-decode checks on
+/// it. Returns the capture along with the notifications raised by rules.
+fn open_capture(filenames: &[String], rules: Vec<Rule>, merge: bool,
+                keep: Option<input::Retention>, trigger: Option<Trigger>,
+                record: Option<&str>)
+    -> Result<(Capture, Vec<String>), String>
+{
+    let is_project = match filenames {
+        [filename] if filename != input::STDIN_FILENAME =>
+            input::detect_format(filename).ok().flatten() ==
+                Some(input::FileFormat::Project),
+        _ => false,
+    };
+    let mut notifications = Vec::new();
+    let storage = storage::CaptureStorage::for_source(filenames)
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to create storage directory: {}", err);
+            storage::CaptureStorage::default()
+        });
+    let mut cap = if is_project {
+        if !rules.is_empty() {
+            eprintln!("Warning: rules are not applied to a saved project");
+        }
+        if keep.is_some() || trigger.is_some() {
+            eprintln!("Warning: a saved project is always opened in full");
+        }
+        let result = std::fs::File::open(&filenames[0])
+            .map_err(project::ProjectError::from)
+            .and_then(|file| project::load_project(
+                &mut std::io::BufReader::new(file), storage));
+        result.map_err(|err| format!("Failed to open project {}: {}",
+                                     filenames[0], err))?
+    } else {
+        let mut cap = Capture::with_storage(storage);
+        let (alert_sender, alert_receiver) = channel();
+        let mut decoder = Decoder::new(&mut cap);
+        decoder.add_observer(Box::new(RuleEngine::new(rules, alert_sender)));
+        if merge {
+            input::merge_files(&mut decoder, filenames)
+                .map_err(|err| err.to_string())?;
+        } else if let Some(retention) = keep {
+            let result =
+                input::decode_files_keeping(&mut decoder, filenames, retention);
+            match result {
+                Ok((warnings, dropped)) => {
+                    for warning in warnings {
+                        eprintln!("Warning: {}", warning);
+                    }
+                    if dropped > 0 {
+                        eprintln!("Dropped the first {} packets, outside \
+                                   the --keep-last limit", fmt_count(dropped));
+                    }
+                },
+                Err(err) => return Err(err.to_string()),
+            }
+        } else if let Some(mut trigger) = trigger {
+            let result = input::decode_files_triggered(
+                &mut decoder, filenames, &mut trigger);
+            match result {
+                Ok((warnings, position)) => {
+                    for warning in warnings {
+                        eprintln!("Warning: {}", warning);
+                    }
+                    match position {
+                        Some(position) => eprintln!(
+                            "Triggered at packet {}", fmt_count(position)),
+                        None => return Err(
+                            "The trigger never matched, so nothing was \
+                             recorded".to_string()),
+                    }
+                },
+                Err(err) => return Err(err.to_string()),
+            }
+        } else if let Some(record) = record {
+            let result =
+                input::decode_files_recording(&mut decoder, filenames, record);
+            match result {
+                Ok(warnings) => for warning in warnings {
+                    eprintln!("Warning: {}", warning);
+                },
+                Err(err) => return Err(err.to_string()),
+            }
+        } else {
+            match input::decode_files(&mut decoder, filenames) {
+                Ok(warnings) => for warning in warnings {
+                    eprintln!("Warning: {}", warning);
+                },
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+        drop(decoder);
+        for alert in alert_receiver.try_iter() {
+            match alert.action {
+                Action::Highlight => if let Some(transfer_index_id) =
+                    alert.transfer_index_id
+                {
+                    cap.set_highlighted(transfer_index_id);
+                },
+                Action::Notify => notifications.push(alert.message),
+            }
+        }
+        cap
+    };
+    match msc::check_capture(&mut cap) {
+        0 => {},
+        count => eprintln!("Warning: {} mass storage errors found", count),
+    }
+    halts::check_capture(&mut cap);
+    for halt in &cap.halts {
+        eprintln!("Warning: {}", halt.description());
+    }
+    // The user's own labels take precedence over any saved in a project.
+    cap.labels.merge(&labels::Labels::load());
+    Ok((cap, notifications))
+}
+
+/// Run a batch script, returning the number of steps which failed.
+fn run_batch(path: &std::path::Path) -> Result<usize, batch::BatchError> {
+    let script = batch::Script::load(path)?;
+    let files = script.capture_files()?;
+    std::fs::create_dir_all(&script.output)?;
+    let mut failures = 0;
+    for file in &files {
+        println!("{}", file.display());
+        let filenames = [file.to_string_lossy().into_owned()];
+        let mut cap = match open_capture(&filenames, Vec::new(), false,
+                                         None, None, None)
+        {
+            Ok((cap, _)) => cap,
+            Err(err) => {
+                println!("  FAILED: {}", err);
+                failures += script.steps.len();
+                continue;
+            }
+        };
+        failures += run_steps(&mut cap, &script.steps, &script.output, file);
+    }
+    println!("{} captures, {} failed steps", fmt_count(files.len() as u64),
+             fmt_count(failures as u64));
+    Ok(failures)
+}
+
+/// Run the steps of a batch script on a capture, writing their files to
+/// the output directory. Returns the number of steps which failed.
+fn run_steps(cap: &mut Capture, steps: &[batch::Step],
+             output: &std::path::Path, file: &std::path::Path) -> usize
+{
+    let mut failures = 0;
+    for step in steps {
+        let output = step.output_path(output, file);
+        let path = output.to_string_lossy();
+        let result = match &step.action {
+            batch::Action::Export(_) =>
+                export_file(cap, &path, &step.filter, None),
+            batch::Action::Summary(_) => {
+                let summary = summary::capture_summary(cap);
+                std::fs::write(&output, summary.lines().join("\n") + "\n")
+            },
+            batch::Action::Report(_) => {
+                let report = quality::quality_report(cap);
+                std::fs::write(&output, report.lines().join("\n") + "\n")
+            },
+        };
+        match result {
+            Ok(()) => println!("  wrote {}", path),
+            Err(err) => {
+                println!("  FAILED to write {}: {}", path, err);
+                failures += 1;
+            },
+        }
+    }
+    failures
+}
+
+/// Watch a directory for new captures, and write a summary and report for
+/// each one to the output directory, until interrupted. With
+/// `flag_warnings`, the warnings found in a capture are also listed in a
+/// file of their own, so that captures with warnings stand out.
+fn run_watch(dir: &std::path::Path, output: &std::path::Path,
+             flag_warnings: bool) -> std::io::Result<()>
+{
+    std::fs::create_dir_all(output)?;
+    let steps = watch::watch_steps();
+    let mut watcher = watch::Watcher::new(dir);
+    println!("Watching {} for new captures", dir.display());
+    loop {
+        for file in watcher.poll()? {
+            // Captures analyzed by an earlier run are left alone.
+            if steps[0].output_path(output, &file).exists() {
+                continue;
+            }
+            println!("{}", file.display());
+            let filenames = [file.to_string_lossy().into_owned()];
+            let mut cap = match open_capture(&filenames, Vec::new(), false,
+                                             None, None, None)
+            {
+                Ok((cap, _)) => cap,
+                Err(err) => {
+                    println!("  FAILED: {}", err);
+                    continue;
+                }
+            };
+            run_steps(&mut cap, &steps, output, &file);
+            if !flag_warnings {
+                continue;
+            }
+            let summary = summary::capture_summary(&mut cap);
+            if summary.warnings() > 0 {
+                let flagged = batch::output_path(output, watch::FLAGGED_PATH,
+                                                 &file);
+                let lines = summary.warning_lines().join("\n") + "\n";
+                match std::fs::write(&flagged, lines) {
+                    Ok(()) => println!("  FLAGGED with {} warnings",
+                                       fmt_count(summary.warnings())),
+                    Err(err) => println!("  FAILED to write {}: {}",
+                                         flagged.display(), err),
+                }
+            }
+        }
+        std::thread::sleep(watch::POLL_INTERVAL);
+    }
+}
+
+const USAGE: &str = "\
+Usage: packetry [OPTIONS] [FILE...]
+       packetry selftest [--record DIR | --baseline DIR] [CORPUS]
+       packetry anonymize INPUT OUTPUT
+       packetry batch SCRIPT
+       packetry watch [--flag-warnings] DIR [OUTPUT]
+
+FILE may be a pcap, pcapng or usbmon text capture, a Total Phase CSV
+export, a hex dump with a packet to a line, a project, or - to read a pcap
+stream from standard input.
+Captures compressed with gzip or zstd are decompressed as they are opened.
+Multiple files are joined in the order given, as parts of one capture.
+With no FILE, an empty window is opened, from which one can be opened.
+
+A link of the form packetry://open?file=PATH&goto=ITEM&filter=FILTER may
+be given instead, to open files with the --goto and --filter options.
+
+Options:
+  --merge               Merge the files in timestamp order instead
+  --keep-last LIMIT     Decode only the end of the input, as a size or
+                        duration such as 100MB or 30min
+  --trigger CONDITION   Decode from the first packet matching address N,
+                        request N (a SETUP's bRequest) or pattern HEX
+  --pre-trigger COUNT   Packets to keep from before the trigger (1000)
+  --record PATH         Write the pcap stream read from standard input to
+                        PATH as it arrives
+  --memory-limit SIZE   Keep the memory used for per-endpoint indexes and
+                        caches to about SIZE, such as 64MB, keeping the
+                        rest in storage files
+  --filter FILTER       Show only traffic matching ADDRESS[.ENDPOINT], or
+                        export only its packets to a .pcap file
+  --packets FIRST-LAST  Export only these packet numbers to a .pcap file
+  --goto ITEM           Select an item, given as transfer:T,
+                        transaction:T.X or packet:T.X.P
+  --highlight RULE      Highlight transfers matching a rule
+  --notify RULE         Send a notification for transfers matching a rule
+  --export PATH         Export to PATH instead of opening the UI
+  --report              Report how much traffic could not be decoded
+  --headless            Print a summary of the capture without the UI
+  --query NAME          Print the transfers matching a saved query
+  --verify              Check the decoder's output for inconsistencies
+  --help                Show this help";
+
+/// Parse a range of packet numbers, given as FIRST-LAST inclusive.
+fn packet_range(text: &str) -> Option<Range<u64>> {
+    let (first, last) = text.split_once('-')?;
+    let first: u64 = first.parse().ok()?;
+    let last: u64 = last.parse().ok()?;
+    (first <= last).then(|| first..last + 1)
+}
+
+/// Report a mistake in the command line, and exit.
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    std::process::exit(2);
+}
+
+/// Take the value given for an option.
+fn option_value(args: &mut impl Iterator<Item=String>, option: &str)
+    -> String
+{
+    args.next().unwrap_or_else(||
+        usage_error(&format!("Missing value for {}", option)))
 }
 
 fn main() {
+    let _profiler = profiling::start();
+    let mut filenames = Vec::new();
+    let mut rules = Vec::new();
+    let mut verify = false;
+    let mut merge = false;
+    let mut keep = None;
+    let mut trigger_condition = None;
+    let mut pre_trigger = trigger::DEFAULT_HISTORY;
+    let mut record = None;
+    let mut report = false;
+    let mut headless = false;
+    let mut query = None;
+    let mut export_path = None;
+    let mut packets = None;
+    let mut filter = String::new();
+    let mut goto = None;
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("selftest") {
+        args.next();
+        let mode = match args.peek().map(String::as_str) {
+            Some(option @ ("--record" | "--baseline")) => {
+                let option = option.to_string();
+                args.next();
+                let baseline = option_value(&mut args, &option);
+                Some((option, baseline))
+            },
+            _ => None,
+        };
+        let dir = args.next()
+            .or_else(|| std::env::var(corpus::CORPUS_VAR).ok())
+            .unwrap_or_else(|| "./tests".to_string());
+        let output = &mut std::io::stdout();
+        let result = match &mode {
+            None => corpus::run_selftest(dir.as_ref(), output),
+            Some((option, baseline)) if option == "--record" =>
+                corpus::record_baseline(dir.as_ref(), baseline.as_ref(),
+                                        output),
+            Some((_, baseline)) =>
+                corpus::compare_baseline(dir.as_ref(), baseline.as_ref(),
+                                         output),
+        };
+        match result {
+            Ok(0) => return,
+            Ok(_) => std::process::exit(1),
+            Err(err) => {
+                eprintln!("Failed to read corpus {}: {}", dir, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if args.peek().map(String::as_str) == Some("batch") {
+        args.next();
+        let script = args.next().unwrap_or_else(||
+            usage_error("A batch script must be given"));
+        clean_stale_sessions();
+        if let Err(err) = storage::start_session() {
+            eprintln!("Failed to create storage directory: {}", err);
+        }
+        let result = run_batch(script.as_ref());
+        storage::end_session().unwrap_or_else(|err|
+            eprintln!("Failed to remove storage files: {}", err));
+        match result {
+            Ok(0) => return,
+            Ok(_) => std::process::exit(1),
+            Err(err) => {
+                eprintln!("Failed to run batch script {}: {}", script, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if args.peek().map(String::as_str) == Some("watch") {
+        args.next();
+        let flag_warnings = args.peek().map(String::as_str) ==
+            Some("--flag-warnings");
+        if flag_warnings {
+            args.next();
+        }
+        let dir = args.next().unwrap_or_else(||
+            usage_error("A directory to watch must be given"));
+        let output = args.next().unwrap_or_else(|| dir.clone());
+        // No storage session is started, so that the storage of each
+        // capture is removed once it has been analyzed.
+        if let Err(err) = run_watch(dir.as_ref(), output.as_ref(),
+                                    flag_warnings)
+        {
+            eprintln!("Failed to watch {}: {}", dir, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.peek().map(String::as_str) == Some("anonymize") {
+        args.next();
+        let (input, output) = match (args.next(), args.next()) {
+            (Some(input), Some(output)) => (input, output),
+            _ => usage_error("Input and output files must be given"),
+        };
+        match anonymize::anonymize_file(&input, &output) {
+            Ok((modified, count)) => println!(
+                "Anonymized {} of {} packets, written to {}",
+                fmt_count(modified), fmt_count(count), output),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    while let Some(arg) = args.next() {
+        let action = match arg.as_str() {
+            "--verify" => {
+                verify = true;
+                continue;
+            },
+            "--merge" => {
+                merge = true;
+                continue;
+            },
+            "--keep-last" => {
+                let limit = option_value(&mut args, &arg);
+                keep = Some(limit.parse::<input::Retention>()
+                    .unwrap_or_else(|err| usage_error(&err.to_string())));
+                continue;
+            },
+            "--trigger" => {
+                let condition = option_value(&mut args, &arg);
+                trigger_condition = Some(condition
+                    .parse::<trigger::TriggerCondition>()
+                    .unwrap_or_else(|err| usage_error(&err.to_string())));
+                continue;
+            },
+            "--pre-trigger" => {
+                let count = option_value(&mut args, &arg);
+                pre_trigger = count.parse().unwrap_or_else(|_|
+                    usage_error(&format!("Invalid packet count {}", count)));
+                continue;
+            },
+            "--record" => {
+                record = Some(option_value(&mut args, &arg));
+                continue;
+            },
+            "--memory-limit" => {
+                let limit = option_value(&mut args, &arg);
+                let megabytes = limit.strip_suffix("MB")
+                    .and_then(|count| count.parse::<u64>().ok())
+                    .filter(|&count| count > 0)
+                    .unwrap_or_else(|| usage_error(&format!(
+                        "Invalid memory limit {}, expected a number of \
+                         megabytes such as 64MB", limit)));
+                storage::set_memory_limits(
+                    storage::MemoryLimits::for_megabytes(megabytes));
+                continue;
+            },
+            "--report" => {
+                report = true;
+                continue;
+            },
+            "--headless" => {
+                headless = true;
+                continue;
+            },
+            "--query" => {
+                let name = option_value(&mut args, &arg);
+                query = Some(queries::load_queries().into_iter()
+                    .find(|query| query.name == name)
+                    .unwrap_or_else(|| usage_error(
+                        &format!("No saved query named '{}'", name))));
+                continue;
+            },
+            "--export" => {
+                export_path = Some(option_value(&mut args, &arg));
+                continue;
+            },
+            "--packets" => {
+                let range = option_value(&mut args, &arg);
+                packets = Some(packet_range(&range).unwrap_or_else(||
+                    usage_error(&format!("Invalid packet range {}", range))));
+                continue;
+            },
+            "--filter" => {
+                filter = option_value(&mut args, &arg);
+                if let Err(err) = filter.parse::<filter::Filter>() {
+                    usage_error(&err.to_string());
+                }
+                continue;
+            },
+            "--goto" => {
+                let item = option_value(&mut args, &arg);
+                goto = Some(item.parse::<capture::Item>()
+                    .unwrap_or_else(|err| usage_error(&err.to_string())));
+                continue;
+            },
+            "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            "--notify" => Action::Notify,
+            "--highlight" => Action::Highlight,
+            option if option.starts_with("--") =>
+                usage_error(&format!("Unknown option {}", option)),
+            link if deeplink::is_link(link) => {
+                let link = link.parse::<deeplink::DeepLink>()
+                    .unwrap_or_else(|err| usage_error(&err.to_string()));
+                filenames.extend(link.files);
+                goto = link.goto.or(goto);
+                if !link.filter.is_empty() {
+                    filter = link.filter;
+                }
+                continue;
+            },
+            // Files opened from the desktop are given as URIs.
+            uri if uri.starts_with("file://") => {
+                match gtk::gio::File::for_uri(uri).path() {
+                    Some(path) =>
+                        filenames.push(path.to_string_lossy().into_owned()),
+                    None => usage_error(&format!("Invalid file URI {}", uri)),
+                }
+                continue;
+            },
+            _ => {
+                filenames.push(arg);
+                continue;
+            }
+        };
+        let condition = option_value(&mut args, &arg);
+        rules.push(Rule::new(action, &condition).unwrap_or_else(|err|
+            usage_error(&format!("Invalid rule '{}': {}", condition, err))));
+    }
+
+    // Without files, only the UI can be opened, to choose one from there.
+    if packets.is_some() && export_path.is_none() {
+        usage_error("--packets can only be used with --export");
+    }
+    if keep.is_some() && (merge || verify) {
+        usage_error("--keep-last cannot be used with --merge or --verify");
+    }
+    if trigger_condition.is_some() && (merge || verify || keep.is_some()) {
+        usage_error("--trigger cannot be used with --merge, --verify or \
+                     --keep-last");
+    }
+    if record.is_some() && (merge || verify || keep.is_some() ||
+        trigger_condition.is_some())
+    {
+        usage_error("--record cannot be used with --merge, --verify, \
+                     --keep-last or --trigger");
+    }
+    if record.is_some() && filenames != [input::STDIN_FILENAME] {
+        usage_error("--record can only be used when reading standard input");
+    }
+    let trigger = trigger_condition.map(|condition|
+        Trigger::new(condition, pre_trigger));
+    if filenames.is_empty() && (verify || report || headless || merge ||
+        export_path.is_some() || !filter.is_empty() || goto.is_some() ||
+        !rules.is_empty() || keep.is_some() || trigger.is_some() ||
+        query.is_some())
+    {
+        usage_error("No capture file given");
+    }
+    let stdin_count = filenames.iter()
+        .filter(|name| name.as_str() == input::STDIN_FILENAME)
+        .count();
+    if stdin_count > 1 || (stdin_count == 1 && verify) {
+        eprintln!("Standard input can only be read once");
+        std::process::exit(1);
+    }
+    // Files to be shown in the UI are passed on to an instance which is
+    // already running, unless this one has to read them itself.
+    let forward = !filenames.is_empty() &&
+        !verify && !report && !headless && !merge &&
+        export_path.is_none() && filter.is_empty() && goto.is_none() &&
+        stdin_count == 0 && rules.is_empty() && keep.is_none() &&
+        trigger.is_none() && query.is_none();
     let application = gtk::Application::new(
         Some("com.greatscottgadgets.packetry"),
-        Default::default(),
+        if forward {
+            gtk::gio::ApplicationFlags::HANDLES_OPEN
+        } else {
+            gtk::gio::ApplicationFlags::HANDLES_OPEN |
+                gtk::gio::ApplicationFlags::NON_UNIQUE
+        },
     );
-
-    let args: Vec<_> = std::env::args().collect();
-    let mut pcap = pcap::Capture::from_file(&args[1]).unwrap();
-    let mut cap = Capture::new();
-    let mut decoder = Decoder::new(&mut cap);
-    while let Ok(packet) = pcap.next() {
-        decoder.handle_raw_packet(&packet);
+    if forward {
+        match application.register(gtk::gio::Cancellable::NONE) {
+            Ok(()) if application.is_remote() => {
+                let files: Vec<gtk::gio::File> = filenames.iter()
+                    .map(gtk::gio::File::for_path)
+                    .collect();
+                application.open(&files, JOIN_HINT);
+                return;
+            },
+            Ok(()) => {},
+            Err(err) => eprintln!("Failed to register application: {}", err),
+        }
+    }
+    // A restored capture is opened if no other was given.
+    let restored = clean_stale_sessions();
+    if filenames.is_empty() {
+        filenames = restored;
+    }
+    if let Err(err) = storage::start_session() {
+        eprintln!("Failed to create storage directory: {}", err);
     }
+    // A streamed capture is recorded as it arrives, so that it can be
+    // restored if this session does not end cleanly.
+    let recovery = stdin_count == 1 && record.is_none() && !merge &&
+        keep.is_none() && trigger.is_none();
+    if recovery {
+        let command = std::env::args().collect::<Vec<String>>().join(" ");
+        match storage::start_recovery(&command) {
+            Ok(path) => record = Some(path.to_string_lossy().into_owned()),
+            Err(err) => eprintln!("Failed to start recording: {}", err),
+        }
+    }
+    if verify {
+        let result = verify::verify_files(&filenames);
+        storage::end_session().unwrap_or_else(|err|
+            eprintln!("Failed to remove storage files: {}", err));
+        match result {
+            Ok(()) => println!("Decode verified, no inconsistencies found"),
+            Err(err) => {
+                eprintln!("Verification failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let opened = open_capture(&filenames, rules, merge, keep, trigger,
+                              record.as_deref());
+    let (mut cap, notifications) = match opened {
+        Ok(opened) => opened,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if report {
+        let report = quality::quality_report(&mut cap);
+        storage::end_session().unwrap_or_else(|err|
+            eprintln!("Failed to remove storage files: {}", err));
+        for line in report.lines() {
+            println!("{}", line);
+        }
+        if !report.is_clean() {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(path) = export_path {
+        let filter = filter.parse::<filter::Filter>().unwrap();
+        let result = export_file(&mut cap, &path, &filter, packets);
+        storage::end_session().unwrap_or_else(|err|
+            eprintln!("Failed to remove storage files: {}", err));
+        if let Err(err) = result {
+            eprintln!("Failed to export to {}: {}", path, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(query) = query {
+        let result = query.run(&mut cap);
+        let found = match result {
+            Ok(found) => found,
+            Err(err) => {
+                eprintln!("Failed to run query {}: {}", query.name, err);
+                std::process::exit(1);
+            }
+        };
+        for item_index in found {
+            let transfer_index_id = cap.item_index.get(item_index).unwrap();
+            let item = capture::Item::Transfer(transfer_index_id);
+            println!("{}  {}", item, cap.get_summary(&item));
+        }
+        storage::end_session().unwrap_or_else(|err|
+            eprintln!("Failed to remove storage files: {}", err));
+        return;
+    }
+    if headless {
+        let summary = summary::capture_summary(&mut cap);
+        storage::end_session().unwrap_or_else(|err|
+            eprintln!("Failed to remove storage files: {}", err));
+        for line in summary.lines() {
+            println!("{}", line);
+        }
+        return;
+    }
+    // A capture recorded only for recovery is removed when the session
+    // ends, so it must be saved to be kept.
+    cap.unsaved = recovery;
     cap.print_storage_summary();
+    println!("Storage files on disk: {}", fmt_size(storage::session_size()));
     let capture = Arc::new(Mutex::new(cap));
+    let title = filenames.join(", ");
 
     application.connect_activate(move |application| {
-        let window = gtk::ApplicationWindow::builder()
-            .default_width(320)
-            .default_height(480)
-            .application(application)
-            .title("Packetry")
-            .build();
+        open_window(application, &capture, &title, &filter, goto.as_ref());
+        for message in &notifications {
+            let notification = gtk::gio::Notification::new("Packetry");
+            notification.set_body(Some(message));
+            application.send_notification(None, &notification);
+        }
+    });
+    application.connect_open(open_files);
+    application.run_with_args::<&str>(&[]);
+    storage::end_session().unwrap_or_else(|err|
+        eprintln!("Failed to remove storage files: {}", err));
+}
 
-        let listview = create_view::
-            <capture::Item, model::Model, row_data::RowData>(&capture);
+/// Hint given with files passed on from the command line of another
+/// instance, which are decoded together as one capture. Files opened from
+/// elsewhere, such as a file manager, are each opened as a capture.
+const JOIN_HINT: &str = "join";
 
-        let scrolled_window = gtk::ScrolledWindow::builder()
-            .hscrollbar_policy(gtk::PolicyType::Automatic) // Disable horizontal scrolling
-            .min_content_height(480)
-            .min_content_width(640)
-            .build();
+/// Show an error in the active window, if there is one.
+fn show_error(application: &gtk::Application, text: &str, detail: &str) {
+    match application.active_window()
+        .and_then(|window| window.downcast::<gtk::ApplicationWindow>().ok())
+    {
+        Some(window) => show_message(&window, text, detail),
+        None => eprintln!("{}: {}", text, detail),
+    }
+}
 
-        scrolled_window.set_child(Some(&listview));
+/// Open files passed to the application, each in a new window once it
+/// has been decoded.
+///
+/// Files may be given by a desktop file manager, including through the
+/// document portal when sandboxed, whose paths are usable as they are.
+fn open_files(application: &gtk::Application, files: &[gtk::gio::File],
+              hint: &str)
+{
+    let mut filenames = Vec::new();
+    for file in files {
+        match file.path() {
+            Some(path) => filenames.push(path.to_string_lossy().into_owned()),
+            None => show_error(application, "Only local files can be opened",
+                               &file.uri()),
+        }
+    }
+    let captures = if hint == JOIN_HINT {
+        vec![filenames]
+    } else {
+        filenames.into_iter().map(|filename| vec![filename]).collect()
+    };
+    for filenames in captures.into_iter().filter(|names| !names.is_empty()) {
+        // Decoding may take a while, so it is done on a worker thread to
+        // keep the open windows responsive. The application is held until
+        // the result arrives, so that it does not exit in the meantime.
+        let (sender, receiver) =
+            gtk::glib::MainContext::channel(gtk::glib::PRIORITY_DEFAULT);
+        let title = filenames.join(", ");
+        std::thread::spawn(move || {
+            let result =
+                open_capture(&filenames, Vec::new(), false, None, None, None);
+            sender.send(result.map(|(cap, _)| cap)).unwrap();
+        });
+        application.hold();
+        let application = application.clone();
+        receiver.attach(None, move |result| {
+            match result {
+                Ok(cap) => open_window(&application,
+                                       &Arc::new(Mutex::new(cap)),
+                                       &title, "", None),
+                Err(err) => show_error(&application, "Failed to open capture",
+                                       &err),
+            }
+            application.release();
+            gtk::glib::Continue(false)
+        });
+    }
+}
 
-        let device_tree = create_view::<capture::DeviceItem,
-                                        model::DeviceModel,
-                                        row_data::DeviceRowData>(&capture);
-        let device_window = gtk::ScrolledWindow::builder()
-            .hscrollbar_policy(gtk::PolicyType::Automatic)
-            .min_content_height(480)
-            .min_content_width(240)
-            .child(&device_tree)
-            .build();
+/// Open a capture file in a new window which takes the place of an old
+/// one. Returns whether it was opened.
+fn open_in_place(window: &gtk::ApplicationWindow, file: &gtk::gio::File)
+    -> bool
+{
+    let application = match window.application() {
+        Some(application) => application,
+        None => return false,
+    };
+    let filename = match file.path() {
+        Some(path) => path.to_string_lossy().into_owned(),
+        None => {
+            show_message(window, "Only local files can be opened",
+                         &file.uri());
+            return false;
+        }
+    };
+    match open_capture(std::slice::from_ref(&filename), Vec::new(), false,
+                       None, None, None)
+    {
+        Ok((cap, _)) => {
+            open_window(&application, &Arc::new(Mutex::new(cap)),
+                        &filename, "", None);
+            // The old window may ask to save its capture before closing.
+            window.close();
+            true
+        },
+        Err(err) => {
+            show_message(window, "Failed to open capture", &err);
+            false
+        }
+    }
+}
 
-        let paned = gtk::Paned::builder()
-            .orientation(Orientation::Horizontal)
-            .wide_handle(true)
-            .start_child(&scrolled_window)
-            .end_child(&device_window)
-            .build();
+/// Open a window showing a capture, titled with the files it came from,
+/// with a filter applied to the traffic view and an item selected in it.
+fn open_window(application: &gtk::Application,
+               capture: &Arc<Mutex<Capture>>,
+               title: &str,
+               filter: &str,
+               goto: Option<&capture::Item>)
+{
+    let builder = gtk::Builder::from_string(WINDOW_UI);
+    let window: gtk::ApplicationWindow = ui_object(&builder, "window");
+    window.set_application(Some(application));
+    if title.is_empty() {
+        window.set_title(Some("Packetry"));
+    } else {
+        window.set_title(Some(&format!("Packetry - {}", title)));
+    }
+
+    let mut css = String::from(
+        ".highlighted { background-color: alpha(@warning_color, 0.3); }\n");
+    match expander::connector_font(&window.pango_context()) {
+        Some(family) => css.push_str(&format!(
+            ".{} {{ font-family: \"{}\"; }}\n",
+            expander::CONNECTORS_CLASS, family)),
+        None => eprintln!(
+            "Warning: no monospace font found, connectors may not align"),
+    }
+    let provider = gtk::CssProvider::new();
+    provider.load_from_data(css.as_bytes());
+    gtk::StyleContext::add_provider_for_display(
+        &gtk::gdk::Display::default().expect("No display available"),
+        &provider,
+        gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
+    let prefs = preferences::Preferences::load();
+    {
+        let mut cap = capture.lock().unwrap();
+        cap.verbosity = prefs.verbosity;
+        cap.time_format = prefs.time_format;
+    }
+    let view_provider = gtk::CssProvider::new();
+    view_provider.load_from_data(prefs.css().as_bytes());
+    gtk::StyleContext::add_provider_for_display(
+        &gtk::gdk::Display::default().expect("No display available"),
+        &view_provider,
+        gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
+
+    let (listview, traffic_model) = create_view::
+        <capture::Item, model::Model, row_data::RowData>(capture);
+    let settings_model = traffic_model.clone();
+
+    let scrolled_window: gtk::ScrolledWindow =
+        ui_object(&builder, "traffic_window");
+    scrolled_window.set_child(Some(&listview));
+
+    let (device_tree, device_model) = create_view::<capture::DeviceItem,
+                                    model::DeviceModel,
+                                    row_data::DeviceRowData>(capture);
+    let device_window: gtk::ScrolledWindow =
+        ui_object(&builder, "device_window");
+    device_window.set_child(Some(&device_tree));
+    let device_slot: gtk::Box = ui_object(&builder, "device_slot");
 
-        window.set_child(Some(&paned));
-        window.show();
+    for popover in ["analyze_popover", "export_popover"] {
+        close_on_click(&ui_object(&builder, popover));
+    }
+    let button = |id| -> gtk::Button { ui_object(&builder, id) };
+    let save_button = button("save_button");
+    let open_button = button("open_button");
+    let chooser_window = window.clone();
+    open_button.connect_clicked(move |_| {
+        choose_capture(&chooser_window);
     });
-    application.run_with_args::<&str>(&[]);
+    let hex_button = button("hex_button");
+    let hex_window = window.clone();
+    hex_button.connect_clicked(move |_| {
+        import_hex_dump(&hex_window);
+    });
+    let descriptors_button = button("descriptors_button");
+    let keyboard_button = button("keyboard_button");
+    let keyboard_window = window.clone();
+    let keyboard_capture = capture.clone();
+    keyboard_button.connect_clicked(move |_| {
+        show_keyboard_text(&keyboard_window, &keyboard_capture);
+    });
+    let pointer_button = button("pointer_button");
+    let pointer_window = window.clone();
+    let pointer_capture = capture.clone();
+    pointer_button.connect_clicked(move |_| {
+        show_pointer_paths(&pointer_window, &pointer_capture);
+    });
+    let pcapng_button = button("pcapng_button");
+    let pcapng_window = window.clone();
+    let pcapng_capture = capture.clone();
+    pcapng_button.connect_clicked(move |_| {
+        save_pcapng(&pcapng_window, &pcapng_capture, false);
+    });
+    let project_button = button("project_button");
+    let project_window = window.clone();
+    let project_capture = capture.clone();
+    project_button.connect_clicked(move |_| {
+        save_project(&project_window, &project_capture);
+    });
+    let requests_button = button("requests_button");
+    let requests_window = window.clone();
+    let requests_capture = capture.clone();
+    requests_button.connect_clicked(move |_| {
+        save_request_log(&requests_window, &requests_capture);
+    });
+    let audio_button = button("audio_button");
+    let audio_window = window.clone();
+    let audio_capture = capture.clone();
+    audio_button.connect_clicked(move |_| {
+        export_audio(&audio_window, &audio_capture);
+    });
+    let video_button = button("video_button");
+    let video_window = window.clone();
+    let video_capture = capture.clone();
+    video_button.connect_clicked(move |_| {
+        export_video(&video_window, &video_capture);
+    });
+    let ethernet_button = button("ethernet_button");
+    let ethernet_window = window.clone();
+    let ethernet_capture = capture.clone();
+    ethernet_button.connect_clicked(move |_| {
+        export_ethernet(&ethernet_window, &ethernet_capture);
+    });
+    let disk_button = button("disk_button");
+    let disk_window = window.clone();
+    let disk_capture = capture.clone();
+    disk_button.connect_clicked(move |_| {
+        export_disk_image(&disk_window, &disk_capture);
+    });
+    let quality_button = button("quality_button");
+    let quality_window = window.clone();
+    let quality_capture = capture.clone();
+    quality_button.connect_clicked(move |_| {
+        let mut cap = quality_capture.lock().unwrap();
+        let report = quality::quality_report(&mut cap);
+        show_message(&quality_window,
+                     if report.is_clean() {
+                         "All traffic was decoded"
+                     } else {
+                         "Some traffic could not be decoded"
+                     },
+                     &report.lines().join("\n"));
+    });
+    let schedule_button = button("schedule_button");
+    let schedule_window = window.clone();
+    let schedule_capture = capture.clone();
+    schedule_button.connect_clicked(move |_| {
+        let mut cap = schedule_capture.lock().unwrap();
+        let report = scheduling::analyze_schedule(&mut cap);
+        let lines = report.lines();
+        if lines.is_empty() {
+            show_message(&schedule_window,
+                         "No periodic endpoint traffic found",
+                         "Interrupt and isochronous endpoints can only be \
+                          analyzed when SOF packets were captured.");
+        } else {
+            show_message(&schedule_window,
+                         "Periodic endpoint scheduling",
+                         &lines.join("\n"));
+        }
+    });
+    let descriptors_window = window.clone();
+    let descriptors_capture = capture.clone();
+    let descriptors_view = listview.clone();
+    let descriptors_model = traffic_model.clone();
+    let descriptors_device_view = device_tree.clone();
+    descriptors_button.connect_clicked(move |_| {
+        show_descriptor_reads(&descriptors_window,
+                              &descriptors_capture,
+                              &descriptors_view,
+                              &descriptors_model,
+                              &descriptors_device_view);
+    });
+    let filter_entry: gtk::SearchEntry =
+        ui_object(&builder, "filter_entry");
+    if let Some(parsed) = filter.parse::<filter::Filter>().ok()
+        .filter(|parsed| !parsed.is_empty())
+    {
+        filter_entry.set_text(filter);
+        traffic_model.set_filter(&parsed);
+    }
+    if let Some(item) = goto {
+        if !select_item(&listview, &traffic_model, capture, item) {
+            eprintln!("Item {} was not found in the traffic view", item);
+        }
+    }
+    let queries_button = button("queries_button");
+    let queries_window = window.clone();
+    let queries_capture = capture.clone();
+    let queries_view = listview.clone();
+    let queries_model = traffic_model.clone();
+    let queries_entry = filter_entry.clone();
+    queries_button.connect_clicked(move |_| {
+        show_queries(&queries_window, &queries_capture, &queries_view,
+                     &queries_model, &queries_entry);
+    });
+    let labels_button = button("labels_button");
+    let labels_window = window.clone();
+    let labels_capture = capture.clone();
+    let labels_model = traffic_model.clone();
+    labels_button.connect_clicked(move |_| {
+        show_labels(&labels_window, &labels_capture, &labels_model,
+                    &device_model);
+    });
+    filter_entry.connect_activate(move |entry| {
+        match entry.text().parse::<filter::Filter>() {
+            Ok(filter) => {
+                entry.remove_css_class("error");
+                entry.set_tooltip_text(None);
+                traffic_model.set_filter(&filter);
+            },
+            Err(err) => {
+                entry.add_css_class("error");
+                entry.set_tooltip_text(Some(&err.to_string()));
+            }
+        }
+    });
+    // The filtered export takes the filter as entered, even if it has not
+    // yet been applied.
+    let filtered_button = button("filtered_button");
+    let filtered_window = window.clone();
+    let filtered_capture = capture.clone();
+    let filtered_entry = filter_entry.clone();
+    filtered_button.connect_clicked(move |_| {
+        match filtered_entry.text().parse::<filter::Filter>() {
+            Ok(filter) =>
+                save_filtered_pcap(&filtered_window, &filtered_capture,
+                                   filter),
+            Err(err) =>
+                show_message(&filtered_window, "Invalid filter",
+                             &err.to_string()),
+        }
+    });
+    let settings_button = button("settings_button");
+    let settings_window = window.clone();
+    let settings_capture = capture.clone();
+    settings_button.connect_clicked(move |_| {
+        show_view_settings(&settings_window, &view_provider,
+                           &settings_capture, &settings_model);
+    });
+    let details_button = button("details_button");
+    let details_window = window.clone();
+    let details_capture = capture.clone();
+    let details_view = listview.clone();
+    details_button.connect_clicked(move |_| {
+        show_details(&details_window, &details_capture, &details_view);
+    });
+    let devices_button: gtk::ToggleButton =
+        ui_object(&builder, "devices_button");
+    let devices_main_window = window.clone();
+    let devices_window: std::cell::RefCell<Option<gtk::Window>> =
+        Default::default();
+    // The device view is moved between the main window and its own
+    // window, so its selection is kept either way.
+    devices_button.connect_toggled(move |button| {
+        if button.is_active() {
+            device_slot.remove(&device_window);
+            device_slot.hide();
+            let popout = gtk::Window::builder()
+                .title("Devices")
+                .transient_for(&devices_main_window)
+                .default_width(320)
+                .default_height(480)
+                .child(&device_window)
+                .build();
+            let button = button.clone();
+            popout.connect_close_request(move |_| {
+                button.set_active(false);
+                gtk::Inhibit(true)
+            });
+            popout.show();
+            devices_window.replace(Some(popout));
+        } else if let Some(popout) = devices_window.take() {
+            popout.set_child(None::<&gtk::Widget>);
+            popout.destroy();
+            device_slot.append(&device_window);
+            device_slot.show();
+        }
+    });
+    let group_button: gtk::ToggleButton =
+        ui_object(&builder, "group_button");
+    let control_button: gtk::ToggleButton =
+        ui_object(&builder, "control_button");
+    let group_window = scrolled_window.clone();
+    let group_capture = capture.clone();
+    let group_filter = filter_entry.clone();
+    let flat_view = listview.clone();
+    // The grouped view is kept while its grouping is unchanged, so that
+    // it keeps its expanded rows.
+    let group_view: std::cell::RefCell<Option<(GroupMode, ListView)>> =
+        Default::default();
+    let show_traffic = std::rc::Rc::new(move |mode: Option<GroupMode>| {
+        // The filter only applies to the flat view.
+        group_filter.set_sensitive(mode.is_none());
+        let mode = match mode {
+            Some(mode) => mode,
+            None => {
+                group_window.set_child(Some(&flat_view));
+                return;
+            }
+        };
+        let mut group_view = group_view.borrow_mut();
+        if !matches!(group_view.as_ref(),
+                     Some((view_mode, _)) if *view_mode == mode)
+        {
+            group_capture.lock().unwrap().set_group_mode(mode);
+            let view = create_view::<
+                capture::GroupItem,
+                model::GroupModel,
+                row_data::GroupRowData>(&group_capture).0;
+            group_view.replace((mode, view));
+        }
+        group_window.set_child(Some(&group_view.as_ref().unwrap().1));
+    });
+    for (button, other, mode) in [
+        (&group_button, &control_button, GroupMode::Interface),
+        (&control_button, &group_button, GroupMode::Control),
+    ] {
+        let other = other.clone();
+        let show_traffic = show_traffic.clone();
+        button.connect_toggled(move |button| {
+            if button.is_active() {
+                other.set_active(false);
+                show_traffic(Some(mode));
+            } else if !other.is_active() {
+                show_traffic(None);
+            }
+        });
+    }
+    let drop_target = gtk::DropTarget::new(
+        gtk::gio::File::static_type(), gtk::gdk::DragAction::COPY);
+    let drop_window = window.clone();
+    drop_target.connect_drop(move |_, value, _, _| {
+        match value.get::<gtk::gio::File>() {
+            Ok(file) => open_in_place(&drop_window, &file),
+            Err(_) => false,
+        }
+    });
+    window.add_controller(&drop_target);
+    let save_window = window.clone();
+    let save_view_window = scrolled_window.clone();
+    save_button.connect_clicked(move |_| {
+        save_view(&save_window, &save_view_window);
+    });
+    let close_capture = capture.clone();
+    window.connect_close_request(move |window| {
+        if close_capture.lock().unwrap().unsaved {
+            confirm_close(window, &close_capture);
+            gtk::Inhibit(true)
+        } else {
+            gtk::Inhibit(false)
+        }
+    });
+
+    window.show();
+    let mut cap = capture.lock().unwrap();
+    // A window opened without a capture has nothing to summarize.
+    if cap.packet_index.len() > 0 {
+        let summary = summary::capture_summary(&mut cap);
+        show_message(&window, "Capture summary",
+                     &summary.lines().join("\n"));
+    }
 }
@@ -6,19 +6,108 @@ use gtk::{gio, glib, prelude::*};
 use crate::capture::{self, Capture};
 
 use std::cell::RefCell;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::{Arc, Mutex};
-use crate::row_data::{RowData, DeviceRowData};
+use crate::row_data::{RowData, DeviceRowData, GroupRowData};
+
+/// Describe an item, showing an error in place of its summary if that
+/// fails, so that one item which cannot be decoded does not take down the
+/// whole view. The item and the failure are logged.
+///
+/// The capture stays locked and in use for further items after a failure.
+/// Describing an item only changes the capture by filling caches, such as
+/// the payload totals and traffic groups, and by parking and unparking
+/// endpoint indexes. Each of these is updated only once it is complete, so
+/// a failure part way through leaves the capture as it was before.
+pub(super) fn describe<T>(item: impl fmt::Debug,
+                          describe: impl FnOnce() -> T,
+                          error: impl FnOnce(String) -> T)
+    -> T
+{
+    catch_unwind(AssertUnwindSafe(describe)).unwrap_or_else(|payload| {
+        let message = payload.downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown error".to_string());
+        eprintln!("Failed to decode item {:?}: {}", item, message);
+        error(format!("\u{26A0} failed to decode item: {}", message))
+    })
+}
+
+/// Describe an item of the traffic tree, giving its summary, connectors
+/// and whether it is highlighted.
+pub(super) fn describe_item(cap: &mut Capture,
+                            item: &capture::Item,
+                            previous: Option<&capture::Item>)
+    -> (String, String, bool)
+{
+    let (summary, connectors) = describe(
+        item,
+        || {
+            let summary = cap.get_summary(item);
+            let summary = match cap.get_time(item, previous) {
+                Some(time) => format!("{}  {}", time, summary),
+                None => summary,
+            };
+            (summary, cap.get_connectors(item))
+        },
+        |error| (error, String::new()));
+    (summary, connectors, cap.is_highlighted(item))
+}
+
+/// Weak references to the objects a model has handed out, so that those
+/// still in use can be updated in place.
+pub struct Live<T: ObjectType>(RefCell<Vec<glib::WeakRef<T>>>);
+
+impl<T: ObjectType> Default for Live<T> {
+    fn default() -> Self {
+        Live(RefCell::new(Vec::new()))
+    }
+}
+
+impl<T: ObjectType> Live<T> {
+    pub(super) fn add(&self, object: &T) {
+        let mut refs = self.0.borrow_mut();
+        // Drop the references to finalized objects before the list would
+        // grow, so that its length stays in proportion to the live ones.
+        if refs.len() == refs.capacity() {
+            refs.retain(|weak| weak.upgrade().is_some());
+        }
+        refs.push(object.downgrade());
+    }
+
+    pub(super) fn get(&self) -> Vec<T> {
+        let mut refs = self.0.borrow_mut();
+        refs.retain(|weak| weak.upgrade().is_some());
+        refs.iter().filter_map(|weak| weak.upgrade()).collect()
+    }
+}
 
 #[derive(Default)]
 pub struct Model {
     pub(super) capture: RefCell<Arc<Mutex<Capture>>>,
     pub(super) parent: RefCell<Option<capture::Item>>,
+    /// Indices of the top-level items shown, if a filter is applied.
+    pub(super) filtered: RefCell<Option<Vec<u64>>>,
+    pub(super) rows: Live<RowData>,
+    pub(super) children: Live<super::Model>,
 }
 
 #[derive(Default)]
 pub struct DeviceModel {
     pub(super) capture: RefCell<Arc<Mutex<Capture>>>,
     pub(super) parent: RefCell<Option<capture::DeviceItem>>,
+    pub(super) rows: Live<DeviceRowData>,
+    pub(super) children: Live<super::DeviceModel>,
+}
+
+#[derive(Default)]
+pub struct GroupModel {
+    pub(super) capture: RefCell<Arc<Mutex<Capture>>>,
+    pub(super) parent: RefCell<Option<capture::GroupItem>>,
+    pub(super) rows: Live<GroupRowData>,
+    pub(super) children: Live<super::GroupModel>,
 }
 
 /// Basic declaration of our type for the GObject type system
@@ -35,24 +124,54 @@ impl ObjectSubclass for DeviceModel {
     type Interfaces = (gio::ListModel,);
 
 }
+#[glib::object_subclass]
+impl ObjectSubclass for GroupModel {
+    const NAME: &'static str = "GroupModel";
+    type Type = super::GroupModel;
+    type Interfaces = (gio::ListModel,);
+}
 
 impl ObjectImpl for Model {}
 impl ObjectImpl for DeviceModel {}
+impl ObjectImpl for GroupModel {}
 
 impl ListModelImpl for Model {
     fn item_type(&self, _list_model: &Self::Type) -> glib::Type {
         RowData::static_type()
     }
     fn n_items(&self, _list_model: &Self::Type) -> u32 {
+        if let Some(filtered) = self.filtered.borrow().as_ref() {
+            return filtered.len() as u32;
+        }
         self.capture.borrow().lock().unwrap().item_count(&self.parent.borrow()) as u32
     }
     fn item(&self, _list_model: &Self::Type, position: u32) -> Option<glib::Object> {
         let arc = self.capture.borrow();
         let mut cap = arc.lock().unwrap();
-        let item = cap.get_item(&self.parent.borrow(), position as u64);
-        let summary = cap.get_summary(&item);
-        let connectors = cap.get_connectors(&item);
-        Some(RowData::new(Some(item), summary, connectors).upcast::<glib::Object>())
+        let index = match self.filtered.borrow().as_ref() {
+            Some(filtered) => *filtered.get(position as usize)?,
+            None => position as u64,
+        };
+        let parent = self.parent.borrow();
+        let item = cap.get_item(&parent, index);
+        // A delta is from the previous sibling, which is found whatever
+        // the time format, so that a refresh can switch to showing deltas.
+        let previous = match position {
+            0 => parent.clone(),
+            _ => {
+                let index = match self.filtered.borrow().as_ref() {
+                    Some(filtered) => filtered[position as usize - 1],
+                    None => index - 1,
+                };
+                Some(cap.get_item(&parent, index))
+            },
+        };
+        let (summary, connectors, highlighted) =
+            describe_item(&mut cap, &item, previous.as_ref());
+        let row = RowData::new(Some(item), previous);
+        row.set_text(summary, connectors, highlighted);
+        self.rows.add(&row);
+        Some(row.upcast::<glib::Object>())
     }
 }
 
@@ -67,7 +186,30 @@ impl ListModelImpl for DeviceModel {
         let arc = self.capture.borrow();
         let mut cap = arc.lock().unwrap();
         let item = cap.get_device_item(&self.parent.borrow(), position as u64);
-        let summary = cap.get_device_summary(&item);
-        Some(DeviceRowData::new(Some(item), summary).upcast::<glib::Object>())
+        let summary = describe(&item, || cap.get_device_summary(&item), |e| e);
+        let row = DeviceRowData::new(Some(item), summary);
+        self.rows.add(&row);
+        Some(row.upcast::<glib::Object>())
+    }
+}
+
+impl ListModelImpl for GroupModel {
+    fn item_type(&self, _list_model: &Self::Type) -> glib::Type {
+        GroupRowData::static_type()
+    }
+    fn n_items(&self, _list_model: &Self::Type) -> u32 {
+        self.capture.borrow().lock().unwrap()
+            .group_item_count(&self.parent.borrow()) as u32
+    }
+    fn item(&self, _list_model: &Self::Type, position: u32)
+        -> Option<glib::Object>
+    {
+        let arc = self.capture.borrow();
+        let mut cap = arc.lock().unwrap();
+        let item = cap.get_group_item(&self.parent.borrow(), position as u64);
+        let summary = describe(&item, || cap.get_group_summary(&item), |e| e);
+        let row = GroupRowData::new(Some(item), summary);
+        self.rows.add(&row);
+        Some(row.upcast::<glib::Object>())
     }
 }
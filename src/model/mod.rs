@@ -4,10 +4,13 @@ mod imp;
 
 use std::sync::{Arc, Mutex};
 
+use gtk::prelude::*;
 use gtk::subclass::prelude::*;
 use gtk::{gio, glib};
 
 use crate::capture::{self, Capture};
+use crate::filter::{diff, Filter};
+use crate::row_data::GenericRowData;
 
 // Public part of the Model type.
 glib::wrapper! {
@@ -16,11 +19,21 @@ glib::wrapper! {
 glib::wrapper! {
     pub struct DeviceModel(ObjectSubclass<imp::DeviceModel>) @implements gio::ListModel;
 }
+glib::wrapper! {
+    pub struct GroupModel(ObjectSubclass<imp::GroupModel>) @implements gio::ListModel;
+}
 
 pub trait GenericModel<Item> {
     fn new(capture: Arc<Mutex<Capture>>, parent: Option<Item>) -> Self;
     fn set_capture(&mut self, capture: Arc<Mutex<Capture>>);
     fn set_parent(&mut self, parent: Option<Item>);
+    /// Create a model for the children of an item, which is refreshed
+    /// along with this one.
+    fn child_model(&self, parent: Option<Item>) -> Self;
+    /// Describe the rows already handed out again, updating any whose text
+    /// has changed. Unlike replacing the items, this keeps the expansion
+    /// state, scroll position and selection of the view.
+    fn refresh(&self);
 }
 
 // Constructor for new instances. This simply calls glib::Object::new()
@@ -39,6 +52,75 @@ impl GenericModel<capture::Item> for Model {
     fn set_parent(&mut self, parent: Option<capture::Item>) {
         self.imp().parent.replace(parent);
     }
+
+    fn child_model(&self, parent: Option<capture::Item>) -> Model {
+        let capture = self.imp().capture.borrow().clone();
+        let child = Model::new(capture, parent);
+        self.imp().children.add(&child);
+        child
+    }
+
+    fn refresh(&self) {
+        let rows: Vec<_> = std::iter::once(self.clone())
+            .chain(self.imp().children.get())
+            .flat_map(|model| model.imp().rows.get())
+            .collect();
+        // The rows are described with the capture locked, but updated
+        // after, as the view reacts to the updates as they are made.
+        let texts: Vec<_> = {
+            let arc = self.imp().capture.borrow();
+            let mut cap = arc.lock().unwrap();
+            rows.iter().map(|row| row.get_item().map(|item|
+                imp::describe_item(&mut cap, &item, row.previous().as_ref())
+            )).collect()
+        };
+        for (row, text) in rows.iter().zip(texts) {
+            if let Some((summary, connectors, highlighted)) = text {
+                row.set_text(summary, connectors, highlighted);
+            }
+        }
+    }
+}
+
+impl Model {
+    /// Apply a filter to the top-level items.
+    ///
+    /// Only the differences from the previous set of items are signalled,
+    /// so the view keeps its scroll position and the expansion state of
+    /// items which remain visible.
+    pub fn set_filter(&self, filter: &Filter) {
+        let (old, new) = {
+            let arc = self.imp().capture.borrow();
+            let mut cap = arc.lock().unwrap();
+            let old = match self.imp().filtered.borrow().as_ref() {
+                Some(filtered) => filtered.clone(),
+                None => (0..cap.item_index.len()).collect(),
+            };
+            (old, filter.apply(&mut cap))
+        };
+        let changes = diff(&old, &new);
+        // The list is updated one change at a time, so that it is as each
+        // signal describes when the view reads it.
+        self.imp().filtered.replace(Some(old));
+        for change in changes {
+            if let Some(filtered) = self.imp().filtered.borrow_mut().as_mut() {
+                change.apply(filtered, &new);
+            }
+            self.items_changed(change.position, change.removed, change.added);
+        }
+        if filter.is_empty() {
+            self.imp().filtered.replace(None);
+        }
+    }
+
+    /// Find the position of a top-level item, if it passes the filter.
+    pub fn item_position(&self, item_index: u64) -> Option<u32> {
+        match self.imp().filtered.borrow().as_ref() {
+            Some(filtered) => filtered.binary_search(&item_index).ok()
+                .map(|position| position as u32),
+            None => Some(item_index as u32),
+        }
+    }
 }
 
 impl GenericModel<capture::DeviceItem> for DeviceModel {
@@ -59,4 +141,78 @@ impl GenericModel<capture::DeviceItem> for DeviceModel {
     fn set_parent(&mut self, parent: Option<capture::DeviceItem>) {
         self.imp().parent.replace(parent);
     }
+
+    fn child_model(&self, parent: Option<capture::DeviceItem>) -> DeviceModel {
+        let capture = self.imp().capture.borrow().clone();
+        let child = DeviceModel::new(capture, parent);
+        self.imp().children.add(&child);
+        child
+    }
+
+    fn refresh(&self) {
+        let rows: Vec<_> = std::iter::once(self.clone())
+            .chain(self.imp().children.get())
+            .flat_map(|model| model.imp().rows.get())
+            .collect();
+        let summaries: Vec<_> = {
+            let arc = self.imp().capture.borrow();
+            let mut cap = arc.lock().unwrap();
+            rows.iter().map(|row| row.get_item().map(|item|
+                imp::describe(&item, || cap.get_device_summary(&item),
+                              |e| e)
+            )).collect()
+        };
+        for (row, summary) in rows.iter().zip(summaries) {
+            if let Some(summary) = summary {
+                row.set_summary(summary);
+            }
+        }
+    }
+}
+
+impl GenericModel<capture::GroupItem> for GroupModel {
+    fn new(capture: Arc<Mutex<Capture>>, parent: Option<capture::GroupItem>)
+        -> GroupModel
+    {
+        let mut model: GroupModel =
+            glib::Object::new(&[]).expect("Failed to create GroupModel");
+        model.set_capture(capture);
+        model.set_parent(parent);
+        model
+    }
+
+    fn set_capture(&mut self, capture: Arc<Mutex<Capture>>) {
+        self.imp().capture.replace(capture);
+    }
+
+    fn set_parent(&mut self, parent: Option<capture::GroupItem>) {
+        self.imp().parent.replace(parent);
+    }
+
+    fn child_model(&self, parent: Option<capture::GroupItem>) -> GroupModel {
+        let capture = self.imp().capture.borrow().clone();
+        let child = GroupModel::new(capture, parent);
+        self.imp().children.add(&child);
+        child
+    }
+
+    fn refresh(&self) {
+        let rows: Vec<_> = std::iter::once(self.clone())
+            .chain(self.imp().children.get())
+            .flat_map(|model| model.imp().rows.get())
+            .collect();
+        let summaries: Vec<_> = {
+            let arc = self.imp().capture.borrow();
+            let mut cap = arc.lock().unwrap();
+            rows.iter().map(|row| row.get_item().map(|item|
+                imp::describe(&item, || cap.get_group_summary(&item),
+                              |e| e)
+            )).collect()
+        };
+        for (row, summary) in rows.iter().zip(summaries) {
+            if let Some(summary) = summary {
+                row.set_summary(summary);
+            }
+        }
+    }
 }
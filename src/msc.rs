@@ -0,0 +1,509 @@
+//! Integrity checks on mass storage traffic.
+//!
+//! Each Bulk-Only Transport command consists of a Command Block Wrapper
+//! (CBW) sent by the host, an optional data stage, and a Command Status
+//! Wrapper (CSW) returned by the device. The CBW states how much data is
+//! expected, and the CSW reports a residue for any that was not processed,
+//! so the two can be checked against the data actually transferred.
+//!
+//! The SCSI commands in the CBWs also allow the blocks read from and
+//! written to the device to be collected into a disk image.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::audio::numbered_path;
+use crate::capture::{Capture, fmt_size};
+use crate::usb::{ClassTriple, PID};
+
+const BULK_ONLY_TRANSPORT: ClassTriple = ClassTriple {
+    class: 0x08,
+    subclass: 0x06,
+    protocol: 0x50,
+};
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_LENGTH: usize = 31;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_LENGTH: usize = 13;
+
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+
+/// An inconsistency found in a mass storage command.
+#[derive(Error, Copy, Clone, Debug, PartialEq)]
+pub enum MscError {
+    #[error("CBW with tag 0x{tag:08x} not followed by a CSW")]
+    MissingStatus { tag: u32 },
+    #[error("data transferred in the wrong direction for the CBW")]
+    WrongDirection,
+    #[error("{transferred} data bytes transferred, more than the {expected} \
+             requested by the CBW")]
+    DataOverrun { transferred: u64, expected: u32 },
+    #[error("CSW tag 0x{found:08x} does not match CBW tag 0x{expected:08x}")]
+    TagMismatch { found: u32, expected: u32 },
+    #[error("CSW residue {residue} inconsistent with {transferred} of \
+             {expected} bytes transferred")]
+    ResidueMismatch { residue: u32, transferred: u64, expected: u32 },
+}
+
+/// A problem exporting disk images.
+#[derive(Error, Debug)]
+pub enum ImageError {
+    #[error("no mass storage reads or writes found in the capture")]
+    NoData,
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn is_cbw(pid: PID, data: &[u8]) -> bool {
+    pid == PID::OUT &&
+        data.len() == CBW_LENGTH &&
+        read_u32(data, 0) == CBW_SIGNATURE
+}
+
+fn is_csw(pid: PID, data: &[u8]) -> bool {
+    pid == PID::IN &&
+        data.len() == CSW_LENGTH &&
+        read_u32(data, 0) == CSW_SIGNATURE
+}
+
+struct Command {
+    transaction_id: u64,
+    tag: u32,
+    length: u32,
+    direction_in: bool,
+    transferred: u64,
+    error_found: bool,
+}
+
+/// Whether the residue in a CSW agrees with a command's data stage.
+///
+/// For Data-In, the residue must account for exactly the data not sent.
+/// For Data-Out, it reports the data the device did not process, which
+/// may include data it received, so it can only be checked against the
+/// length the CBW expected.
+fn residue_valid(cmd: &Command, residue: u32) -> bool {
+    if cmd.direction_in {
+        residue as u64 + cmd.transferred == cmd.length as u64
+    } else {
+        residue <= cmd.length
+    }
+}
+
+/// Check the commands in a sequence of acknowledged bulk transactions,
+/// given as transaction ID, token PID and payload, in capture order.
+///
+/// Errors are returned with the ID of the transaction they were found at.
+pub fn check_transactions<I>(transactions: I) -> Vec<(u64, MscError)>
+    where I: IntoIterator<Item=(u64, PID, Vec<u8>)>
+{
+    let mut errors = Vec::new();
+    let mut command: Option<Command> = None;
+    for (transaction_id, pid, data) in transactions {
+        if is_cbw(pid, &data) {
+            if let Some(previous) = command.take() {
+                errors.push((previous.transaction_id,
+                             MscError::MissingStatus { tag: previous.tag }));
+            }
+            command = Some(Command {
+                transaction_id: transaction_id,
+                tag: read_u32(&data, 4),
+                length: read_u32(&data, 8),
+                direction_in: data[12] & 0x80 != 0,
+                transferred: 0,
+                error_found: false,
+            });
+        } else if is_csw(pid, &data) {
+            // A CSW without a CBW may be from before the capture started.
+            if let Some(cmd) = command.take() {
+                let tag = read_u32(&data, 4);
+                let residue = read_u32(&data, 8);
+                if tag != cmd.tag {
+                    errors.push((transaction_id, MscError::TagMismatch {
+                        found: tag,
+                        expected: cmd.tag,
+                    }));
+                } else if !cmd.error_found && !residue_valid(&cmd, residue) {
+                    errors.push((transaction_id, MscError::ResidueMismatch {
+                        residue: residue,
+                        transferred: cmd.transferred,
+                        expected: cmd.length,
+                    }));
+                }
+            }
+        } else if let Some(cmd) = command.as_mut() {
+            if cmd.error_found {
+                continue;
+            }
+            cmd.transferred += data.len() as u64;
+            if (pid == PID::IN) != cmd.direction_in {
+                cmd.error_found = true;
+                errors.push((transaction_id, MscError::WrongDirection));
+            } else if cmd.transferred > cmd.length as u64 {
+                cmd.error_found = true;
+                errors.push((transaction_id, MscError::DataOverrun {
+                    transferred: cmd.transferred,
+                    expected: cmd.length,
+                }));
+            }
+        }
+    }
+    if let Some(cmd) = command {
+        errors.push((cmd.transaction_id,
+                     MscError::MissingStatus { tag: cmd.tag }));
+    }
+    errors
+}
+
+/// The data of a logical unit seen in the capture, in blocks.
+#[derive(Default)]
+pub struct DiskImage {
+    pub address: u8,
+    pub lun: u8,
+    /// Block size, from READ CAPACITY if seen, or else inferred from the
+    /// length of the first read or write.
+    pub block_size: Option<u32>,
+    /// Number of blocks on the unit, from READ CAPACITY if seen.
+    pub block_count: Option<u64>,
+    /// Data read or written, by LBA. Later transfers replace earlier ones.
+    pub blocks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl DiskImage {
+    pub fn summary(&self) -> String {
+        format!("Device {}, LUN {}: {} block{} seen, image size {}",
+                self.address, self.lun, self.blocks.len(),
+                if self.blocks.len() == 1 {""} else {"s"},
+                fmt_size(self.length()))
+    }
+
+    /// Length of the image, covering the whole unit if its capacity is
+    /// known, or otherwise up to the last block seen.
+    pub fn length(&self) -> u64 {
+        let block_size = self.block_size.unwrap_or(0) as u64;
+        let last_seen = self.blocks.keys().next_back()
+            .map_or(0, |lba| lba + 1);
+        self.block_count.unwrap_or(0).max(last_seen) * block_size
+    }
+
+    /// Add the result of a successful SCSI command to the image.
+    fn add(&mut self, cdb: &[u8], data: &[u8]) {
+        match cdb.first() {
+            Some(&READ_CAPACITY_10) if data.len() >= 8 => {
+                let last_lba = read_u32_be(data, 0) as u64;
+                let block_size = read_u32_be(data, 4);
+                if block_size != 0 {
+                    self.block_size = Some(block_size);
+                    self.block_count = Some(last_lba + 1);
+                }
+            },
+            Some(&READ_10) | Some(&WRITE_10) if cdb.len() >= 10 => {
+                let lba = read_u32_be(cdb, 2) as u64;
+                let count = u16::from_be_bytes([cdb[7], cdb[8]]) as usize;
+                if count == 0 || data.is_empty() {
+                    return;
+                }
+                let block_size = match self.block_size {
+                    Some(size) => size as usize,
+                    None if data.len() / count * count == data.len() => {
+                        let size = data.len() / count;
+                        self.block_size = Some(size as u32);
+                        size
+                    },
+                    None => return,
+                };
+                for (i, block) in data.chunks_exact(block_size)
+                    .take(count)
+                    .enumerate()
+                {
+                    self.blocks.insert(lba + i as u64, block.to_vec());
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// Write the blocks seen at their positions in the image, seeking
+    /// past any blocks not seen, which on most filesystems leaves holes.
+    pub fn write<W: Write + Seek>(&self, output: &mut W)
+        -> std::io::Result<()>
+    {
+        let block_size = self.block_size.unwrap_or(0) as u64;
+        for (lba, block) in &self.blocks {
+            output.seek(SeekFrom::Start(lba * block_size))?;
+            output.write_all(block)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collect the blocks read and written by the successful commands in a
+/// sequence of acknowledged bulk transactions, given as in
+/// [check_transactions], returning an image for each LUN.
+pub fn collect_images<I>(transactions: I) -> Vec<DiskImage>
+    where I: IntoIterator<Item=(u64, PID, Vec<u8>)>
+{
+    let mut images: BTreeMap<u8, DiskImage> = BTreeMap::new();
+    // LUN, command block and data of the command in progress.
+    let mut command: Option<(u8, Vec<u8>, Vec<u8>)> = None;
+    for (_, pid, data) in transactions {
+        if is_cbw(pid, &data) {
+            let lun = data[13] & 0x0F;
+            let cdb_length = (data[14] as usize).min(16);
+            command = Some((lun, data[15..15 + cdb_length].to_vec(),
+                            Vec::new()));
+        } else if is_csw(pid, &data) {
+            let status = data[12];
+            if let Some((lun, cdb, cmd_data)) = command.take() {
+                if status == 0 {
+                    images.entry(lun)
+                        .or_insert_with(|| DiskImage {
+                            lun: lun,
+                            ..Default::default()
+                        })
+                        .add(&cdb, &cmd_data);
+                }
+            }
+        } else if let Some((_, _, cmd_data)) = command.as_mut() {
+            cmd_data.extend(data);
+        }
+    }
+    images.into_values()
+        .filter(|image| !image.blocks.is_empty())
+        .collect()
+}
+
+/// Find the acknowledged transactions on a device's Bulk-Only Transport
+/// interfaces, in capture order. Their payloads are read from the capture
+/// as the transactions are taken.
+fn device_transactions(cap: &mut Capture, device_id: u64)
+    -> Option<impl Iterator<Item=(u64, PID, Vec<u8>)> + '_>
+{
+    let addresses: Vec<u8> = cap.device_data[device_id as usize]
+        .configuration()?
+        .interfaces.iter()
+        .filter(|iface|
+            iface.descriptor.class_triple() == BULK_ONLY_TRANSPORT)
+        .flat_map(|iface| iface.endpoint_descriptors.iter())
+        .map(|ep_desc| ep_desc.endpoint_address)
+        .collect();
+    if addresses.is_empty() {
+        return None;
+    }
+    let transaction_ids =
+        cap.get_device_transaction_ids(device_id, &addresses);
+    Some(transaction_ids
+        .into_iter()
+        .filter_map(|id| cap.get_acked_payload(id)
+            .map(|(pid, data)| (id, pid, data))))
+}
+
+/// Export the data of each logical unit read or written in the capture
+/// to a disk image. The first is written to the path given, and any
+/// others to numbered paths alongside it. Returns a summary of each
+/// image written, with its path.
+pub fn export_images(cap: &mut Capture, path: &Path)
+    -> Result<Vec<(String, PathBuf)>, ImageError>
+{
+    let mut paths = Vec::new();
+    for device_id in 0..cap.device_data.len() as u64 {
+        let address = cap.devices.get(device_id).unwrap().address;
+        let transactions = match device_transactions(cap, device_id) {
+            Some(transactions) => transactions,
+            None => continue,
+        };
+        for mut image in collect_images(transactions) {
+            image.address = address;
+            let image_path = numbered_path(path, paths.len());
+            let file = File::create(&image_path)?;
+            let mut output = BufWriter::new(&file);
+            image.write(&mut output)?;
+            output.flush()?;
+            drop(output);
+            file.set_len(image.length())?;
+            paths.push((image.summary(), image_path));
+        }
+    }
+    if paths.is_empty() {
+        return Err(ImageError::NoData);
+    }
+    Ok(paths)
+}
+
+/// Check all mass storage traffic in a capture, storing any errors found
+/// against the transactions concerned. Returns the number of errors.
+pub fn check_capture(cap: &mut Capture) -> usize {
+    profile!("msc::check_capture");
+    let mut count = 0;
+    for device_id in 0..cap.device_data.len() as u64 {
+        let transactions = match device_transactions(cap, device_id) {
+            Some(transactions) => transactions,
+            None => continue,
+        };
+        for (transaction_id, error) in check_transactions(transactions) {
+            cap.msc_errors.insert(transaction_id, error);
+            count += 1;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cbw(tag: u32, length: u32, direction_in: bool) -> Vec<u8> {
+        let mut data = vec![0; CBW_LENGTH];
+        data[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        data[4..8].copy_from_slice(&tag.to_le_bytes());
+        data[8..12].copy_from_slice(&length.to_le_bytes());
+        data[12] = if direction_in {0x80} else {0};
+        data
+    }
+
+    fn csw(tag: u32, residue: u32) -> Vec<u8> {
+        let mut data = vec![0; CSW_LENGTH];
+        data[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        data[4..8].copy_from_slice(&tag.to_le_bytes());
+        data[8..12].copy_from_slice(&residue.to_le_bytes());
+        data
+    }
+
+    fn scsi_cbw(tag: u32, length: u32, direction_in: bool, cdb: &[u8])
+        -> Vec<u8>
+    {
+        let mut data = cbw(tag, length, direction_in);
+        data[14] = cdb.len() as u8;
+        data[15..15 + cdb.len()].copy_from_slice(cdb);
+        data
+    }
+
+    fn rw_10(opcode: u8, lba: u32, count: u16) -> Vec<u8> {
+        let mut cdb = vec![opcode, 0];
+        cdb.extend(lba.to_be_bytes());
+        cdb.push(0);
+        cdb.extend(count.to_be_bytes());
+        cdb.push(0);
+        cdb
+    }
+
+    fn numbered(stages: Vec<(PID, Vec<u8>)>)
+        -> impl Iterator<Item=(u64, PID, Vec<u8>)>
+    {
+        stages.into_iter().enumerate()
+            .map(|(id, (pid, data))| (id as u64, pid, data))
+    }
+
+    fn check(stages: Vec<(PID, Vec<u8>)>) -> Vec<(u64, MscError)> {
+        check_transactions(numbered(stages))
+    }
+
+    #[test]
+    fn test_valid_commands() {
+        use PID::*;
+        assert!(check(vec![
+            (OUT, cbw(1, 1024, true)),
+            (IN, vec![0; 512]),
+            (IN, vec![0; 512]),
+            (IN, csw(1, 0)),
+            // Short read, with the shortfall reported as residue.
+            (OUT, cbw(2, 1024, true)),
+            (IN, vec![0; 36]),
+            (IN, csw(2, 988)),
+            (OUT, cbw(3, 512, false)),
+            (OUT, vec![0; 512]),
+            (IN, csw(3, 0)),
+            (OUT, cbw(4, 0, false)),
+            (IN, csw(4, 0)),
+            // Data written but not all processed by the device.
+            (OUT, cbw(5, 1024, false)),
+            (OUT, vec![0; 1024]),
+            (IN, csw(5, 512)),
+        ]).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        use PID::*;
+        assert!(check(vec![
+            (OUT, cbw(1, 1024, true)),
+            (IN, vec![0; 512]),
+            (IN, csw(1, 0)),
+            (OUT, cbw(2, 512, true)),
+            (IN, vec![0; 512]),
+            (IN, vec![0; 64]),
+            (IN, csw(2, 0)),
+            (OUT, cbw(3, 512, false)),
+            (IN, vec![0; 512]),
+            (IN, csw(3, 0)),
+            (OUT, cbw(4, 0, false)),
+            (IN, csw(5, 0)),
+            (OUT, cbw(6, 0, false)),
+            (OUT, cbw(7, 0, false)),
+            (OUT, cbw(8, 512, false)),
+            (OUT, vec![0; 512]),
+            (IN, csw(8, 1024)),
+        ]) == vec![
+            (2, MscError::ResidueMismatch {
+                residue: 0, transferred: 512, expected: 1024 }),
+            (5, MscError::DataOverrun { transferred: 576, expected: 512 }),
+            (8, MscError::WrongDirection),
+            (11, MscError::TagMismatch { found: 5, expected: 4 }),
+            (12, MscError::MissingStatus { tag: 6 }),
+            (13, MscError::MissingStatus { tag: 7 }),
+            (16, MscError::ResidueMismatch {
+                residue: 1024, transferred: 512, expected: 512 }),
+        ]);
+    }
+
+    #[test]
+    fn test_disk_image() {
+        use PID::*;
+        let mut failed = csw(4, 512);
+        failed[12] = 1;
+        let images = collect_images(numbered(vec![
+            (OUT, scsi_cbw(1, 8, true, &[READ_CAPACITY_10, 0, 0, 0, 0, 0,
+                                         0, 0, 0, 0])),
+            (IN, vec![0, 0, 0, 7, 0, 0, 2, 0]),
+            (IN, csw(1, 0)),
+            (OUT, scsi_cbw(2, 1024, true, &rw_10(READ_10, 1, 2))),
+            (IN, vec![1; 512]),
+            (IN, vec![2; 512]),
+            (IN, csw(2, 0)),
+            (OUT, scsi_cbw(3, 512, false, &rw_10(WRITE_10, 2, 1))),
+            (OUT, vec![3; 512]),
+            (IN, csw(3, 0)),
+            // A failed read, which must not be used.
+            (OUT, scsi_cbw(4, 512, true, &rw_10(READ_10, 5, 1))),
+            (IN, vec![4; 512]),
+            (IN, failed),
+        ]));
+        assert!(images.len() == 1);
+        let image = &images[0];
+        assert!(image.block_size == Some(512));
+        assert!(image.block_count == Some(8));
+        assert!(image.blocks.keys().cloned().collect::<Vec<u64>>() == [1, 2]);
+        assert!(image.length() == 4096);
+        let mut output = std::io::Cursor::new(Vec::new());
+        image.write(&mut output).unwrap();
+        let output = output.into_inner();
+        assert!(output.len() == 1536);
+        assert!(output[..512].iter().all(|byte| *byte == 0));
+        assert!(output[512..1024].iter().all(|byte| *byte == 1));
+        assert!(output[1024..].iter().all(|byte| *byte == 3));
+    }
+}
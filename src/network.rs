@@ -0,0 +1,345 @@
+//! Export of Ethernet frames carried by CDC-ECM and CDC-NCM devices.
+//!
+//! ECM sends each Ethernet frame as a bulk transfer of its own, ending with
+//! a short packet. NCM instead packs frames into NCM Transfer Blocks
+//! (NTBs), each of which has a header pointing to one or more datagram
+//! pointer tables (NDPs), which give the position of each frame in the
+//! block. The frames found are written to a pcap file, so that standard
+//! network tools can be used on them.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::capture::Capture;
+use crate::usb::PID;
+
+const COMMUNICATIONS_CLASS: u8 = 0x02;
+const ECM_SUBCLASS: u8 = 0x06;
+const NCM_SUBCLASS: u8 = 0x0D;
+const CDC_DATA_CLASS: u8 = 0x0A;
+
+const CS_INTERFACE: u8 = 0x24;
+const UNION_FUNCTIONAL: u8 = 0x06;
+
+const NTH16_SIGNATURE: &[u8; 4] = b"NCMH";
+const NTH32_SIGNATURE: &[u8; 4] = b"ncmh";
+
+/// Largest number of NDPs followed in one NTB, in case of loops.
+const MAX_NDPS: usize = 64;
+
+const PCAP_MAGIC: u32 = 0xA1B2C3D4;
+const LINKTYPE_ETHERNET: u32 = 1;
+const PCAP_SNAPLEN: u32 = 65535;
+
+#[derive(Error, Debug)]
+pub enum NetworkError {
+    #[error("no CDC-ECM or CDC-NCM interfaces found in the capture")]
+    NoInterfaces,
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// A problem found in an NCM Transfer Block.
+#[derive(Error, Copy, Clone, Debug, PartialEq)]
+pub enum NtbError {
+    #[error("NTB header signature not found")]
+    BadHeader,
+    #[error("NDP at offset {0} not valid")]
+    BadPointerTable(usize),
+    #[error("datagram at offset {offset} with length {length} \
+             outside the NTB")]
+    OutOfBounds { offset: usize, length: usize },
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<usize> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<usize> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+/// Find the datagrams in an NCM Transfer Block, with 16 or 32 bit
+/// format, returning the range of the block occupied by each.
+pub fn parse_ntb(block: &[u8]) -> Result<Vec<Range<usize>>, NtbError> {
+    let wide = match block.get(0..4) {
+        Some(signature) if signature == NTH16_SIGNATURE => false,
+        Some(signature) if signature == NTH32_SIGNATURE => true,
+        _ => return Err(NtbError::BadHeader),
+    };
+    let read = if wide {read_u32} else {read_u16};
+    // Offsets of the fields in the header and in each NDP.
+    let (first_ndp, next_ndp, entries) = if wide {
+        (12, 8, 16)
+    } else {
+        (10, 6, 8)
+    };
+    let entry_size = if wide {8} else {4};
+    let mut datagrams = Vec::new();
+    let mut ndp = read(block, first_ndp).ok_or(NtbError::BadHeader)?;
+    for _ in 0..MAX_NDPS {
+        if ndp == 0 {
+            break;
+        }
+        let bad_ndp = NtbError::BadPointerTable(ndp);
+        let signature = block.get(ndp..ndp + 3).ok_or(bad_ndp)?;
+        let expected: &[u8] = if wide {b"ncm"} else {b"NCM"};
+        if signature != expected {
+            return Err(bad_ndp);
+        }
+        let length = read_u16(block, ndp + 4).ok_or(bad_ndp)?;
+        let end = ndp + length;
+        let mut entry = ndp + entries;
+        while entry + entry_size <= end {
+            let offset = read(block, entry).ok_or(bad_ndp)?;
+            let length = read(block, entry + entry_size / 2)
+                .ok_or(bad_ndp)?;
+            if offset == 0 || length == 0 {
+                break;
+            }
+            if offset + length > block.len() {
+                return Err(NtbError::OutOfBounds {
+                    offset: offset,
+                    length: length,
+                });
+            }
+            datagrams.push(offset..offset + length);
+            entry += entry_size;
+        }
+        ndp = read(block, ndp + next_ndp).ok_or(bad_ndp)?;
+    }
+    Ok(datagrams)
+}
+
+/// A CDC networking function, with the bulk endpoints of its data
+/// interface.
+pub struct NetworkFunction {
+    pub device_id: u64,
+    pub address: u8,
+    pub ncm: bool,
+    /// Number, direction (true for IN) and maximum packet size of the
+    /// bulk endpoints.
+    pub endpoints: Vec<(u8, bool, usize)>,
+}
+
+/// Find the CDC-ECM and CDC-NCM functions in the capture.
+pub fn find_network_functions(cap: &mut Capture) -> Vec<NetworkFunction> {
+    let mut functions = Vec::new();
+    for device_id in 0..cap.device_data.len() {
+        let config = match cap.device_data[device_id].configuration() {
+            Some(config) => config,
+            None => continue,
+        };
+        for iface in &config.interfaces {
+            let desc = &iface.descriptor;
+            let ncm = match (desc.interface_class, desc.interface_subclass) {
+                (COMMUNICATIONS_CLASS, ECM_SUBCLASS) => false,
+                (COMMUNICATIONS_CLASS, NCM_SUBCLASS) => true,
+                _ => continue,
+            };
+            // The union descriptor names the data interface, which
+            // otherwise is usually the next one.
+            let data_number = iface.other_descriptors.iter()
+                .find(|d| d.len() >= 5 && d[1] == CS_INTERFACE &&
+                          d[2] == UNION_FUNCTIONAL)
+                .map(|d| d[4])
+                .unwrap_or(desc.interface_number + 1);
+            let mut endpoints: Vec<(u8, bool, usize)> = config.interfaces.iter()
+                .filter(|data| {
+                    let data = &data.descriptor;
+                    data.interface_number == data_number &&
+                        data.interface_class == CDC_DATA_CLASS
+                })
+                .flat_map(|data| data.endpoint_descriptors.iter())
+                .filter(|ep_desc| ep_desc.attributes & 0x03 == 0x02)
+                .map(|ep_desc| (
+                    ep_desc.endpoint_address & 0x0F,
+                    ep_desc.endpoint_address & 0x80 != 0,
+                    (ep_desc.max_packet_size & 0x7FF) as usize))
+                .collect();
+            endpoints.dedup();
+            if !endpoints.is_empty() {
+                functions.push(NetworkFunction {
+                    device_id: device_id as u64,
+                    address: 0,
+                    ncm: ncm,
+                    endpoints: endpoints,
+                });
+            }
+        }
+    }
+    for function in functions.iter_mut() {
+        function.address = cap.devices.get(function.device_id).unwrap().address;
+    }
+    functions
+}
+
+impl NetworkFunction {
+    /// Find the bulk transfers made to and from the function, in the
+    /// order in which they were completed. Each transfer ends with a short
+    /// packet. Payloads are read from the capture as transfers are taken.
+    pub fn transfers<'c>(&self, cap: &'c mut Capture)
+        -> impl Iterator<Item=Vec<u8>> + 'c
+    {
+        let addresses: Vec<u8> = self.endpoints.iter()
+            .map(|(number, is_in, _)| number | if *is_in { 0x80 } else { 0 })
+            .collect();
+        let endpoints = self.endpoints.clone();
+        // Transfers in progress, for OUT and IN.
+        let mut current = [Vec::new(), Vec::new()];
+        cap.get_device_transaction_ids(self.device_id, &addresses)
+            .into_iter()
+            .filter_map(move |id| {
+                let (pid, data) = cap.get_acked_payload(id)?;
+                let direction_in = pid == PID::IN;
+                let (_, _, max_packet_size) = endpoints.iter()
+                    .find(|(_, is_in, _)| *is_in == direction_in)?;
+                let short = data.len() < *max_packet_size;
+                let transfer = &mut current[direction_in as usize];
+                transfer.extend(data);
+                if short && !transfer.is_empty() {
+                    Some(std::mem::take(transfer))
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Find the Ethernet frames sent to and from the function. NTBs
+    /// which cannot be parsed are skipped.
+    pub fn frames<'c>(&self, cap: &'c mut Capture)
+        -> impl Iterator<Item=Vec<u8>> + 'c
+    {
+        let ncm = self.ncm;
+        self.transfers(cap).flat_map(move |block| {
+            if !ncm {
+                return vec![block];
+            }
+            match parse_ntb(&block) {
+                Ok(ranges) => ranges.into_iter()
+                    .map(|range| block[range].to_vec())
+                    .collect(),
+                Err(_) => Vec::new(),
+            }
+        })
+    }
+}
+
+/// Write the header of a pcap file with Ethernet link type.
+pub fn write_pcap_header<W: Write>(output: &mut W) -> std::io::Result<()> {
+    output.write_all(&PCAP_MAGIC.to_le_bytes())?;
+    output.write_all(&2u16.to_le_bytes())?;
+    output.write_all(&4u16.to_le_bytes())?;
+    output.write_all(&0i32.to_le_bytes())?;
+    output.write_all(&0u32.to_le_bytes())?;
+    output.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+    output.write_all(&LINKTYPE_ETHERNET.to_le_bytes())
+}
+
+/// Write frames to a pcap file after its header, as they are taken.
+/// Returns the number of frames written. The frames are not matched to
+/// packet times, so all are given a zero timestamp.
+pub fn write_pcap_frames<W, I>(output: &mut W, frames: I)
+    -> std::io::Result<usize>
+    where W: Write, I: IntoIterator<Item=Vec<u8>>
+{
+    let mut count = 0;
+    for frame in frames {
+        let length = frame.len() as u32;
+        let captured = length.min(PCAP_SNAPLEN);
+        output.write_all(&0u32.to_le_bytes())?;
+        output.write_all(&0u32.to_le_bytes())?;
+        output.write_all(&captured.to_le_bytes())?;
+        output.write_all(&length.to_le_bytes())?;
+        output.write_all(&frame[..captured as usize])?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Export the Ethernet frames of all CDC-ECM and CDC-NCM functions in the
+/// capture to a pcap file. Returns the number of frames written.
+pub fn export_ethernet(cap: &mut Capture, path: &Path)
+    -> Result<usize, NetworkError>
+{
+    let functions = find_network_functions(cap);
+    if functions.is_empty() {
+        return Err(NetworkError::NoInterfaces);
+    }
+    let mut output = BufWriter::new(File::create(path)?);
+    write_pcap_header(&mut output)?;
+    let mut count = 0;
+    for function in &functions {
+        count += write_pcap_frames(&mut output, function.frames(cap))?;
+    }
+    output.flush()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ntb16(datagrams: &[&[u8]]) -> Vec<u8> {
+        let ndp_length = 8 + 4 * (datagrams.len() + 1);
+        let mut block = Vec::new();
+        block.extend(NTH16_SIGNATURE);
+        block.extend([12, 0, 0, 0, 0, 0, 12, 0]);
+        block.extend(b"NCM0");
+        block.extend((ndp_length as u16).to_le_bytes());
+        block.extend([0, 0]);
+        let mut offset = 12 + ndp_length;
+        for datagram in datagrams {
+            block.extend((offset as u16).to_le_bytes());
+            block.extend((datagram.len() as u16).to_le_bytes());
+            offset += datagram.len();
+        }
+        block.extend([0, 0, 0, 0]);
+        for datagram in datagrams {
+            block.extend(*datagram);
+        }
+        let length = block.len() as u16;
+        block[8..10].copy_from_slice(&length.to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn test_parse_ntb16() {
+        let block = ntb16(&[&[1, 2, 3], &[4, 5]]);
+        let ranges = parse_ntb(&block).unwrap();
+        assert!(ranges.len() == 2);
+        assert!(block[ranges[0].clone()] == [1, 2, 3]);
+        assert!(block[ranges[1].clone()] == [4, 5]);
+    }
+
+    #[test]
+    fn test_invalid_ntb() {
+        assert!(parse_ntb(&[0; 16]) == Err(NtbError::BadHeader));
+        let mut block = ntb16(&[&[1, 2, 3]]);
+        block[12] = b'X';
+        assert!(parse_ntb(&block) == Err(NtbError::BadPointerTable(12)));
+        let mut block = ntb16(&[&[1, 2, 3]]);
+        block[22] = 0x40;
+        assert!(parse_ntb(&block) == Err(NtbError::OutOfBounds {
+            offset: 28, length: 64 }));
+    }
+
+    #[test]
+    fn test_write_pcap() {
+        let mut output = Vec::new();
+        write_pcap_header(&mut output).unwrap();
+        let frames = vec![vec![0xAA; 14], vec![0xBB; 60]];
+        assert!(write_pcap_frames(&mut output, frames).unwrap() == 2);
+        assert!(output.len() == 24 + 16 + 14 + 16 + 60);
+        assert!(output[0..4] == [0xD4, 0xC3, 0xB2, 0xA1]);
+        assert!(output[20..24] == LINKTYPE_ETHERNET.to_le_bytes());
+        assert!(output[32..36] == 14u32.to_le_bytes());
+        assert!(output[54 + 8..54 + 12] == 60u32.to_le_bytes());
+    }
+}
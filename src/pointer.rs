@@ -0,0 +1,180 @@
+//! Reconstruction of pointer movement from HID mouse and digitizer traffic.
+//!
+//! Mice report relative movements, which are summed to find the path of
+//! the pointer. Digitizers such as touch screens and tablets report
+//! absolute positions, which are used as they are.
+
+use crate::capture::Capture;
+use crate::hid::{ReportDescriptor, ReportType, ReportValue, find_interfaces};
+use crate::hid_usages::GENERIC_DESKTOP;
+
+const MOUSE_PROTOCOL: u8 = 0x02;
+
+const X: u32 = (GENERIC_DESKTOP as u32) << 16 | 0x30;
+const Y: u32 = (GENERIC_DESKTOP as u32) << 16 | 0x31;
+
+/// The path of the pointer controlled by one HID interface.
+pub struct PointerPath {
+    pub address: u8,
+    pub interface_number: u8,
+    /// Whether the positions were found by summing relative movements.
+    pub relative: bool,
+    /// Positions in the device's own units, starting from the first
+    /// report. Y increases downwards.
+    pub points: Vec<(i64, i64)>,
+}
+
+impl PointerPath {
+    pub fn summary(&self) -> String {
+        format!("Device {}, interface {}: {} {} position{}",
+                self.address, self.interface_number, self.points.len(),
+                if self.relative {"relative"} else {"absolute"},
+                if self.points.len() == 1 {""} else {"s"})
+    }
+
+    /// The smallest and largest X and Y positions reached.
+    pub fn bounds(&self) -> Option<((i64, i64), (i64, i64))> {
+        let (first, rest) = self.points.split_first()?;
+        Some(rest.iter().fold((*first, *first), |(min, max), (x, y)|
+            ((min.0.min(*x), min.1.min(*y)),
+             (max.0.max(*x), max.1.max(*y)))))
+    }
+
+    /// Add a report's X and Y values to the path.
+    fn update(&mut self, x: Option<i64>, y: Option<i64>) {
+        if x.is_none() && y.is_none() {
+            return;
+        }
+        let last = self.points.last().cloned();
+        let point = match (self.relative, last) {
+            (true, Some((last_x, last_y))) =>
+                (last_x + x.unwrap_or(0), last_y + y.unwrap_or(0)),
+            (true, None) => (x.unwrap_or(0), y.unwrap_or(0)),
+            (false, Some((last_x, last_y))) =>
+                (x.unwrap_or(last_x), y.unwrap_or(last_y)),
+            (false, None) => (x.unwrap_or(0), y.unwrap_or(0)),
+        };
+        if last != Some(point) {
+            self.points.push(point);
+        }
+    }
+}
+
+/// Whether a descriptor's X axis input is relative, or None if it has no
+/// X axis.
+fn x_relative(descriptor: &ReportDescriptor) -> Option<bool> {
+    descriptor.fields.iter()
+        .find(|field| field.report_type == ReportType::Input &&
+                      field.is_variable() &&
+                      field.usages.contains(&X))
+        .map(|field| field.is_relative())
+}
+
+/// Find the X and Y values in a decoded report.
+fn axis_values(values: &[ReportValue]) -> (Option<i64>, Option<i64>) {
+    let find = |usage| values.iter()
+        .find(|value| value.usage == usage && !value.selected)
+        .map(|value| value.value as i64);
+    (find(X), find(Y))
+}
+
+/// Read the movement from a boot protocol mouse report.
+fn boot_axis_values(data: &[u8]) -> (Option<i64>, Option<i64>) {
+    match data {
+        [_, x, y, ..] => (Some(*x as i8 as i64), Some(*y as i8 as i64)),
+        _ => (None, None),
+    }
+}
+
+/// Reconstruct the pointer path of each pointing device in the capture.
+///
+/// Reports are decoded using the interface's report descriptor if it was
+/// read, and otherwise as boot protocol reports if the interface supports
+/// the boot mouse protocol.
+pub fn find_pointer_paths(cap: &mut Capture) -> Vec<PointerPath> {
+    profile!("find_pointer_paths");
+    let mut paths = Vec::new();
+    for iface in find_interfaces(cap) {
+        let relative = match &iface.descriptor {
+            Some(descriptor) => match x_relative(descriptor) {
+                Some(relative) => relative,
+                None => continue,
+            },
+            None if iface.is_boot(MOUSE_PROTOCOL) => true,
+            None => continue,
+        };
+        let mut path = PointerPath {
+            address: iface.address,
+            interface_number: iface.interface_number,
+            relative: relative,
+            points: Vec::new(),
+        };
+        for data in iface.input_reports(cap) {
+            let (x, y) = match &iface.descriptor {
+                Some(descriptor) =>
+                    match descriptor.decode(ReportType::Input, &data) {
+                        Some(values) => axis_values(&values),
+                        None => continue,
+                    },
+                None => boot_axis_values(&data),
+            };
+            path.update(x, y);
+        }
+        paths.push(path);
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    fn path(relative: bool) -> PointerPath {
+        PointerPath {
+            address: 1,
+            interface_number: 0,
+            relative: relative,
+            points: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let mut path = path(true);
+        for report in [[0, 1, 2], [0, 0xFF, 0], [0, 0, 0], [1, 0, 0xFE]] {
+            let (x, y) = boot_axis_values(&report);
+            path.update(x, y);
+        }
+        assert!(path.points == vec![(1, 2), (0, 2), (0, 0)]);
+        assert!(path.bounds() == Some(((0, 0), (1, 2))));
+    }
+
+    #[test]
+    fn test_absolute_path() {
+        let mut path = path(false);
+        path.update(Some(100), Some(200));
+        path.update(Some(150), None);
+        path.update(None, None);
+        path.update(None, Some(50));
+        assert!(path.points == vec![(100, 200), (150, 200), (150, 50)]);
+        assert!(path.bounds() == Some(((100, 50), (150, 200))));
+    }
+
+    #[test]
+    fn test_mouse_capture() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let paths = find_pointer_paths(&mut cap);
+        assert!(paths.len() == 1);
+        let path = &paths[0];
+        assert!(path.address == 4);
+        assert!(path.relative);
+        assert!(path.points.len() > 100);
+        assert!(path.points[0] == (-1, 0));
+        assert!(path.points[1] == (-3, 0));
+    }
+}
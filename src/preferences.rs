@@ -0,0 +1,187 @@
+//! User preferences, kept as "key = value" lines in a file in the user's
+//! configuration directory.
+
+use std::path::PathBuf;
+
+use crate::capture::{TimeFormat, Verbosity};
+
+/// Vertical spacing of rows in the traffic and device views.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RowDensity {
+    /// No padding, so that connectors join up between rows.
+    Compact,
+    /// Padding between rows, for easier reading.
+    Comfortable,
+}
+
+impl RowDensity {
+    pub fn name(self) -> &'static str {
+        match self {
+            RowDensity::Compact => "compact",
+            RowDensity::Comfortable => "comfortable",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<RowDensity> {
+        match name {
+            "compact" => Some(RowDensity::Compact),
+            "comfortable" => Some(RowDensity::Comfortable),
+            _ => None,
+        }
+    }
+}
+
+/// Smallest and largest font sizes accepted, in points.
+pub const MIN_FONT_SIZE: u32 = 6;
+pub const MAX_FONT_SIZE: u32 = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preferences {
+    /// Font size of the views in points, or None for the system default.
+    pub font_size: Option<u32>,
+    pub row_density: RowDensity,
+    /// Detail given in the summaries of packets.
+    pub verbosity: Verbosity,
+    /// How times are shown in the traffic view.
+    pub time_format: TimeFormat,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Preferences {
+            font_size: None,
+            row_density: RowDensity::Compact,
+            verbosity: Verbosity::Verbose,
+            time_format: TimeFormat::Hidden,
+        }
+    }
+}
+
+/// The directory for Packetry's own configuration files, within the
+/// user's configuration directory for the platform.
+pub fn config_dir() -> PathBuf {
+    gtk::glib::user_config_dir().join("packetry")
+}
+
+impl Preferences {
+    fn path() -> PathBuf {
+        config_dir().join("preferences")
+    }
+
+    /// Load the saved preferences, using defaults for any not saved.
+    pub fn load() -> Preferences {
+        match std::fs::read_to_string(Preferences::path()) {
+            Ok(text) => Preferences::parse(&text),
+            Err(_) => Preferences::default(),
+        }
+    }
+
+    /// Parse preferences, ignoring unknown keys and invalid values.
+    pub fn parse(text: &str) -> Preferences {
+        let mut prefs = Preferences::default();
+        for line in text.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "font_size" => prefs.font_size = value.parse().ok()
+                    .filter(|size| (MIN_FONT_SIZE..=MAX_FONT_SIZE)
+                        .contains(size)),
+                "row_density" => if let Some(density) =
+                    RowDensity::from_name(value)
+                {
+                    prefs.row_density = density;
+                },
+                "verbosity" => if let Some(verbosity) =
+                    Verbosity::from_name(value)
+                {
+                    prefs.verbosity = verbosity;
+                },
+                "time_format" => if let Some(format) =
+                    TimeFormat::from_name(value)
+                {
+                    prefs.time_format = format;
+                },
+                _ => {}
+            }
+        }
+        prefs
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        if let Some(size) = self.font_size {
+            text.push_str(&format!("font_size = {}\n", size));
+        }
+        text.push_str(&format!("row_density = {}\n",
+                               self.row_density.name()));
+        text.push_str(&format!("verbosity = {}\n", self.verbosity.name()));
+        text.push_str(&format!("time_format = {}\n",
+                               self.time_format.name()));
+        text
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = Preferences::path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.to_text())
+    }
+
+    /// CSS applying the preferences to the views and the hex display.
+    ///
+    /// The connectors are text in the same font, so they scale with it.
+    pub fn css(&self) -> String {
+        let mut css = String::new();
+        if let Some(size) = self.font_size {
+            css.push_str(&format!(
+                "listview, .monospace {{ font-size: {}pt; }}\n", size));
+        }
+        let padding = match self.row_density {
+            RowDensity::Compact => 0,
+            RowDensity::Comfortable => 4,
+        };
+        css.push_str(&format!(
+            "listview > row {{ min-height: 0; padding-top: {0}px; \
+             padding-bottom: {0}px; }}\n", padding));
+        css
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_preferences() {
+        let prefs = Preferences {
+            font_size: Some(14),
+            row_density: RowDensity::Comfortable,
+            verbosity: Verbosity::Short,
+            time_format: TimeFormat::SiblingDelta,
+        };
+        assert!(Preferences::parse(&prefs.to_text()) == prefs);
+        assert!(Preferences::parse("") == Preferences::default());
+        assert!(Preferences::parse(
+            "font_size = 2\nrow_density = tight\nverbosity = 0\n\
+             time_format = utc\nother = 1\n") ==
+            Preferences::default());
+    }
+
+    #[test]
+    fn test_preferences_css() {
+        let css = Preferences::default().css();
+        assert!(!css.contains("font-size"));
+        assert!(css.contains("padding-top: 0px"));
+        let css = Preferences {
+            font_size: Some(12),
+            row_density: RowDensity::Comfortable,
+            verbosity: Verbosity::Normal,
+            time_format: TimeFormat::Relative,
+        }.css();
+        assert!(css.contains("font-size: 12pt"));
+        assert!(css.contains("padding-top: 4px"));
+    }
+}
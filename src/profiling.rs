@@ -0,0 +1,46 @@
+//! Optional instrumentation of hot paths, for guiding optimisation.
+//!
+//! When built with the `profiling` feature, `profile!` opens a tracing span
+//! which lasts until the end of the enclosing block, and the time spent in
+//! each stack of spans is written out in the folded format accepted by
+//! flamegraph tools such as inferno. Otherwise, `profile!` compiles to
+//! nothing.
+
+#[cfg(feature = "profiling")]
+macro_rules! profile {
+    ($name:literal) => {
+        let _span = tracing::trace_span!($name).entered();
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+macro_rules! profile {
+    ($name:literal) => {};
+}
+
+/// Keeps profiling active, and flushes the output when dropped.
+pub struct Profiler {
+    #[cfg(feature = "profiling")]
+    _guard: tracing_flame::FlushGuard<std::io::BufWriter<std::fs::File>>,
+}
+
+/// Start profiling, if enabled.
+///
+/// Output is written to the file named by the `PACKETRY_PROFILE`
+/// environment variable, or `packetry.folded` by default. A flamegraph can
+/// then be produced with e.g. `inferno-flamegraph < packetry.folded`.
+#[cfg(feature = "profiling")]
+pub fn start() -> Profiler {
+    use tracing_subscriber::prelude::*;
+    let path = std::env::var("PACKETRY_PROFILE")
+        .unwrap_or_else(|_| "packetry.folded".to_string());
+    let (layer, guard) = tracing_flame::FlameLayer::with_file(&path)
+        .expect("Failed to create profile output file");
+    tracing_subscriber::registry().with(layer).init();
+    Profiler { _guard: guard }
+}
+
+#[cfg(not(feature = "profiling"))]
+pub fn start() -> Profiler {
+    Profiler {}
+}
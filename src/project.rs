@@ -0,0 +1,411 @@
+//! Saving a decoded capture as a project file, which can be reopened
+//! without decoding the capture again.
+//!
+//! A project holds the contents of each of the capture's storage files,
+//! its device data as the descriptors it was parsed from, and the labels
+//! given to its devices and endpoints. The results
+//! of the post-decode checks are not saved, as they are quick to find
+//! again once the project is loaded.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+
+use thiserror::Error;
+
+use crate::capture::{
+    Capture,
+    DeviceData,
+    EndpointTraffic,
+    EndpointType,
+    SofGap,
+    SofGapKind,
+};
+use crate::file_vec::{FileVec, FileVecError};
+use crate::hid::ReportDescriptor;
+use crate::hybrid_index::{HybridIndex, HybridIndexError};
+use crate::labels::Labels;
+use crate::record::{Record, encode_all};
+use crate::storage::{CaptureStorage, read_u64};
+use crate::usb::{Configuration, DeviceDescriptor, PhyErrors, Speed};
+
+/// File extension of project files.
+pub const PROJECT_EXTENSION: &str = ".packetry";
+
+/// Identifies a project file.
+pub const MAGIC: &[u8; 8] = b"PKTRYPRJ";
+
+/// Version of the project format written. Projects of other versions are
+/// not loaded.
+const VERSION: u64 = 7;
+
+#[derive(Error, Debug)]
+pub enum ProjectError {
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error(transparent)]
+    FileVecError(#[from] FileVecError),
+    #[error(transparent)]
+    HybridIndexError(#[from] HybridIndexError),
+    #[error("not a project file")]
+    NotProject,
+    #[error("unsupported project version {0}")]
+    Version(u64),
+    #[error("project file is corrupt")]
+    Corrupt,
+}
+
+fn write_u64(output: &mut dyn Write, value: u64) -> std::io::Result<()> {
+    output.write_all(&value.to_le_bytes())
+}
+
+fn write_bytes(output: &mut dyn Write, bytes: &[u8]) -> std::io::Result<()> {
+    write_u64(output, bytes.len() as u64)?;
+    output.write_all(bytes)
+}
+
+fn read_bytes(input: &mut dyn Read) -> Result<Vec<u8>, ProjectError> {
+    let length = read_u64(input)?;
+    let mut bytes = Vec::new();
+    input.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length {
+        return Err(ProjectError::Corrupt);
+    }
+    Ok(bytes)
+}
+
+fn write_option(output: &mut dyn Write, bytes: Option<&[u8]>)
+    -> std::io::Result<()>
+{
+    match bytes {
+        Some(bytes) => {
+            output.write_all(&[1])?;
+            write_bytes(output, bytes)
+        },
+        None => output.write_all(&[0]),
+    }
+}
+
+fn read_option(input: &mut dyn Read)
+    -> Result<Option<Vec<u8>>, ProjectError>
+{
+    let mut present = [0];
+    input.read_exact(&mut present)?;
+    match present[0] {
+        0 => Ok(None),
+        1 => Ok(Some(read_bytes(input)?)),
+        _ => Err(ProjectError::Corrupt),
+    }
+}
+
+/// Write a list of values, each as an optional byte string.
+fn write_list<T>(output: &mut dyn Write, items: &[T],
+                 encode: impl Fn(&T) -> Option<Vec<u8>>)
+    -> std::io::Result<()>
+{
+    write_u64(output, items.len() as u64)?;
+    for item in items {
+        write_option(output, encode(item).as_deref())?;
+    }
+    Ok(())
+}
+
+fn read_list<T>(input: &mut dyn Read,
+                decode: impl Fn(Vec<u8>) -> Result<T, ProjectError>)
+    -> Result<Vec<Option<T>>, ProjectError>
+{
+    let count = read_u64(input)?;
+    let mut items = Vec::new();
+    for _ in 0..count {
+        items.push(match read_option(input)? {
+            Some(bytes) => Some(decode(bytes)?),
+            None => None,
+        });
+    }
+    Ok(items)
+}
+
+fn write_device_data(output: &mut dyn Write, data: &DeviceData)
+    -> std::io::Result<()>
+{
+    let device_descriptor = data.device_descriptor.map(|desc|
+        encode_all(&[desc]));
+    write_option(output, device_descriptor.as_deref())?;
+    write_list(output, &data.configurations, |config|
+        config.as_ref().map(Configuration::to_bytes))?;
+    let config_id = data.configuration_id.map(|id| encode_all(&[id as u64]));
+    write_option(output, config_id.as_deref())?;
+    let endpoint_types: Vec<u8> = data.endpoint_types.iter()
+        .map(|ep_type| *ep_type as u8)
+        .collect();
+    write_bytes(output, &endpoint_types)?;
+    write_list(output, &data.strings, |string| string.clone())?;
+    write_list(output, &data.report_descriptors, |desc|
+        desc.as_ref().map(ReportDescriptor::to_bytes))
+}
+
+fn read_device_data(input: &mut dyn Read)
+    -> Result<DeviceData, ProjectError>
+{
+    let device_descriptor = match read_option(input)? {
+        Some(bytes) if bytes.len() == DeviceDescriptor::SIZE =>
+            Some(DeviceDescriptor::decode(&bytes)),
+        Some(_) => return Err(ProjectError::Corrupt),
+        None => None,
+    };
+    let configurations = read_list(input, |bytes|
+        Configuration::from_bytes(&bytes).ok_or(ProjectError::Corrupt))?;
+    let configuration_id = match read_option(input)? {
+        Some(bytes) if bytes.len() == u64::SIZE =>
+            Some(u64::decode(&bytes) as usize),
+        Some(_) => return Err(ProjectError::Corrupt),
+        None => None,
+    };
+    let endpoint_types = read_bytes(input)?.into_iter()
+        .map(EndpointType::from)
+        .collect();
+    let strings = read_list(input, Ok)?;
+    let report_descriptors = read_list(input, |bytes|
+        Ok(ReportDescriptor::from_bytes(&bytes)))?;
+    Ok(DeviceData {
+        device_descriptor: device_descriptor,
+        configurations: configurations,
+        configuration_id: configuration_id,
+        endpoint_types: endpoint_types,
+        strings: strings,
+        report_descriptors: report_descriptors,
+    })
+}
+
+fn sof_gap_kind(value: u8) -> Result<SofGapKind, ProjectError> {
+    use SofGapKind::*;
+    match value {
+        0 => Ok(DroppedFrames),
+        1 => Ok(Suspend),
+        2 => Ok(Disconnect),
+        _ => Err(ProjectError::Corrupt),
+    }
+}
+
+fn speed(value: u8) -> Result<Option<Speed>, ProjectError> {
+    use Speed::*;
+    match value {
+        0 => Ok(None),
+        1 => Ok(Some(Low)),
+        2 => Ok(Some(Full)),
+        3 => Ok(Some(High)),
+        _ => Err(ProjectError::Corrupt),
+    }
+}
+
+/// Save a decoded capture as a project.
+pub fn save_project(cap: &mut Capture, output: &mut dyn Write)
+    -> Result<(), ProjectError>
+{
+    profile!("save_project");
+    output.write_all(MAGIC)?;
+    write_u64(output, VERSION)?;
+    output.write_all(&[match cap.speed {
+        None => 0,
+        Some(Speed::Low) => 1,
+        Some(Speed::Full) => 2,
+        Some(Speed::High) => 3,
+    }])?;
+    cap.item_index.save(output)?;
+    cap.packet_index.save(output)?;
+    cap.packet_times.save(output)?;
+    cap.packet_data.save(output)?;
+    cap.transaction_index.save(output)?;
+    cap.retry_index.save(output)?;
+    cap.retry_ids.save(output)?;
+    cap.transfer_index.save(output)?;
+    cap.devices.save(output)?;
+    cap.endpoints.save(output)?;
+    cap.endpoint_states.save(output)?;
+    cap.endpoint_state_index.save(output)?;
+    write_u64(output, cap.device_data.len() as u64)?;
+    for data in &cap.device_data {
+        write_device_data(output, data)?;
+    }
+    write_u64(output, cap.endpoint_traffic.len() as u64)?;
+    for endpoint_id in 0..cap.endpoint_traffic.len() {
+        let traffic = &mut cap.endpoint_traffic[endpoint_id];
+        traffic.transaction_ids.save(output)?;
+        traffic.transfer_index.save(output)?;
+    }
+    write_u64(output, cap.endpoint_first_entry.len() as u64)?;
+    for entry in &cap.endpoint_first_entry {
+        write_u64(output, *entry)?;
+    }
+    write_u64(output, cap.highlights.len() as u64)?;
+    for transfer_index_id in &cap.highlights {
+        write_u64(output, *transfer_index_id)?;
+    }
+    write_u64(output, cap.sof_gaps.len() as u64)?;
+    for (packet_id, gap) in &cap.sof_gaps {
+        write_u64(output, *packet_id)?;
+        output.write_all(&[gap.kind as u8])?;
+        output.write_all(&gap.missing_frames.to_le_bytes())?;
+    }
+    write_u64(output, cap.phy_errors.len() as u64)?;
+    for (packet_id, errors) in &cap.phy_errors {
+        write_u64(output, *packet_id)?;
+        output.write_all(&[errors.bits()])?;
+    }
+    write_bytes(output, cap.labels.to_text().as_bytes())?;
+    Ok(())
+}
+
+/// Load a capture from a project, with its storage files created in
+/// `storage`. The post-decode checks must then be run on it, as for a newly
+/// decoded capture.
+pub fn load_project(input: &mut dyn Read, storage: CaptureStorage)
+    -> Result<Capture, ProjectError>
+{
+    profile!("load_project");
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(ProjectError::NotProject);
+    }
+    let version = read_u64(input)?;
+    if version != VERSION {
+        return Err(ProjectError::Version(version));
+    }
+    // The empty storage of a new capture is replaced by that loaded, so
+    // is left anonymous.
+    let mut cap = Capture::new();
+    let index = |input: &mut dyn Read, purpose: &str|
+        HybridIndex::load_in(&storage, purpose, input);
+    let mut speed_byte = [0];
+    input.read_exact(&mut speed_byte)?;
+    cap.speed = speed(speed_byte[0])?;
+    cap.item_index = index(input, "item_index")?;
+    cap.packet_index = index(input, "packet_index")?;
+    cap.packet_times = FileVec::load_in(&storage, "packet_times", input)?;
+    cap.packet_data = FileVec::load_in(&storage, "packet_data", input)?;
+    cap.transaction_index = index(input, "transaction_index")?;
+    cap.retry_index = index(input, "retry_index")?;
+    cap.retry_ids = FileVec::load_in(&storage, "retry_ids", input)?;
+    cap.transfer_index =
+        FileVec::load_in(&storage, "transfer_index", input)?;
+    cap.devices = FileVec::load_in(&storage, "devices", input)?;
+    cap.endpoints = FileVec::load_in(&storage, "endpoints", input)?;
+    cap.endpoint_states =
+        FileVec::load_in(&storage, "endpoint_states", input)?;
+    cap.endpoint_state_index = index(input, "endpoint_state_index")?;
+    for _ in 0..read_u64(input)? {
+        cap.device_data.push(read_device_data(input)?);
+    }
+    for endpoint_id in 0..read_u64(input)? {
+        let purpose = |name| format!("endpoint_{}_{}", endpoint_id, name);
+        cap.endpoint_traffic.push(EndpointTraffic {
+            transaction_ids: index(input, &purpose("transaction_ids"))?,
+            transfer_index: index(input, &purpose("transfer_index"))?,
+        });
+    }
+    for _ in 0..read_u64(input)? {
+        cap.endpoint_first_entry.push(read_u64(input)?);
+    }
+    let mut highlights = BTreeSet::new();
+    for _ in 0..read_u64(input)? {
+        highlights.insert(read_u64(input)?);
+    }
+    cap.highlights = highlights;
+    let mut sof_gaps = BTreeMap::new();
+    for _ in 0..read_u64(input)? {
+        let packet_id = read_u64(input)?;
+        let mut bytes = [0; 3];
+        input.read_exact(&mut bytes)?;
+        sof_gaps.insert(packet_id, SofGap {
+            kind: sof_gap_kind(bytes[0])?,
+            missing_frames: u16::from_le_bytes([bytes[1], bytes[2]]),
+        });
+    }
+    cap.sof_gaps = sof_gaps;
+    for _ in 0..read_u64(input)? {
+        let packet_id = read_u64(input)?;
+        let mut errors = [0];
+        input.read_exact(&mut errors)?;
+        cap.phy_errors.insert(packet_id, PhyErrors::from_bits(errors[0]));
+    }
+    let labels = String::from_utf8(read_bytes(input)?)
+        .map_err(|_| ProjectError::Corrupt)?;
+    cap.labels = Labels::parse(&labels);
+    cap.storage = storage;
+    Ok(cap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{DeviceItem, Item};
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+    use crate::labels::LabelKey;
+
+    fn traffic_summaries(cap: &mut Capture, parent: &Option<Item>,
+                         summaries: &mut Vec<String>)
+    {
+        let count = match parent {
+            Some(item) => cap.child_count(item),
+            None => cap.item_count(&None),
+        };
+        for i in 0..count {
+            let item = match parent {
+                Some(item) => cap.get_child(item, i),
+                None => cap.get_item(&None, i),
+            };
+            summaries.push(cap.get_summary(&item));
+            traffic_summaries(cap, &Some(item), summaries);
+        }
+    }
+
+    fn device_summaries(cap: &mut Capture, parent: &Option<DeviceItem>,
+                        summaries: &mut Vec<String>)
+    {
+        for i in 0..cap.device_item_count(parent) {
+            let item = cap.get_device_item(parent, i);
+            summaries.push(cap.get_device_summary(&item));
+            device_summaries(cap, &Some(item), summaries);
+        }
+    }
+
+    #[test]
+    fn test_save_load_project() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        cap.set_highlighted(3);
+        let descriptor = cap.device_data[1].device_descriptor.unwrap();
+        cap.labels.set(LabelKey {
+            vendor_id: descriptor.vendor_id,
+            product_id: descriptor.product_id,
+            endpoint: Some(0x81),
+        }, "Mouse reports");
+        let mut saved = Vec::new();
+        save_project(&mut cap, &mut saved).unwrap();
+        let mut loaded = load_project(&mut saved.as_slice(),
+                                       CaptureStorage::default()).unwrap();
+        assert!(loaded.packet_index.len() == cap.packet_index.len());
+        assert!(loaded.packet_times.len() == cap.packet_times.len());
+        assert!(loaded.highlights == cap.highlights);
+        assert!(loaded.labels == cap.labels);
+        let mut summaries = Vec::new();
+        for cap in [&mut cap, &mut loaded] {
+            let mut traffic = Vec::new();
+            let mut devices = Vec::new();
+            traffic_summaries(cap, &None, &mut traffic);
+            device_summaries(cap, &None, &mut devices);
+            summaries.push((traffic, devices));
+        }
+        assert!(!summaries[0].1.is_empty());
+        assert!(summaries[0].0.iter().any(|summary|
+            summary.starts_with("Mouse reports: Interrupt transfer")));
+        assert!(summaries[0] == summaries[1]);
+        saved[0] = b'X';
+        assert!(matches!(load_project(&mut saved.as_slice(),
+                                      CaptureStorage::default()),
+                         Err(ProjectError::NotProject)));
+    }
+}
@@ -0,0 +1,216 @@
+//! A report on how much of a capture could be decoded, to help judge how
+//! far the displayed structure can be trusted.
+
+use crate::capture::{Capture, EndpointType, fmt_count};
+use crate::usb::{
+    DescriptorType,
+    Direction,
+    PID,
+    RequestType,
+    StandardRequest,
+};
+
+/// Counts of the traffic which the decoder could not fully make sense of.
+pub struct QualityReport {
+    pub packets: u64,
+    /// Packets with errors reported by the capture hardware.
+    pub phy_errors: u64,
+    /// Packets which did not form part of a valid transaction.
+    pub invalid_packets: u64,
+    pub transactions: u64,
+    /// Transactions with a token but no handshake, on endpoints which use
+    /// handshakes, so that it is unknown whether they succeeded.
+    pub unknown_outcomes: u64,
+    pub descriptor_reads: u64,
+    /// Positions among the top-level items of descriptor reads whose data
+    /// could not be parsed.
+    pub descriptor_failures: Vec<u64>,
+}
+
+fn fmt_fraction(count: u64, total: u64, what: &str) -> String {
+    let percent = match total {
+        0 => 0.0,
+        total => count as f64 * 100.0 / total as f64,
+    };
+    format!("{} of {} {} ({:.1}%)",
+            fmt_count(count), fmt_count(total), what, percent)
+}
+
+impl QualityReport {
+    /// Whether everything in the capture was decoded.
+    pub fn is_clean(&self) -> bool {
+        self.phy_errors == 0 &&
+            self.invalid_packets == 0 &&
+            self.unknown_outcomes == 0 &&
+            self.descriptor_failures.is_empty()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        vec![
+            format!("{} with PHY errors",
+                    fmt_fraction(self.phy_errors, self.packets, "packets")),
+            format!("{} in invalid groups",
+                    fmt_fraction(self.invalid_packets, self.packets,
+                                 "packets")),
+            format!("{} with unknown outcome",
+                    fmt_fraction(self.unknown_outcomes, self.transactions,
+                                 "transactions")),
+            format!("{} failed to parse",
+                    fmt_fraction(self.descriptor_failures.len() as u64,
+                                 self.descriptor_reads,
+                                 "descriptor reads")),
+        ]
+    }
+}
+
+/// Whether the data returned for a standard descriptor read is malformed.
+///
+/// Reads which returned no data, such as those stalled by the device, are
+/// not failures, and neither are reads which stopped short because fewer
+/// bytes were requested than the descriptor holds. Descriptor types not
+/// parsed by the decoder are never failures.
+pub fn descriptor_failed(descriptor_type: u8, requested: u16, data: &[u8])
+    -> bool
+{
+    let requested = requested as usize;
+    let length = data.len();
+    if length == 0 {
+        return false;
+    }
+    match DescriptorType::from(descriptor_type) {
+        DescriptorType::Device => requested >= 18 &&
+            (length != 18 || data[0] != 18 ||
+             data[1] != DescriptorType::Device as u8),
+        DescriptorType::Configuration => {
+            if length < 4 {
+                return requested >= 4;
+            }
+            let total = u16::from_le_bytes([data[2], data[3]]) as usize;
+            data[1] != DescriptorType::Configuration as u8 ||
+                total < 9 ||
+                (requested >= total && length != total)
+        },
+        DescriptorType::String => {
+            if length < 2 {
+                return requested >= 2;
+            }
+            let string_length = data[0] as usize;
+            data[1] != DescriptorType::String as u8 ||
+                string_length < 2 ||
+                (requested >= string_length && length != string_length)
+        },
+        _ => false,
+    }
+}
+
+/// Examine a decoded capture for traffic the decoder could not make sense
+/// of.
+pub fn quality_report(cap: &mut Capture) -> QualityReport {
+    profile!("quality_report");
+    let mut report = QualityReport {
+        packets: cap.packet_index.len(),
+        phy_errors: cap.phy_errors.len() as u64,
+        invalid_packets: 0,
+        transactions: cap.transaction_index.len(),
+        unknown_outcomes: 0,
+        descriptor_reads: 0,
+        descriptor_failures: Vec::new(),
+    };
+    let endpoint_count = cap.endpoints.len();
+    let endpoints = cap.endpoints.get_range(0..endpoint_count).unwrap();
+    for (endpoint_id, endpoint) in endpoints.iter().enumerate() {
+        let dev_data = &cap.device_data[endpoint.device_id() as usize];
+        let ep_type = dev_data.endpoint_type(endpoint.endpoint_address());
+        if matches!(ep_type, EndpointType::Framing | EndpointType::Isochronous)
+        {
+            continue;
+        }
+        let ep_traf = &mut cap.endpoint_traffic[endpoint_id];
+        let transaction_ids = ep_traf.transaction_ids
+            .get_range(0..ep_traf.transaction_ids.len())
+            .unwrap();
+        for transaction_id in transaction_ids {
+            let packets = cap.get_transaction_packets(transaction_id);
+            if matches!(ep_type, EndpointType::Invalid) {
+                report.invalid_packets += packets.len() as u64;
+                continue;
+            }
+            let first = packets.iter()
+                .map(|packet| PID::from(packet[0]))
+                .find(|pid| !cap.is_prefix(*pid))
+                .unwrap_or(PID::Malformed);
+            let last = PID::from(packets[packets.len() - 1][0]);
+            if matches!(first, PID::SETUP | PID::IN | PID::OUT) &&
+                !matches!(last, PID::ACK | PID::NAK | PID::NYET | PID::STALL)
+            {
+                report.unknown_outcomes += 1;
+            }
+        }
+    }
+    for item_index in 0..cap.item_index.len() {
+        let transfer_index_id = cap.item_index.get(item_index).unwrap();
+        let transfer = match cap.control_transfer(transfer_index_id) {
+            Some(transfer) => transfer,
+            None => continue,
+        };
+        let fields = &transfer.fields;
+        let is_get_descriptor =
+            matches!(fields.type_fields.request_type(), RequestType::Standard)
+            && matches!(fields.type_fields.direction(), Direction::In)
+            && matches!(StandardRequest::from(fields.request),
+                        StandardRequest::GetDescriptor);
+        if !is_get_descriptor {
+            continue;
+        }
+        report.descriptor_reads += 1;
+        if descriptor_failed((fields.value >> 8) as u8, fields.length,
+                             &transfer.data)
+        {
+            report.descriptor_failures.push(item_index);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    #[test]
+    fn test_descriptor_failed() {
+        let device = [18, 1, 0, 2, 0, 0, 0, 64, 0x50, 0x1d, 0x4b, 0x60,
+                      0, 1, 1, 2, 3, 1];
+        assert!(!descriptor_failed(1, 18, &device));
+        assert!(!descriptor_failed(1, 8, &device[..8]));
+        assert!(!descriptor_failed(1, 18, &[]));
+        assert!(descriptor_failed(1, 18, &device[..12]));
+        let config = [9, 2, 18, 0, 1, 1, 0, 0x80, 50,
+                      9, 4, 0, 0, 0, 0xff, 0, 0, 0];
+        assert!(!descriptor_failed(2, 255, &config));
+        assert!(!descriptor_failed(2, 9, &config[..9]));
+        assert!(descriptor_failed(2, 255, &config[..12]));
+        assert!(descriptor_failed(2, 255, &device));
+        assert!(!descriptor_failed(3, 255, &[4, 3, 0x09, 0x04]));
+        assert!(!descriptor_failed(3, 2, &[4, 3]));
+        assert!(descriptor_failed(3, 255, &[6, 3, 0x41, 0]));
+        assert!(descriptor_failed(3, 255, &[4, 1, 0x09, 0x04]));
+        assert!(!descriptor_failed(0x22, 255, &[0xff]));
+    }
+
+    #[test]
+    fn test_quality_report() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let report = quality_report(&mut cap);
+        assert!(report.packets == cap.packet_index.len());
+        assert!(report.descriptor_reads == 7);
+        assert!(report.descriptor_failures.is_empty());
+        assert!(report.phy_errors == 0);
+        assert!(report.lines()[3] ==
+                "0 of 7 descriptor reads (0.0%) failed to parse");
+    }
+}
@@ -0,0 +1,172 @@
+//! Saved queries, each a filter and search text kept under a name, which
+//! can be run again on any capture.
+//!
+//! Queries are kept in a file in the user's configuration directory, one
+//! per line, as the name, filter and search text separated by tabs.
+
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::capture::{Capture, Item};
+use crate::filter::{Filter, FilterError};
+use crate::preferences::config_dir;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum QueryError {
+    #[error("a query needs a name")]
+    NoName,
+    #[error("query names and searches cannot contain tabs or newlines")]
+    InvalidText,
+    #[error(transparent)]
+    FilterError(#[from] FilterError),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub name: String,
+    /// Filter on the traffic, as given to `--filter`.
+    pub filter: String,
+    /// Text to find in the summaries of transfers, ignoring case. Empty
+    /// to match every transfer passing the filter.
+    pub search: String,
+}
+
+impl Query {
+    pub fn new(name: &str, filter: &str, search: &str)
+        -> Result<Query, QueryError>
+    {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(QueryError::NoName);
+        }
+        let invalid = |text: &str| text.contains(['\t', '\n', '\r']);
+        if invalid(name) || invalid(search) {
+            return Err(QueryError::InvalidText);
+        }
+        filter.parse::<Filter>()?;
+        Ok(Query {
+            name: name.to_string(),
+            filter: filter.trim().to_string(),
+            search: search.to_string(),
+        })
+    }
+
+    /// Find the top-level items matching this query, by item index.
+    pub fn run(&self, cap: &mut Capture) -> Result<Vec<u64>, QueryError> {
+        let filter: Filter = self.filter.parse()?;
+        let items = filter.apply(cap);
+        if self.search.is_empty() {
+            return Ok(items);
+        }
+        let search = self.search.to_lowercase();
+        Ok(items.into_iter()
+            .filter(|item_index| {
+                let transfer_index_id =
+                    cap.item_index.get(*item_index).unwrap();
+                cap.get_summary(&Item::Transfer(transfer_index_id))
+                    .to_lowercase()
+                    .contains(&search)
+            })
+            .collect())
+    }
+}
+
+/// Parse saved queries, ignoring any lines which are not valid.
+pub fn parse_queries(text: &str) -> Vec<Query> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(filter), Some(search)) =>
+                    Query::new(name, filter, search).ok(),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+pub fn queries_text(queries: &[Query]) -> String {
+    queries.iter()
+        .map(|query| format!("{}\t{}\t{}\n",
+                             query.name, query.filter, query.search))
+        .collect()
+}
+
+fn queries_path() -> PathBuf {
+    config_dir().join("queries")
+}
+
+/// Load the saved queries, in the order they were saved.
+pub fn load_queries() -> Vec<Query> {
+    match std::fs::read_to_string(queries_path()) {
+        Ok(text) => parse_queries(&text),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn save_queries(queries: &[Query]) -> std::io::Result<()> {
+    let path = queries_path();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, queries_text(queries))
+}
+
+/// Add a query to a list, replacing any of the same name.
+pub fn add_query(queries: &mut Vec<Query>, query: Query) {
+    match queries.iter_mut().find(|saved| saved.name == query.name) {
+        Some(saved) => *saved = query,
+        None => queries.push(query),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    #[test]
+    fn test_parse_queries() {
+        let mut queries = vec![
+            Query::new("Mouse reports", "4.1", "").unwrap(),
+            Query::new(" Strings ", "", "String descriptor").unwrap(),
+        ];
+        assert!(queries[1].name == "Strings");
+        let text = queries_text(&queries);
+        assert!(parse_queries(&text) == queries);
+        assert!(parse_queries("x\t4.1\nbad\t999\t\n\n") == vec![]);
+
+        add_query(&mut queries, Query::new("Strings", "4", "").unwrap());
+        assert!(queries.len() == 2);
+        assert!(queries[1].filter == "4");
+
+        assert!(Query::new("", "", "a") == Err(QueryError::NoName));
+        assert!(Query::new("a", "", "b\tc") == Err(QueryError::InvalidText));
+        assert!(matches!(Query::new("a", "x", ""),
+                         Err(QueryError::FilterError(_))));
+    }
+
+    #[test]
+    fn test_run_query() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let all = Query::new("All", "", "").unwrap();
+        assert!(all.run(&mut cap).unwrap().len() as u64 ==
+                cap.item_index.len());
+        let strings = Query::new("Strings", "4", "STRING DESCRIPTOR")
+            .unwrap();
+        let found = strings.run(&mut cap).unwrap();
+        assert!(found.len() == 2);
+        for item_index in found {
+            let transfer_index_id = cap.item_index.get(item_index).unwrap();
+            let summary = cap.get_summary(&Item::Transfer(transfer_index_id));
+            assert!(summary.contains("string descriptor"));
+        }
+        let other = Query::new("Other", "5", "").unwrap();
+        assert!(other.run(&mut cap).unwrap().is_empty());
+    }
+}
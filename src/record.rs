@@ -0,0 +1,73 @@
+//! Encoding of the items kept in storage files.
+//!
+//! Each kind of item is stored as a fixed number of bytes, laid out
+//! explicitly field by field in little-endian order, rather than as a copy
+//! of its bytes in memory. The layout of the files then does not depend on
+//! the layout Rust chooses for a struct, or on the host's byte order.
+
+/// An item which can be stored as a fixed number of bytes.
+pub trait Record: Sized {
+    /// Number of bytes in the stored form of every item.
+    const SIZE: usize;
+
+    /// Write the stored form of the item to `bytes`, which is `SIZE`
+    /// bytes long.
+    fn encode(&self, bytes: &mut [u8]);
+
+    /// Read an item from its stored form in `bytes`, which is `SIZE`
+    /// bytes long.
+    fn decode(bytes: &[u8]) -> Self;
+}
+
+macro_rules! integer_record {
+    ($type:ty) => {
+        impl Record for $type {
+            const SIZE: usize = std::mem::size_of::<$type>();
+
+            fn encode(&self, bytes: &mut [u8]) {
+                bytes.copy_from_slice(&self.to_le_bytes());
+            }
+
+            fn decode(bytes: &[u8]) -> Self {
+                <$type>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    }
+}
+
+integer_record!(u8);
+integer_record!(u16);
+integer_record!(u32);
+integer_record!(u64);
+integer_record!(i64);
+
+/// Encode a sequence of items, one after another.
+pub fn encode_all<T: Record>(items: &[T]) -> Vec<u8> {
+    let mut bytes = vec![0; items.len() * T::SIZE];
+    for (item, chunk) in items.iter().zip(bytes.chunks_exact_mut(T::SIZE)) {
+        item.encode(chunk);
+    }
+    bytes
+}
+
+/// Decode a sequence of items stored one after another.
+pub fn decode_all<T: Record>(bytes: &[u8]) -> Vec<T> {
+    bytes.chunks_exact(T::SIZE).map(T::decode).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let bytes = encode_all(&[0x0102_u16, 0x0304]);
+        assert!(bytes == [0x02, 0x01, 0x04, 0x03]);
+        assert!(decode_all::<u16>(&bytes) == [0x0102, 0x0304]);
+        assert!(decode_all::<u32>(&bytes) == [0x03040102]);
+        let mut bytes = [0; 8];
+        0x0102030405060708_u64.encode(&mut bytes);
+        assert!(bytes == [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert!(u64::decode(&bytes) == 0x0102030405060708);
+    }
+}
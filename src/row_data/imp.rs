@@ -1,5 +1,7 @@
-use gtk::glib::{self, subclass::prelude::*};
-use std::cell::RefCell;
+use gtk::glib::{self, subclass::prelude::*, ParamFlags, ParamSpec, Value};
+use gtk::prelude::*;
+use once_cell::sync::Lazy;
+use std::cell::{Cell, RefCell};
 use crate::capture;
 
 // The actual data structure that stores our values. This is not accessible
@@ -8,7 +10,9 @@ use crate::capture;
 pub struct RowData {
     pub summary: RefCell<String>,
     pub connectors: RefCell<String>,
+    pub highlighted: Cell<bool>,
     pub(super) item: RefCell<Option<capture::Item>>,
+    pub(super) previous: RefCell<Option<capture::Item>>,
 }
 
 #[derive(Default)]
@@ -17,6 +21,12 @@ pub struct DeviceRowData {
     pub(super) item: RefCell<Option<capture::DeviceItem>>,
 }
 
+#[derive(Default)]
+pub struct GroupRowData {
+    pub summary: RefCell<String>,
+    pub(super) item: RefCell<Option<capture::GroupItem>>,
+}
+
 // Basic declaration of our type for the GObject type system
 #[glib::object_subclass]
 impl ObjectSubclass for RowData {
@@ -30,5 +40,119 @@ impl ObjectSubclass for DeviceRowData {
     type Type = super::DeviceRowData;
 }
 
-impl ObjectImpl for RowData {}
-impl ObjectImpl for DeviceRowData {}
+#[glib::object_subclass]
+impl ObjectSubclass for GroupRowData {
+    const NAME: &'static str = "GroupRowData";
+    type Type = super::GroupRowData;
+}
+
+const FLAGS: ParamFlags = ParamFlags::READWRITE;
+
+// Property IDs, which GLib numbers from one in the order the properties
+// are listed. The summary is listed first for every type.
+const SUMMARY: usize = 1;
+const CONNECTORS: usize = 2;
+const HIGHLIGHTED: usize = 3;
+
+/// GLib only passes the IDs of properties a type lists, and rejects any
+/// other property before it reaches the type's handlers.
+fn unknown_property(pspec: &ParamSpec) -> ! {
+    unreachable!("unknown property {}", pspec.name())
+}
+
+fn summary_property() -> ParamSpec {
+    glib::ParamSpecString::new(
+        "summary", "Summary", "Summary text for this row", None, FLAGS)
+}
+
+// The text for each row is computed by the model, and exposed as properties
+// so that binding a row does not need the capture. The model sets them again
+// when the text changes, which notifies anything bound to them.
+impl ObjectImpl for RowData {
+    fn properties() -> &'static [ParamSpec] {
+        static PROPERTIES: Lazy<Vec<ParamSpec>> = Lazy::new(|| vec![
+            summary_property(),
+            glib::ParamSpecString::new(
+                "connectors", "Connectors",
+                "Connector graphics for this row", None, FLAGS),
+            glib::ParamSpecBoolean::new(
+                "highlighted", "Highlighted",
+                "Whether this row matched a highlight rule", false, FLAGS),
+        ]);
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _obj: &Self::Type, id: usize, value: &Value,
+                    pspec: &ParamSpec)
+    {
+        match id {
+            SUMMARY => { self.summary.replace(value.get().unwrap()); },
+            CONNECTORS => { self.connectors.replace(value.get().unwrap()); },
+            HIGHLIGHTED => self.highlighted.set(value.get().unwrap()),
+            _ => unknown_property(pspec),
+        }
+    }
+
+    fn property(&self, _obj: &Self::Type, id: usize, pspec: &ParamSpec)
+        -> Value
+    {
+        match id {
+            SUMMARY => self.summary.borrow().to_value(),
+            CONNECTORS => self.connectors.borrow().to_value(),
+            HIGHLIGHTED => self.highlighted.get().to_value(),
+            _ => unknown_property(pspec),
+        }
+    }
+}
+
+impl ObjectImpl for DeviceRowData {
+    fn properties() -> &'static [ParamSpec] {
+        static PROPERTIES: Lazy<Vec<ParamSpec>> = Lazy::new(||
+            vec![summary_property()]);
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _obj: &Self::Type, id: usize, value: &Value,
+                    pspec: &ParamSpec)
+    {
+        match id {
+            SUMMARY => { self.summary.replace(value.get().unwrap()); },
+            _ => unknown_property(pspec),
+        }
+    }
+
+    fn property(&self, _obj: &Self::Type, id: usize, pspec: &ParamSpec)
+        -> Value
+    {
+        match id {
+            SUMMARY => self.summary.borrow().to_value(),
+            _ => unknown_property(pspec),
+        }
+    }
+}
+
+impl ObjectImpl for GroupRowData {
+    fn properties() -> &'static [ParamSpec] {
+        static PROPERTIES: Lazy<Vec<ParamSpec>> = Lazy::new(||
+            vec![summary_property()]);
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _obj: &Self::Type, id: usize, value: &Value,
+                    pspec: &ParamSpec)
+    {
+        match id {
+            SUMMARY => { self.summary.replace(value.get().unwrap()); },
+            _ => unknown_property(pspec),
+        }
+    }
+
+    fn property(&self, _obj: &Self::Type, id: usize, pspec: &ParamSpec)
+        -> Value
+    {
+        match id {
+            SUMMARY => self.summary.borrow().to_value(),
+            _ => unknown_property(pspec),
+        }
+    }
+}
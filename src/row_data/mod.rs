@@ -1,12 +1,14 @@
-//! Our GObject subclass for carrying a name and count for the ListBox model
+//! Our GObject subclasses for the rows of the traffic and device trees
 //!
-//! Both name and count are stored in a RefCell to allow for interior mutability
-//! and are exposed via normal GObject properties. This allows us to use property
-//! bindings below to bind the values with what widgets display in the UI
+//! Each row carries the capture item it shows, and exposes the text shown
+//! for it as GObject properties, so that the view can bind to them without
+//! locking the capture. The models update these properties in place when
+//! the text for an item changes, and the view follows the notifications.
 
 mod imp;
 
 use gtk::glib;
+use gtk::prelude::*;
 use gtk::subclass::prelude::*;
 use crate::capture;
 
@@ -18,16 +20,28 @@ glib::wrapper! {
 glib::wrapper! {
     pub struct DeviceRowData(ObjectSubclass<imp::DeviceRowData>);
 }
+glib::wrapper! {
+    pub struct GroupRowData(ObjectSubclass<imp::GroupRowData>);
+}
+
+/// Set a property, only if its value has changed, so that the view is not
+/// notified of rows which look the same as before.
+fn update<V>(object: &impl ObjectExt, name: &str, value: V)
+    where V: ToValue + PartialEq + for<'b> glib::value::FromValue<'b> + 'static
+{
+    if object.property::<V>(name) != value {
+        object.set_property(name, value);
+    }
+}
 
 impl RowData {
-    pub fn new(item: Option<capture::Item>, summary: String, connectors: String)
+    pub fn new(item: Option<capture::Item>, previous: Option<capture::Item>)
         -> RowData
     {
         let mut row: RowData =
             glib::Object::new(&[]).expect("Failed to create row data");
         row.set_item(item);
-        row.set_summary(summary);
-        row.set_connectors(connectors);
+        row.imp().previous.replace(previous);
         row
     }
 
@@ -35,21 +49,27 @@ impl RowData {
         self.imp().item.replace(item);
     }
 
-    fn set_summary(&mut self, summary: String) {
-        self.imp().summary.replace(summary);
+    /// The item this row's time is shown relative to, if any.
+    pub fn previous(&self) -> Option<capture::Item> {
+        self.imp().previous.borrow().clone()
     }
 
-    fn set_connectors(&mut self, connectors: String) {
-        self.imp().connectors.replace(connectors);
+    /// Update the text shown for this row.
+    pub fn set_text(&self, summary: String, connectors: String,
+                    highlighted: bool)
+    {
+        update(self, "summary", summary);
+        update(self, "connectors", connectors);
+        update(self, "highlighted", highlighted);
     }
 }
 
 impl DeviceRowData {
     pub fn new(item: Option<capture::DeviceItem>, summary: String) -> DeviceRowData {
         let mut row: DeviceRowData =
-            glib::Object::new(&[]).expect("Failed to create row data");
+            glib::Object::new(&[("summary", &summary)])
+                .expect("Failed to create row data");
         row.set_item(item);
-        row.set_summary(summary);
         row
     }
 
@@ -57,8 +77,30 @@ impl DeviceRowData {
         self.imp().item.replace(item);
     }
 
-    fn set_summary(&mut self, summary: String) {
-        self.imp().summary.replace(summary);
+    /// Update the summary shown for this row.
+    pub fn set_summary(&self, summary: String) {
+        update(self, "summary", summary);
+    }
+}
+
+impl GroupRowData {
+    pub fn new(item: Option<capture::GroupItem>, summary: String)
+        -> GroupRowData
+    {
+        let mut row: GroupRowData =
+            glib::Object::new(&[("summary", &summary)])
+                .expect("Failed to create row data");
+        row.set_item(item);
+        row
+    }
+
+    fn set_item(&mut self, item: Option<capture::GroupItem>) {
+        self.imp().item.replace(item);
+    }
+
+    /// Update the summary shown for this row.
+    pub fn set_summary(&self, summary: String) {
+        update(self, "summary", summary);
     }
 }
 
@@ -66,8 +108,7 @@ pub trait GenericRowData<Item> {
     const CONNECTORS: bool;
     fn get_item(&self) -> Option<Item>;
     fn child_count(&self, capture: &mut capture::Capture) -> u64;
-    fn get_summary(&self) -> String;
-    fn get_connectors(&self) -> Option<String>;
+    fn is_highlighted(&self) -> bool;
 }
 
 impl GenericRowData<capture::Item> for RowData {
@@ -81,12 +122,8 @@ impl GenericRowData<capture::Item> for RowData {
         capture.item_count(&self.imp().item.borrow())
     }
 
-    fn get_summary(&self) -> String {
-        self.imp().summary.borrow().clone()
-    }
-
-    fn get_connectors(&self) -> Option<String> {
-        Some(self.imp().connectors.borrow().clone())
+    fn is_highlighted(&self) -> bool {
+        self.property("highlighted")
     }
 }
 
@@ -101,11 +138,23 @@ impl GenericRowData<capture::DeviceItem> for DeviceRowData {
         capture.device_item_count(&self.imp().item.borrow())
     }
 
-    fn get_summary(&self) -> String {
-        self.imp().summary.borrow().clone()
+    fn is_highlighted(&self) -> bool {
+        false
+    }
+}
+
+impl GenericRowData<capture::GroupItem> for GroupRowData {
+    const CONNECTORS: bool = false;
+
+    fn get_item(&self) -> Option<capture::GroupItem> {
+        self.imp().item.borrow().clone()
+    }
+
+    fn child_count(&self, capture: &mut capture::Capture) -> u64 {
+        capture.group_item_count(&self.imp().item.borrow())
     }
 
-    fn get_connectors(&self) -> Option<String> {
-        None
+    fn is_highlighted(&self) -> bool {
+        false
     }
 }
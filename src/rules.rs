@@ -0,0 +1,276 @@
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+
+use thiserror::Error;
+
+use crate::decoder::{DecoderEvent, DecoderObserver};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RuleError {
+    #[error("empty rule")]
+    Empty,
+    #[error("unknown rule condition '{0}'")]
+    UnknownCondition(String),
+    #[error("invalid device ID '{0}', expected VID:PID in hex")]
+    InvalidDeviceId(String),
+    #[error("invalid endpoint ID '{0}'")]
+    InvalidEndpointId(String),
+}
+
+/// Conditions that a rule can match on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Condition {
+    /// A device with the given VID:PID was enumerated.
+    DeviceEnumerated { vendor_id: u16, product_id: u16 },
+    /// Any endpoint responded with a STALL handshake.
+    EndpointStall,
+    /// Any device was set to a configuration.
+    ConfigurationSet,
+    /// A transfer was completed on the endpoint with the given ID.
+    TransferCompleted { endpoint_id: usize },
+    /// Traffic was seen which could not be decoded.
+    DecodeError,
+}
+
+/// What to do when a rule matches.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Raise a desktop notification.
+    Notify,
+    /// Highlight the matching transfer in the traffic view.
+    Highlight,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub condition: Condition,
+    pub action: Action,
+}
+
+/// Produced whenever a rule matches a decoder event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub action: Action,
+    pub message: String,
+    /// The transfer matched, if the event was for one.
+    pub transfer_index_id: Option<u64>,
+}
+
+impl FromStr for Condition {
+    type Err = RuleError;
+
+    /// Parse a condition, e.g. "enumerate 1d50:615b", "stall", "configure",
+    /// "transfer 5" or "error".
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            [] => Err(RuleError::Empty),
+            ["enumerate", id] => {
+                let invalid = || RuleError::InvalidDeviceId(id.to_string());
+                let (vid, pid) = id.split_once(':').ok_or_else(invalid)?;
+                Ok(Condition::DeviceEnumerated {
+                    vendor_id: u16::from_str_radix(vid, 16)
+                        .map_err(|_| invalid())?,
+                    product_id: u16::from_str_radix(pid, 16)
+                        .map_err(|_| invalid())?,
+                })
+            },
+            ["stall"] => Ok(Condition::EndpointStall),
+            ["configure"] => Ok(Condition::ConfigurationSet),
+            ["transfer", id] => Ok(Condition::TransferCompleted {
+                endpoint_id: id.parse().map_err(|_|
+                    RuleError::InvalidEndpointId(id.to_string()))?,
+            }),
+            ["error"] => Ok(Condition::DecodeError),
+            _ => Err(RuleError::UnknownCondition(text.to_string())),
+        }
+    }
+}
+
+impl Rule {
+    pub fn new(action: Action, condition: &str) -> Result<Rule, RuleError> {
+        Ok(Rule {
+            condition: condition.parse()?,
+            action: action,
+        })
+    }
+
+    fn check(&self, event: &DecoderEvent) -> Option<Alert> {
+        use Condition::*;
+        let (message, transfer_index_id) = match (self.condition, event) {
+            (DeviceEnumerated { vendor_id, product_id },
+             DecoderEvent::DeviceEnumerated {
+                 address, descriptor, transfer_index_id, ..})
+                if descriptor.vendor_id == vendor_id &&
+                   descriptor.product_id == product_id =>
+            (
+                format!("Device {:04x}:{:04x} enumerated at address {}",
+                        vendor_id, product_id, address),
+                Some(*transfer_index_id)
+            ),
+            (EndpointStall,
+             DecoderEvent::EndpointStalled {
+                 endpoint_id, transfer_index_id })
+            => (
+                format!("Endpoint {} stalled", endpoint_id),
+                Some(*transfer_index_id)
+            ),
+            (ConfigurationSet,
+             DecoderEvent::ConfigurationSet {
+                 address, config_id, transfer_index_id })
+            => (
+                format!("Device {} set to configuration {}",
+                        address, config_id),
+                Some(*transfer_index_id)
+            ),
+            (TransferCompleted { endpoint_id },
+             DecoderEvent::TransferCompleted {
+                 endpoint_id: completed, transfer_index_id })
+                if *completed == endpoint_id =>
+            (
+                format!("Transfer completed on endpoint {}", endpoint_id),
+                Some(*transfer_index_id)
+            ),
+            (DecodeError,
+             DecoderEvent::ErrorDetected { endpoint_id, packet_id, error })
+            => (
+                format!("Decode error at packet {} on endpoint {}: {}",
+                        packet_id, endpoint_id, error),
+                None
+            ),
+            (..) => return None
+        };
+        Some(Alert {
+            action: self.action,
+            message: message,
+            transfer_index_id: transfer_index_id,
+        })
+    }
+}
+
+/// Decoder observer which checks events against a set of rules, and sends
+/// an alert for each match.
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+    sender: Sender<Alert>,
+}
+
+impl RuleEngine {
+    pub fn new(rules: Vec<Rule>, sender: Sender<Alert>) -> Self {
+        RuleEngine {
+            rules: rules,
+            sender: sender,
+        }
+    }
+}
+
+impl DecoderObserver for RuleEngine {
+    fn handle_event(&mut self, event: &DecoderEvent) {
+        for rule in &self.rules {
+            if let Some(alert) = rule.check(event) {
+                // If the receiver has gone away, nobody is listening.
+                let _ = self.sender.send(alert);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use crate::decoder::DecodeError;
+    use crate::usb::DeviceDescriptor;
+
+    #[test]
+    fn test_parse_rules() {
+        assert!(Rule::new(Action::Notify, "enumerate 1d50:615b").unwrap() ==
+            Rule {
+                action: Action::Notify,
+                condition: Condition::DeviceEnumerated {
+                    vendor_id: 0x1d50,
+                    product_id: 0x615b,
+                }
+            });
+        assert!(Rule::new(Action::Highlight, " stall ").unwrap().condition ==
+            Condition::EndpointStall);
+        assert!(Rule::new(Action::Notify, "").unwrap_err() ==
+            RuleError::Empty);
+        assert!(Rule::new(Action::Notify, "enumerate 1d50").unwrap_err() ==
+            RuleError::InvalidDeviceId("1d50".to_string()));
+        assert!(Rule::new(Action::Notify, "enumerate x:1").unwrap_err() ==
+            RuleError::InvalidDeviceId("x:1".to_string()));
+        assert!(Rule::new(Action::Notify, "configure").unwrap().condition ==
+            Condition::ConfigurationSet);
+        assert!(Rule::new(Action::Notify, "transfer 5").unwrap().condition ==
+            Condition::TransferCompleted { endpoint_id: 5 });
+        assert!(Rule::new(Action::Notify, "transfer x").unwrap_err() ==
+            RuleError::InvalidEndpointId("x".to_string()));
+        assert!(Rule::new(Action::Notify, "error").unwrap().condition ==
+            Condition::DecodeError);
+        assert!(Rule::new(Action::Notify, "nak").unwrap_err() ==
+            RuleError::UnknownCondition("nak".to_string()));
+    }
+
+    #[test]
+    fn test_rule_engine() {
+        let (sender, receiver) = channel();
+        let rules = vec![
+            Rule::new(Action::Notify, "enumerate 1d50:615b").unwrap(),
+            Rule::new(Action::Highlight, "stall").unwrap(),
+            Rule::new(Action::Notify, "configure").unwrap(),
+            Rule::new(Action::Highlight, "transfer 6").unwrap(),
+            Rule::new(Action::Notify, "error").unwrap(),
+        ];
+        let mut engine = RuleEngine::new(rules, sender);
+        let mut descriptor = DeviceDescriptor::default();
+        descriptor.vendor_id = 0x1d50;
+        descriptor.product_id = 0x615b;
+        engine.handle_event(&DecoderEvent::DeviceEnumerated {
+            address: 3,
+            descriptor: descriptor,
+            transfer_index_id: 10,
+        });
+        descriptor.product_id = 0x6089;
+        engine.handle_event(&DecoderEvent::DeviceEnumerated {
+            address: 4,
+            descriptor: descriptor,
+            transfer_index_id: 20,
+        });
+        engine.handle_event(&DecoderEvent::EndpointStalled {
+            endpoint_id: 5,
+            transfer_index_id: 30,
+        });
+        engine.handle_event(&DecoderEvent::ConfigurationSet {
+            address: 3,
+            config_id: 1,
+            transfer_index_id: 40,
+        });
+        engine.handle_event(&DecoderEvent::TransferCompleted {
+            endpoint_id: 5,
+            transfer_index_id: 50,
+        });
+        engine.handle_event(&DecoderEvent::TransferCompleted {
+            endpoint_id: 6,
+            transfer_index_id: 60,
+        });
+        engine.handle_event(&DecoderEvent::ErrorDetected {
+            endpoint_id: 0,
+            packet_id: 70,
+            error: DecodeError::InvalidTransaction,
+        });
+        let alerts: Vec<Alert> = receiver.try_iter().collect();
+        assert!(alerts.len() == 5);
+        assert!(alerts[0].action == Action::Notify);
+        assert!(alerts[0].transfer_index_id == Some(10));
+        assert!(alerts[1].action == Action::Highlight);
+        assert!(alerts[1].transfer_index_id == Some(30));
+        assert!(alerts[2].message == "Device 3 set to configuration 1");
+        assert!(alerts[2].transfer_index_id == Some(40));
+        assert!(alerts[3].action == Action::Highlight);
+        assert!(alerts[3].transfer_index_id == Some(60));
+        assert!(alerts[4].message ==
+            "Decode error at packet 70 on endpoint 0: invalid transaction");
+        assert!(alerts[4].transfer_index_id.is_none());
+    }
+}
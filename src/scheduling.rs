@@ -0,0 +1,274 @@
+//! Analysis of how regularly the host serviced periodic endpoints.
+//!
+//! Each transaction is placed in the frame, or at high speed the
+//! microframe, given by the SOF packets before it. An interrupt or
+//! isochronous endpoint should be serviced at least once in every interval
+//! given by its descriptor, and each interval in which it was not is a
+//! missed service opportunity.
+//!
+//! Transactions before the first SOF cannot be placed in a frame, so are
+//! left out.
+
+use std::collections::BTreeMap;
+
+use crate::capture::{Capture, EndpointType, fmt_endpoint_address};
+use crate::usb::{PacketFields, SOFFields, Speed};
+
+/// Follows the bus frame and microframe from SOF frame numbers.
+#[derive(Default)]
+pub struct FrameCounter {
+    last_frame_number: Option<u16>,
+    /// Frames since the first SOF, counting through frame number wraps.
+    frame: u64,
+    /// Number of SOFs seen in the current frame, less one.
+    microframe: u64,
+    /// Whether a frame number has been repeated, which happens only at
+    /// high speed, where each frame has 8 microframes.
+    pub high_speed: bool,
+}
+
+impl FrameCounter {
+    pub fn sof(&mut self, sof: &SOFFields) {
+        let frame_number = sof.frame_number();
+        if let Some(last) = self.last_frame_number {
+            match frame_number.wrapping_sub(last) & 0x7FF {
+                0 => {
+                    self.microframe += 1;
+                    self.high_speed = true;
+                },
+                delta => {
+                    self.frame += delta as u64;
+                    self.microframe = 0;
+                }
+            }
+        }
+        self.last_frame_number = Some(frame_number);
+    }
+
+    /// The current frame and microframe, if any SOF has been seen.
+    pub fn position(&self) -> Option<(u64, u64)> {
+        self.last_frame_number.map(|_| (self.frame, self.microframe))
+    }
+}
+
+/// The service interval of a periodic endpoint, in frames, or microframes
+/// at high speed, from its type and descriptor bInterval value.
+pub fn service_interval(endpoint_type: EndpointType, interval: u8,
+                        high_speed: bool) -> Option<u64>
+{
+    match endpoint_type {
+        EndpointType::Interrupt if !high_speed =>
+            Some(interval.max(1) as u64),
+        EndpointType::Interrupt | EndpointType::Isochronous =>
+            Some(1 << (interval.clamp(1, 16) - 1)),
+        _ => None,
+    }
+}
+
+/// Count the service opportunities missed between polls in the given
+/// frames or microframes, which must be in order. Also returns the longest
+/// gap between polls.
+pub fn missed_services(slots: &[u64], interval: u64) -> (u64, u64) {
+    let mut missed = 0;
+    let mut longest_gap = 0;
+    for pair in slots.windows(2) {
+        let gap = pair[1] - pair[0];
+        missed += gap.saturating_sub(1) / interval;
+        longest_gap = longest_gap.max(gap);
+    }
+    (missed, longest_gap)
+}
+
+/// How regularly one periodic endpoint was serviced.
+pub struct EndpointSchedule {
+    pub address: u8,
+    /// Address of the endpoint, with the direction in bit 7.
+    pub endpoint_address: u8,
+    pub endpoint_type: EndpointType,
+    /// Expected service interval, in frames or microframes.
+    pub interval: u64,
+    /// Number of frames or microframes in which the endpoint was polled.
+    pub polls: u64,
+    pub missed: u64,
+    pub longest_gap: u64,
+}
+
+pub struct ScheduleReport {
+    pub high_speed: bool,
+    pub endpoints: Vec<EndpointSchedule>,
+}
+
+impl ScheduleReport {
+    fn unit(&self, count: u64) -> String {
+        format!("{} {}{}", count,
+                if self.high_speed {"microframe"} else {"frame"},
+                if count == 1 {""} else {"s"})
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.endpoints.iter()
+            .map(|ep| format!(
+                "Device {} endpoint {}, {:?} every {}: \
+                 polled {} times, {} missed, longest gap {}",
+                ep.address, fmt_endpoint_address(ep.endpoint_address),
+                ep.endpoint_type,
+                self.unit(ep.interval), ep.polls, ep.missed,
+                self.unit(ep.longest_gap)))
+            .collect()
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Owner {
+    Framing,
+    Periodic(usize),
+}
+
+/// Analyze the servicing of every interrupt and isochronous endpoint seen
+/// polled after an SOF.
+pub fn analyze_schedule(cap: &mut Capture) -> ScheduleReport {
+    profile!("analyze_schedule");
+    let endpoint_count = cap.endpoints.len();
+    let endpoints = cap.endpoints.get_range(0..endpoint_count).unwrap();
+    let mut owners = BTreeMap::new();
+    let mut periodic = Vec::new();
+    for (endpoint_id, endpoint) in endpoints.iter().enumerate() {
+        let dev_data = &cap.device_data[endpoint.device_id() as usize];
+        let endpoint_address = endpoint.endpoint_address();
+        let owner = match dev_data.endpoint_type(endpoint_address) {
+            EndpointType::Framing => Owner::Framing,
+            ep_type @ (EndpointType::Interrupt | EndpointType::Isochronous) => {
+                let interval = dev_data.configuration()
+                    .and_then(|config| config.interfaces.iter()
+                        .flat_map(|iface| iface.endpoint_descriptors.iter())
+                        .find(|ep_desc|
+                              ep_desc.endpoint_address == endpoint_address))
+                    .map_or(1, |ep_desc| ep_desc.interval);
+                periodic.push((endpoint.device_address(), endpoint_address,
+                               ep_type, interval, Vec::new()));
+                Owner::Periodic(periodic.len() - 1)
+            },
+            _ => continue,
+        };
+        let ep_traf = &mut cap.endpoint_traffic[endpoint_id];
+        let transaction_ids = ep_traf.transaction_ids
+            .get_range(0..ep_traf.transaction_ids.len())
+            .unwrap();
+        for transaction_id in transaction_ids {
+            owners.insert(transaction_id, owner);
+        }
+    }
+    let mut counter = FrameCounter::default();
+    if !periodic.is_empty() {
+        for (transaction_id, owner) in owners {
+            match owner {
+                Owner::Framing => {
+                    for packet in cap.get_transaction_packets(transaction_id) {
+                        if let PacketFields::SOF(sof) =
+                            PacketFields::from_packet(&packet)
+                        {
+                            counter.sof(&sof);
+                        }
+                    }
+                },
+                Owner::Periodic(index) => {
+                    if let Some(position) = counter.position() {
+                        periodic[index].4.push(position);
+                    }
+                }
+            }
+        }
+    }
+    // Use the speed the capture recorded, if it did.
+    let high_speed = match cap.speed {
+        Some(speed) => speed == Speed::High,
+        None => counter.high_speed,
+    };
+    let endpoints = periodic.into_iter()
+        .filter(|(.., positions)| !positions.is_empty())
+        .map(|(address, endpoint_address, ep_type, interval, positions)| {
+            let mut slots: Vec<u64> = positions.iter()
+                .map(|(frame, microframe)| if high_speed {
+                    frame * 8 + microframe
+                } else {
+                    *frame
+                })
+                .collect();
+            slots.dedup();
+            let interval =
+                service_interval(ep_type, interval, high_speed).unwrap();
+            let (missed, longest_gap) = missed_services(&slots, interval);
+            EndpointSchedule {
+                address: address,
+                endpoint_address: endpoint_address,
+                endpoint_type: ep_type,
+                interval: interval,
+                polls: slots.len() as u64,
+                missed: missed,
+                longest_gap: longest_gap,
+            }
+        })
+        .collect();
+    ScheduleReport {
+        high_speed: high_speed,
+        endpoints: endpoints,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    fn sof(frame_number: u16) -> SOFFields {
+        SOFFields(frame_number)
+    }
+
+    #[test]
+    fn test_frame_counter() {
+        let mut counter = FrameCounter::default();
+        assert!(counter.position().is_none());
+        counter.sof(&sof(2046));
+        counter.sof(&sof(2047));
+        counter.sof(&sof(1));
+        assert!(counter.position() == Some((3, 0)));
+        assert!(!counter.high_speed);
+        counter.sof(&sof(1));
+        counter.sof(&sof(1));
+        assert!(counter.position() == Some((3, 2)));
+        counter.sof(&sof(2));
+        assert!(counter.position() == Some((4, 0)));
+        assert!(counter.high_speed);
+    }
+
+    #[test]
+    fn test_missed_services() {
+        assert!(service_interval(EndpointType::Interrupt, 10, false) ==
+                Some(10));
+        assert!(service_interval(EndpointType::Interrupt, 4, true) ==
+                Some(8));
+        assert!(service_interval(EndpointType::Isochronous, 1, false) ==
+                Some(1));
+        assert!(service_interval(EndpointType::Bulk, 1, false).is_none());
+        assert!(missed_services(&[], 8) == (0, 0));
+        assert!(missed_services(&[0, 8, 16, 24], 8) == (0, 8));
+        assert!(missed_services(&[0, 8, 32, 36], 8) == (2, 24));
+        assert!(missed_services(&[0, 1, 3, 4], 1) == (1, 2));
+    }
+
+    #[test]
+    fn test_no_periodic_traffic() {
+        for path in ["./tests/mouse/capture.pcap",
+                     "./tests/hackrf-connect/capture.pcap"]
+        {
+            let mut cap = Capture::new();
+            let mut decoder = Decoder::new(&mut cap);
+            decode_files(&mut decoder, &[path]).unwrap();
+            drop(decoder);
+            // The mouse capture has no SOFs, and the HackRF only bulk
+            // endpoints.
+            assert!(analyze_schedule(&mut cap).endpoints.is_empty());
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Rendering of the current view to PNG or PDF files.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::path::Path;
+
+use gtk::prelude::*;
+use gtk::{cairo, gdk};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RenderError {
+    #[error("nothing to render, the view is empty")]
+    Empty,
+    #[error(transparent)]
+    CairoError(#[from] cairo::Error),
+    #[error(transparent)]
+    PngError(#[from] cairo::IoError),
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Render a widget as currently displayed to a file.
+///
+/// The output is PDF if the path ends in `.pdf`, and PNG otherwise. Row
+/// colours and connector graphics are included, as they are drawn by the
+/// widget itself.
+pub fn render_to_file(widget: &impl IsA<gtk::Widget>, path: &Path)
+    -> Result<(), RenderError>
+{
+    let width = widget.width();
+    let height = widget.height();
+    let paintable = gtk::WidgetPaintable::new(Some(widget));
+    let snapshot = gtk::Snapshot::new();
+    paintable.snapshot(snapshot.upcast_ref::<gdk::Snapshot>(),
+                       width as f64, height as f64);
+    let node = snapshot.to_node().ok_or(RenderError::Empty)?;
+    if path.extension() == Some(OsStr::new("pdf")) {
+        let surface = cairo::PdfSurface::new(
+            width as f64, height as f64, path)?;
+        let context = cairo::Context::new(&surface)?;
+        node.draw(&context);
+        drop(context);
+        surface.finish();
+    } else {
+        let surface = cairo::ImageSurface::create(
+            cairo::Format::ARgb32, width, height)?;
+        let context = cairo::Context::new(&surface)?;
+        node.draw(&context);
+        drop(context);
+        let mut file = File::create(path)?;
+        surface.write_to_png(&mut file)?;
+    }
+    Ok(())
+}
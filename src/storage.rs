@@ -0,0 +1,607 @@
+//! Management of the temporary files used to store capture data.
+//!
+//! While a session is active, storage files are created in a directory
+//! named after the process ID, under the user's cache directory. This lets
+//! their total size be reported, and lets files left behind by a session
+//! that crashed be identified and removed at the next startup. Each session
+//! holds a lock on a file in its directory until it ends, and the operating
+//! system releases the lock if the process exits without ending it, so a
+//! directory whose lock can be taken belongs to no running session.
+//!
+//! Within a session, each capture's files are kept in a directory of its
+//! own, named after the capture's files and a hash of their paths, and
+//! each file is named for what it holds. This shows what belongs to what,
+//! and gives the files a layout which stays the same between runs. The
+//! directory is removed once the capture is dropped.
+//!
+//! A capture streamed into a session is also recorded in its directory as
+//! it arrives, so that it can be restored if the session does not end
+//! cleanly.
+
+use std::fs::{File, read_dir, remove_dir_all};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::OnceCell;
+use tempfile::{Builder, NamedTempFile, tempfile};
+
+use crate::input::STDIN_FILENAME;
+
+static SESSION_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+static SESSION_LOCK: Mutex<Option<File>> = Mutex::new(None);
+
+static MEMORY_LIMITS: OnceCell<MemoryLimits> = OnceCell::new();
+
+/// Estimated memory used by an endpoint's indexes while active: the
+/// buffers of their two files, and their entries.
+const ACTIVE_ENDPOINT_BYTES: u64 = 24 << 10;
+
+/// Estimated memory used by each transfer payload total cached.
+const CACHED_PAYLOAD_BYTES: u64 = 64;
+
+/// Limits on the capture data held in memory which would otherwise grow
+/// with the size of a capture, beyond which it is kept only in storage
+/// files.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MemoryLimits {
+    /// Endpoints whose indexes are kept active. Those least recently used
+    /// beyond this are parked in their files until next needed.
+    pub active_endpoints: usize,
+    /// Transfer payload totals kept once found.
+    pub cached_payloads: usize,
+}
+
+impl Default for MemoryLimits {
+    fn default() -> Self {
+        MemoryLimits {
+            active_endpoints: 256,
+            cached_payloads: 1 << 16,
+        }
+    }
+}
+
+impl MemoryLimits {
+    /// Limits which keep the data they cover to about this many megabytes,
+    /// split evenly between endpoints and caches.
+    pub fn for_megabytes(count: u64) -> MemoryLimits {
+        let half = (count << 20) / 2;
+        MemoryLimits {
+            active_endpoints:
+                (half / ACTIVE_ENDPOINT_BYTES).max(1) as usize,
+            cached_payloads: (half / CACHED_PAYLOAD_BYTES).max(1) as usize,
+        }
+    }
+}
+
+/// Set the memory limits of captures opened from now on. Only the first
+/// limits set take effect.
+pub fn set_memory_limits(limits: MemoryLimits) {
+    let _ = MEMORY_LIMITS.set(limits);
+}
+
+/// The memory limits of captures opened now.
+pub fn memory_limits() -> MemoryLimits {
+    MEMORY_LIMITS.get().copied().unwrap_or_default()
+}
+
+/// File in a session directory which is locked while the session runs.
+const LOCK_FILE: &str = "session.lock";
+
+/// File in a session directory to which a streamed capture is recorded.
+const RECOVERY_FILE: &str = "recovery.pcap";
+
+/// File in a session directory giving the command which streamed the
+/// capture being recorded.
+const RECOVERY_INFO: &str = "recovery.txt";
+
+/// A streamed capture recorded by a session which did not end cleanly.
+pub struct Recovery {
+    pub path: PathBuf,
+    /// Command line of the session.
+    pub command: String,
+    pub size: u64,
+}
+
+/// Storage left behind by a previous session.
+pub struct StaleSession {
+    pub path: PathBuf,
+    pub file_count: u64,
+    pub size: u64,
+    pub recovery: Option<Recovery>,
+}
+
+/// Base directory under which session directories are created, within
+/// the user's cache directory for the platform.
+pub fn base_dir() -> PathBuf {
+    gtk::glib::user_cache_dir()
+        .join("packetry")
+        .join("sessions")
+}
+
+/// Start a session, after which storage files are created in its directory.
+pub fn start_session() -> Result<()> {
+    let dir = base_dir().join(std::process::id().to_string());
+    std::fs::create_dir_all(&dir)?;
+    *SESSION_LOCK.lock().unwrap() = Some(lock_session(&dir)?);
+    let _ = SESSION_DIR.set(dir);
+    Ok(())
+}
+
+/// End the session, removing all its storage files.
+pub fn end_session() -> Result<()> {
+    // The lock file must be closed before it can be removed on Windows.
+    SESSION_LOCK.lock().unwrap().take();
+    match SESSION_DIR.get() {
+        Some(dir) => remove_dir_all(dir),
+        None => Ok(())
+    }
+}
+
+/// Start recording a streamed capture in the session, returning the path
+/// of the pcap file to record it to.
+pub fn start_recovery(command: &str) -> Result<PathBuf> {
+    let dir = SESSION_DIR.get()
+        .ok_or_else(|| Error::other("no storage session started"))?;
+    std::fs::write(dir.join(RECOVERY_INFO), command)?;
+    Ok(dir.join(RECOVERY_FILE))
+}
+
+/// Directory to which captures are restored from stale sessions.
+pub fn restored_dir() -> PathBuf {
+    base_dir().with_file_name("restored")
+}
+
+/// Move the capture recorded by a stale session into `dir`, returning its
+/// new path.
+pub fn restore(session: &StaleSession, dir: &Path) -> Result<PathBuf> {
+    let recovery = session.recovery.as_ref()
+        .ok_or_else(|| Error::other("no capture to restore"))?;
+    let name = session.path.file_name().unwrap_or_default();
+    let path = dir.join(name).with_extension("pcap");
+    std::fs::create_dir_all(dir)?;
+    std::fs::rename(&recovery.path, &path)?;
+    Ok(path)
+}
+
+/// Find the streamed capture recorded in a session directory, if any.
+fn find_recovery(dir: &Path) -> Option<Recovery> {
+    let path = dir.join(RECOVERY_FILE);
+    let size = path.metadata().ok()?.len();
+    if size == 0 {
+        return None;
+    }
+    let command = std::fs::read_to_string(dir.join(RECOVERY_INFO))
+        .unwrap_or_default();
+    Some(Recovery {
+        path: path,
+        command: command.trim().to_string(),
+        size: size,
+    })
+}
+
+/// Create a new storage file.
+///
+/// Outside of a session, the file is anonymous and removed automatically.
+pub fn create_file() -> Result<File> {
+    match SESSION_DIR.get() {
+        Some(dir) => {
+            let (file, _path) = Builder::new()
+                .prefix("packetry-")
+                .tempfile_in(dir)?
+                .keep()?;
+            Ok(file)
+        },
+        None => tempfile()
+    }
+}
+
+/// Create a storage file with a path, for data which must be read by name.
+///
+/// The file is removed when dropped.
+pub fn create_named_file() -> Result<NamedTempFile> {
+    let builder = Builder::new().prefix("packetry-").clone();
+    match SESSION_DIR.get() {
+        Some(dir) => builder.tempfile_in(dir),
+        None => builder.tempfile(),
+    }
+}
+
+/// Write the first `length` bytes of a storage file to `output`.
+///
+/// Reads are positional, so on Unix the file offset used for appending to
+/// the file is not disturbed.
+pub fn copy_contents(file: &File, length: u64, output: &mut dyn Write)
+    -> Result<()>
+{
+    let mut buffer = vec![0; 0x10000];
+    let mut offset = 0;
+    while offset < length {
+        let size = (length - offset).min(buffer.len() as u64) as usize;
+        read_exact_at(file, &mut buffer[..size], offset)?;
+        output.write_all(&buffer[..size])?;
+        offset += size as u64;
+    }
+    Ok(())
+}
+
+/// Read exactly enough bytes to fill `buffer` from `offset` in a file.
+///
+/// On Unix this leaves the file offset alone. On Windows the offset is
+/// moved to the end of the bytes read.
+#[cfg(unix)]
+pub fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64)
+    -> Result<()>
+{
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buffer, offset)
+}
+
+#[cfg(windows)]
+pub fn read_exact_at(file: &File, mut buffer: &mut [u8], mut offset: u64)
+    -> Result<()>
+{
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset) {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof,
+                                           "failed to fill whole buffer")),
+            Ok(n) => {
+                buffer = &mut buffer[n..];
+                offset += n as u64;
+            },
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Write all of `buffer` at `offset` in a file.
+///
+/// On Unix this leaves the file offset alone. On Windows the offset is
+/// moved to the end of the bytes written.
+#[cfg(unix)]
+pub fn write_all_at(file: &File, buffer: &[u8], offset: u64) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buffer, offset)
+}
+
+#[cfg(windows)]
+pub fn write_all_at(file: &File, mut buffer: &[u8], mut offset: u64)
+    -> Result<()>
+{
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        match file.seek_write(buffer, offset) {
+            Ok(0) => return Err(Error::new(ErrorKind::WriteZero,
+                                           "failed to write whole buffer")),
+            Ok(n) => {
+                buffer = &buffer[n..];
+                offset += n as u64;
+            },
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Write the next `length` bytes of `input` to a new storage file.
+fn fill_file(mut file: File, input: &mut dyn Read, length: u64)
+    -> Result<File>
+{
+    let copied = std::io::copy(&mut input.take(length), &mut file)?;
+    if copied != length {
+        return Err(Error::new(ErrorKind::UnexpectedEof,
+                              "storage file contents truncated"));
+    }
+    Ok(file)
+}
+
+/// Name of the directory for a capture's storage files: the name of its
+/// first file, and a hash of the full paths of all its files.
+fn capture_dir_name<S: AsRef<str>>(filenames: &[S]) -> String {
+    // FNV-1a, which unlike the standard library's hasher gives the same
+    // result in every build.
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for filename in filenames {
+        let filename = filename.as_ref();
+        let path = std::fs::canonicalize(filename)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| filename.to_string());
+        for byte in path.bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    let name = match filenames.first().map(AsRef::as_ref) {
+        Some(STDIN_FILENAME) => "stdin".to_string(),
+        Some(filename) => Path::new(filename).file_name()
+            .map(|name| name.to_string_lossy().chars()
+                .take(MAX_NAME_LENGTH)
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+                    _ => '_',
+                })
+                .collect())
+            .unwrap_or_default(),
+        None => String::new(),
+    };
+    format!("{}-{:016x}", name, hash)
+}
+
+/// Longest part of a file name used in a capture's directory name.
+const MAX_NAME_LENGTH: usize = 40;
+
+/// Create a file or directory at a path, or the first of `path.2`,
+/// `path.3`, and so on which does not exist yet.
+fn create_new<T>(path: &Path, create: impl Fn(&Path) -> Result<T>)
+    -> Result<(PathBuf, T)>
+{
+    let mut count = 1;
+    loop {
+        let path = match count {
+            1 => path.to_path_buf(),
+            _ => {
+                let mut name = path.as_os_str().to_owned();
+                name.push(format!(".{}", count));
+                PathBuf::from(name)
+            }
+        };
+        match create(&path) {
+            Ok(created) => return Ok((path, created)),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => count += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The directory of a capture's storage files, which is removed with the
+/// files in it when dropped.
+#[derive(Debug)]
+struct CaptureDir {
+    path: PathBuf,
+}
+
+impl Drop for CaptureDir {
+    fn drop(&mut self) {
+        // The session may have ended already, removing it.
+        let _ = remove_dir_all(&self.path);
+    }
+}
+
+/// Where the storage files of a capture are created.
+///
+/// Outside of a session, or for a capture whose source is not known, the
+/// files are anonymous, as from [create_file]. Otherwise they are kept in
+/// a directory which is removed when the last clone of the CaptureStorage
+/// is dropped.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureStorage {
+    dir: Option<Arc<CaptureDir>>,
+}
+
+impl CaptureStorage {
+    /// Create the directory for a capture of the given files in the
+    /// current session.
+    pub fn for_source<S: AsRef<str>>(filenames: &[S])
+        -> Result<CaptureStorage>
+    {
+        match SESSION_DIR.get() {
+            Some(session) => CaptureStorage::create_in(session, filenames),
+            None => Ok(CaptureStorage::default()),
+        }
+    }
+
+    fn create_in<S: AsRef<str>>(session: &Path, filenames: &[S])
+        -> Result<CaptureStorage>
+    {
+        let path = session.join(capture_dir_name(filenames));
+        let (dir, _) = create_new(&path, |path| std::fs::create_dir(path))?;
+        Ok(CaptureStorage { dir: Some(Arc::new(CaptureDir { path: dir })) })
+    }
+
+    /// Directory holding the capture's files, if it has one.
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_ref().map(|dir| dir.path.as_path())
+    }
+
+    /// Create a new storage file, named for its purpose.
+    pub fn create_file(&self, purpose: &str) -> Result<File> {
+        let dir = match self.dir() {
+            Some(dir) => dir,
+            None => return create_file(),
+        };
+        let (_, file) = create_new(&dir.join(purpose), |path|
+            File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(path))?;
+        Ok(file)
+    }
+
+    /// Create a new storage file, named for its purpose, holding the next
+    /// `length` bytes of `input`.
+    pub fn create_file_from(&self, purpose: &str, input: &mut dyn Read,
+                            length: u64) -> Result<File>
+    {
+        fill_file(self.create_file(purpose)?, input, length)
+    }
+}
+
+/// Read a little-endian u64, as written along with saved storage files.
+pub fn read_u64(input: &mut dyn Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Number and total size of the files in a directory.
+fn dir_usage(dir: &Path) -> Result<(u64, u64)> {
+    let mut file_count = 0;
+    let mut size = 0;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && entry.file_name() != LOCK_FILE {
+            file_count += 1;
+            size += metadata.len();
+        }
+    }
+    Ok((file_count, size))
+}
+
+/// Total size of the files in the current session.
+pub fn session_size() -> u64 {
+    SESSION_DIR.get()
+        .and_then(|dir| dir_usage(dir).ok())
+        .map_or(0, |(_, size)| size)
+}
+
+/// Take the lock marking a session directory as in use, which is held
+/// until the file returned is closed.
+fn lock_session(dir: &Path) -> Result<File> {
+    let file = File::create(dir.join(LOCK_FILE))?;
+    file.try_lock()?;
+    Ok(file)
+}
+
+/// Whether a session directory is locked by a session still running.
+fn session_running(dir: &Path) -> bool {
+    match File::open(dir.join(LOCK_FILE)) {
+        Ok(file) => file.try_lock_shared().is_err(),
+        Err(_) => false,
+    }
+}
+
+/// Find the directories of sessions under `base` whose process has exited.
+pub fn find_stale_sessions(base: &Path) -> Vec<StaleSession> {
+    let mut stale = Vec::new();
+    let entries = match read_dir(base) {
+        Ok(entries) => entries,
+        Err(_) => return stale,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        match name.to_str() {
+            Some(pid) if pid.parse::<u32>().is_ok() => {},
+            _ => continue,
+        };
+        if session_running(&entry.path()) {
+            continue;
+        }
+        if let Ok((file_count, size)) = dir_usage(&entry.path()) {
+            stale.push(StaleSession {
+                path: entry.path(),
+                file_count: file_count,
+                size: size,
+                recovery: find_recovery(&entry.path()),
+            });
+        }
+    }
+    stale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Seek;
+
+    #[test]
+    fn test_memory_limits() {
+        let limits = MemoryLimits::for_megabytes(64);
+        assert!(limits.active_endpoints == 1365);
+        assert!(limits.cached_payloads == 1 << 19);
+        let limits = MemoryLimits::for_megabytes(0);
+        assert!(limits.active_endpoints == 1);
+        assert!(limits.cached_payloads == 1);
+    }
+
+    #[test]
+    fn test_find_stale_sessions() {
+        let base = tempfile::tempdir().unwrap();
+        let running = base.path().join("1");
+        let crashed = base.path().join("2");
+        let unlocked = base.path().join("3");
+        let other = base.path().join("not-a-session");
+        for dir in [&running, &crashed, &unlocked, &other] {
+            std::fs::create_dir(dir).unwrap();
+            let mut file = File::create(dir.join("packetry-x")).unwrap();
+            file.write_all(&[0; 100]).unwrap();
+        }
+        File::create(crashed.join("packetry-y")).unwrap()
+            .write_all(&[0; 23]).unwrap();
+        let _lock = lock_session(&running).unwrap();
+        drop(lock_session(&crashed).unwrap());
+        let stale = find_stale_sessions(base.path());
+        assert!(stale.len() == 2);
+        assert!(stale.iter().any(|session| session.path == unlocked));
+        std::fs::remove_dir_all(&unlocked).unwrap();
+        let stale = find_stale_sessions(base.path());
+        assert!(stale.len() == 1);
+        assert!(stale[0].path == crashed);
+        assert!(stale[0].file_count == 2);
+        assert!(stale[0].size == 123);
+        assert!(stale[0].recovery.is_none());
+        assert!(find_stale_sessions(&base.path().join("missing")).is_empty());
+    }
+
+    #[test]
+    fn test_capture_storage() {
+        let session = tempfile::tempdir().unwrap();
+        let name = capture_dir_name(&["/no/such/capture one.pcap"]);
+        assert!(name == capture_dir_name(&["/no/such/capture one.pcap"]));
+        assert!(name.starts_with("capture_one.pcap-"));
+        assert!(name != capture_dir_name(&["/no/other/capture one.pcap"]));
+        assert!(capture_dir_name(&["-"]).starts_with("stdin-"));
+
+        let first = CaptureStorage::create_in(session.path(),
+                                              &["capture one.pcap"]).unwrap();
+        let second = CaptureStorage::create_in(session.path(),
+                                               &["capture one.pcap"]).unwrap();
+        let first_dir = first.dir().unwrap();
+        let second_dir = second.dir().unwrap();
+        assert!(second_dir.as_os_str() ==
+                format!("{}.2", first_dir.display()).as_str());
+        first.create_file("item_index").unwrap();
+        let mut file = first.create_file_from("item_index",
+                                              &mut &[1, 2, 3][..], 3).unwrap();
+        let mut contents = Vec::new();
+        file.seek(std::io::SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert!(contents == [1, 2, 3]);
+        assert!(first_dir.join("item_index").exists());
+        assert!(std::fs::read(first_dir.join("item_index.2")).unwrap() ==
+                [1, 2, 3]);
+
+        // The directory is removed with the last clone of its storage.
+        let first_dir = first_dir.to_path_buf();
+        let clone = first.clone();
+        drop(first);
+        assert!(first_dir.exists());
+        drop(clone);
+        assert!(!first_dir.exists());
+        assert!(second_dir.exists());
+    }
+
+    #[test]
+    fn test_restore() {
+        let base = tempfile::tempdir().unwrap();
+        let crashed = base.path().join("4294967295");
+        std::fs::create_dir(&crashed).unwrap();
+        std::fs::write(crashed.join(RECOVERY_FILE), [0; 24]).unwrap();
+        std::fs::write(crashed.join(RECOVERY_INFO), "packetry -\n").unwrap();
+        let stale = find_stale_sessions(base.path());
+        let recovery = stale[0].recovery.as_ref().unwrap();
+        assert!(recovery.command == "packetry -");
+        assert!(recovery.size == 24);
+        let restored = base.path().join("restored");
+        let path = restore(&stale[0], &restored).unwrap();
+        assert!(path == restored.join("4294967295.pcap"));
+        assert!(std::fs::read(&path).unwrap() == [0; 24]);
+        assert!(find_stale_sessions(base.path())[0].recovery.is_none());
+    }
+}
@@ -0,0 +1,157 @@
+//! A summary of a capture, shown when it is opened to give a quick idea of
+//! what it contains.
+
+use crate::capture::{Capture, EndpointType, fmt_count};
+use crate::usb_ids::fmt_device_id;
+
+/// Endpoint types whose transfers are counted, in the order shown.
+const TRANSFER_TYPES: [EndpointType; 4] = [
+    EndpointType::Control,
+    EndpointType::Bulk,
+    EndpointType::Interrupt,
+    EndpointType::Isochronous,
+];
+
+pub struct CaptureSummary {
+    pub packets: u64,
+    pub transactions: u64,
+    /// Each device seen, as its address, any label given to it, and a
+    /// description.
+    pub devices: Vec<(u8, Option<String>, String)>,
+    /// Number of transfers on endpoints of each of [TRANSFER_TYPES].
+    pub transfers: [u64; 4],
+    pub halts: u64,
+    pub msc_errors: u64,
+    pub sof_gaps: u64,
+    pub phy_errors: u64,
+}
+
+impl CaptureSummary {
+    pub fn warnings(&self) -> u64 {
+        self.halts + self.msc_errors + self.sof_gaps + self.phy_errors
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("{} packets in {} transactions",
+                    fmt_count(self.packets), fmt_count(self.transactions)),
+            format!("{} devices:", self.devices.len()),
+        ];
+        for (address, label, description) in &self.devices {
+            lines.push(match label {
+                Some(label) => format!("  Device {} ({}): {}",
+                                       address, label, description),
+                None => format!("  Device {}: {}", address, description),
+            });
+        }
+        lines.push("Transfers:".to_string());
+        for (ep_type, count) in TRANSFER_TYPES.iter().zip(self.transfers) {
+            lines.push(format!("  {:?}: {}", ep_type, fmt_count(count)));
+        }
+        lines.extend(self.warning_lines());
+        lines
+    }
+
+    /// Lines giving the number of warnings, and of each kind found.
+    pub fn warning_lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("{} warnings", fmt_count(self.warnings())),
+        ];
+        for (count, what) in [(self.halts, "endpoint halts"),
+                              (self.msc_errors, "mass storage errors"),
+                              (self.sof_gaps, "gaps in SOF frame numbers"),
+                              (self.phy_errors, "packets with PHY errors")]
+        {
+            if count > 0 {
+                lines.push(format!("  {} {}", fmt_count(count), what));
+            }
+        }
+        lines
+    }
+}
+
+/// Summarize a decoded capture, after its post-decode checks have run.
+pub fn capture_summary(cap: &mut Capture) -> CaptureSummary {
+    profile!("capture_summary");
+    let device_count = cap.devices.len();
+    let devices = cap.devices.get_range(0..device_count).unwrap()
+        .iter()
+        .zip(cap.device_data.iter())
+        .enumerate()
+        .map(|(device_id, (device, data))| (
+            device.address,
+            cap.label(device_id, None),
+            match data.device_descriptor {
+                Some(descriptor) => {
+                    let id = fmt_device_id(descriptor.vendor_id,
+                                           descriptor.product_id);
+                    let product = data.strings
+                        .get(descriptor.product_str_id as usize)
+                        .and_then(|string| string.as_ref())
+                        .filter(|_| descriptor.product_str_id != 0);
+                    match product {
+                        Some(bytes) => format!("{}, '{}'", id,
+                                               utf16_string(bytes)),
+                        None => id,
+                    }
+                },
+                None => "Unknown".to_string(),
+            }))
+        .collect();
+    let mut transfers = [0; 4];
+    let entry_count = cap.transfer_index.len();
+    let entries = cap.transfer_index.get_range(0..entry_count).unwrap();
+    for entry in entries.iter().filter(|entry| entry.is_start()) {
+        let endpoint = cap.endpoints.get(entry.endpoint_id() as u64).unwrap();
+        let dev_data = &cap.device_data[endpoint.device_id() as usize];
+        let ep_type = dev_data.endpoint_type(endpoint.endpoint_address());
+        if let Some(index) = TRANSFER_TYPES.iter()
+            .position(|t| *t as u8 == ep_type as u8)
+        {
+            transfers[index] += 1;
+        }
+    }
+    CaptureSummary {
+        packets: cap.packet_index.len(),
+        transactions: cap.transaction_index.len(),
+        devices: devices,
+        transfers: transfers,
+        halts: cap.halts.len() as u64,
+        msc_errors: cap.msc_errors.len() as u64,
+        sof_gaps: cap.sof_gaps.len() as u64,
+        phy_errors: cap.phy_errors.len() as u64,
+    }
+}
+
+fn utf16_string(bytes: &[u8]) -> String {
+    let chars: Vec<u16> = bytes.chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16_lossy(&chars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    #[test]
+    fn test_capture_summary() {
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &["./tests/mouse/capture.pcap"]).unwrap();
+        drop(decoder);
+        let summary = capture_summary(&mut cap);
+        assert!(summary.packets == cap.packet_index.len());
+        assert!(summary.devices.len() == 2);
+        assert!(summary.devices[1].0 == 4);
+        assert!(summary.devices[1].1.is_none());
+        assert!(summary.devices[1].2.ends_with(", 'USB Optical Mouse'"));
+        assert!(summary.transfers == [10, 0, 1, 0]);
+        assert!(summary.warnings() == 0);
+        let lines = summary.lines();
+        assert!(lines.contains(&"Transfers:".to_string()));
+        assert!(lines.last().unwrap() == "0 warnings");
+    }
+}
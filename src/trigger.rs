@@ -0,0 +1,181 @@
+//! Triggers, which start recording a streamed capture only once some
+//! traffic of interest is seen.
+//!
+//! A trigger is checked against each packet as it is read, before it is
+//! decoded. Until it matches, only a limited history of packets is kept,
+//! so that the traffic leading up to the match can still be seen.
+
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::usb::{PID, PacketFields};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TriggerError {
+    #[error("empty trigger")]
+    Empty,
+    #[error("unknown trigger condition '{0}', expected address ADDRESS, \
+             request REQUEST or pattern HEX")]
+    UnknownCondition(String),
+    #[error("invalid number '{0}'")]
+    InvalidNumber(String),
+    #[error("invalid byte pattern '{0}', expected bytes in hex")]
+    InvalidPattern(String),
+}
+
+/// Conditions that a trigger can match on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TriggerCondition {
+    /// A token was sent to the device at this address.
+    Address(u8),
+    /// A SETUP was sent with this bRequest.
+    Request(u8),
+    /// A data packet's payload contains these bytes.
+    Pattern(Vec<u8>),
+}
+
+/// Parse a number in decimal, or in hex with a 0x prefix.
+fn parse_number(text: &str) -> Result<u8, TriggerError> {
+    let result = match text.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    result.map_err(|_| TriggerError::InvalidNumber(text.to_string()))
+}
+
+impl FromStr for TriggerCondition {
+    type Err = TriggerError;
+
+    /// Parse a condition, e.g. "address 4", "request 0x09" or
+    /// "pattern deadbeef".
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            [] => Err(TriggerError::Empty),
+            ["address", address] =>
+                Ok(TriggerCondition::Address(parse_number(address)?)),
+            ["request", request] =>
+                Ok(TriggerCondition::Request(parse_number(request)?)),
+            ["pattern", hex] => {
+                let invalid = || TriggerError::InvalidPattern(hex.to_string());
+                if hex.is_empty() || hex.len() % 2 != 0 {
+                    return Err(invalid());
+                }
+                let bytes = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| hex.get(i..i + 2)
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or_else(invalid)?;
+                Ok(TriggerCondition::Pattern(bytes))
+            },
+            _ => Err(TriggerError::UnknownCondition(text.to_string())),
+        }
+    }
+}
+
+/// Number of packets kept from before a match, unless set otherwise.
+pub const DEFAULT_HISTORY: usize = 1000;
+
+/// Checks packets against a condition, in the order they were captured.
+pub struct Trigger {
+    pub condition: TriggerCondition,
+    /// Number of packets before the match to keep.
+    pub history: usize,
+    after_setup: bool,
+}
+
+impl Trigger {
+    pub fn new(condition: TriggerCondition, history: usize) -> Trigger {
+        Trigger {
+            condition: condition,
+            history: history,
+            after_setup: false,
+        }
+    }
+
+    /// Check the next packet, returning whether the condition matched.
+    pub fn check(&mut self, packet: &[u8]) -> bool {
+        let pid = match packet.first() {
+            Some(byte) => PID::from(*byte),
+            None => return false,
+        };
+        let after_setup = self.after_setup;
+        self.after_setup = pid == PID::SETUP;
+        match (&self.condition, PacketFields::from_packet(packet)) {
+            (TriggerCondition::Address(address), PacketFields::Token(token)) =>
+                token.device_address() == *address,
+            (TriggerCondition::Request(request), PacketFields::Data(_)) =>
+                after_setup && packet.get(2) == Some(request),
+            (TriggerCondition::Pattern(pattern), PacketFields::Data(_))
+                if packet.len() >= 3 =>
+            {
+                let payload = &packet[1..packet.len() - 2];
+                payload.windows(pattern.len()).any(|bytes| bytes == pattern)
+            },
+            (..) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::{data_crc, token_crc};
+
+    fn token(pid: PID, address: u8) -> Vec<u8> {
+        let fields = address as u16;
+        let fields = fields | (token_crc(fields) as u16) << 11;
+        let bytes = fields.to_le_bytes();
+        vec![pid as u8, bytes[0], bytes[1]]
+    }
+
+    fn data(payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![PID::DATA0 as u8];
+        packet.extend_from_slice(payload);
+        packet.extend_from_slice(&data_crc(payload).to_le_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        use TriggerCondition::*;
+        assert!("address 4".parse() == Ok(Address(4)));
+        assert!("request 0x09".parse() == Ok(Request(9)));
+        assert!("pattern DEADbeef".parse() ==
+                Ok(Pattern(vec![0xde, 0xad, 0xbe, 0xef])));
+        for (text, error) in [
+            ("", TriggerError::Empty),
+            ("address", TriggerError::UnknownCondition("address".into())),
+            ("address 256", TriggerError::InvalidNumber("256".into())),
+            ("pattern abc", TriggerError::InvalidPattern("abc".into())),
+            ("pattern zz", TriggerError::InvalidPattern("zz".into())),
+        ] {
+            assert!(text.parse::<TriggerCondition>() == Err(error));
+        }
+    }
+
+    #[test]
+    fn test_trigger() {
+        let set_config = [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let packets = [
+            token(PID::OUT, 3),
+            data(&[0x00, 0x09, 0x01, 0x02]),
+            token(PID::SETUP, 4),
+            data(&set_config),
+            vec![PID::ACK as u8],
+        ];
+        let matches = |condition: &str| {
+            let mut trigger = Trigger::new(condition.parse().unwrap(), 0);
+            packets.iter()
+                .map(|packet| trigger.check(packet))
+                .collect::<Vec<bool>>()
+        };
+        assert!(matches("address 4") == [false, false, true, false, false]);
+        // The request is only matched in the data packet of a SETUP.
+        assert!(matches("request 9") == [false, false, false, true, false]);
+        assert!(matches("pattern 0102") == [false, true, false, false, false]);
+        assert!(matches("pattern 0100") == [false, false, false, true, false]);
+    }
+}
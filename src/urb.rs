@@ -0,0 +1,214 @@
+//! Conversion of USB request blocks (URBs), as recorded by host-side
+//! capture tools, back into the packets which carried them.
+//!
+//! A host-side capture records the URBs submitted to the host controller
+//! and their completions, rather than the packets on the bus. To decode it
+//! like any other capture, each completed URB is turned back into the
+//! transactions which would have carried it: a control transfer becomes
+//! its setup, data and status stages, and a bulk or interrupt transfer a
+//! single transaction with all its data.
+//!
+//! The packet boundaries within a URB, and any NAKs and retries, are not
+//! recorded, so are not shown. Isochronous URBs are not converted.
+
+use std::collections::HashMap;
+
+use crate::usb::{data_crc, token_crc, PID};
+
+pub const ISOCHRONOUS: u8 = 0;
+pub const INTERRUPT: u8 = 1;
+pub const CONTROL: u8 = 2;
+pub const BULK: u8 = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UrbEvent {
+    Submission,
+    Completion,
+    /// The URB could not be submitted.
+    Error,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UrbStatus {
+    InProgress,
+    /// Completed, possibly with less data than requested.
+    Success,
+    /// Stalled by the device.
+    Stalled,
+    /// Failed or cancelled for any other reason.
+    Failed,
+}
+
+/// The fields of a URB record which are used to rebuild its packets.
+pub struct UrbHeader {
+    /// Identifies the URB among those in progress.
+    pub id: u64,
+    pub event: UrbEvent,
+    /// One of the transfer types above, as numbered in endpoint
+    /// descriptors.
+    pub transfer_type: u8,
+    /// Endpoint number, with the direction in bit 7.
+    pub endpoint: u8,
+    pub device: u8,
+    pub status: UrbStatus,
+    pub setup: Option<[u8; 8]>,
+}
+
+impl UrbHeader {
+    fn is_in(&self) -> bool {
+        self.endpoint & 0x80 != 0
+    }
+
+    fn toggle_key(&self) -> (u8, u8) {
+        match self.transfer_type {
+            CONTROL => (self.device, self.endpoint & 0x0F),
+            _ => (self.device, self.endpoint),
+        }
+    }
+}
+
+/// Converts the records of a host-side capture file into packets.
+pub trait RecordImporter {
+    /// Convert one record to packets. Returns no packets for records
+    /// which do not complete a URB, and for URBs which failed or cannot
+    /// be imported.
+    fn packets(&mut self, record: &[u8]) -> Vec<Vec<u8>>;
+}
+
+/// A URB awaiting completion.
+struct PendingUrb {
+    setup: Option<[u8; 8]>,
+    /// Data sent by the host, for OUT transfers.
+    data: Vec<u8>,
+}
+
+/// Converts URB submissions and completions into packets.
+#[derive(Default)]
+pub struct UrbConverter {
+    pending: HashMap<u64, PendingUrb>,
+    /// Whether the next data packet on each device address and endpoint
+    /// address is DATA1. Control endpoints are keyed by number alone.
+    toggles: HashMap<(u8, u8), bool>,
+}
+
+impl UrbConverter {
+    /// Convert a URB event to packets, given the data recorded with it.
+    /// Returns no packets for submissions, and for URBs which failed or
+    /// cannot be converted.
+    pub fn packets(&mut self, header: UrbHeader, data: &[u8])
+        -> Vec<Vec<u8>>
+    {
+        match header.event {
+            UrbEvent::Submission => {
+                self.pending.insert(header.id, PendingUrb {
+                    setup: header.setup,
+                    data: data.to_vec(),
+                });
+                return Vec::new();
+            },
+            UrbEvent::Completion => {},
+            UrbEvent::Error => {
+                self.pending.remove(&header.id);
+                return Vec::new();
+            }
+        }
+        let pending = self.pending.remove(&header.id);
+        let stalled = match header.status {
+            UrbStatus::Success => false,
+            UrbStatus::Stalled => true,
+            _ => return Vec::new(),
+        };
+        let mut packets = Vec::new();
+        match header.transfer_type {
+            ISOCHRONOUS => {},
+            CONTROL => {
+                let pending = match pending {
+                    Some(pending) => pending,
+                    None => return packets,
+                };
+                let setup = match pending.setup {
+                    Some(setup) => setup,
+                    None => return packets,
+                };
+                self.control(&mut packets, &header, &setup, &pending.data,
+                             data, stalled);
+            },
+            _ if header.is_in() => {
+                self.transaction(&mut packets, &header, PID::IN, data,
+                                 stalled);
+            },
+            _ => {
+                let data = match &pending {
+                    Some(pending) => &pending.data[..],
+                    None => return packets,
+                };
+                self.transaction(&mut packets, &header, PID::OUT, data,
+                                 stalled);
+            },
+        }
+        packets
+    }
+
+    /// Add the packets of a control transfer, stopping at the stage which
+    /// was stalled if any.
+    fn control(&mut self, packets: &mut Vec<Vec<u8>>, header: &UrbHeader,
+               setup: &[u8; 8], out_data: &[u8], in_data: &[u8],
+               stalled: bool)
+    {
+        let number = header.endpoint & 0x0F;
+        let key = header.toggle_key();
+        packets.push(token(PID::SETUP, header.device, number));
+        packets.push(data_packet(PID::DATA0, setup));
+        packets.push(vec![PID::ACK as u8]);
+        let is_in = setup[0] & 0x80 != 0;
+        let length = u16::from_le_bytes([setup[6], setup[7]]);
+        let stages = match (length, is_in) {
+            (0, _) => vec![(PID::IN, &[][..])],
+            (_, true) => vec![(PID::IN, in_data), (PID::OUT, &[][..])],
+            (_, false) => vec![(PID::OUT, out_data), (PID::IN, &[][..])],
+        };
+        // Any stall is shown at the first stage after setup, as the URB
+        // does not record which stage it happened in.
+        self.toggles.insert(key, true);
+        for (pid, data) in stages {
+            self.transaction(packets, header, pid, data, stalled);
+            if stalled {
+                break;
+            }
+            self.toggles.insert(key, true);
+        }
+    }
+
+    /// Add the packets of a single transaction, toggling the data PID of
+    /// the endpoint if it succeeded.
+    fn transaction(&mut self, packets: &mut Vec<Vec<u8>>, header: &UrbHeader,
+                   pid: PID, data: &[u8], stalled: bool)
+    {
+        let number = header.endpoint & 0x0F;
+        packets.push(token(pid, header.device, number));
+        if stalled {
+            packets.push(vec![PID::STALL as u8]);
+            return;
+        }
+        let toggle = self.toggles.entry(header.toggle_key())
+            .or_insert(false);
+        let data_pid = if *toggle { PID::DATA1 } else { PID::DATA0 };
+        *toggle = !*toggle;
+        packets.push(data_packet(data_pid, data));
+        packets.push(vec![PID::ACK as u8]);
+    }
+}
+
+fn token(pid: PID, address: u8, number: u8) -> Vec<u8> {
+    let fields = (address & 0x7F) as u16 | (number as u16) << 7;
+    let fields = fields | (token_crc(fields) as u16) << 11;
+    let bytes = fields.to_le_bytes();
+    vec![pid as u8, bytes[0], bytes[1]]
+}
+
+fn data_packet(pid: PID, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![pid as u8];
+    packet.extend_from_slice(payload);
+    packet.extend_from_slice(&data_crc(payload).to_le_bytes());
+    packet
+}
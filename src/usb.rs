@@ -1,9 +1,10 @@
-use std::mem::size_of;
+use std::cmp::max;
 
-use bytemuck_derive::{Pod, Zeroable};
-use bytemuck::pod_read_unaligned;
 use num_enum::{IntoPrimitive, FromPrimitive};
 
+use crate::record::Record;
+use crate::usb_ids::{self, fmt_named};
+
 #[derive(Copy, Clone, Debug, IntoPrimitive, FromPrimitive, PartialEq)]
 #[repr(u8)]
 pub enum PID {
@@ -19,6 +20,7 @@ pub enum PID {
     IN    = 0x69,
     NAK   = 0x5A,
     DATA1 = 0x4B,
+    /// ERR at high speed, and PRE at low and full speed.
     ERR   = 0x3C,
     SETUP = 0x2D,
     STALL = 0x1E,
@@ -54,11 +56,111 @@ bitfield! {
     pub u8, crc, _: 15, 11;
 }
 
+bitfield! {
+    /// Fields of a SPLIT token, which a high speed host sends to a hub
+    /// before the token of a transaction with a low or full speed device.
+    #[derive(Debug)]
+    pub struct SplitFields(u32);
+    pub u8, hub_address, _: 6, 0;
+    pub u8, _complete, _: 7, 7;
+    pub u8, port, _: 14, 8;
+}
+
+impl SplitFields {
+    pub fn from_packet(packet: &[u8]) -> SplitFields {
+        let mut bytes = [0; 4];
+        for (byte, value) in bytes.iter_mut().zip(&packet[1..]) {
+            *byte = *value;
+        }
+        SplitFields(u32::from_le_bytes(bytes))
+    }
+
+    /// Whether this is a complete-split, rather than a start-split.
+    pub fn is_complete(&self) -> bool {
+        self._complete() != 0
+    }
+}
+
+/// Speed of a USB 2.0 bus.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+}
+
+bitfield! {
+    /// Errors detected at the physical layer while receiving a packet.
+    ///
+    /// Capture hardware which checks the line encoding reports these as a
+    /// byte of flags in the metadata of each raw packet it sends. A packet
+    /// with any of these errors may have been received incorrectly.
+    #[derive(Copy, Clone, Debug, Default, PartialEq)]
+    pub struct PhyErrors(u8);
+    /// More than six consecutive ones were seen.
+    pub bit_stuff, set_bit_stuff: 0;
+    /// The packet did not end with a valid end of packet signal.
+    pub eop, set_eop: 1;
+    /// The bus was still active at the end of the frame.
+    pub babble, set_babble: 2;
+}
+
+impl PhyErrors {
+    pub fn from_bits(bits: u8) -> PhyErrors {
+        PhyErrors(bits)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn description(&self) -> String {
+        let mut errors = Vec::new();
+        if self.bit_stuff() {
+            errors.push("bit stuff error");
+        }
+        if self.eop() {
+            errors.push("EOP error");
+        }
+        if self.babble() {
+            errors.push("babble");
+        }
+        errors.join(", ")
+    }
+}
+
 #[derive(Debug)]
 pub struct DataFields {
     pub crc: u16,
 }
 
+/// Calculate the CRC5 sent in a token packet, from its address and
+/// endpoint fields.
+pub fn token_crc(fields: u16) -> u8 {
+    let mut crc: u8 = 0x1F;
+    for bit in 0..11 {
+        let input = (fields >> bit) as u8 & 1;
+        crc = if (crc ^ input) & 1 == 1 { (crc >> 1) ^ 0x14 } else { crc >> 1 };
+    }
+    !crc & 0x1F
+}
+
+/// Calculate the CRC16 sent after the payload of a data packet.
+pub fn data_crc(payload: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in payload {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
 #[derive(Debug)]
 pub enum PacketFields {
     SOF(SOFFields),
@@ -117,8 +219,7 @@ pub enum Direction {
 }
 
 bitfield! {
-    #[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
-    #[repr(C)]
+    #[derive(Copy, Clone, Debug, Default)]
     pub struct RequestTypeFields(u8);
     pub u8, _recipient, _: 4, 0;
     pub u8, _type, _: 6, 5;
@@ -227,13 +328,19 @@ pub enum DescriptorType {
     DeviceQualifier = 6,
     OtherSpeedConfiguration = 7,
     InterfacePower = 8,
+    OnTheGo = 9,
+    Debug = 10,
+    InterfaceAssociation = 11,
+    // Not a standard type: any value past the last standard one is parsed
+    // as Unknown, so this only indexes the description strings below and
+    // is never stored or compared against a descriptor's type byte.
     #[default]
-    Unknown = 9
+    Unknown = 12
 }
 
 impl DescriptorType {
     pub fn description(self) -> &'static str {
-        const STRINGS: [&str; 10] = [
+        const STRINGS: [&str; 13] = [
             "invalid",
             "device",
             "configuration",
@@ -243,12 +350,166 @@ impl DescriptorType {
             "device qualifier",
             "other speed configuration",
             "interface power",
+            "OTG",
+            "debug",
+            "interface association",
             "unknown",
         ];
         STRINGS[self as usize]
     }
 }
 
+/// Names of standard class codes, as used in the device, interface and
+/// interface association descriptors.
+const CLASS_NAMES: [(u8, &str); 21] = [
+    (0x00, "Defined at interface level"),
+    (0x01, "Audio"),
+    (0x02, "Communications"),
+    (0x03, "HID"),
+    (0x05, "Physical"),
+    (0x06, "Image"),
+    (0x07, "Printer"),
+    (0x08, "Mass storage"),
+    (0x09, "Hub"),
+    (0x0A, "CDC data"),
+    (0x0B, "Smart card"),
+    (0x0D, "Content security"),
+    (0x0E, "Video"),
+    (0x0F, "Personal healthcare"),
+    (0x10, "Audio/Video"),
+    (0x11, "Billboard"),
+    (0x12, "Type-C bridge"),
+    (0xDC, "Diagnostic"),
+    (0xE0, "Wireless controller"),
+    (0xEF, "Miscellaneous"),
+    (0xFE, "Application specific"),
+];
+
+const SUBCLASS_NAMES: [(u8, u8, &str); 19] = [
+    (0x01, 0x01, "Audio control"),
+    (0x01, 0x02, "Audio streaming"),
+    (0x01, 0x03, "MIDI streaming"),
+    (0x02, 0x01, "Direct line control model"),
+    (0x02, 0x02, "Abstract control model"),
+    (0x02, 0x06, "Ethernet control model"),
+    (0x02, 0x0C, "Ethernet emulation model"),
+    (0x02, 0x0D, "Network control model"),
+    (0x03, 0x00, "No subclass"),
+    (0x03, 0x01, "Boot interface"),
+    (0x08, 0x06, "SCSI transparent command set"),
+    (0x0E, 0x01, "Video control"),
+    (0x0E, 0x02, "Video streaming"),
+    (0x0E, 0x03, "Video interface collection"),
+    (0xE0, 0x01, "RF controller"),
+    (0xEF, 0x02, "Common class"),
+    (0xFE, 0x01, "Device firmware upgrade"),
+    (0xFE, 0x02, "IrDA bridge"),
+    (0xFE, 0x03, "Test and measurement"),
+];
+
+const PROTOCOL_NAMES: [(u8, u8, u8, &str); 13] = [
+    (0x02, 0x02, 0x01, "AT commands"),
+    (0x03, 0x01, 0x01, "Keyboard"),
+    (0x03, 0x01, 0x02, "Mouse"),
+    (0x08, 0x06, 0x50, "Bulk-only transport"),
+    (0x08, 0x06, 0x62, "USB attached SCSI"),
+    (0x09, 0x00, 0x00, "Full speed"),
+    (0x09, 0x00, 0x01, "High speed, single TT"),
+    (0x09, 0x00, 0x02, "High speed, multiple TTs"),
+    (0xE0, 0x01, 0x01, "Bluetooth"),
+    (0xEF, 0x02, 0x01, "Interface association"),
+    (0xFE, 0x01, 0x01, "Runtime"),
+    (0xFE, 0x01, 0x02, "DFU mode"),
+    (0xFE, 0x03, 0x01, "USBTMC"),
+];
+
+/// A class, subclass and protocol triple as found in descriptors.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClassTriple {
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+impl ClassTriple {
+    /// Names come from the usb.ids database where it has them, falling
+    /// back to the built-in table of standard codes.
+    pub fn class_name(&self) -> Option<&'static str> {
+        usb_ids::database().class(self.class)
+            .or_else(|| self.builtin_class_name())
+    }
+
+    pub fn subclass_name(&self) -> Option<&'static str> {
+        usb_ids::database().subclass(self.class, self.subclass)
+            .or_else(|| self.builtin_subclass_name())
+    }
+
+    pub fn protocol_name(&self) -> Option<&'static str> {
+        usb_ids::database()
+            .protocol(self.class, self.subclass, self.protocol)
+            .or_else(|| self.builtin_protocol_name())
+    }
+
+    fn builtin_class_name(&self) -> Option<&'static str> {
+        match self.class {
+            0xFF => Some("Vendor specific"),
+            class => CLASS_NAMES.iter()
+                .find(|(c, _)| *c == class)
+                .map(|(_, name)| *name),
+        }
+    }
+
+    fn builtin_subclass_name(&self) -> Option<&'static str> {
+        match (self.class, self.subclass) {
+            (0xFF, _) | (_, 0xFF) => Some("Vendor specific"),
+            (class, subclass) => SUBCLASS_NAMES.iter()
+                .find(|(c, s, _)| (*c, *s) == (class, subclass))
+                .map(|(.., name)| *name),
+        }
+    }
+
+    fn builtin_protocol_name(&self) -> Option<&'static str> {
+        let (class, subclass, protocol) =
+            (self.class, self.subclass, self.protocol);
+        match (class, subclass, protocol) {
+            (0xFF, ..) | (_, 0xFF, _) | (.., 0xFF) => Some("Vendor specific"),
+            _ => PROTOCOL_NAMES.iter()
+                .find(|(c, s, p, _)| (*c, *s, *p) == (class, subclass, protocol))
+                .map(|(.., name)| *name)
+        }
+    }
+
+    /// Describe the triple as a whole, e.g. "HID, Boot interface, Mouse".
+    pub fn description(&self) -> Option<String> {
+        let mut parts = vec![self.class_name()?];
+        if let Some(subclass) = self.subclass_name() {
+            parts.push(subclass);
+            // Protocol zero usually just means no specific protocol.
+            let protocol = match self.protocol {
+                0 => self.builtin_protocol_name(),
+                _ => self.protocol_name(),
+            };
+            if let Some(protocol) = protocol {
+                parts.push(protocol);
+            }
+        }
+        parts.dedup();
+        Some(parts.join(", "))
+    }
+
+    fn field_text(&self, id: u8) -> String {
+        match id {
+        0 => fmt_named(format!("Class: 0x{:02X}", self.class),
+                       self.class_name()),
+        1 => fmt_named(format!("Subclass: 0x{:02X}", self.subclass),
+                       self.subclass_name()),
+        2 => fmt_named(format!("Protocol: 0x{:02X}", self.protocol),
+                       self.protocol_name()),
+        _ => panic!("Invalid field ID")
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, FromPrimitive)]
 #[repr(u16)]
 pub enum StandardFeature {
@@ -271,8 +532,7 @@ impl StandardFeature {
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
-#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct DeviceDescriptor {
     pub length: u8,
     pub descriptor_type: u8,
@@ -290,23 +550,68 @@ pub struct DeviceDescriptor {
     pub num_configurations: u8
 }
 
+impl Record for DeviceDescriptor {
+    const SIZE: usize = 18;
+
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes[0] = self.length;
+        bytes[1] = self.descriptor_type;
+        self.usb.encode(&mut bytes[2..4]);
+        bytes[4] = self.device_class;
+        bytes[5] = self.device_subclass;
+        bytes[6] = self.device_protocol;
+        bytes[7] = self.max_packet_size_0;
+        self.vendor_id.encode(&mut bytes[8..10]);
+        self.product_id.encode(&mut bytes[10..12]);
+        self.device_version.encode(&mut bytes[12..14]);
+        bytes[14] = self.manufacturer_str_id;
+        bytes[15] = self.product_str_id;
+        bytes[16] = self.serial_str_id;
+        bytes[17] = self.num_configurations;
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        DeviceDescriptor {
+            length: bytes[0],
+            descriptor_type: bytes[1],
+            usb: u16::decode(&bytes[2..4]),
+            device_class: bytes[4],
+            device_subclass: bytes[5],
+            device_protocol: bytes[6],
+            max_packet_size_0: bytes[7],
+            vendor_id: u16::decode(&bytes[8..10]),
+            product_id: u16::decode(&bytes[10..12]),
+            device_version: u16::decode(&bytes[12..14]),
+            manufacturer_str_id: bytes[14],
+            product_str_id: bytes[15],
+            serial_str_id: bytes[16],
+            num_configurations: bytes[17],
+        }
+    }
+}
+
 impl DeviceDescriptor {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        pod_read_unaligned::<DeviceDescriptor>(bytes)
+    pub fn class_triple(&self) -> ClassTriple {
+        ClassTriple {
+            class: self.device_class,
+            subclass: self.device_subclass,
+            protocol: self.device_protocol,
+        }
     }
 
     pub fn field_text(&self, id: u8, strings: &Vec<Option<Vec<u8>>>) -> String {
+        let ids = usb_ids::database();
         match id {
         0  => format!("Length: {} bytes", self.length),
         1  => format!("Type: 0x{:02X}", self.descriptor_type),
         2  => format!("USB Version: {:X}.{:02X}",
                       self.usb >> 8, self.usb & 0xFF),
-        3  => format!("Class: 0x{:02X}", self.device_class),
-        4  => format!("Subclass: 0x{:02X}", self.device_subclass),
-        5  => format!("Protocol: 0x{:02X}", self.device_protocol),
+        3 | 4 | 5 => self.class_triple().field_text(id - 3),
         6  => format!("Max EP0 packet size: {} bytes", self.max_packet_size_0),
-        7  => format!("Vendor ID: 0x{:04X}", self.vendor_id),
-        8  => format!("Product ID: 0x{:04X}", self.product_id),
+        7  => fmt_named(format!("Vendor ID: 0x{:04X}", self.vendor_id),
+                        ids.vendor(self.vendor_id)),
+        8  => fmt_named(format!("Product ID: 0x{:04X}", self.product_id),
+                        ids.product(self.vendor_id, self.product_id)),
         9  => format!("Version: {:X}.{:02X}",
                       self.device_version >> 8, self.device_version & 0xFF),
         10 => format!("Manufacturer string: {}",
@@ -320,8 +625,7 @@ impl DeviceDescriptor {
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
-#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct ConfigDescriptor {
     pub length: u8,
     pub descriptor_type: u8,
@@ -333,13 +637,40 @@ pub struct ConfigDescriptor {
     pub max_power: u8
 }
 
+impl Record for ConfigDescriptor {
+    const SIZE: usize = 9;
+
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes[0] = self.length;
+        bytes[1] = self.descriptor_type;
+        self.total_length.encode(&mut bytes[2..4]);
+        bytes[4] = self.num_interfaces;
+        bytes[5] = self.config_value;
+        bytes[6] = self.config_str_id;
+        bytes[7] = self.attributes;
+        bytes[8] = self.max_power;
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        ConfigDescriptor {
+            length: bytes[0],
+            descriptor_type: bytes[1],
+            total_length: u16::decode(&bytes[2..4]),
+            num_interfaces: bytes[4],
+            config_value: bytes[5],
+            config_str_id: bytes[6],
+            attributes: bytes[7],
+            max_power: bytes[8],
+        }
+    }
+}
+
 impl ConfigDescriptor {
     pub fn field_text(&self, id: u8, strings: &Vec<Option<Vec<u8>>>) -> String {
         match id {
         0 => format!("Length: {} bytes", self.length),
         1 => format!("Type: 0x{:02X}", self.descriptor_type),
-        2 => format!("Total length: {} bytes", {
-            let length: u16 = self.total_length; length }),
+        2 => format!("Total length: {} bytes", self.total_length),
         3 => format!("Number of interfaces: {}", self.num_interfaces),
         4 => format!("Configuration number: {}", self.config_value),
         5 => format!("Configuration string: {}",
@@ -351,8 +682,7 @@ impl ConfigDescriptor {
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
-#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct InterfaceDescriptor {
     pub length: u8,
     pub descriptor_type: u8,
@@ -365,7 +695,47 @@ pub struct InterfaceDescriptor {
     pub interface_str_id: u8,
 }
 
+impl Record for InterfaceDescriptor {
+    const SIZE: usize = 9;
+
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&[
+            self.length,
+            self.descriptor_type,
+            self.interface_number,
+            self.alternate_setting,
+            self.num_endpoints,
+            self.interface_class,
+            self.interface_subclass,
+            self.interface_protocol,
+            self.interface_str_id,
+        ]);
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        InterfaceDescriptor {
+            length: bytes[0],
+            descriptor_type: bytes[1],
+            interface_number: bytes[2],
+            alternate_setting: bytes[3],
+            num_endpoints: bytes[4],
+            interface_class: bytes[5],
+            interface_subclass: bytes[6],
+            interface_protocol: bytes[7],
+            interface_str_id: bytes[8],
+        }
+    }
+}
+
 impl InterfaceDescriptor {
+    pub fn class_triple(&self) -> ClassTriple {
+        ClassTriple {
+            class: self.interface_class,
+            subclass: self.interface_subclass,
+            protocol: self.interface_protocol,
+        }
+    }
+
     pub fn field_text(&self, id: u8, strings: &Vec<Option<Vec<u8>>>) -> String {
         match id {
         0 => format!("Length: {} bytes", self.length),
@@ -373,9 +743,7 @@ impl InterfaceDescriptor {
         2 => format!("Interface number: {}", self.interface_number),
         3 => format!("Alternate setting: {}", self.alternate_setting),
         4 => format!("Number of endpoints: {}", self.num_endpoints),
-        5 => format!("Class: 0x{:02X}", self.interface_class),
-        6 => format!("Subclass: 0x{:02X}", self.interface_subclass),
-        7 => format!("Protocol: 0x{:02X}", self.interface_protocol),
+        5 | 6 | 7 => self.class_triple().field_text(id - 5),
         8 => format!("Interface string: {}",
                       fmt_str_id(strings, self.interface_str_id)),
         _ => panic!("Invalid field ID")
@@ -383,8 +751,72 @@ impl InterfaceDescriptor {
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
-#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct InterfaceAssociationDescriptor {
+    pub length: u8,
+    pub descriptor_type: u8,
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub function_str_id: u8,
+}
+
+impl Record for InterfaceAssociationDescriptor {
+    const SIZE: usize = 8;
+
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&[
+            self.length,
+            self.descriptor_type,
+            self.first_interface,
+            self.interface_count,
+            self.function_class,
+            self.function_subclass,
+            self.function_protocol,
+            self.function_str_id,
+        ]);
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        InterfaceAssociationDescriptor {
+            length: bytes[0],
+            descriptor_type: bytes[1],
+            first_interface: bytes[2],
+            interface_count: bytes[3],
+            function_class: bytes[4],
+            function_subclass: bytes[5],
+            function_protocol: bytes[6],
+            function_str_id: bytes[7],
+        }
+    }
+}
+
+impl InterfaceAssociationDescriptor {
+    pub fn class_triple(&self) -> ClassTriple {
+        ClassTriple {
+            class: self.function_class,
+            subclass: self.function_subclass,
+            protocol: self.function_protocol,
+        }
+    }
+
+    pub fn field_text(&self, id: u8, strings: &Vec<Option<Vec<u8>>>) -> String {
+        match id {
+        0 => format!("Length: {} bytes", self.length),
+        1 => format!("Type: 0x{:02X}", self.descriptor_type),
+        2 => format!("First interface: {}", self.first_interface),
+        3 => format!("Interface count: {}", self.interface_count),
+        4 | 5 | 6 => self.class_triple().field_text(id - 4),
+        7 => format!("Function string: {}",
+                      fmt_str_id(strings, self.function_str_id)),
+        _ => panic!("Invalid field ID")
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
 pub struct EndpointDescriptor {
     pub length: u8,
     pub descriptor_type: u8,
@@ -394,6 +826,30 @@ pub struct EndpointDescriptor {
     pub interval: u8,
 }
 
+impl Record for EndpointDescriptor {
+    const SIZE: usize = 7;
+
+    fn encode(&self, bytes: &mut [u8]) {
+        bytes[0] = self.length;
+        bytes[1] = self.descriptor_type;
+        bytes[2] = self.endpoint_address;
+        bytes[3] = self.attributes;
+        self.max_packet_size.encode(&mut bytes[4..6]);
+        bytes[6] = self.interval;
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        EndpointDescriptor {
+            length: bytes[0],
+            descriptor_type: bytes[1],
+            endpoint_address: bytes[2],
+            attributes: bytes[3],
+            max_packet_size: u16::decode(&bytes[4..6]),
+            interval: bytes[6],
+        }
+    }
+}
+
 impl EndpointDescriptor {
     pub fn field_text(&self, id: u8) -> String {
         match id {
@@ -401,8 +857,7 @@ impl EndpointDescriptor {
         1 => format!("Type: 0x{:02X}", self.descriptor_type),
         2 => format!("Endpoint address: 0x{:02X}", self.endpoint_address),
         3 => format!("Attributes: 0x{:02X}", self.attributes),
-        4 => format!("Max packet size: {} bytes", {
-            let size: u16 = self.max_packet_size; size }),
+        4 => format!("Max packet size: {} bytes", self.max_packet_size),
         5 => format!("Interval: 0x{:02X}", self.interval),
         _ => panic!("Invalid field ID")
         }
@@ -411,25 +866,30 @@ impl EndpointDescriptor {
 
 pub struct Interface {
     pub descriptor: InterfaceDescriptor,
-    pub endpoint_descriptors: Vec<EndpointDescriptor>
+    pub endpoint_descriptors: Vec<EndpointDescriptor>,
+    /// Any other descriptors following the interface descriptor, such as
+    /// class-specific descriptors, in order.
+    pub other_descriptors: Vec<Vec<u8>>,
 }
 
 pub struct Configuration {
     pub descriptor: ConfigDescriptor,
+    /// Interfaces, including each alternate setting, in the order given.
     pub interfaces: Vec<Interface>,
+    pub associations: Vec<InterfaceAssociationDescriptor>,
 }
 
 impl Configuration {
     pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        let config_size = size_of::<ConfigDescriptor>();
-        let iface_size = size_of::<InterfaceDescriptor>();
-        let ep_size = size_of::<EndpointDescriptor>();
+        let config_size = ConfigDescriptor::SIZE;
+        let iface_size = InterfaceDescriptor::SIZE;
+        let iad_size = InterfaceAssociationDescriptor::SIZE;
+        let ep_size = EndpointDescriptor::SIZE;
         if bytes.len() < config_size {
             return None;
         }
         let config_bytes = &bytes[0 .. config_size];
-        let config_desc =
-            pod_read_unaligned::<ConfigDescriptor>(config_bytes);
+        let config_desc = ConfigDescriptor::decode(config_bytes);
         if config_desc.descriptor_type != DescriptorType::Configuration as u8 {
             return None;
         }
@@ -437,44 +897,79 @@ impl Configuration {
             descriptor: config_desc,
             interfaces:
                 Vec::with_capacity(config_desc.num_interfaces as usize),
+            associations: Vec::new(),
         };
         let mut offset = config_size;
-        for _ in 0 .. config.descriptor.num_interfaces {
-            if offset + iface_size > bytes.len() {
+        while offset + 2 <= bytes.len() {
+            let length = bytes[offset] as usize;
+            if length < 2 || offset + length > bytes.len() {
                 break;
             }
-            let iface_bytes = &bytes[offset .. offset + iface_size];
-            let iface_desc =
-                pod_read_unaligned::<InterfaceDescriptor>(iface_bytes);
-            offset += iface_size;
-            if iface_desc.descriptor_type != DescriptorType::Interface as u8{
-                break;
-            }
-            let mut iface = Interface {
-                descriptor: iface_desc,
-                endpoint_descriptors:
-                    Vec::with_capacity(iface_desc.num_endpoints as usize),
-            };
-            while iface.endpoint_descriptors.len() <
-                iface.descriptor.num_endpoints as usize
-            {
-                if offset + ep_size > bytes.len() {
-                    break;
-                }
-                let ep_bytes = &bytes[offset .. offset + ep_size];
-                let ep_desc =
-                    pod_read_unaligned::<EndpointDescriptor>(ep_bytes);
-                offset += ep_desc.length as usize;
-                if ep_desc.descriptor_type != DescriptorType::Endpoint as u8 {
-                    // Could be HID or other class descriptor; skip over it.
-                    continue;
+            let desc_bytes = &bytes[offset .. offset + length];
+            offset += length;
+            let desc_type = DescriptorType::from(desc_bytes[1]);
+            match desc_type {
+                DescriptorType::InterfaceAssociation if length >= iad_size => {
+                    config.associations.push(
+                        InterfaceAssociationDescriptor::decode(
+                            &desc_bytes[.. iad_size]));
+                },
+                DescriptorType::Interface if length >= iface_size => {
+                    config.interfaces.push(Interface {
+                        descriptor: InterfaceDescriptor::decode(
+                            &desc_bytes[.. iface_size]),
+                        endpoint_descriptors: Vec::new(),
+                        other_descriptors: Vec::new(),
+                    });
+                },
+                _ => match config.interfaces.last_mut() {
+                    Some(iface) if desc_type == DescriptorType::Endpoint &&
+                        length >= ep_size =>
+                    {
+                        iface.endpoint_descriptors.push(
+                            EndpointDescriptor::decode(
+                                &desc_bytes[.. ep_size]));
+                    },
+                    // Could be HID or other class descriptor.
+                    Some(iface) =>
+                        iface.other_descriptors.push(desc_bytes.to_vec()),
+                    None => {}
                 }
-                iface.endpoint_descriptors.push(ep_desc);
-            };
-            config.interfaces.push(iface);
-        };
+            }
+        }
         Some(config)
     }
+
+    /// Encode the configuration as descriptors which `from_bytes` parses
+    /// back into the same configuration. Associations are placed before
+    /// all interfaces, and each interface's other descriptors before its
+    /// endpoints.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Descriptors keep their original length, with any fields beyond
+        // those parsed set to zero.
+        fn push<T: Record>(bytes: &mut Vec<u8>, desc: &T) {
+            let start = bytes.len();
+            bytes.resize(start + T::SIZE, 0);
+            desc.encode(&mut bytes[start..]);
+            let length = max(bytes[start] as usize, T::SIZE);
+            bytes.resize(start + length, 0);
+        }
+        let mut bytes = Vec::new();
+        push(&mut bytes, &self.descriptor);
+        for association in &self.associations {
+            push(&mut bytes, association);
+        }
+        for iface in &self.interfaces {
+            push(&mut bytes, &iface.descriptor);
+            for desc in &iface.other_descriptors {
+                bytes.extend_from_slice(desc);
+            }
+            for ep_desc in &iface.endpoint_descriptors {
+                push(&mut bytes, ep_desc);
+            }
+        }
+        bytes
+    }
 }
 
 pub struct ControlTransfer {
@@ -572,6 +1067,7 @@ fn fmt_utf16(bytes: &[u8]) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::record::encode_all;
 
     #[test]
     fn test_parse_sof() {
@@ -620,4 +1116,126 @@ mod tests {
             panic!("Expected Data but got {:?}", p);
         }
     }
+
+    #[test]
+    fn test_data_crc() {
+        assert!(data_crc(&[0x40, 0x01, 0x01, 0x00,
+                           0x00, 0x00, 0x00, 0x00]) == 0xd5aa);
+        assert!(data_crc(&[]) == 0x0000);
+    }
+
+    #[test]
+    fn test_token_crc() {
+        assert!(token_crc(0x000) == 0x02);
+        assert!(token_crc(0x004) == 0x05);
+        assert!(token_crc(0x084) == 0x13);
+    }
+
+    #[test]
+    fn test_phy_errors() {
+        let mut errors = PhyErrors::default();
+        assert!(errors.is_empty() && errors.description().is_empty());
+        errors.set_bit_stuff(true);
+        errors.set_babble(true);
+        assert!(!errors.is_empty() && !errors.eop());
+        assert!(errors.description() == "bit stuff error, babble");
+        assert!(PhyErrors(0x02).description() == "EOP error");
+    }
+
+    #[test]
+    fn test_class_triple() {
+        let mouse = ClassTriple { class: 0x03, subclass: 0x01, protocol: 0x02 };
+        assert!(mouse.description().unwrap() ==
+                "Human Interface Device, Boot Interface Subclass, Mouse");
+        assert!(mouse.field_text(0) == "Class: 0x03 (Human Interface Device)");
+        assert!(mouse.field_text(2) == "Protocol: 0x02 (Mouse)");
+        let hid = ClassTriple { class: 0x03, subclass: 0x00, protocol: 0x00 };
+        assert!(hid.description().unwrap() ==
+                "Human Interface Device, No Subclass");
+        let vendor = ClassTriple { class: 0xFF, subclass: 0x00, protocol: 0x00 };
+        assert!(vendor.description().unwrap() ==
+                "Vendor Specific Class, Vendor specific");
+        let unknown = ClassTriple { class: 0x42, subclass: 0x00, protocol: 0x00 };
+        assert!(unknown.description() == None);
+        assert!(unknown.field_text(1) == "Subclass: 0x00");
+    }
+
+    #[test]
+    fn test_parse_device_descriptor() {
+        let bytes = [0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40,
+                     0x6D, 0x04, 0x2B, 0xC5, 0x03, 0x01, 0x01, 0x02,
+                     0x00, 0x01];
+        let desc = DeviceDescriptor::decode(&bytes);
+        assert!(desc.usb == 0x0200);
+        assert!(desc.vendor_id == 0x046D);
+        assert!(desc.product_id == 0xC52B);
+        assert!(desc.device_version == 0x0103);
+        assert!(encode_all(&[desc]) == bytes);
+    }
+
+    #[test]
+    fn test_parse_association() {
+        let bytes = vec![
+            // Configuration descriptor
+            0x09, 0x02, 0x4B, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32,
+            // Interface association descriptor
+            0x08, 0x0B, 0x00, 0x02, 0x02, 0x02, 0x01, 0x00,
+            // Interface 0 with no endpoints
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x02, 0x02, 0x01, 0x00,
+            // Interface 1 with no endpoints
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x0A, 0x00, 0x00, 0x00,
+        ];
+        let config = Configuration::from_bytes(&bytes).unwrap();
+        assert!(config.interfaces.len() == 2);
+        assert!(config.associations.len() == 1);
+        let iad = &config.associations[0];
+        assert!(iad.first_interface == 0);
+        assert!(iad.interface_count == 2);
+        assert!(iad.class_triple().description().unwrap() ==
+                "Communications, Abstract (modem), AT-commands (v.25ter)");
+        assert!(config.interfaces[1].descriptor.interface_class == 0x0A);
+    }
+
+    #[test]
+    fn test_parse_alternate_settings() {
+        let bytes = vec![
+            // Configuration descriptor
+            0x09, 0x02, 0x3A, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+            // Interface 1, alternate 0, with no endpoints
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00,
+            // Interface 1, alternate 1, with one endpoint
+            0x09, 0x04, 0x01, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00,
+            // Class-specific AS general descriptor
+            0x07, 0x24, 0x01, 0x01, 0x01, 0x01, 0x00,
+            // Isochronous IN endpoint, in 9 byte audio form
+            0x09, 0x05, 0x81, 0x05, 0xC0, 0x00, 0x01, 0x00, 0x00,
+            // Class-specific endpoint descriptor
+            0x07, 0x25, 0x01, 0x01, 0x00, 0x00, 0x00,
+        ];
+        let config = Configuration::from_bytes(&bytes).unwrap();
+        assert!(config.interfaces.len() == 2);
+        let alt = &config.interfaces[1];
+        assert!(alt.descriptor.alternate_setting == 1);
+        assert!(alt.endpoint_descriptors.len() == 1);
+        assert!(alt.endpoint_descriptors[0].endpoint_address == 0x81);
+        assert!({ alt.endpoint_descriptors[0].max_packet_size } == 0xC0);
+        assert!({ config.descriptor.total_length } == 0x3A);
+        assert!(alt.other_descriptors == vec![
+            vec![0x07, 0x24, 0x01, 0x01, 0x01, 0x01, 0x00],
+            vec![0x07, 0x25, 0x01, 0x01, 0x00, 0x00, 0x00],
+        ]);
+        // Encoding again zeroes the audio fields of the endpoint
+        // descriptor, which are not parsed.
+        let encoded = config.to_bytes();
+        assert!(encoded.len() == bytes.len());
+        assert!(encoded[..9] == bytes[..9]);
+        assert!(encoded[41..48] == bytes[34..41]);
+        assert!(encoded[48..50] == [0, 0]);
+        let reparsed = Configuration::from_bytes(&encoded).unwrap();
+        assert!(reparsed.interfaces.len() == 2);
+        assert!(reparsed.interfaces[1].other_descriptors ==
+                alt.other_descriptors);
+        assert!(reparsed.interfaces[1].endpoint_descriptors[0]
+                .endpoint_address == 0x81);
+    }
 }
@@ -0,0 +1,279 @@
+//! Lookup of vendor, product and class names from a usb.ids database.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use once_cell::sync::Lazy;
+
+use crate::preferences::config_dir;
+
+const BUNDLED_IDS: &str = include_str!("../data/usb.ids");
+
+const SYSTEM_PATHS: [&str; 4] = [
+    "/usr/share/hwdata/usb.ids",
+    "/usr/share/misc/usb.ids",
+    "/usr/share/usb.ids",
+    "/var/lib/usbutils/usb.ids",
+];
+
+// Tests use only the bundled database, so that their results do not depend
+// on the copies installed where they run.
+static DATABASE: Lazy<UsbIds> = Lazy::new(||
+    if cfg!(test) {
+        UsbIds::load_from(&[])
+    } else {
+        UsbIds::load()
+    });
+
+#[derive(Default)]
+pub struct Vendor {
+    pub name: String,
+    pub products: HashMap<u16, String>,
+}
+
+#[derive(Default)]
+pub struct Subclass {
+    pub name: String,
+    pub protocols: HashMap<u8, String>,
+}
+
+#[derive(Default)]
+pub struct Class {
+    pub name: String,
+    pub subclasses: HashMap<u8, Subclass>,
+}
+
+#[derive(Default)]
+pub struct UsbIds {
+    pub vendors: HashMap<u16, Vendor>,
+    pub classes: HashMap<u8, Class>,
+}
+
+#[derive(PartialEq)]
+enum Section {
+    Vendors,
+    Classes,
+    Other,
+}
+
+/// Split a line into a hex ID and a name, separated by whitespace.
+fn parse_entry(text: &str) -> Option<(u16, &str)> {
+    let (id, name) = text.split_once(char::is_whitespace)?;
+    let id = u16::from_str_radix(id, 16).ok()?;
+    Some((id, name.trim()))
+}
+
+impl UsbIds {
+    /// Load the first database found, in order of preference: the user's
+    /// own copy, any system copy, and finally the bundled subset.
+    pub fn load() -> UsbIds {
+        UsbIds::load_from(&UsbIds::search_paths())
+    }
+
+    /// Load the first database found at the paths given, in order, or the
+    /// bundled subset if none can be read.
+    pub fn load_from(paths: &[PathBuf]) -> UsbIds {
+        for path in paths {
+            if let Ok(text) = std::fs::read(path) {
+                // usb.ids is not guaranteed to be valid UTF-8.
+                return UsbIds::parse(&String::from_utf8_lossy(&text));
+            }
+        }
+        UsbIds::parse(BUNDLED_IDS)
+    }
+
+    fn search_paths() -> Vec<PathBuf> {
+        let mut paths = vec![config_dir().join("usb.ids")];
+        paths.extend(SYSTEM_PATHS.iter().map(PathBuf::from));
+        paths
+    }
+
+    pub fn parse(text: &str) -> UsbIds {
+        let mut ids = UsbIds::default();
+        let mut section = Section::Vendors;
+        let mut vendor_id = None;
+        let mut class_id = None;
+        let mut subclass_id = None;
+        for line in text.lines() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let depth = line.chars().take_while(|c| *c == '\t').count();
+            let text = &line[depth..];
+            match (depth, &section) {
+                (0, _) => {
+                    if let Some(rest) = text.strip_prefix("C ") {
+                        section = Section::Classes;
+                        if let Some((id, name)) = parse_entry(rest) {
+                            let class = Class {
+                                name: name.to_string(),
+                                .. Default::default()
+                            };
+                            ids.classes.insert(id as u8, class);
+                            class_id = Some(id as u8);
+                            subclass_id = None;
+                        }
+                    } else if let Some((id, name)) = parse_entry(text) {
+                        // Vendor IDs are always four hex digits; anything
+                        // else is the header of a section we don't use.
+                        if text.find(char::is_whitespace) == Some(4) {
+                            section = Section::Vendors;
+                            let vendor = Vendor {
+                                name: name.to_string(),
+                                .. Default::default()
+                            };
+                            ids.vendors.insert(id, vendor);
+                            vendor_id = Some(id);
+                        } else {
+                            section = Section::Other;
+                        }
+                    } else {
+                        section = Section::Other;
+                    }
+                },
+                (1, Section::Vendors) => {
+                    if let (Some(vid), Some((id, name))) =
+                        (vendor_id, parse_entry(text))
+                    {
+                        let vendor = ids.vendors.get_mut(&vid).unwrap();
+                        vendor.products.insert(id, name.to_string());
+                    }
+                },
+                (1, Section::Classes) => {
+                    if let (Some(cid), Some((id, name))) =
+                        (class_id, parse_entry(text))
+                    {
+                        let subclass = Subclass {
+                            name: name.to_string(),
+                            .. Default::default()
+                        };
+                        let class = ids.classes.get_mut(&cid).unwrap();
+                        class.subclasses.insert(id as u8, subclass);
+                        subclass_id = Some(id as u8);
+                    }
+                },
+                (2, Section::Classes) => {
+                    if let (Some(cid), Some(sid), Some((id, name))) =
+                        (class_id, subclass_id, parse_entry(text))
+                    {
+                        let class = ids.classes.get_mut(&cid).unwrap();
+                        let subclass = class.subclasses.get_mut(&sid).unwrap();
+                        subclass.protocols.insert(id as u8, name.to_string());
+                    }
+                },
+                // Interfaces of products, and other sections, are skipped.
+                (..) => {}
+            }
+        }
+        ids
+    }
+
+    pub fn vendor(&self, vendor_id: u16) -> Option<&str> {
+        self.vendors.get(&vendor_id).map(|v| v.name.as_str())
+    }
+
+    pub fn product(&self, vendor_id: u16, product_id: u16) -> Option<&str> {
+        self.vendors.get(&vendor_id)?
+            .products.get(&product_id).map(|p| p.as_str())
+    }
+
+    pub fn class(&self, class: u8) -> Option<&str> {
+        self.classes.get(&class).map(|c| c.name.as_str())
+    }
+
+    pub fn subclass(&self, class: u8, subclass: u8) -> Option<&str> {
+        self.classes.get(&class)?
+            .subclasses.get(&subclass).map(|s| s.name.as_str())
+    }
+
+    pub fn protocol(&self, class: u8, subclass: u8, protocol: u8)
+        -> Option<&str>
+    {
+        self.classes.get(&class)?
+            .subclasses.get(&subclass)?
+            .protocols.get(&protocol).map(|p| p.as_str())
+    }
+}
+
+/// Access the database, loading it on first use.
+pub fn database() -> &'static UsbIds {
+    &DATABASE
+}
+
+/// Format a device's VID:PID along with any names known for it.
+pub fn fmt_device_id(vendor_id: u16, product_id: u16) -> String {
+    let ids = database();
+    let id = format!("{:04X}:{:04X}", vendor_id, product_id);
+    match (ids.vendor(vendor_id), ids.product(vendor_id, product_id)) {
+        (Some(vendor), Some(product)) =>
+            format!("{} {}, {}", id, vendor, product),
+        (Some(vendor), None) => format!("{} {}", id, vendor),
+        (None, _) => id,
+    }
+}
+
+/// Format an ID with its name in brackets, if known.
+pub fn fmt_named(text: String, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("{} ({})", text, name),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bundled() {
+        let ids = UsbIds::parse(BUNDLED_IDS);
+        assert!(ids.vendor(0x1d50) == Some("OpenMoko, Inc."));
+        assert!(ids.product(0x1d50, 0x615b) == Some("Cynthion"));
+        assert!(ids.product(0x1d50, 0xffff) == None);
+        assert!(ids.vendor(0xffff) == None);
+        assert!(ids.class(0x03) == Some("Human Interface Device"));
+        assert!(ids.subclass(0x03, 0x01) == Some("Boot Interface Subclass"));
+        assert!(ids.protocol(0x03, 0x01, 0x02) == Some("Mouse"));
+        assert!(ids.protocol(0x08, 0x06, 0x50) == Some("Bulk-Only"));
+        // Entries from sections after the classes must not leak in.
+        assert!(ids.vendor(0x0100) == None);
+        assert!(ids.subclass(0xff, 0xff) == Some("Vendor Specific Subclass"));
+    }
+
+    #[test]
+    fn test_load_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.ids");
+        let path = dir.path().join("usb.ids");
+        std::fs::write(&path, b"1d50  Other Name\n").unwrap();
+        let ids = UsbIds::load_from(&[missing.clone(), path]);
+        assert!(ids.vendor(0x1d50) == Some("Other Name"));
+        let ids = UsbIds::load_from(&[missing]);
+        assert!(ids.vendor(0x1d50) == Some("OpenMoko, Inc."));
+        assert!(database().vendor(0x1d50) == Some("OpenMoko, Inc."));
+    }
+
+    #[test]
+    fn test_parse_sections() {
+        let ids = UsbIds::parse(concat!(
+            "# comment\n",
+            "1234  Vendor A\n",
+            "\t0001  Product 1\n",
+            "\t\t00  Interface 0\n",
+            "abcd  Vendor B\n",
+            "\n",
+            "C 02  Class 2\n",
+            "\t06  Subclass 6\n",
+            "\t\t00  Protocol 0\n",
+            "HID 00  None\n",
+            "\t01  Ignored\n",
+            "5678  Vendor C\n",
+        ));
+        assert!(ids.vendors.len() == 3);
+        assert!(ids.product(0x1234, 0x0001) == Some("Product 1"));
+        assert!(ids.vendor(0xabcd) == Some("Vendor B"));
+        assert!(ids.vendor(0x5678) == Some("Vendor C"));
+        assert!(ids.protocol(0x02, 0x06, 0x00) == Some("Protocol 0"));
+        assert!(ids.subclass(0x02, 0x01) == None);
+    }
+}
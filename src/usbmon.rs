@@ -0,0 +1,334 @@
+//! Import of captures taken on a Linux host with usbmon, in the binary
+//! format used in pcap files or the text format read from the kernel's
+//! debugfs files, e.g. `/sys/kernel/debug/usb/usbmon/0u`.
+//!
+//! Each URB is converted to packets as described in [crate::urb]. Records
+//! are assumed to be in little-endian byte order, as written on x86 and
+//! ARM hosts. Device addresses are taken as they are, so a capture should
+//! come from a single bus.
+//!
+//! The text format records at most 32 bytes of data for each URB, so any
+//! longer data is imported truncated.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+use pcap::Linktype;
+
+use crate::urb::{
+    RecordImporter,
+    UrbConverter,
+    UrbEvent,
+    UrbHeader,
+    UrbStatus,
+    BULK,
+    CONTROL,
+    INTERRUPT,
+    ISOCHRONOUS,
+};
+
+/// Link type of usbmon captures with a 48-byte header for each URB.
+pub const LINKTYPE_USB_LINUX: i32 = 189;
+/// Link type of usbmon captures with a 64-byte header for each URB, as
+/// written through the memory-mapped interface.
+pub const LINKTYPE_USB_LINUX_MMAPPED: i32 = 220;
+
+/// Number of bytes read from the start of a file to see if it is a usbmon
+/// text capture.
+const TEXT_PROBE_LENGTH: u64 = 256;
+
+/// Status of a URB which was stalled by the device.
+const EPIPE: i32 = -32;
+/// Status of a URB which has been submitted but not completed.
+const EINPROGRESS: i32 = -115;
+/// Status of a URB which completed with less data than requested.
+const EREMOTEIO: i32 = -121;
+
+fn event(event_type: u8) -> UrbEvent {
+    match event_type {
+        b'S' => UrbEvent::Submission,
+        b'C' => UrbEvent::Completion,
+        _ => UrbEvent::Error,
+    }
+}
+
+fn status(status: i32) -> UrbStatus {
+    match status {
+        0 | EREMOTEIO => UrbStatus::Success,
+        EPIPE => UrbStatus::Stalled,
+        EINPROGRESS => UrbStatus::InProgress,
+        _ => UrbStatus::Failed,
+    }
+}
+
+fn parse_record(record: &[u8]) -> Option<UrbHeader> {
+    if record.len() < 48 {
+        return None;
+    }
+    let word = |offset: usize|
+        i32::from_le_bytes(record[offset..offset + 4].try_into().unwrap());
+    Some(UrbHeader {
+        id: u64::from_le_bytes(record[0..8].try_into().unwrap()),
+        event: event(record[8]),
+        transfer_type: record[9],
+        endpoint: record[10],
+        device: record[11],
+        status: status(word(28)),
+        setup: match record[14] {
+            0 => Some(record[40..48].try_into().unwrap()),
+            _ => None,
+        },
+    })
+}
+
+/// Parse a line of the text format, returning the header and data.
+fn parse_line(line: &str) -> Option<(UrbHeader, Vec<u8>)> {
+    let mut fields = line.split_whitespace();
+    let id = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let _timestamp: u64 = fields.next()?.parse().ok()?;
+    let event = match fields.next()? {
+        "S" => UrbEvent::Submission,
+        "C" => UrbEvent::Completion,
+        "E" => UrbEvent::Error,
+        _ => return None,
+    };
+    let address: Vec<&str> = fields.next()?.split(':').collect();
+    let (transfer_type, direction) = match address[..] {
+        [kind, _bus, _, _] => match kind {
+            "Ci" | "Co" => (CONTROL, kind),
+            "Bi" | "Bo" => (BULK, kind),
+            "Ii" | "Io" => (INTERRUPT, kind),
+            "Zi" | "Zo" => (ISOCHRONOUS, kind),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let device: u8 = address[2].parse().ok()?;
+    let number: u8 = address[3].parse().ok()?;
+    let endpoint = if direction.ends_with('i') {
+        number | 0x80
+    } else {
+        number
+    };
+    let mut setup = None;
+    let status = match fields.next()? {
+        "s" => {
+            let mut bytes = Vec::new();
+            for width in [1, 1, 2, 2, 2] {
+                let value = u16::from_str_radix(fields.next()?, 16).ok()?;
+                bytes.extend_from_slice(&value.to_le_bytes()[..width]);
+            }
+            setup = Some(bytes.try_into().unwrap());
+            UrbStatus::InProgress
+        },
+        // Interrupt and isochronous URBs give further values after
+        // the status, separated by colons.
+        status => self::status(status.split(':').next()?.parse().ok()?),
+    };
+    let mut data = Vec::new();
+    if transfer_type != ISOCHRONOUS {
+        let _length = fields.next();
+        if fields.next() == Some("=") {
+            for word in fields {
+                for i in (0..word.len()).step_by(2) {
+                    let byte = word.get(i..i + 2)?;
+                    data.push(u8::from_str_radix(byte, 16).ok()?);
+                }
+            }
+        }
+    }
+    Some((UrbHeader {
+        id: id,
+        event: event,
+        transfer_type: transfer_type,
+        endpoint: endpoint,
+        device: device,
+        status: status,
+        setup: setup,
+    }, data))
+}
+
+/// Converts usbmon records into the packets which carried them.
+pub struct UsbmonImporter {
+    /// Size of the header of each binary record.
+    header_size: usize,
+    converter: UrbConverter,
+}
+
+impl UsbmonImporter {
+    /// Create an importer for a capture with the given link type, if it
+    /// is a usbmon capture.
+    pub fn new(linktype: Linktype) -> Option<UsbmonImporter> {
+        let header_size = match linktype.0 {
+            LINKTYPE_USB_LINUX => 48,
+            LINKTYPE_USB_LINUX_MMAPPED => 64,
+            _ => return None,
+        };
+        Some(UsbmonImporter {
+            header_size: header_size,
+            converter: UrbConverter::default(),
+        })
+    }
+
+    /// Create an importer for a capture in the text format.
+    pub fn text() -> UsbmonImporter {
+        UsbmonImporter {
+            header_size: 0,
+            converter: UrbConverter::default(),
+        }
+    }
+
+    /// Convert one line of a text capture to packets, as for binary
+    /// records.
+    pub fn text_packets(&mut self, line: &str) -> Vec<Vec<u8>> {
+        match parse_line(line) {
+            Some((header, data)) => self.converter.packets(header, &data),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl RecordImporter for UsbmonImporter {
+    fn packets(&mut self, record: &[u8]) -> Vec<Vec<u8>> {
+        match parse_record(record) {
+            Some(header) => {
+                let data = record.get(self.header_size..).unwrap_or(&[]);
+                self.converter.packets(header, data)
+            },
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Whether a file is a usbmon capture in the text format, judged by
+/// whether its first line can be parsed.
+pub fn is_text_capture(path: &str) -> std::io::Result<bool> {
+    let file = File::open(path)?;
+    let mut line = Vec::new();
+    BufReader::new(file.take(TEXT_PROBE_LENGTH))
+        .read_until(b'\n', &mut line)?;
+    Ok(match std::str::from_utf8(&line) {
+        Ok(line) => parse_line(line).is_some(),
+        Err(_) => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{Capture, Item};
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    fn record(id: u64, event_type: u8, transfer_type: u8, endpoint: u8,
+              status: i32, setup: Option<[u8; 8]>, data: &[u8]) -> Vec<u8>
+    {
+        let mut record = vec![0; 64];
+        record[0..8].copy_from_slice(&id.to_le_bytes());
+        record[8] = event_type;
+        record[9] = transfer_type;
+        record[10] = endpoint;
+        record[11] = 3;
+        record[12..14].copy_from_slice(&1u16.to_le_bytes());
+        record[14] = if setup.is_some() { 0 } else { b'-' };
+        record[28..32].copy_from_slice(&status.to_le_bytes());
+        record[32..36].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        record[36..40].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        if let Some(setup) = setup {
+            record[40..48].copy_from_slice(&setup);
+        }
+        record.extend_from_slice(data);
+        record
+    }
+
+    #[test]
+    fn test_usbmon_import() {
+        let get_device = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let descriptor = [18, 1, 0, 2, 0, 0, 0, 64, 0x50, 0x1d, 0x4b, 0x60,
+                          0, 1, 1, 2, 3, 1];
+        let records = [
+            record(1, b'S', 2, 0x80, -115, Some(get_device), &[]),
+            record(1, b'C', 2, 0x80, 0, None, &descriptor),
+            record(2, b'S', 3, 0x02, -115, None, &[1, 2, 3]),
+            record(2, b'C', 3, 0x02, 0, None, &[]),
+            record(3, b'S', 3, 0x81, -115, None, &[]),
+            record(3, b'C', 3, 0x81, 0, None, &[4, 5]),
+            record(4, b'S', 3, 0x81, -115, None, &[]),
+            record(4, b'C', 3, 0x81, EPIPE, None, &[]),
+            // Cancelled, so not imported.
+            record(5, b'S', 3, 0x81, -115, None, &[]),
+            record(5, b'C', 3, 0x81, -2, None, &[]),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usbmon.pcap");
+        let mut file = vec![];
+        for word in [0xA1B2C3D4, 0x00040002, 0, 0, 65535,
+                     LINKTYPE_USB_LINUX_MMAPPED as u32]
+        {
+            file.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        for record in &records {
+            let length = record.len() as u32;
+            for word in [0, 0, length, length] {
+                file.extend_from_slice(&u32::to_le_bytes(word));
+            }
+            file.extend_from_slice(record);
+        }
+        std::fs::write(&path, file).unwrap();
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &[path.to_str().unwrap()]).unwrap();
+        drop(decoder);
+        // Three control stages, then OUT, IN and a stalled IN.
+        assert!(cap.transaction_index.len() == 6);
+        assert!(cap.packet_index.len() == 9 + 3 + 3 + 2);
+        let control = cap.get_item(&None, 0);
+        assert!(cap.get_summary(&control) ==
+                "Getting device descriptor #0 for device 3, \
+                 reading 18 bytes");
+        let transfers: Vec<Item> = (0..cap.item_index.len())
+            .map(|i| cap.get_item(&None, i))
+            .collect();
+        let out = cap.get_child(&transfers[1], 0);
+        assert!(cap.get_summary(&out) ==
+                "OUT transaction, 3 packets with 3 data bytes");
+        assert!(cap.device_data[1].device_descriptor.is_some());
+    }
+
+    #[test]
+    fn test_usbmon_text_import() {
+        let text = concat!(
+            "d5ea89a0 3575914555 S Ci:1:003:0 s 80 06 0100 0000 0012 18 <\n",
+            "d5ea89a0 3575914560 C Ci:1:003:0 0 18 = 12010002 00000040 ",
+            "501d4b60 00010102 0301\n",
+            "d6ee9bc0 3575914608 S Bo:1:003:2 -115 3 = 010203\n",
+            "d6ee9bc0 3575914620 C Bo:1:003:2 0 3 >\n",
+            "dd65f0e8 4128379752 S Ii:1:003:1 -115:8 4 <\n",
+            "dd65f0e8 4128379808 C Ii:1:003:1 0:8 1 = 01\n");
+        let (header, data) = parse_line(
+            text.lines().next().unwrap()).unwrap();
+        assert!(header.id == 0xd5ea89a0);
+        assert!(header.event == UrbEvent::Submission);
+        assert!(header.endpoint == 0x80 && header.device == 3);
+        assert!(header.setup == Some([0x80, 0x06, 0x00, 0x01,
+                                      0x00, 0x00, 0x12, 0x00]));
+        assert!(header.status == UrbStatus::InProgress && data.is_empty());
+        assert!(parse_line("not a usbmon line").is_none());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usbmon.txt");
+        std::fs::write(&path, text).unwrap();
+        let path = path.to_str().unwrap();
+        assert!(is_text_capture(path).unwrap());
+        assert!(!is_text_capture("./tests/mouse/capture.pcap").unwrap());
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &[path]).unwrap();
+        drop(decoder);
+        assert!(cap.transaction_index.len() == 5);
+        let control = cap.get_item(&None, 0);
+        assert!(cap.get_summary(&control) ==
+                "Getting device descriptor #0 for device 3, \
+                 reading 18 bytes");
+        assert!(cap.device_data[1].device_descriptor.is_some());
+    }
+}
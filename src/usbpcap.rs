@@ -0,0 +1,213 @@
+//! Import of captures taken on a Windows host with USBPcap.
+//!
+//! Each record starts with a pseudo-header describing the I/O request it
+//! was captured from, either on its way to the host controller or on its
+//! completion. Each URB is converted to packets as described in
+//! [crate::urb]. Control transfers are recorded as a separate record for
+//! each stage, which are gathered until the transfer completes.
+
+use std::collections::HashMap;
+
+use pcap::Linktype;
+
+use crate::urb::{
+    RecordImporter,
+    UrbConverter,
+    UrbEvent,
+    UrbHeader,
+    UrbStatus,
+    CONTROL,
+};
+
+/// Link type of USBPcap captures.
+pub const LINKTYPE_USBPCAP: i32 = 249;
+
+/// Length of the pseudo-header common to all transfer types.
+const HEADER_LENGTH: usize = 27;
+
+/// Bit of the info field set for records of completed requests.
+const INFO_COMPLETION: u8 = 0x01;
+
+const STAGE_SETUP: u8 = 0;
+const STAGE_DATA: u8 = 1;
+
+const USBD_STATUS_SUCCESS: u32 = 0;
+const USBD_STATUS_STALL_PID: u32 = 0xC0000004;
+const USBD_STATUS_ENDPOINT_HALTED: u32 = 0xC0000030;
+
+/// The stages of a control transfer seen so far.
+#[derive(Default)]
+struct ControlStages {
+    setup: Option<[u8; 8]>,
+    out_data: Vec<u8>,
+    in_data: Vec<u8>,
+}
+
+/// Converts USBPcap records into the packets which carried them.
+#[derive(Default)]
+pub struct UsbpcapImporter {
+    converter: UrbConverter,
+    /// Control transfers in progress, by request ID.
+    controls: HashMap<u64, ControlStages>,
+}
+
+impl UsbpcapImporter {
+    /// Create an importer for a capture with the given link type, if it
+    /// is a USBPcap capture.
+    pub fn new(linktype: Linktype) -> Option<UsbpcapImporter> {
+        match linktype.0 {
+            LINKTYPE_USBPCAP => Some(UsbpcapImporter::default()),
+            _ => None,
+        }
+    }
+}
+
+impl RecordImporter for UsbpcapImporter {
+    fn packets(&mut self, record: &[u8]) -> Vec<Vec<u8>> {
+        if record.len() < HEADER_LENGTH {
+            return Vec::new();
+        }
+        let header_length =
+            u16::from_le_bytes([record[0], record[1]]) as usize;
+        let id = u64::from_le_bytes(record[2..10].try_into().unwrap());
+        let status = u32::from_le_bytes(record[10..14].try_into().unwrap());
+        let completion = record[16] & INFO_COMPLETION != 0;
+        let device = u16::from_le_bytes([record[19], record[20]]) as u8;
+        let mut header = UrbHeader {
+            id: id,
+            event: if completion {
+                UrbEvent::Completion
+            } else {
+                UrbEvent::Submission
+            },
+            transfer_type: record[22],
+            endpoint: record[21],
+            device: device & 0x7F,
+            status: match status {
+                USBD_STATUS_SUCCESS => UrbStatus::Success,
+                USBD_STATUS_STALL_PID | USBD_STATUS_ENDPOINT_HALTED =>
+                    UrbStatus::Stalled,
+                _ => UrbStatus::Failed,
+            },
+            setup: None,
+        };
+        let data = record.get(header_length..).unwrap_or(&[]);
+        if header.transfer_type != CONTROL {
+            return self.converter.packets(header, data);
+        }
+        let stage = match record.get(HEADER_LENGTH) {
+            Some(stage) => *stage,
+            None => return Vec::new(),
+        };
+        let stages = self.controls.entry(id).or_default();
+        match (completion, stage) {
+            (false, STAGE_SETUP) => {
+                stages.setup = data.get(..8)
+                    .map(|setup| setup.try_into().unwrap());
+                Vec::new()
+            },
+            (false, STAGE_DATA) => {
+                stages.out_data.extend_from_slice(data);
+                Vec::new()
+            },
+            (true, STAGE_DATA) => {
+                stages.in_data.extend_from_slice(data);
+                Vec::new()
+            },
+            (false, _) => Vec::new(),
+            // The status or completion stage ends the transfer.
+            (true, _) => {
+                let mut stages = self.controls.remove(&id).unwrap();
+                stages.in_data.extend_from_slice(data);
+                let submission = UrbHeader {
+                    id: id,
+                    event: UrbEvent::Submission,
+                    transfer_type: CONTROL,
+                    endpoint: header.endpoint,
+                    device: header.device,
+                    status: UrbStatus::InProgress,
+                    setup: stages.setup,
+                };
+                self.converter.packets(submission, &stages.out_data);
+                header.setup = stages.setup;
+                self.converter.packets(header, &stages.in_data)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{Capture, Item};
+    use crate::decoder::Decoder;
+    use crate::input::decode_files;
+
+    fn record(id: u64, completion: bool, transfer_type: u8, endpoint: u8,
+              status: u32, stage: Option<u8>, data: &[u8]) -> Vec<u8>
+    {
+        let mut record = vec![0; HEADER_LENGTH];
+        let header_length = HEADER_LENGTH + stage.iter().count();
+        record[0..2].copy_from_slice(&(header_length as u16).to_le_bytes());
+        record[2..10].copy_from_slice(&id.to_le_bytes());
+        record[10..14].copy_from_slice(&status.to_le_bytes());
+        record[16] = completion as u8;
+        record[17..19].copy_from_slice(&1u16.to_le_bytes());
+        record[19..21].copy_from_slice(&3u16.to_le_bytes());
+        record[21] = endpoint;
+        record[22] = transfer_type;
+        record[23..27].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend(stage);
+        record.extend_from_slice(data);
+        record
+    }
+
+    #[test]
+    fn test_usbpcap_import() {
+        let get_device = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00];
+        let descriptor = [18, 1, 0, 2, 0, 0, 0, 64, 0x50, 0x1d, 0x4b, 0x60,
+                          0, 1, 1, 2, 3, 1];
+        let records = [
+            record(1, false, 2, 0x80, 0, Some(STAGE_SETUP), &get_device),
+            record(1, true, 2, 0x80, 0, Some(STAGE_DATA), &descriptor),
+            record(1, true, 2, 0x80, 0, Some(2), &[]),
+            record(2, false, 3, 0x02, 0, None, &[1, 2, 3]),
+            record(2, true, 3, 0x02, 0, None, &[]),
+            record(3, false, 3, 0x81, 0, None, &[]),
+            record(3, true, 3, 0x81, USBD_STATUS_STALL_PID, None, &[]),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("usbpcap.pcap");
+        let mut file = vec![];
+        for word in [0xA1B2C3D4, 0x00040002, 0, 0, 65535,
+                     LINKTYPE_USBPCAP as u32]
+        {
+            file.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        for record in &records {
+            let length = record.len() as u32;
+            for word in [0, 0, length, length] {
+                file.extend_from_slice(&u32::to_le_bytes(word));
+            }
+            file.extend_from_slice(record);
+        }
+        std::fs::write(&path, file).unwrap();
+        let mut cap = Capture::new();
+        let mut decoder = Decoder::new(&mut cap);
+        decode_files(&mut decoder, &[path.to_str().unwrap()]).unwrap();
+        drop(decoder);
+        // Three control stages, then OUT and a stalled IN.
+        assert!(cap.transaction_index.len() == 5);
+        let control = cap.get_item(&None, 0);
+        assert!(cap.get_summary(&control) ==
+                "Getting device descriptor #0 for device 3, \
+                 reading 18 bytes");
+        assert!(cap.device_data[1].device_descriptor.is_some());
+        let transfers: Vec<Item> = (0..cap.item_index.len())
+            .map(|i| cap.get_item(&None, i))
+            .collect();
+        let out = cap.get_child(&transfers[1], 0);
+        assert!(cap.get_summary(&out) ==
+                "OUT transaction, 3 packets with 3 data bytes");
+    }
+}
@@ -0,0 +1,321 @@
+//! Consistency checks on decoded captures, for catching indexing bugs.
+
+use std::ops::Range;
+
+use thiserror::Error;
+
+use crate::capture::Capture;
+use crate::decoder::Decoder;
+use crate::file_vec::{FileVec, FileVecError};
+use crate::hybrid_index::{HybridIndex, HybridIndexError};
+use crate::input::{decode_files, InputError};
+use crate::record::Record;
+
+#[derive(Error, Debug)]
+pub enum VerifyError {
+    #[error(transparent)]
+    InputError(#[from] InputError),
+    #[error(transparent)]
+    IndexError(#[from] HybridIndexError),
+    #[error(transparent)]
+    VecError(#[from] FileVecError),
+    #[error("{name}[{index}] = {value} is less than the previous value {previous}")]
+    NotMonotonic { name: String, index: u64, value: u64, previous: u64 },
+    #[error("{name}[{index}] = {value} is out of bounds, limit is {limit}")]
+    OutOfBounds { name: String, index: u64, value: u64, limit: u64 },
+    #[error("{name} has {actual} entries, expected {expected}")]
+    LengthMismatch { name: String, actual: u64, expected: u64 },
+    #[error("{name} differs between decodes at index {index}")]
+    Nondeterministic { name: String, index: u64 },
+}
+
+/// Check that an index is non-decreasing, and that every value in it lies
+/// within `0..=limit`, so that ranges formed from adjacent values are valid.
+fn check_index(name: &str, index: &mut HybridIndex, limit: u64)
+    -> Result<Vec<u64>, VerifyError>
+{
+    let values = index.get_range(0..index.len())?;
+    let mut previous = 0;
+    for (i, &value) in values.iter().enumerate() {
+        if value < previous {
+            return Err(VerifyError::NotMonotonic {
+                name: name.to_string(),
+                index: i as u64,
+                value: value,
+                previous: previous,
+            });
+        }
+        if value > limit {
+            return Err(VerifyError::OutOfBounds {
+                name: name.to_string(),
+                index: i as u64,
+                value: value,
+                limit: limit,
+            });
+        }
+        previous = value;
+    }
+    Ok(values)
+}
+
+fn check_bound(name: &str, index: u64, value: u64, range: Range<u64>)
+    -> Result<(), VerifyError>
+{
+    if range.contains(&value) {
+        Ok(())
+    } else {
+        Err(VerifyError::OutOfBounds {
+            name: name.to_string(),
+            index: index,
+            value: value,
+            limit: range.end,
+        })
+    }
+}
+
+fn check_length(name: &str, actual: u64, expected: u64)
+    -> Result<(), VerifyError>
+{
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(VerifyError::LengthMismatch {
+            name: name.to_string(),
+            actual: actual,
+            expected: expected,
+        })
+    }
+}
+
+/// Check all indices of a capture for internal consistency.
+pub fn check(cap: &mut Capture) -> Result<(), VerifyError> {
+    check_index("packet_index", &mut cap.packet_index,
+                cap.packet_data.len())?;
+    check_index("transaction_index", &mut cap.transaction_index,
+                cap.packet_index.len())?;
+    if cap.packet_times.len() != 0 {
+        check_length("packet_times", cap.packet_times.len(),
+                     cap.packet_index.len())?;
+    }
+
+    check_length("retry_index", cap.retry_index.len(),
+                 cap.transaction_index.len())?;
+    check_index("retry_index", &mut cap.retry_index, cap.retry_ids.len())?;
+    let retry_ids = cap.retry_ids.get_range(0..cap.retry_ids.len())?;
+    for (i, &retry_id) in retry_ids.iter().enumerate() {
+        check_bound("retry_ids", i as u64,
+                    retry_id, 0..cap.transaction_index.len())?;
+    }
+
+    let endpoint_count = cap.endpoints.len();
+    check_length("endpoint_traffic",
+                 cap.endpoint_traffic.len() as u64, endpoint_count)?;
+    check_length("device_data",
+                 cap.device_data.len() as u64, cap.devices.len())?;
+    let endpoints = cap.endpoints.get_range(0..endpoint_count)?;
+    for (i, endpoint) in endpoints.iter().enumerate() {
+        check_bound("endpoints.device_id", i as u64,
+                    endpoint.device_id(), 0..cap.devices.len())?;
+    }
+
+    let transaction_count = cap.transaction_index.len();
+    for i in 0..cap.endpoint_traffic.len() {
+        let ep_traf = &mut cap.endpoint_traffic[i];
+        let name = format!("endpoint_traffic[{}].transaction_ids", i);
+        let transaction_ids = check_index(
+            &name, &mut ep_traf.transaction_ids, transaction_count)?;
+        // Each transaction belongs to one endpoint, so must appear only once.
+        for (j, pair) in transaction_ids.windows(2).enumerate() {
+            if pair[1] == pair[0] {
+                return Err(VerifyError::NotMonotonic {
+                    name: name,
+                    index: j as u64 + 1,
+                    value: pair[1],
+                    previous: pair[0],
+                });
+            }
+        }
+        if let Some(&last) = transaction_ids.last() {
+            check_bound(&name, transaction_ids.len() as u64 - 1,
+                        last, 0..transaction_count)?;
+        }
+        let name = format!("endpoint_traffic[{}].transfer_index", i);
+        check_index(&name, &mut ep_traf.transfer_index,
+                    ep_traf.transaction_ids.len())?;
+    }
+
+    let entry_count = cap.transfer_index.len();
+    let entries = cap.transfer_index.get_range(0..entry_count)?;
+    let mut started = vec![0; endpoint_count as usize];
+    for (i, entry) in entries.iter().enumerate() {
+        let i = i as u64;
+        let endpoint_id = entry.endpoint_id() as u64;
+        check_bound("transfer_index.endpoint_id", i,
+                    endpoint_id, 0..endpoint_count)?;
+        let transfer_count = &mut started[endpoint_id as usize];
+        if entry.is_start() {
+            check_bound("transfer_index.transfer_id", i, entry.transfer_id(),
+                        *transfer_count..(*transfer_count + 1))?;
+            *transfer_count += 1;
+        } else {
+            check_bound("transfer_index.transfer_id", i, entry.transfer_id(),
+                        0..(*transfer_count + 1))?;
+        }
+    }
+    for (i, ep_traf) in cap.endpoint_traffic.iter().enumerate() {
+        check_length(&format!("endpoint_traffic[{}].transfer_index", i),
+                     ep_traf.transfer_index.len(), started[i])?;
+    }
+
+    let items = check_index("item_index", &mut cap.item_index,
+                            entry_count)?;
+    if let Some(&last) = items.last() {
+        check_bound("item_index", items.len() as u64 - 1,
+                    last, 0..entry_count)?;
+    }
+
+    check_length("endpoint_first_entry",
+                 cap.endpoint_first_entry.len() as u64, endpoint_count)?;
+    for (i, pair) in cap.endpoint_first_entry.windows(2).enumerate() {
+        if pair[1] < pair[0] {
+            return Err(VerifyError::NotMonotonic {
+                name: "endpoint_first_entry".to_string(),
+                index: i as u64 + 1,
+                value: pair[1],
+                previous: pair[0],
+            });
+        }
+    }
+    check_length("endpoint_state_index",
+                 cap.endpoint_state_index.len(), entry_count)?;
+    check_index("endpoint_state_index", &mut cap.endpoint_state_index,
+                cap.endpoint_states.len())?;
+
+    Ok(())
+}
+
+fn compare_values<T: PartialEq>(name: &str, a: &[T], b: &[T])
+    -> Result<(), VerifyError>
+{
+    check_length(name, b.len() as u64, a.len() as u64)?;
+    match a.iter().zip(b.iter()).position(|(x, y)| x != y) {
+        Some(index) => Err(VerifyError::Nondeterministic {
+            name: name.to_string(),
+            index: index as u64,
+        }),
+        None => Ok(())
+    }
+}
+
+fn compare_index(name: &str, a: &mut HybridIndex, b: &mut HybridIndex)
+    -> Result<(), VerifyError>
+{
+    check_length(name, b.len(), a.len())?;
+    let a_values = a.get_range(0..a.len())?;
+    let b_values = b.get_range(0..b.len())?;
+    compare_values(name, &a_values, &b_values)
+}
+
+fn compare_vec<T: Record + PartialEq>(name: &str,
+                                      a: &mut FileVec<T>,
+                                      b: &mut FileVec<T>)
+    -> Result<(), VerifyError>
+{
+    check_length(name, b.len(), a.len())?;
+    let a_values = a.get_range(0..a.len())?;
+    let b_values = b.get_range(0..b.len())?;
+    compare_values(name, &a_values, &b_values)
+}
+
+/// Check that two decodes of the same input produced identical indices.
+pub fn compare(a: &mut Capture, b: &mut Capture) -> Result<(), VerifyError> {
+    compare_index("packet_index", &mut a.packet_index, &mut b.packet_index)?;
+    compare_vec("packet_times", &mut a.packet_times, &mut b.packet_times)?;
+    compare_index("transaction_index",
+                  &mut a.transaction_index, &mut b.transaction_index)?;
+    compare_index("retry_index", &mut a.retry_index, &mut b.retry_index)?;
+    compare_vec("retry_ids", &mut a.retry_ids, &mut b.retry_ids)?;
+    compare_index("item_index", &mut a.item_index, &mut b.item_index)?;
+    compare_index("endpoint_state_index",
+                  &mut a.endpoint_state_index, &mut b.endpoint_state_index)?;
+    check_length("endpoint_traffic",
+                 b.endpoint_traffic.len() as u64,
+                 a.endpoint_traffic.len() as u64)?;
+    for i in 0..a.endpoint_traffic.len() {
+        let (a_traf, b_traf) =
+            (&mut a.endpoint_traffic[i], &mut b.endpoint_traffic[i]);
+        compare_index(&format!("endpoint_traffic[{}].transaction_ids", i),
+                      &mut a_traf.transaction_ids,
+                      &mut b_traf.transaction_ids)?;
+        compare_index(&format!("endpoint_traffic[{}].transfer_index", i),
+                      &mut a_traf.transfer_index,
+                      &mut b_traf.transfer_index)?;
+    }
+    check_length("transfer_index",
+                 b.transfer_index.len(), a.transfer_index.len())?;
+    let count = a.transfer_index.len();
+    let a_entries = a.transfer_index.get_range(0..count)?;
+    let b_entries = b.transfer_index.get_range(0..count)?;
+    for (i, (x, y)) in a_entries.iter().zip(b_entries.iter()).enumerate() {
+        if x.0 != y.0 {
+            return Err(VerifyError::Nondeterministic {
+                name: "transfer_index".to_string(),
+                index: i as u64,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn decode<S: AsRef<str>>(filenames: &[S]) -> Result<Capture, VerifyError> {
+    let mut cap = Capture::new();
+    let mut decoder = Decoder::new(&mut cap);
+    decode_files(&mut decoder, filenames)?;
+    drop(decoder);
+    Ok(cap)
+}
+
+/// Decode files twice, checking each result and comparing them.
+pub fn verify_files<S: AsRef<str>>(filenames: &[S])
+    -> Result<(), VerifyError>
+{
+    let mut first = decode(filenames)?;
+    check(&mut first)?;
+    let mut second = decode(filenames)?;
+    check(&mut second)?;
+    compare(&mut first, &mut second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::read_dir;
+
+    #[test]
+    fn test_verify_captures() {
+        for result in read_dir("./tests/").unwrap() {
+            let entry = result.unwrap();
+            let mut cap_path = entry.path();
+            cap_path.push("capture.pcap");
+            verify_files(&[cap_path.to_str().unwrap()]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_detect_corruption() {
+        let mut cap = decode(&["./tests/mouse/capture.pcap"]).unwrap();
+        let transaction_count = cap.transaction_index.len();
+        cap.endpoint_traffic[0].transaction_ids
+            .push(transaction_count + 1).unwrap();
+        assert!(matches!(check(&mut cap),
+                         Err(VerifyError::OutOfBounds { .. })));
+
+        let mut a = decode(&["./tests/mouse/capture.pcap"]).unwrap();
+        let mut b = decode(&["./tests/mouse/capture.pcap"]).unwrap();
+        let last = b.packet_index.get(b.packet_index.len() - 1).unwrap();
+        a.packet_index.push(last + 1).unwrap();
+        b.packet_index.push(last + 2).unwrap();
+        assert!(matches!(compare(&mut a, &mut b),
+                         Err(VerifyError::Nondeterministic { .. })));
+    }
+}
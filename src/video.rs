@@ -0,0 +1,471 @@
+//! Export of USB Video Class streams as image files.
+//!
+//! Each video payload starts with a header, whose frame ID bit toggles at
+//! the start of each new frame, and which may also flag the end of a frame
+//! or an error in it. Payloads are isochronous transactions, or for bulk
+//! endpoints, runs of transactions ending with a short packet. Complete
+//! frames are reassembled from the payloads, then written out as JPEG
+//! files for MJPEG streams, or converted to PPM files for uncompressed
+//! YUY2 and NV12 streams.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::capture::Capture;
+use crate::usb::{PID, Recipient, RequestType};
+
+const VIDEO_CLASS: u8 = 0x0E;
+const VIDEO_STREAMING: u8 = 0x02;
+
+const CS_INTERFACE: u8 = 0x24;
+const VS_FORMAT_UNCOMPRESSED: u8 = 0x04;
+const VS_FRAME_UNCOMPRESSED: u8 = 0x05;
+const VS_FORMAT_MJPEG: u8 = 0x06;
+const VS_FRAME_MJPEG: u8 = 0x07;
+
+const SET_CUR: u8 = 0x01;
+const VS_COMMIT_CONTROL: u8 = 0x02;
+
+const HEADER_FID: u8 = 0x01;
+const HEADER_EOF: u8 = 0x02;
+const HEADER_ERR: u8 = 0x40;
+
+#[derive(Error, Debug)]
+pub enum VideoError {
+    #[error("no video streams found in the capture")]
+    NoStreams,
+    #[error("no complete video frames found in the capture")]
+    NoFrames,
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// How the pixels of a frame are encoded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Encoding {
+    Mjpeg,
+    Uncompressed { fourcc: [u8; 4], bits_per_pixel: u8 },
+}
+
+/// A video format, with the frame sizes offered for it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoFormat {
+    pub index: u8,
+    pub encoding: Encoding,
+    /// Frame index, width and height of each frame size.
+    pub frames: Vec<(u8, u16, u16)>,
+}
+
+impl VideoFormat {
+    /// Read the formats from the class-specific descriptors of a video
+    /// streaming interface. Frame descriptors follow their format.
+    pub fn from_descriptors(descriptors: &[Vec<u8>]) -> Vec<VideoFormat> {
+        let mut formats: Vec<VideoFormat> = Vec::new();
+        for desc in descriptors {
+            if desc.len() < 4 || desc[1] != CS_INTERFACE {
+                continue;
+            }
+            match desc[2] {
+                VS_FORMAT_MJPEG => formats.push(VideoFormat {
+                    index: desc[3],
+                    encoding: Encoding::Mjpeg,
+                    frames: Vec::new(),
+                }),
+                VS_FORMAT_UNCOMPRESSED if desc.len() >= 22 =>
+                    formats.push(VideoFormat {
+                        index: desc[3],
+                        encoding: Encoding::Uncompressed {
+                            fourcc: desc[5..9].try_into().unwrap(),
+                            bits_per_pixel: desc[21],
+                        },
+                        frames: Vec::new(),
+                    }),
+                VS_FRAME_MJPEG | VS_FRAME_UNCOMPRESSED if desc.len() >= 9 => {
+                    if let Some(format) = formats.last_mut() {
+                        format.frames.push((
+                            desc[3],
+                            u16::from_le_bytes([desc[5], desc[6]]),
+                            u16::from_le_bytes([desc[7], desc[8]])));
+                    }
+                },
+                _ => {}
+            }
+        }
+        formats
+    }
+}
+
+/// A video stream from a streaming interface.
+pub struct VideoStream {
+    pub device_id: u64,
+    pub address: u8,
+    pub interface_number: u8,
+    pub endpoint_address: u8,
+    pub bulk: bool,
+    pub max_packet_size: u16,
+    pub encoding: Encoding,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl VideoStream {
+    pub fn summary(&self) -> String {
+        format!("Device {}, interface {}: {}x{} {}",
+            self.address, self.interface_number, self.width, self.height,
+            match self.encoding {
+                Encoding::Mjpeg => "MJPEG".to_string(),
+                Encoding::Uncompressed { fourcc, .. } =>
+                    String::from_utf8_lossy(&fourcc).to_string(),
+            })
+    }
+
+    /// Split the data on the stream's endpoint into payloads, which are
+    /// read from the capture as they are taken.
+    fn payloads<'c>(&self, cap: &'c mut Capture)
+        -> impl Iterator<Item=Vec<u8>> + 'c
+    {
+        let address = self.endpoint_address;
+        let bulk = self.bulk;
+        let max_packet_size = self.max_packet_size as usize;
+        let mut current = Vec::new();
+        cap.get_device_transaction_ids(self.device_id, &[address])
+            .into_iter()
+            .filter_map(move |id| {
+                let data = match cap.get_payload(id) {
+                    Some((PID::IN, data)) => data,
+                    _ => return None,
+                };
+                if !bulk {
+                    return Some(data);
+                }
+                // A bulk payload ends with a short packet.
+                let short = data.len() < max_packet_size;
+                current.extend(data);
+                if short {
+                    Some(std::mem::take(&mut current))
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+/// Reassemble frames from video payloads, dropping any frames which were
+/// flagged as having errors or were not seen complete. Frames are given as
+/// the payloads which complete them are taken.
+pub fn reassemble_frames<I>(payloads: I) -> impl Iterator<Item=Vec<u8>>
+    where I: IntoIterator<Item=Vec<u8>>
+{
+    let mut current = Vec::new();
+    let mut frame_id = None;
+    let mut error = false;
+    // The first frame may have started before the capture.
+    let mut started = false;
+    payloads.into_iter().flat_map(move |payload| {
+        let mut frames = Vec::new();
+        let header_length = match payload.first() {
+            Some(length) if *length >= 2 &&
+                (*length as usize) <= payload.len() => *length as usize,
+            _ => return frames,
+        };
+        let info = payload[1];
+        let this_id = info & HEADER_FID;
+        if frame_id.is_some() && frame_id != Some(this_id) {
+            if started && !error && !current.is_empty() {
+                frames.push(std::mem::take(&mut current));
+            }
+            current.clear();
+            error = false;
+            started = true;
+        }
+        frame_id = Some(this_id);
+        error |= info & HEADER_ERR != 0;
+        current.extend(&payload[header_length..]);
+        if info & HEADER_EOF != 0 {
+            if started && !error && !current.is_empty() {
+                frames.push(std::mem::take(&mut current));
+            }
+            current.clear();
+            error = false;
+            started = true;
+            // The next payload starts a new frame whatever its ID.
+            frame_id = None;
+        }
+        frames
+    })
+}
+
+/// Find the video streams in the capture.
+///
+/// The format and frame size used are those last committed by a
+/// VS_COMMIT_CONTROL request, or otherwise the first offered.
+pub fn find_video_streams(cap: &mut Capture) -> Vec<VideoStream> {
+    profile!("find_video_streams");
+    // Committed format and frame indices, by device and interface.
+    let mut commits = BTreeMap::new();
+    for item_index in 0..cap.item_index.len() {
+        let transfer_index_id = cap.item_index.get(item_index).unwrap();
+        let transfer = match cap.control_transfer(transfer_index_id) {
+            Some(transfer) => transfer,
+            None => continue,
+        };
+        let fields = &transfer.fields;
+        let is_commit =
+            matches!(fields.type_fields.request_type(), RequestType::Class)
+            && matches!(fields.type_fields.recipient(), Recipient::Interface)
+            && fields.request == SET_CUR
+            && (fields.value >> 8) as u8 == VS_COMMIT_CONTROL
+            && transfer.data.len() >= 4;
+        if is_commit {
+            let entry = cap.transfer_index.get(transfer_index_id).unwrap();
+            let endpoint =
+                cap.endpoints.get(entry.endpoint_id() as u64).unwrap();
+            let key = (endpoint.device_id(), fields.index as u8);
+            commits.insert(key, (transfer.data[2], transfer.data[3]));
+        }
+    }
+    let mut streams = Vec::new();
+    for device_id in 0..cap.device_data.len() {
+        let config = match cap.device_data[device_id].configuration() {
+            Some(config) => config,
+            None => continue,
+        };
+        let address = cap.devices.get(device_id as u64).unwrap().address;
+        // Descriptors and endpoints of each streaming interface, from all
+        // of its alternate settings.
+        let mut interfaces = BTreeMap::new();
+        for iface in &config.interfaces {
+            let desc = &iface.descriptor;
+            if desc.interface_class != VIDEO_CLASS ||
+                desc.interface_subclass != VIDEO_STREAMING
+            {
+                continue;
+            }
+            let (descriptors, endpoints) = interfaces
+                .entry(desc.interface_number)
+                .or_insert_with(|| (Vec::new(), Vec::new()));
+            descriptors.extend(iface.other_descriptors.iter().cloned());
+            endpoints.extend(iface.endpoint_descriptors.iter()
+                .filter(|ep_desc| ep_desc.endpoint_address & 0x80 != 0)
+                .cloned());
+        }
+        for (number, (descriptors, endpoints)) in interfaces {
+            let formats = VideoFormat::from_descriptors(&descriptors);
+            let endpoint = match endpoints.first() {
+                Some(endpoint) => endpoint,
+                None => continue,
+            };
+            let commit = commits.get(&(device_id as u64, number));
+            let format = match commit {
+                Some((format_index, _)) =>
+                    formats.iter().find(|f| f.index == *format_index),
+                None => formats.first(),
+            };
+            let format = match format {
+                Some(format) => format,
+                None => continue,
+            };
+            let frame = match commit {
+                Some((_, frame_index)) =>
+                    format.frames.iter().find(|f| f.0 == *frame_index),
+                None => format.frames.first(),
+            };
+            let (_, width, height) = match frame {
+                Some(frame) => *frame,
+                None => continue,
+            };
+            streams.push(VideoStream {
+                device_id: device_id as u64,
+                address: address,
+                interface_number: number,
+                endpoint_address: endpoint.endpoint_address,
+                bulk: endpoint.attributes & 0x03 == 0x02,
+                max_packet_size: endpoint.max_packet_size & 0x7FF,
+                encoding: format.encoding,
+                width: width,
+                height: height,
+            });
+        }
+    }
+    streams
+}
+
+fn clamp(value: i32) -> u8 {
+    value.clamp(0, 255) as u8
+}
+
+/// Convert a YUV pixel to RGB, using the BT.601 coefficients.
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    [clamp((c + 409 * e + 128) >> 8),
+     clamp((c - 100 * d - 208 * e + 128) >> 8),
+     clamp((c + 516 * d + 128) >> 8)]
+}
+
+/// Convert an uncompressed frame to RGB, if its format is supported and
+/// it has the expected size.
+pub fn frame_to_rgb(fourcc: &[u8; 4], width: usize, height: usize,
+                    frame: &[u8]) -> Option<Vec<u8>>
+{
+    let pixels = width * height;
+    let mut rgb = Vec::with_capacity(pixels * 3);
+    match fourcc {
+        b"YUY2" if frame.len() == pixels * 2 => {
+            for chunk in frame.chunks_exact(4) {
+                let (y0, u, y1, v) = (chunk[0], chunk[1], chunk[2], chunk[3]);
+                rgb.extend(yuv_to_rgb(y0, u, v));
+                rgb.extend(yuv_to_rgb(y1, u, v));
+            }
+        },
+        b"NV12" if frame.len() == pixels * 3 / 2 => {
+            let (luma, chroma) = frame.split_at(pixels);
+            for row in 0..height {
+                for column in 0..width {
+                    let uv = (row / 2) * width + (column / 2) * 2;
+                    rgb.extend(yuv_to_rgb(luma[row * width + column],
+                                          chroma[uv], chroma[uv + 1]));
+                }
+            }
+        },
+        _ => return None,
+    }
+    Some(rgb)
+}
+
+/// Write an RGB image as a binary PPM file.
+fn write_ppm<W: Write>(output: &mut W, width: u16, height: u16, rgb: &[u8])
+    -> std::io::Result<()>
+{
+    write!(output, "P6\n{} {}\n255\n", width, height)?;
+    output.write_all(rgb)
+}
+
+/// Export the complete frames of each video stream in the capture to
+/// numbered image files in a directory. Returns a summary of each stream
+/// exported, with the number of frames written.
+pub fn export_frames(cap: &mut Capture, directory: &Path)
+    -> Result<Vec<(String, usize)>, VideoError>
+{
+    let streams = find_video_streams(cap);
+    if streams.is_empty() {
+        return Err(VideoError::NoStreams);
+    }
+    let mut exported = Vec::new();
+    for stream in streams {
+        let frames = reassemble_frames(stream.payloads(cap));
+        let mut count = 0;
+        for frame in frames {
+            let name = format!("device{}-interface{}-frame{:04}",
+                               stream.address, stream.interface_number,
+                               count + 1);
+            let mut path = PathBuf::from(directory);
+            match stream.encoding {
+                Encoding::Mjpeg => {
+                    path.push(format!("{}.jpg", name));
+                    File::create(&path)?.write_all(&frame)?;
+                },
+                Encoding::Uncompressed { fourcc, .. } => {
+                    let rgb = match frame_to_rgb(
+                        &fourcc, stream.width as usize,
+                        stream.height as usize, &frame)
+                    {
+                        Some(rgb) => rgb,
+                        None => continue,
+                    };
+                    path.push(format!("{}.ppm", name));
+                    let mut output = BufWriter::new(File::create(&path)?);
+                    write_ppm(&mut output, stream.width, stream.height, &rgb)?;
+                    output.flush()?;
+                }
+            }
+            count += 1;
+        }
+        if count > 0 {
+            exported.push((stream.summary(), count));
+        }
+    }
+    if exported.is_empty() {
+        return Err(VideoError::NoFrames);
+    }
+    Ok(exported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_formats() {
+        let mut uncompressed = vec![0x1B, 0x24, 0x04, 0x02, 0x01];
+        uncompressed.extend(b"YUY2");
+        uncompressed.extend([0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA,
+                             0x00, 0x38, 0x9B, 0x71, 0x10, 0x01, 0x00, 0x00,
+                             0x00, 0x00]);
+        let descriptors = vec![
+            vec![0x0B, 0x24, 0x06, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+                 0x00],
+            vec![0x1E, 0x24, 0x07, 0x01, 0x00, 0x80, 0x02, 0xE0, 0x01],
+            vec![0x1E, 0x24, 0x07, 0x02, 0x00, 0x40, 0x01, 0xF0, 0x00],
+            uncompressed,
+            vec![0x1E, 0x24, 0x05, 0x01, 0x00, 0xA0, 0x00, 0x78, 0x00],
+        ];
+        let formats = VideoFormat::from_descriptors(&descriptors);
+        assert!(formats == vec![
+            VideoFormat {
+                index: 1,
+                encoding: Encoding::Mjpeg,
+                frames: vec![(1, 640, 480), (2, 320, 240)],
+            },
+            VideoFormat {
+                index: 2,
+                encoding: Encoding::Uncompressed {
+                    fourcc: *b"YUY2",
+                    bits_per_pixel: 16,
+                },
+                frames: vec![(1, 160, 120)],
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_reassemble_frames() {
+        let payloads = vec![
+            // End of a frame that started before the capture.
+            vec![2, 0x80, 9, 9],
+            vec![2, 0x81, 1, 2],
+            vec![2, 0x81, 3],
+            // Frame ID toggles with no end of frame flag.
+            vec![2, 0x80, 4, 5],
+            vec![2, 0x82, 6],
+            // Frame with an error.
+            vec![2, 0x81, 7],
+            vec![2, 0xC3, 8],
+            // Header only, then an invalid payload.
+            vec![12, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10],
+            vec![],
+            vec![2, 0x82, 11],
+            // Incomplete frame at the end.
+            vec![2, 0x81, 12],
+        ];
+        assert!(reassemble_frames(payloads).collect::<Vec<_>>() ==
+                vec![vec![1, 2, 3], vec![4, 5, 6], vec![10, 11]]);
+    }
+
+    #[test]
+    fn test_frame_to_rgb() {
+        let yuy2 = [235, 128, 16, 128];
+        assert!(frame_to_rgb(b"YUY2", 2, 1, &yuy2) ==
+                Some(vec![255, 255, 255, 0, 0, 0]));
+        assert!(frame_to_rgb(b"YUY2", 2, 2, &yuy2).is_none());
+        let nv12 = [81, 81, 81, 81, 90, 240];
+        assert!(frame_to_rgb(b"NV12", 2, 2, &nv12) ==
+                Some([255, 0, 0].repeat(4)));
+        assert!(frame_to_rgb(b"I420", 2, 2, &nv12).is_none());
+    }
+}
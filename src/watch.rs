@@ -0,0 +1,98 @@
+//! Watching a directory for new captures, such as those written by the
+//! machines of a hardware test farm, to analyze each as it arrives.
+//!
+//! The directory is polled, rather than watched for events, so that it may
+//! be on a network share. A capture is only taken once its size has stayed
+//! the same between two polls, so that one still being written is not read
+//! part of the way through.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::batch::{find_captures, Action, Step};
+use crate::filter::Filter;
+
+/// Time between checks of the directory for new captures.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Name of the file listing the warnings found in a flagged capture.
+pub const FLAGGED_PATH: &str = "{name}.flagged.txt";
+
+/// Steps run on each capture, writing its summary and decode report.
+pub fn watch_steps() -> Vec<Step> {
+    [Action::Summary("{name}.summary.txt".to_string()),
+     Action::Report("{name}.report.txt".to_string())]
+        .into_iter()
+        .map(|action| Step {
+            action: action,
+            filter: Filter::default(),
+        })
+        .collect()
+}
+
+/// Finds the captures in a directory which are ready to be analyzed.
+pub struct Watcher {
+    dir: PathBuf,
+    /// Sizes of the captures not yet taken, when last polled.
+    sizes: HashMap<PathBuf, u64>,
+    taken: HashSet<PathBuf>,
+}
+
+impl Watcher {
+    pub fn new(dir: &Path) -> Watcher {
+        Watcher {
+            dir: dir.to_path_buf(),
+            sizes: HashMap::new(),
+            taken: HashSet::new(),
+        }
+    }
+
+    /// Check the directory, returning the captures which have become ready
+    /// since the last poll, in name order.
+    pub fn poll(&mut self) -> std::io::Result<Vec<PathBuf>> {
+        let mut ready = Vec::new();
+        let mut sizes = HashMap::new();
+        for path in find_captures(&self.dir)? {
+            if self.taken.contains(&path) {
+                continue;
+            }
+            // A file may be removed between listing and reading it.
+            let size = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            if size > 0 && self.sizes.get(&path) == Some(&size) {
+                self.taken.insert(path.clone());
+                ready.push(path);
+            } else {
+                sizes.insert(path, size);
+            }
+        }
+        self.sizes = sizes;
+        Ok(ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watcher() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        let mut watcher = Watcher::new(dir.path());
+        std::fs::write(path("a.pcap"), "1").unwrap();
+        std::fs::write(path("b.pcap"), "").unwrap();
+        std::fs::write(path("notes.txt"), "1").unwrap();
+        assert!(watcher.poll().unwrap().is_empty());
+        std::fs::write(path("b.pcap"), "12").unwrap();
+        assert!(watcher.poll().unwrap() == [path("a.pcap")]);
+        assert!(watcher.poll().unwrap() == [path("b.pcap")]);
+        // Captures are only taken once, even if they change afterwards.
+        std::fs::write(path("a.pcap"), "123").unwrap();
+        assert!(watcher.poll().unwrap().is_empty());
+        assert!(watcher.poll().unwrap().is_empty());
+    }
+}